@@ -0,0 +1,333 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	env,
+	hash::Hash,
+	sync::{Arc, Mutex as StdMutex},
+	time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+/// Interval of dropping expired entries, so their memory is reclaimed even if they are never read again.
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a webhook delivery is remembered. GitHub redelivers failed deliveries only on request.
+const WEBHOOK_DELIVERY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// All in-memory caches of the process, reported by `/admin/caches`.
+/// State kept in memory goes into a `BoundedCache` registered here, so it can't grow without bound.
+pub struct Caches {
+	/// IDs of the applied webhook deliveries (`X-GitHub-Delivery`), so a redelivery is not applied twice.
+	pub webhook_deliveries: BoundedCache<String, ()>,
+}
+
+impl Caches {
+	/// Capacities from `PR_DASHBOARD_CACHE_<NAME>` (e.g. `PR_DASHBOARD_CACHE_WEBHOOK_DELIVERIES`).
+	pub fn from_env() -> Self {
+		Self {
+			webhook_deliveries: BoundedCache::new(
+				"webhook_deliveries",
+				capacity_from_env("PR_DASHBOARD_CACHE_WEBHOOK_DELIVERIES", 10_000),
+				WEBHOOK_DELIVERY_TTL,
+			),
+		}
+	}
+
+	pub fn stats(&self) -> Vec<CacheStats> {
+		vec![self.webhook_deliveries.stats()]
+	}
+
+	/// Drop the expired entries of all caches, returns how many were dropped.
+	pub fn purge_expired(&self) -> usize {
+		self.webhook_deliveries.purge_expired()
+	}
+}
+
+fn capacity_from_env(name: &str, default: usize) -> usize {
+	env::var(name)
+		.map(|x| {
+			x.parse()
+				.ok()
+				.filter(|x| *x > 0)
+				.unwrap_or_else(|| panic!("invalid {name}"))
+		})
+		.unwrap_or(default)
+}
+
+/// Purge expired cache entries in the background every `JANITOR_INTERVAL`.
+pub fn spawn_janitor(caches: Arc<Caches>) {
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(JANITOR_INTERVAL);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			let purged = caches.purge_expired();
+			if purged > 0 {
+				tracing::debug!("cache: purged {purged} expired entries");
+			}
+		}
+	});
+}
+
+/// Current size and counters of a cache.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+	pub name: &'static str,
+	pub len: usize,
+	pub capacity: usize,
+	pub ttl_secs: u64,
+	pub hits: u64,
+	pub misses: u64,
+	/// Entries dropped to stay within the capacity.
+	pub evictions: u64,
+	/// Entries dropped after their TTL, on read or by the janitor.
+	pub expirations: u64,
+}
+
+/// A map holding at most `capacity` entries, each for at most `ttl`.
+/// When full, the oldest entry is evicted first. Safe to share between tasks.
+pub struct BoundedCache<K, V> {
+	name: &'static str,
+	capacity: usize,
+	ttl: Duration,
+	inner: StdMutex<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+	entries: HashMap<K, Entry<V>>,
+	/// Keys in insertion order with the sequence number of their insertion.
+	/// Items whose key was since removed or inserted again are stale and skipped.
+	order: VecDeque<(u64, K)>,
+	next_seq: u64,
+	hits: u64,
+	misses: u64,
+	evictions: u64,
+	expirations: u64,
+}
+
+struct Entry<V> {
+	value: V,
+	seq: u64,
+	expires: Instant,
+}
+
+impl<K, V> Inner<K, V>
+where
+	K: Eq + Hash + Clone,
+{
+	fn is_current(&self, seq: u64, key: &K) -> bool {
+		self.entries.get(key).is_some_and(|x| x.seq == seq)
+	}
+
+	/// Remove the oldest entry, skipping stale items of `order`.
+	fn pop_oldest(&mut self) -> bool {
+		while let Some((seq, key)) = self.order.pop_front() {
+			if self.is_current(seq, &key) {
+				self.entries.remove(&key);
+				return true;
+			}
+		}
+		false
+	}
+}
+
+impl<K, V> BoundedCache<K, V>
+where
+	K: Eq + Hash + Clone,
+	V: Clone,
+{
+	pub fn new(name: &'static str, capacity: usize, ttl: Duration) -> Self {
+		Self {
+			name,
+			capacity,
+			ttl,
+			inner: StdMutex::new(Inner {
+				entries: HashMap::new(),
+				order: VecDeque::new(),
+				next_seq: 0,
+				hits: 0,
+				misses: 0,
+				evictions: 0,
+				expirations: 0,
+			}),
+		}
+	}
+
+	/// The value of `key`, if it is present and not expired.
+	pub fn get(&self, key: &K) -> Option<V> {
+		self.get_at(key, Instant::now())
+	}
+
+	fn get_at(&self, key: &K, now: Instant) -> Option<V> {
+		let mut inner = self.inner.lock().unwrap();
+		match inner.entries.get(key).map(|x| x.expires) {
+			Some(expires) if expires > now => {
+				inner.hits += 1;
+				Some(inner.entries[key].value.clone())
+			},
+			Some(_) => {
+				inner.entries.remove(key);
+				inner.expirations += 1;
+				inner.misses += 1;
+				None
+			},
+			None => {
+				inner.misses += 1;
+				None
+			},
+		}
+	}
+
+	pub fn contains(&self, key: &K) -> bool {
+		self.get(key).is_some()
+	}
+
+	/// Insert or replace `key`, evicting the oldest entries if the cache is full.
+	pub fn insert(&self, key: K, value: V) {
+		self.insert_at(key, value, Instant::now());
+	}
+
+	fn insert_at(&self, key: K, value: V, now: Instant) {
+		let mut inner = self.inner.lock().unwrap();
+		let seq = inner.next_seq;
+		inner.next_seq += 1;
+		let expires = now + self.ttl;
+		inner.entries.insert(key.clone(), Entry { value, seq, expires });
+		inner.order.push_back((seq, key));
+		while inner.entries.len() > self.capacity {
+			if !inner.pop_oldest() {
+				break;
+			}
+			inner.evictions += 1;
+		}
+		// replaced keys leave stale items behind, don't let them pile up
+		if inner.order.len() > 2 * self.capacity.max(1) {
+			let Inner { entries, order, .. } = &mut *inner;
+			order.retain(|(seq, key)| entries.get(key).is_some_and(|x| x.seq == *seq));
+		}
+	}
+
+	/// Drop all expired entries, returns how many were dropped.
+	pub fn purge_expired(&self) -> usize {
+		self.purge_expired_at(Instant::now())
+	}
+
+	fn purge_expired_at(&self, now: Instant) -> usize {
+		let mut inner = self.inner.lock().unwrap();
+		let mut purged = 0;
+		// all entries share the TTL, so they expire in insertion order
+		while let Some((seq, key)) = inner.order.front().cloned() {
+			match inner.entries.get(&key).filter(|x| x.seq == seq).map(|x| x.expires) {
+				Some(expires) if expires > now => break,
+				Some(_) => {
+					inner.entries.remove(&key);
+					purged += 1;
+				},
+				None => {},
+			}
+			inner.order.pop_front();
+		}
+		inner.expirations += purged as u64;
+		purged
+	}
+
+	pub fn stats(&self) -> CacheStats {
+		let inner = self.inner.lock().unwrap();
+		CacheStats {
+			name: self.name,
+			len: inner.entries.len(),
+			capacity: self.capacity,
+			ttl_secs: self.ttl.as_secs(),
+			hits: inner.hits,
+			misses: inner.misses,
+			evictions: inner.evictions,
+			expirations: inner.expirations,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use super::*;
+
+	const HOUR: Duration = Duration::from_secs(60 * 60);
+
+	#[test]
+	fn evicts_oldest_at_capacity() {
+		let cache = BoundedCache::new("test", 3, HOUR);
+		for i in 0..5 {
+			cache.insert(i, i * 10);
+		}
+		assert_eq!(cache.get(&0), None);
+		assert_eq!(cache.get(&1), None);
+		assert_eq!(cache.get(&2), Some(20));
+		assert_eq!(cache.get(&4), Some(40));
+		let stats = cache.stats();
+		assert_eq!((stats.len, stats.evictions), (3, 2));
+		assert_eq!((stats.hits, stats.misses), (2, 2));
+
+		// replacing a key makes it the newest
+		cache.insert(2, 21);
+		cache.insert(5, 50);
+		assert_eq!(cache.get(&2), Some(21));
+		assert_eq!(cache.get(&3), None);
+	}
+
+	#[test]
+	fn expires_after_ttl() {
+		let cache = BoundedCache::new("test", 10, HOUR);
+		let start = Instant::now();
+		cache.insert_at("a", (), start);
+		cache.insert_at("b", (), start);
+		assert_eq!(cache.get_at(&"a", start + HOUR - Duration::from_secs(1)), Some(()));
+		let later = start + HOUR;
+		cache.insert_at("c", (), later);
+		assert_eq!(cache.get_at(&"a", later), None);
+		// purged without being read
+		assert_eq!(cache.purge_expired_at(later), 1);
+		assert_eq!(cache.stats().len, 1);
+		assert_eq!(cache.stats().expirations, 2);
+		assert_eq!(cache.get_at(&"c", later), Some(()));
+		assert_eq!(cache.purge_expired_at(later + HOUR), 1);
+		assert_eq!(cache.stats().len, 0);
+	}
+
+	#[test]
+	fn concurrent_access() {
+		let cache = Arc::new(BoundedCache::new("test", 1000, HOUR));
+		let threads: Vec<_> = (0..8)
+			.map(|t| {
+				let cache = cache.clone();
+				thread::spawn(move || {
+					for i in 0..2000 {
+						cache.insert((t, i), i);
+						// may already be evicted by the other threads, but never mixed up
+						assert!(cache.get(&(t, i)).is_none_or(|x| x == i));
+						cache.get(&((t + 1) % 8, i));
+					}
+				})
+			})
+			.collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		let stats = cache.stats();
+		assert_eq!(stats.len, 1000);
+		assert_eq!(stats.evictions, 8 * 2000 - 1000);
+	}
+
+	#[test]
+	fn stays_bounded_under_flood() {
+		let cache = BoundedCache::new("test", 100, HOUR);
+		for i in 0..100_000u32 {
+			cache.insert(i.to_string(), ());
+			// the same keys over and over must not grow the insertion order either
+			cache.insert((i % 10).to_string(), ());
+		}
+		let inner = cache.inner.lock().unwrap();
+		assert_eq!(inner.entries.len(), 100);
+		assert!(inner.order.len() <= 2 * 100 + 1, "{}", inner.order.len());
+	}
+}
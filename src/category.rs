@@ -0,0 +1,492 @@
+use std::{env, error::Error, fs};
+
+use chrono::{Duration, Utc};
+use rusqlite::types::Value;
+use serde::Deserialize;
+
+use crate::{
+	database::{approvals_sql, has_label_prefix_sql, has_label_sql, CHANGES_REQUESTED_SQL},
+	AWAITING_AUTHOR, AWAITING_REVIEWER, NEEDS_EVAL, NEEDS_MERGER, NEEDS_REVIEWER, STALE, UTC_TIME_FORMAT,
+};
+
+/// All categories, besides `New` for uncategorized PRs.
+pub const CATEGORIES: [&str; 6] = [
+	AWAITING_AUTHOR,
+	NEEDS_REVIEWER,
+	AWAITING_REVIEWER,
+	NEEDS_MERGER,
+	STALE,
+	NEEDS_EVAL,
+];
+
+/// Categories a rule may assign. The other categories are managed by reservations.
+pub const RULE_CATEGORIES: [&str; 3] = [AWAITING_AUTHOR, NEEDS_MERGER, NEEDS_REVIEWER];
+
+/// Conditions for moving a PR into a category, any matching condition applies.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CategoryRule {
+	/// Shown in the category history, defaults to the category.
+	pub name: String,
+	pub category: String,
+	/// PR has a label with exactly one of these names.
+	pub labels: Vec<String>,
+	/// PR has a label starting with one of these prefixes.
+	pub label_prefixes: Vec<String>,
+	/// PR is a draft.
+	pub draft: bool,
+	/// The latest review of a reviewer requests changes.
+	pub changes_requested: bool,
+	/// At least this many reviewers approved the current head commit.
+	pub min_approvals: Option<usize>,
+	/// Like `labels`, but only used if the reviews were never fetched.
+	pub unreviewed_labels: Vec<String>,
+}
+
+/// Check for PRs the evaluator never labeled, which would otherwise stay uncategorized.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvalCheck {
+	/// Hours after its creation a PR is expected to be evaluated, 0 disables the check.
+	pub hours: i64,
+	/// A label starting with one of these prefixes shows that the PR was evaluated.
+	pub label_prefixes: Vec<String>,
+}
+
+impl Default for EvalCheck {
+	/// ofborg adds the `10.rebuild-*` labels, the hours come from `PR_DASHBOARD_NEEDS_EVAL_HOURS`.
+	fn default() -> Self {
+		Self {
+			hours: env::var("PR_DASHBOARD_NEEDS_EVAL_HOURS")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_NEEDS_EVAL_HOURS"))
+				.unwrap_or(12),
+			label_prefixes: vec!["10.".to_owned()],
+		}
+	}
+}
+
+impl EvalCheck {
+	/// SQL condition matching the PRs in `pulls` still waiting for evaluation after `hours`.
+	/// Drafts are not evaluated, so they never match.
+	pub fn sql(&self, params: &mut Vec<Value>) -> String {
+		if self.hours <= 0 {
+			return "0".to_owned();
+		}
+		let created_before = (Utc::now() - Duration::hours(self.hours))
+			.format(UTC_TIME_FORMAT)
+			.to_string();
+		params.push(Value::from(created_before));
+		let evaluated = has_label_prefix_sql(&self.label_prefixes, params);
+		format!(
+			"(json_extract(pull_data(pulls.data, pulls.data_compressed), '$.created_at') < ? \
+			AND NOT coalesce(json_extract(pull_data(pulls.data, pulls.data_compressed), '$.draft'), 0) AND NOT {evaluated})"
+		)
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+	/// In priority order, the first matching rule wins.
+	rule: Vec<CategoryRule>,
+	#[serde(default)]
+	needs_eval: EvalCheck,
+}
+
+/// The categorization rules and checks.
+pub struct CategoryRules {
+	pub rules: Vec<CategoryRule>,
+	pub needs_eval: EvalCheck,
+}
+
+impl CategoryRule {
+	pub fn name(&self) -> &str {
+		if self.name.is_empty() {
+			&self.category
+		} else {
+			&self.name
+		}
+	}
+
+	/// SQL condition matching the PRs in `pulls` this rule applies to, binding its values to `params`.
+	fn sql(&self, params: &mut Vec<Value>) -> String {
+		let mut reviewed = vec!["0".to_owned()];
+		if self.changes_requested {
+			reviewed.push(CHANGES_REQUESTED_SQL.to_owned());
+		}
+		if let Some(min_approvals) = self.min_approvals {
+			reviewed.push(format!("{} >= ?", approvals_sql()));
+			params.push(Value::from(min_approvals as i64));
+		}
+		let unreviewed = has_label_sql(&self.unreviewed_labels, params);
+		let labels = has_label_sql(&self.labels, params);
+		let prefixes = has_label_prefix_sql(&self.label_prefixes, params);
+		let draft = if self.draft {
+			"coalesce(json_extract(pull_data(pulls.data, pulls.data_compressed), '$.draft'), 0)"
+		} else {
+			"0"
+		};
+		format!(
+			"((CASE WHEN pulls.reviews_synced IS NOT NULL THEN {} ELSE {unreviewed} END) \
+			OR {labels} OR {prefixes} OR {draft})",
+			reviewed.join(" OR ")
+		)
+	}
+
+	/// Whether the rule applies to a PR, evaluated like the condition of `sql`.
+	#[cfg(test)]
+	fn matches(&self, labels: &[&str], draft: bool, reviews: Option<&Reviews>) -> bool {
+		let has_label = |names: &[String]| labels.iter().any(|x| names.iter().any(|name| name == x));
+		let review_match = match reviews {
+			Some(reviews) => {
+				(self.changes_requested && reviews.changes_requested)
+					|| self.min_approvals.is_some_and(|x| reviews.approvals >= x)
+			},
+			None => has_label(&self.unreviewed_labels),
+		};
+		review_match
+			|| has_label(&self.labels)
+			|| labels
+				.iter()
+				.any(|x| self.label_prefixes.iter().any(|prefix| x.starts_with(&**prefix)))
+			|| (self.draft && draft)
+	}
+}
+
+/// Review verdicts of a PR, for evaluating the rules without SQL.
+#[cfg(test)]
+pub struct Reviews {
+	/// Approvals of the current head.
+	pub approvals: usize,
+	pub changes_requested: bool,
+}
+
+/// Category of the first rule matching a PR, `None` if no rule matches. Evaluates the rules row by row,
+/// as housekeeping did before they were translated to SQL.
+#[cfg(test)]
+pub fn categorize<'a>(
+	rules: &'a [CategoryRule],
+	labels: &[&str],
+	draft: bool,
+	reviews: Option<&Reviews>,
+) -> Option<&'a str> {
+	rules
+		.iter()
+		.find(|x| x.matches(labels, draft, reviews))
+		.map(|x| &*x.category)
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+	values.iter().map(|x| (*x).to_owned()).collect()
+}
+
+/// Rules tuned for nixpkgs.
+pub fn default_rules() -> Vec<CategoryRule> {
+	vec![
+		CategoryRule {
+			name: "needs changes".to_owned(),
+			category: AWAITING_AUTHOR.to_owned(),
+			labels: strings(&["2.status: merge conflict"]),
+			draft: true,
+			changes_requested: true,
+			unreviewed_labels: strings(&["awaiting_changes", "2.status: needs-changes"]),
+			..Default::default()
+		},
+		CategoryRule {
+			name: "approved".to_owned(),
+			category: NEEDS_MERGER.to_owned(),
+			labels: strings(&["needs_merger", "awaiting_merger", "12.approved-by: package-maintainer"]),
+			min_approvals: Some(3),
+			unreviewed_labels: strings(&["12.approvals: 3+"]),
+			..Default::default()
+		},
+		// ofborg labels new PRs once they are evaluated
+		CategoryRule {
+			name: "evaluated".to_owned(),
+			category: NEEDS_REVIEWER.to_owned(),
+			label_prefixes: strings(&["10."]),
+			..Default::default()
+		},
+	]
+}
+
+/// Load rules from the TOML file at `PR_DASHBOARD_RULES`, a list of `[[rule]]` tables in priority order
+/// and optionally a `[needs_eval]` table.
+/// Example:
+/// ```toml
+/// [[rule]]
+/// name = "needs rebase"
+/// category = "AwaitingAuthor"
+/// labels = ["needs-rebase"]
+/// draft = true
+/// changes_requested = true
+///
+/// [[rule]]
+/// category = "NeedsReviewer"
+/// label_prefixes = ["ci: "]
+///
+/// [needs_eval]
+/// hours = 6
+/// label_prefixes = ["ci: "]
+/// ```
+pub fn load_rules() -> Result<CategoryRules, Box<dyn Error>> {
+	let Ok(path) = env::var("PR_DASHBOARD_RULES") else {
+		return Ok(CategoryRules {
+			rules: default_rules(),
+			needs_eval: EvalCheck::default(),
+		});
+	};
+	let config = fs::read_to_string(&path).map_err(|e| format!("failed to read PR_DASHBOARD_RULES {path}: {e}"))?;
+	Ok(parse_rules(&config).map_err(|e| format!("invalid PR_DASHBOARD_RULES {path}: {e}"))?)
+}
+
+fn parse_rules(config: &str) -> Result<CategoryRules, String> {
+	let rules: RulesFile = toml::from_str(config).map_err(|e| e.to_string())?;
+	for rule in &rules.rule {
+		if !RULE_CATEGORIES.contains(&&*rule.category) {
+			return Err(format!(
+				"unknown category {:?}, expected one of {RULE_CATEGORIES:?}",
+				rule.category
+			));
+		}
+	}
+	Ok(CategoryRules {
+		rules: rules.rule,
+		needs_eval: rules.needs_eval,
+	})
+}
+
+/// SQL expression for the index of the first rule matching a PR in `pulls`, NULL if no rule matches.
+/// Binds the rules' values to `params`.
+pub fn sql(rules: &[CategoryRule], params: &mut Vec<Value>) -> String {
+	if rules.is_empty() {
+		return "NULL".to_owned();
+	}
+	let mut sql = "CASE".to_owned();
+	for (i, rule) in rules.iter().enumerate() {
+		sql += &format!(" WHEN {} THEN {i}", rule.sql(params));
+	}
+	sql + " END"
+}
+
+/// SQL expression for a value of the rule with the index in `index`, like its category.
+pub fn lookup_sql(
+	rules: &[CategoryRule],
+	index: &str,
+	value: impl Fn(&CategoryRule) -> &str,
+	params: &mut Vec<Value>,
+) -> String {
+	if rules.is_empty() {
+		return "NULL".to_owned();
+	}
+	let mut sql = format!("CASE {index}");
+	for (i, rule) in rules.iter().enumerate() {
+		sql += &format!(" WHEN {i} THEN ?");
+		params.push(Value::from(value(rule).to_owned()));
+	}
+	sql + " END"
+}
+
+#[cfg(test)]
+mod tests {
+	use rusqlite::{params, params_from_iter, Transaction};
+
+	use super::*;
+	use crate::database::{DB, IN_MEMORY};
+
+	struct Case {
+		labels: &'static [&'static str],
+		draft: bool,
+		/// States of the reviews on the head commit, `None` if the reviews were never fetched.
+		reviews: Option<&'static [&'static str]>,
+		expected: Option<&'static str>,
+	}
+
+	const fn case(
+		labels: &'static [&'static str],
+		draft: bool,
+		reviews: Option<&'static [&'static str]>,
+		expected: Option<&'static str>,
+	) -> Case {
+		Case {
+			labels,
+			draft,
+			reviews,
+			expected,
+		}
+	}
+
+	const EVALUATED: &str = "10.rebuild-linux: 1-10";
+
+	/// The categories of the default rules, as housekeeping applied them before they were configurable.
+	const CASES: &[Case] = &[
+		case(&[], false, None, None),
+		case(&["6.topic: python"], false, None, None),
+		case(&[EVALUATED], false, None, Some(NEEDS_REVIEWER)),
+		case(
+			&[EVALUATED, "2.status: merge conflict"],
+			false,
+			None,
+			Some(AWAITING_AUTHOR),
+		),
+		case(&[EVALUATED], true, None, Some(AWAITING_AUTHOR)),
+		case(&[EVALUATED, "awaiting_changes"], false, None, Some(AWAITING_AUTHOR)),
+		case(
+			&[EVALUATED, "2.status: needs-changes"],
+			false,
+			None,
+			Some(AWAITING_AUTHOR),
+		),
+		// the label is stale once the reviews are known
+		case(&[EVALUATED, "awaiting_changes"], false, Some(&[]), Some(NEEDS_REVIEWER)),
+		case(&[EVALUATED], false, Some(&["CHANGES_REQUESTED"]), Some(AWAITING_AUTHOR)),
+		case(&[EVALUATED, "12.approvals: 3+"], false, None, Some(NEEDS_MERGER)),
+		case(
+			&[EVALUATED, "12.approvals: 3+"],
+			false,
+			Some(&["APPROVED"]),
+			Some(NEEDS_REVIEWER),
+		),
+		case(
+			&[EVALUATED],
+			false,
+			Some(&["APPROVED", "APPROVED", "APPROVED"]),
+			Some(NEEDS_MERGER),
+		),
+		case(
+			&[EVALUATED, "12.approved-by: package-maintainer"],
+			false,
+			None,
+			Some(NEEDS_MERGER),
+		),
+		case(&["needs_merger"], false, None, Some(NEEDS_MERGER)),
+		// needing changes wins over approvals
+		case(
+			&["needs_merger", "2.status: merge conflict"],
+			false,
+			None,
+			Some(AWAITING_AUTHOR),
+		),
+		case(
+			&[],
+			true,
+			Some(&["APPROVED", "APPROVED", "APPROVED"]),
+			Some(AWAITING_AUTHOR),
+		),
+	];
+
+	fn insert(tx: &Transaction, id: usize, case: &Case) {
+		let labels: Vec<_> = case
+			.labels
+			.iter()
+			.map(|x| serde_json::json!({"name": x, "color": "ffffff"}))
+			.collect();
+		let data = serde_json::json!({
+			"number": id,
+			"labels": labels,
+			"draft": case.draft,
+			"head": {"sha": "abc"},
+		});
+		tx.execute(
+			"INSERT INTO pulls (repo, id, author, last_updated, data, reviews_synced)
+			VALUES ('o/r', ?1, 'a', '2024-01-01T00:00:00Z', ?2, ?3)",
+			params![id, data.to_string(), case.reviews.map(|_| "2024-01-01T00:00:00Z")],
+		)
+		.unwrap();
+		for (i, state) in case.reviews.unwrap_or_default().iter().enumerate() {
+			tx.execute(
+				"INSERT INTO pull_reviews (repo, pull_id, reviewer, state, commit_id) VALUES ('o/r', ?1, ?2, ?3, 'abc')",
+				params![id, format!("reviewer{i}"), state],
+			)
+			.unwrap();
+		}
+	}
+
+	/// Category and rule name of each PR in `pulls`, by id.
+	fn categorize(tx: &Transaction, rules: &[CategoryRule]) -> Vec<(Option<String>, Option<String>)> {
+		let mut params = vec![];
+		let category = lookup_sql(rules, "rule", |x| &x.category, &mut params);
+		let name = lookup_sql(rules, "rule", |x| x.name(), &mut params);
+		let rule = sql(rules, &mut params);
+		let query = format!("SELECT {category}, {name} FROM (SELECT id, {rule} AS rule FROM pulls) ORDER BY id");
+		tx.prepare(&query)
+			.unwrap()
+			.query_map(params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?)))
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap()
+	}
+
+	#[test]
+	fn default_rules_table() {
+		let mut db = DB::new_with_path(IN_MEMORY).unwrap();
+		let tx = db.transaction().unwrap();
+		for (id, case) in CASES.iter().enumerate() {
+			insert(&tx, id, case);
+		}
+		let categories = categorize(&tx, &default_rules());
+		for (i, (case, (category, name))) in CASES.iter().zip(categories).enumerate() {
+			assert_eq!(category.as_deref(), case.expected, "case {i}: {:?}", case.labels);
+			assert_eq!(name.is_some(), case.expected.is_some());
+		}
+	}
+
+	#[test]
+	fn configured_rules() {
+		let rules = parse_rules(
+			r#"
+			[[rule]]
+			name = "needs rebase"
+			category = "AwaitingAuthor"
+			labels = ["needs-rebase"]
+
+			[[rule]]
+			category = "NeedsReviewer"
+			label_prefixes = ["ci: "]
+			draft = true
+
+			[needs_eval]
+			hours = 6
+			label_prefixes = ["ci: "]
+			"#,
+		)
+		.unwrap();
+		assert_eq!(rules.rules.len(), 2);
+		assert_eq!(rules.rules[1].name(), NEEDS_REVIEWER);
+		assert_eq!(rules.needs_eval.hours, 6);
+
+		let mut db = DB::new_with_path(IN_MEMORY).unwrap();
+		let tx = db.transaction().unwrap();
+		for (id, case) in [
+			case(&["needs-rebase", "ci: passed"], false, None, None),
+			case(&["ci: passed"], false, None, None),
+			case(&[], true, None, None),
+			case(&["2.status: merge conflict"], false, None, None),
+		]
+		.iter()
+		.enumerate()
+		{
+			insert(&tx, id, case);
+		}
+		let categories = categorize(&tx, &rules.rules);
+		assert_eq!(
+			categories,
+			[
+				(Some(AWAITING_AUTHOR.to_owned()), Some("needs rebase".to_owned())),
+				(Some(NEEDS_REVIEWER.to_owned()), Some(NEEDS_REVIEWER.to_owned())),
+				(Some(NEEDS_REVIEWER.to_owned()), Some(NEEDS_REVIEWER.to_owned())),
+				(None, None),
+			]
+		);
+		assert!(categorize(&tx, &[]).iter().all(|x| *x == (None, None)));
+	}
+
+	#[test]
+	fn invalid_rules() {
+		let err = parse_rules("[[rule]]\ncategory = \"Stale\"").err().unwrap();
+		assert!(err.contains("unknown category \"Stale\""), "{err}");
+		assert!(parse_rules("[[rule]]\ncategory = \"NeedsMerger\"\nlabel = [\"x\"]").is_err());
+		assert!(parse_rules("[[rule]]\ncategory = \"NeedsMerger\"\nmin_approvals = \"two\"").is_err());
+		assert!(parse_rules("rule = 1").is_err());
+	}
+}
@@ -1,68 +1,1162 @@
 use std::{
 	cmp::Reverse,
+	collections::HashMap,
 	env,
 	error::Error,
 	ops::{Deref, DerefMut},
+	path::Path,
+	sync::Mutex as StdMutex,
+	time::Duration,
 };
 
-use chrono::Utc;
-use octocrab::models::pulls::PullRequest;
-use rusqlite::{params, Connection, Transaction};
+use chrono::{DateTime, Utc};
+use octocrab::models::{pulls::PullRequest, IssueState};
+use rusqlite::{
+	backup::{Backup, Progress},
+	functions::FunctionFlags,
+	params, params_from_iter,
+	types::Value,
+	Connection, OptionalExtension, ParamsFromIter, Transaction, TransactionBehavior,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::{
+	category, configured_repos, extract_row, parse_duration, parse_timestamp, parse_utc, AWAITING_AUTHOR,
+	AWAITING_REVIEWER, NEEDS_EVAL, NEEDS_MERGER, NEEDS_REVIEWER, STALE, UTC_TIME_FORMAT,
+};
+
+/// Pages copied per step of a backup, other connections can write in between.
+const BACKUP_PAGES_PER_STEP: i32 = 10_000;
+/// Pause between the steps of a backup.
+const BACKUP_PAUSE: Duration = Duration::from_millis(10);
+
+/// Path of a database that only exists in memory, for tests.
+pub const IN_MEMORY: &str = ":memory:";
+
+/// Connections to the database, each used by one task at a time.
+pub struct DbPool {
+	path: String,
+	idle: StdMutex<Vec<DB>>,
+	permits: Semaphore,
+}
+
+impl DbPool {
+	/// Open the database at `PR_DASHBOARD_DATABASE` and update its schema.
+	/// Up to `size` connections are opened as needed.
+	pub fn new(size: usize) -> Result<Self, Box<dyn Error>> {
+		Self::new_with_path(&database_path(), size)
+	}
+
+	/// Like `new`, for the database at `path`.
+	/// An `IN_MEMORY` database has a single connection, every further one would open another database.
+	pub fn new_with_path(path: &str, size: usize) -> Result<Self, Box<dyn Error>> {
+		let size = if path == IN_MEMORY { 1 } else { size };
+		Ok(Self {
+			path: path.to_owned(),
+			idle: StdMutex::new(vec![DB::new_with_path(path)?]),
+			permits: Semaphore::new(size),
+		})
+	}
+
+	/// Run `code` with a connection, waiting for one if all are in use.
+	/// The runtime moves the other tasks of this worker elsewhere while `code` blocks it,
+	/// unlike `spawn_blocking` this lets `code` borrow from the request.
+	pub async fn run<T>(&self, code: impl FnOnce(&mut DB) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+		let _permit = self.permits.acquire().await?;
+		let idle = self.idle.lock().unwrap().pop();
+		let mut db = match idle {
+			Some(db) => db,
+			None => DB {
+				db: connect(&self.path)?,
+			},
+		};
+		let result = tokio::task::block_in_place(|| code(&mut db));
+		self.idle.lock().unwrap().push(db);
+		result
+	}
+}
+
+/// `PR_DASHBOARD_DATABASE`, `./pr-dashboard.db` by default.
+fn database_path() -> String {
+	env::var("PR_DASHBOARD_DATABASE").unwrap_or_else(|_err| "./pr-dashboard.db".to_owned())
+}
+
+/// Open a connection to the database at `path`.
+/// WAL lets readers continue during a write, and a write waits for up to the busy timeout
+/// for another one to finish instead of failing with `database is locked`.
+/// `PR_DASHBOARD_JOURNAL_MODE`, `PR_DASHBOARD_SYNCHRONOUS` and `PR_DASHBOARD_BUSY_TIMEOUT_MS` override the defaults.
+fn connect(path: &str) -> Result<Connection, Box<dyn Error>> {
+	let db = Connection::open(path)?;
+	rusqlite::vtab::array::load_module(&db)?;
+	let journal_mode = env::var("PR_DASHBOARD_JOURNAL_MODE").unwrap_or_else(|_| "WAL".to_owned());
+	let synchronous = env::var("PR_DASHBOARD_SYNCHRONOUS").unwrap_or_else(|_| "NORMAL".to_owned());
+	let busy_timeout = env::var("PR_DASHBOARD_BUSY_TIMEOUT_MS")
+		.map(|x| x.parse().expect("invalid PR_DASHBOARD_BUSY_TIMEOUT_MS"))
+		.unwrap_or(5000);
+	db.pragma_update(None, "foreign_keys", true)?;
+	// returns the mode in effect, which stays the old one if the requested mode is unsupported
+	let mode: String = db.pragma_update_and_check(None, "journal_mode", &journal_mode, |row| row.get(0))?;
+	// an in-memory database always uses the `memory` mode
+	if !mode.eq_ignore_ascii_case(&journal_mode) && path != IN_MEMORY {
+		tracing::warn!("database: requested journal_mode {journal_mode}, but {mode} is in effect");
+	}
+	db.pragma_update(None, "synchronous", &synchronous)?;
+	db.busy_timeout(Duration::from_millis(busy_timeout))?;
+	register_functions(&db)?;
+	Ok(db)
+}
+
+/// SQL functions for the PR data, which is stored as text in `data`
+/// or zstd-compressed in `data_compressed`, leaving `data` empty.
+///
+/// `pull_data(data, data_compressed)` is the JSON of either, NULL if it fails to decompress.
+/// `compress_data(json, level)` is the compressed JSON, NULL if the level is NULL.
+fn register_functions(db: &Connection) -> rusqlite::Result<()> {
+	let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+	db.create_scalar_function("pull_data", 2, flags, |ctx| {
+		let Some(compressed) = ctx.get::<Option<Vec<u8>>>(1)? else {
+			return ctx.get::<Option<String>>(0);
+		};
+		Ok(decompress_data(&compressed).ok())
+	})?;
+	db.create_scalar_function("compress_data", 2, flags, |ctx| {
+		let (Some(data), Some(level)) = (ctx.get::<Option<String>>(0)?, ctx.get::<Option<i32>>(1)?) else {
+			return Ok(None);
+		};
+		zstd::encode_all(data.as_bytes(), level)
+			.map(Some)
+			.map_err(|err| rusqlite::Error::UserFunctionError(err.into()))
+	})
+}
 
-use crate::{construct_sql_filter, extract_row, NEEDS_MERGER};
+/// Inverse of `compress_data`.
+pub fn decompress_data(compressed: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>> {
+	Ok(String::from_utf8(zstd::decode_all(compressed)?)?)
+}
+
+/// Log the connection settings in effect, to confirm that the pragmas applied.
+fn log_pragmas(db: &Connection) -> Result<(), Box<dyn Error>> {
+	let mut settings = vec![];
+	for pragma in ["journal_mode", "synchronous", "busy_timeout", "foreign_keys"] {
+		let value: Value = db.pragma_query_value(None, pragma, |row| row.get(0))?;
+		let value = match value {
+			Value::Integer(x) => x.to_string(),
+			Value::Text(x) => x,
+			x => format!("{x:?}"),
+		};
+		settings.push(format!("{pragma}={value}"));
+	}
+	tracing::info!("database: {}", settings.join(" "));
+	Ok(())
+}
 
 pub struct DB {
 	db: Connection,
 }
 
 impl DB {
-	pub fn new() -> Result<Self, Box<dyn Error>> {
-		let db =
-			Connection::open(env::var("PR_DASHBOARD_DATABASE").unwrap_or_else(|_err| "./pr-dashboard.db".to_owned()))?;
-		rusqlite::vtab::array::load_module(&db)?;
+	/// Open the database at `path`, which may be `IN_MEMORY`, and update its schema.
+	pub fn new_with_path(path: &str) -> Result<Self, Box<dyn Error>> {
+		let mut db = connect(path)?;
+		log_pragmas(&db)?;
 
-		db.execute(
-			"CREATE TABLE IF NOT EXISTS pulls(
-            id INTEGER NOT NULL PRIMARY KEY,
+		migrate(&mut db)?;
+		strip_stored_data(&db)?;
+
+		Ok(Self { db })
+	}
+
+	/// Start of the last complete update of a repository, PRs updated before it are already stored.
+	pub fn last_update(&self, repo: &str) -> Result<Option<String>, Box<dyn Error>> {
+		let cursor = self.sync_state(&sync_cursor_key(repo))?;
+		if cursor.is_some() {
+			return Ok(cursor);
+		}
+		// databases from before the sync cursor: continue from the newest stored PR
+		Ok(self.db.query_row(
+			"SELECT MAX(last_updated) FROM pulls WHERE repo = ?1",
+			params![repo],
+			|row| row.get::<_, Option<String>>(0),
+		)?)
+	}
+
+	/// PRs whose reviews were not fetched since their last update, most recently updated first.
+	pub fn pulls_with_stale_reviews(&self) -> Result<Vec<(String, u64, String)>, Box<dyn Error>> {
+		let mut stmt = self.db.prepare(
+			"SELECT repo, id, last_updated FROM pulls
+			WHERE state = 'open' AND (reviews_synced IS NULL OR reviews_synced != last_updated)
+			ORDER BY last_updated DESC",
+		)?;
+		let rows = stmt
+			.query_map([], extract_row!(String u64 String))?
+			.collect::<Result<_, _>>()?;
+		Ok(rows)
+	}
+
+	/// Number of tracked PRs in all repositories.
+	pub fn count_pulls(&self) -> Result<usize, Box<dyn Error>> {
+		Ok(self
+			.db
+			.query_row("SELECT COUNT(*) FROM pulls WHERE state = 'open'", [], |row| row.get(0))?)
+	}
+
+	/// Value stored in `sync_state` under `key`.
+	pub fn sync_state(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+		Ok(self
+			.db
+			.query_row("SELECT value FROM sync_state WHERE key = ?1", params![key], |row| {
+				row.get::<_, String>(0)
+			})
+			.optional()?)
+	}
+
+	/// Time of the last successful update from GitHub (UTC).
+	pub fn last_sync(&self) -> Result<Option<String>, Box<dyn Error>> {
+		Ok(self
+			.db
+			.query_row("SELECT value FROM sync_state WHERE key = 'last_success'", [], |row| {
+				row.get::<_, String>(0)
+			})
+			.optional()?)
+	}
+
+	/// Map an identity that was merged into another viewer to the kept identity.
+	pub fn resolve_viewer(&self, identity: &str) -> Result<String, Box<dyn Error>> {
+		let viewer = self
+			.db
+			.query_row(
+				"SELECT viewer FROM viewer_aliases WHERE alias = ?1",
+				params![identity],
+				|row| row.get::<_, String>(0),
+			)
+			.optional()?;
+		Ok(viewer.unwrap_or_else(|| identity.to_owned()))
+	}
+
+	pub fn transaction(&mut self) -> Result<Transaction, Box<dyn Error>> {
+		Ok(self.db.transaction()?)
+	}
+
+	/// Return free pages to the file system, refresh the statistics of the query planner and check the integrity.
+	/// The incremental vacuum only does something after a `full` vacuum switched the database to it,
+	/// a full vacuum rewrites the whole file and blocks all writers meanwhile.
+	pub fn maintain(&self, full: bool) -> Result<Maintenance, Box<dyn Error>> {
+		let size = || {
+			self.db.query_row(
+				"SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+				[],
+				|row| row.get::<_, i64>(0),
+			)
+		};
+		let size_before = size()?;
+		if full {
+			self.db.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+		} else {
+			self.db.execute_batch("PRAGMA incremental_vacuum;")?;
+		}
+		self.db.execute_batch("ANALYZE;")?;
+		let size_after = size()?;
+		let mut stmt = self.db.prepare("PRAGMA integrity_check")?;
+		let integrity = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+		Ok(Maintenance {
+			size_before,
+			size_after,
+			integrity,
+		})
+	}
+
+	/// Copy the database to `path` with the online backup API.
+	/// The copy is consistent, it starts over if another connection writes during a step.
+	pub fn backup_to(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+		let mut target = Connection::open(path)?;
+		let backup = Backup::new(&self.db, &mut target)?;
+		backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_PAUSE, Some(log_backup_progress))?;
+		Ok(())
+	}
+}
+
+fn log_backup_progress(progress: Progress) {
+	tracing::info!(
+		"backup: {} of {} pages copied",
+		progress.pagecount - progress.remaining,
+		progress.pagecount
+	);
+}
+
+/// `sync_state` key of the time the last complete update of a repository started.
+pub fn sync_cursor_key(repo: &str) -> String {
+	format!("cursor:{repo}")
+}
+
+/// `sync_state` key of the ETag of a page of the PR listing (sorted by update time).
+pub fn listing_etag_key(repo: &str, state: &str, page: u32) -> String {
+	format!("etag:{repo}:updated:{state}:{page}")
+}
+
+/// Whether a `pull_reviews` row was submitted on the current head of its PR in `pulls`.
+/// Reviews or PRs stored by older versions don't know their commit and always count.
+const REVIEW_ON_HEAD: &str =
+	"coalesce(pull_reviews.commit_id = json_extract(pull_data(pulls.data, pulls.data_compressed), '$.head.sha'), 1)";
+
+/// SQL condition that a PR in `pulls` has a label with one of these names, binding them to `params`.
+pub fn has_label_sql(names: &[String], params: &mut Vec<Value>) -> String {
+	if names.is_empty() {
+		return "0".to_owned();
+	}
+	params.extend(names.iter().map(|x| Value::from(x.clone())));
+	format!(
+		"EXISTS (SELECT 1 FROM json_each(pull_data(pulls.data, pulls.data_compressed), '$.labels') WHERE json_extract(value, '$.name') IN ({}))",
+		vec!["?"; names.len()].join(", ")
+	)
+}
+
+/// SQL condition that a PR in `pulls` has a label starting with one of these prefixes, binding them to `params`.
+pub fn has_label_prefix_sql(prefixes: &[String], params: &mut Vec<Value>) -> String {
+	if prefixes.is_empty() {
+		return "0".to_owned();
+	}
+	params.extend(prefixes.iter().map(|x| Value::from(x.clone())));
+	let conditions = vec!["instr(json_extract(value, '$.name'), ?) = 1"; prefixes.len()].join(" OR ");
+	format!(
+		"EXISTS (SELECT 1 FROM json_each(pull_data(pulls.data, pulls.data_compressed), '$.labels') WHERE {conditions})"
+	)
+}
+
+/// SQL expression for the sorted label names of a PR in `pulls`, to detect label changes.
+pub const LABEL_SET_SQL: &str = "(SELECT group_concat(name, char(10)) FROM
+	(SELECT json_extract(value, '$.name') AS name FROM json_each(pull_data(pulls.data, pulls.data_compressed), '$.labels') ORDER BY name))";
+
+/// SQL condition that the latest review of a reviewer of a PR in `pulls` requests changes.
+pub const CHANGES_REQUESTED_SQL: &str = "EXISTS (SELECT 1 FROM pull_reviews
+	WHERE pull_reviews.repo = pulls.repo AND pull_id = pulls.id AND pull_reviews.state = 'CHANGES_REQUESTED')";
+
+/// SQL expression for the number of approvals of the current head of a PR in `pulls`.
+pub fn approvals_sql() -> String {
+	format!(
+		"(SELECT COUNT(*) FROM pull_reviews
+		WHERE pull_reviews.repo = pulls.repo AND pull_id = pulls.id AND pull_reviews.state = 'APPROVED'
+			AND {REVIEW_ON_HEAD})"
+	)
+}
+
+/// End a temporary category of a PR, moving it back to `prev_category`.
+/// Nothing changes if the PR left the temporary category in the meantime.
+pub fn restore_category(
+	tx: &Transaction,
+	repo: &str,
+	id: i64,
+	temporary: &str,
+	time: &str,
+	reason: &str,
+) -> Result<(), Box<dyn Error>> {
+	tx.execute(
+		"INSERT INTO category_history (repo, pull_id, from_category, to_category, changed_at, reason)
+		SELECT repo, id, category, prev_category, ?4, ?5 FROM pulls
+		WHERE repo = ?1 AND id = ?2 AND category = ?3 AND prev_category IS NOT category",
+		params![repo, id, temporary, time, reason],
+	)?;
+	tx.execute(
+		"UPDATE pulls SET
+		category = CASE WHEN category = ?3 THEN prev_category ELSE category END,
+		category_since = CASE WHEN category = ?3 THEN ?4 ELSE category_since END,
+		prev_category = NULL
+		WHERE repo = ?1 AND id = ?2",
+		params![repo, id, temporary, time],
+	)?;
+	Ok(())
+}
+
+/// Result of `DB::maintain`.
+#[derive(Debug)]
+pub struct Maintenance {
+	/// Size of the database in bytes before and after the vacuum, not counting the WAL.
+	pub size_before: i64,
+	pub size_after: i64,
+	/// Rows of `PRAGMA integrity_check`, a single `ok` for a healthy database.
+	pub integrity: Vec<String>,
+}
+
+/// An open PR whose stored data can't be read as `StoredPr`, like after a schema change.
+#[derive(Debug, Serialize)]
+pub struct BrokenRow {
+	pub repo: String,
+	pub id: u64,
+	pub error: String,
+}
+
+/// Result of `check_consistency`.
+#[derive(Debug, Default, Serialize)]
+pub struct ConsistencyReport {
+	pub orphan_reservations: Vec<OrphanReservation>,
+	pub dangling_reserved_by: Vec<ReservedByMismatch>,
+	pub invalid_categories: Vec<InvalidCategory>,
+	/// Not repaired, the PRs have to be fetched again.
+	pub broken_rows: Vec<BrokenRow>,
+}
+
+impl ConsistencyReport {
+	pub fn is_consistent(&self) -> bool {
+		self.orphan_reservations.is_empty()
+			&& self.dangling_reserved_by.is_empty()
+			&& self.invalid_categories.is_empty()
+			&& self.broken_rows.is_empty()
+	}
+
+	/// Counts of each problem, for the log.
+	pub fn summary(&self) -> String {
+		format!(
+			"{} orphan reservations, {} dangling reserved_by, {} invalid categories, {} broken rows",
+			self.orphan_reservations.len(),
+			self.dangling_reserved_by.len(),
+			self.invalid_categories.len(),
+			self.broken_rows.len()
+		)
+	}
+}
+
+/// A reservation of a PR that is closed or not tracked at all.
+#[derive(Debug, Serialize)]
+pub struct OrphanReservation {
+	pub repo: String,
+	pub id: u64,
+	pub reserved_by: Option<String>,
+}
+
+/// An open PR whose `reserved_by` is not the holder of its reservation, or set without a reservation.
+#[derive(Debug, Serialize)]
+pub struct ReservedByMismatch {
+	pub repo: String,
+	pub id: u64,
+	pub reserved_by: Option<String>,
+	/// Holder of the reservation, if there is one.
+	pub reservation: Option<String>,
+}
+
+/// An open PR in a category that doesn't exist (anymore).
+#[derive(Debug, Serialize)]
+pub struct InvalidCategory {
+	pub repo: String,
+	pub id: u64,
+	pub category: String,
+}
+
+/// A change of the category of a PR, `None` being uncategorized.
+#[derive(Debug, Serialize)]
+pub struct CategoryChange {
+	pub from: Option<String>,
+	pub to: Option<String>,
+	/// `UTC_TIME_FORMAT`.
+	pub changed_at: String,
+	/// Name of the categorization rule, or the action like `reserved`.
+	pub reason: String,
+}
+
+/// Category changes of a PR, oldest first.
+pub fn category_history(tx: &Transaction, repo: &str, id: i64) -> Result<Vec<CategoryChange>, Box<dyn Error>> {
+	let mut query = tx.prepare(
+		"SELECT from_category, to_category, changed_at, reason FROM category_history
+		WHERE repo = ?1 AND pull_id = ?2 ORDER BY changed_at, rowid",
+	)?;
+	let changes = query
+		.query_map(params![repo, id], |row| {
+			Ok(CategoryChange {
+				from: row.get(0)?,
+				to: row.get(1)?,
+				changed_at: row.get(2)?,
+				reason: row.get(3)?,
+			})
+		})?
+		.collect::<Result<_, _>>()?;
+	Ok(changes)
+}
+
+/// Schema changes in order, `PRAGMA user_version` is the number of applied ones.
+/// New tables and columns are added as a new migration at the end, applied ones are never changed.
+const MIGRATIONS: &[fn(&Connection) -> Result<(), Box<dyn Error>>] = &[
+	initial_schema,
+	query_indexes,
+	title_column,
+	compressed_data,
+	rfc3339_times,
+	count_snapshots,
+];
+
+/// Apply the pending migrations, each in a transaction. Fails if the database was created by a newer version.
+fn migrate(db: &mut Connection) -> Result<(), Box<dyn Error>> {
+	let version: usize = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+	if version > MIGRATIONS.len() {
+		return Err(format!(
+			"database schema version {version} is newer than the supported version {}",
+			MIGRATIONS.len()
+		)
+		.into());
+	}
+	if version == MIGRATIONS.len() {
+		return Ok(());
+	}
+	// tables are rebuilt by creating a new one and dropping the old one, which would cascade otherwise.
+	// Can't be changed inside a transaction
+	db.pragma_update(None, "foreign_keys", false)?;
+	for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+		tracing::info!("migrating the database to schema version {}", i + 1);
+		let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+		migration(&tx)?;
+		tx.pragma_update(None, "user_version", i + 1)?;
+		tx.commit()?;
+	}
+	db.pragma_update(None, "foreign_keys", true)?;
+	let violations: usize = db.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0))?;
+	if violations > 0 {
+		tracing::warn!("database: {violations} rows violate foreign keys after migrating");
+	}
+	Ok(())
+}
+
+/// Version 1: the schema before the versioning. Idempotent, to upgrade the databases of all older versions.
+fn initial_schema(db: &Connection) -> Result<(), Box<dyn Error>> {
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS pulls(
+            repo TEXT NOT NULL,
+            id INTEGER NOT NULL,
             author TEXT NOT NULL,
             last_updated TEXT NOT NULL,
             data TEXT NOT NULL,
             category TEXT,
-            reserved_by TEXT
+            reserved_by TEXT,
+            milestone TEXT,
+            effort TEXT,
+            category_since TEXT,
+            PRIMARY KEY (repo, id)
         ) STRICT",
+		[],
+	)?;
+	if add_column(db, "pulls", "milestone", "TEXT")? {
+		db.execute(
+			"UPDATE pulls SET milestone = json_extract(data, '$.milestone.title')",
+			[],
+		)?;
+	}
+	// filled in by the next housekeeping run
+	add_column(db, "pulls", "effort", "TEXT")?;
+	if add_column(db, "pulls", "category_since", "TEXT")? {
+		db.execute(
+			"UPDATE pulls SET category_since = last_updated WHERE category IS NOT NULL",
+			[],
+		)?;
+	}
+	// category before the PR was reserved, restored when the reservation ends
+	add_column(db, "pulls", "prev_category", "TEXT")?;
+	// last_updated of the PR when its reviews were fetched, NULL if they never were
+	add_column(db, "pulls", "reviews_synced", "TEXT")?;
+	// closed PRs are kept as tombstones, so a reopened PR keeps its category, reviews and hidden flags
+	add_column(db, "pulls", "state", "TEXT NOT NULL DEFAULT 'open'")?;
+	add_column(db, "pulls", "closed_at", "TEXT")?;
+	// UTC time the dashboard first saw the PR, unlike last_updated not reset by comments
+	if add_column(db, "pulls", "first_seen", "TEXT NOT NULL DEFAULT ''")? {
+		db.execute("UPDATE pulls SET first_seen = last_updated", [])?;
+	}
+	// description of the PR, truncated to `MAX_BODY_LENGTH`
+	if add_column(db, "pulls", "body", "TEXT")? {
+		db.execute("UPDATE pulls SET body = json_extract(data, '$.body')", [])?;
+	}
+	// relation of the author to the repository, like `FIRST_TIME_CONTRIBUTOR`
+	if add_column(db, "pulls", "author_association", "TEXT")? {
+		db.execute(
+			"UPDATE pulls SET author_association = json_extract(data, '$.author_association')",
 			[],
 		)?;
+	}
+
+	// category set by hand with `/set-category`, kept by housekeeping until the labels change
+	add_column(db, "pulls", "category_locked", "INTEGER NOT NULL DEFAULT 0")?;
+	// `LABEL_SET_SQL` of the PR when its category was locked
+	add_column(db, "pulls", "locked_labels", "TEXT")?;
+
+	// latest review state per reviewer, see `/update-reviews`
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS pull_reviews(
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            reviewer TEXT NOT NULL,
+            state TEXT NOT NULL,
+            submitted_at TEXT,
+            commit_id TEXT,
+            PRIMARY KEY (repo, pull_id, reviewer),
+            FOREIGN KEY (repo, pull_id) REFERENCES pulls(repo, id) ON DELETE CASCADE
+        ) STRICT",
+		[],
+	)?;
+	// the commit a review was submitted on, to ignore approvals of code that was pushed over
+	add_column(db, "pull_reviews", "commit_id", "TEXT")?;
 
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS reservations(
+            repo TEXT NOT NULL,
+            id INTEGER NOT NULL,
+            time TEXT NOT NULL,
+            reserved_by TEXT,
+            expires_at TEXT,
+            note TEXT,
+            notify TEXT,
+            notified INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (repo, id),
+            FOREIGN KEY (repo, id) REFERENCES pulls(repo, id) ON DELETE CASCADE
+        ) STRICT",
+		[],
+	)?;
+	if add_column(db, "reservations", "reserved_by", "TEXT")? {
 		db.execute(
-			"CREATE TABLE IF NOT EXISTS reservations(
-            id INTEGER NOT NULL PRIMARY KEY,
+			"UPDATE reservations SET reserved_by = (SELECT reserved_by FROM pulls WHERE pulls.id = reservations.id)",
+			[],
+		)?;
+	}
+	add_column(db, "reservations", "expires_at", "TEXT")?;
+	add_column(db, "reservations", "note", "TEXT")?;
+	// URL to notify before expiry, and whether that happened
+	add_column(db, "reservations", "notify", "TEXT")?;
+	add_column(db, "reservations", "notified", "INTEGER NOT NULL DEFAULT 0")?;
+	// reservations from older versions expired one hour after their creation time
+	db.execute(
+		"UPDATE reservations SET expires_at = datetime(time, '+1 hour') WHERE expires_at IS NULL",
+		[],
+	)?;
+	reservation_times_to_utc(db)?;
+
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS hidden(
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            hidden_by TEXT NOT NULL,
+            time TEXT NOT NULL,
+            PRIMARY KEY (repo, pull_id, hidden_by)
+        ) STRICT",
+		[],
+	)?;
+
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS report_inclusions(
+            report TEXT NOT NULL,
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            week TEXT NOT NULL,
+            PRIMARY KEY (report, repo, pull_id, week)
+        ) STRICT",
+		[],
+	)?;
+
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS viewer_aliases(
+            alias TEXT NOT NULL PRIMARY KEY,
+            viewer TEXT NOT NULL,
+            time TEXT NOT NULL
+        ) STRICT",
+		[],
+	)?;
+
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS sync_state(
+            key TEXT NOT NULL PRIMARY KEY,
+            value TEXT NOT NULL
+        ) STRICT",
+		[],
+	)?;
+
+	// PRs closed on GitHub, kept for a while after removing them from pulls
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS departures(
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            data TEXT NOT NULL,
+            merged INTEGER NOT NULL,
+            time TEXT NOT NULL,
+            PRIMARY KEY (repo, pull_id)
+        ) STRICT",
+		[],
+	)?;
+
+	// append-only record of how tracked PRs ended, never cleaned up
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS pull_outcomes(
+            repo TEXT NOT NULL,
+            id INTEGER NOT NULL,
+            title TEXT,
+            author TEXT NOT NULL,
+            outcome TEXT NOT NULL,
+            closed_at TEXT NOT NULL,
+            last_category TEXT,
+            was_reserved_by TEXT,
+            PRIMARY KEY (repo, id, closed_at)
+        ) STRICT",
+		[],
+	)?;
+
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS expired_reservations(
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            reserved_by TEXT,
             time TEXT NOT NULL
         ) STRICT",
+		[],
+	)?;
+
+	// every reservation, kept after the PR is removed from pulls (UTC times)
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS reservation_log(
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            title TEXT,
+            reserved_by TEXT,
+            reserved_at TEXT NOT NULL,
+            released_at TEXT,
+            outcome TEXT
+        ) STRICT",
+		[],
+	)?;
+	// category the PR was reserved from
+	add_column(db, "reservation_log", "category", "TEXT")?;
+
+	// category changes with the rule or action that made them (UTC times)
+	db.execute(
+		"CREATE TABLE IF NOT EXISTS category_history(
+            repo TEXT NOT NULL,
+            pull_id INTEGER NOT NULL,
+            from_category TEXT,
+            to_category TEXT,
+            changed_at TEXT NOT NULL,
+            reason TEXT NOT NULL
+        ) STRICT",
+		[],
+	)?;
+	db.execute(
+		"CREATE INDEX IF NOT EXISTS category_history_pull ON category_history(repo, pull_id)",
+		[],
+	)?;
+
+	repository_keys(db, &configured_repos()[0])?;
+	Ok(())
+}
+
+/// Version 2: indexes for the dashboard, which lists each category in order of last update,
+/// skips reserved PRs and the PRs hidden by the viewer.
+fn query_indexes(db: &Connection) -> Result<(), Box<dyn Error>> {
+	db.execute_batch(
+		"CREATE INDEX IF NOT EXISTS pulls_category ON pulls(category, last_updated);
+		CREATE INDEX IF NOT EXISTS pulls_reserved_by ON pulls(reserved_by);
+		CREATE INDEX IF NOT EXISTS hidden_by ON hidden(hidden_by);",
+	)?;
+	Ok(())
+}
+
+/// Version 3: the title of a PR as a column, for lists that don't need the stored data.
+fn title_column(db: &Connection) -> Result<(), Box<dyn Error>> {
+	if add_column(db, "pulls", "title", "TEXT")? {
+		db.execute("UPDATE pulls SET title = json_extract(data, '$.title')", [])?;
+	}
+	Ok(())
+}
+
+/// Version 4: room for the compressed PR data, see `register_functions`.
+/// Existing rows are converted by `/compress-data`.
+fn compressed_data(db: &Connection) -> Result<(), Box<dyn Error>> {
+	add_column(db, "pulls", "data_compressed", "BLOB")?;
+	Ok(())
+}
+
+/// Version 5: rewrite the `TIME_FORMAT` timestamps stored by older versions to `UTC_TIME_FORMAT`.
+/// `hidden` and `viewer_aliases` were written in local time, all other tables in UTC.
+fn rfc3339_times(db: &Connection) -> Result<(), Box<dyn Error>> {
+	let columns = [
+		("pulls", "last_updated"),
+		("pulls", "category_since"),
+		("pulls", "reviews_synced"),
+		("pulls", "closed_at"),
+		("pulls", "first_seen"),
+		("pull_reviews", "submitted_at"),
+		("departures", "time"),
+		("pull_outcomes", "closed_at"),
+		("expired_reservations", "time"),
+		("reservation_log", "reserved_at"),
+		("reservation_log", "released_at"),
+		("category_history", "changed_at"),
+		("hidden", "time"),
+		("viewer_aliases", "time"),
+	];
+	for (table, column) in columns {
+		let modifier = if matches!(table, "hidden" | "viewer_aliases") {
+			", 'utc'"
+		} else {
+			""
+		};
+		db.execute(
+			&format!(
+				"UPDATE {table} SET {column} = strftime('%Y-%m-%dT%H:%M:%SZ', {column}{modifier})
+				WHERE {column} LIKE '____-__-__ __:__:__'"
+			),
 			[],
 		)?;
+	}
+	db.execute(
+		"UPDATE sync_state SET value = strftime('%Y-%m-%dT%H:%M:%SZ', value)
+		WHERE (key = 'last_success' OR key LIKE 'cursor:%') AND value LIKE '____-__-__ __:__:__'",
+		[],
+	)?;
+	Ok(())
+}
 
-		Ok(Self { db })
+/// Version 6: the number of open PRs per category after each housekeeping, see `/stats`.
+/// `filter` is empty for the counts of all PRs.
+fn count_snapshots(db: &Connection) -> Result<(), Box<dyn Error>> {
+	db.execute_batch(
+		"CREATE TABLE IF NOT EXISTS count_snapshots(
+            taken_at TEXT NOT NULL,
+            category TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            filter TEXT NOT NULL DEFAULT '',
+            PRIMARY KEY (filter, category, taken_at)
+        ) STRICT;
+		CREATE INDEX IF NOT EXISTS count_snapshots_taken_at ON count_snapshots(taken_at);",
+	)?;
+	Ok(())
+}
+
+/// Rewrite reservation times stored in local time by older versions to `UTC_TIME_FORMAT`.
+fn reservation_times_to_utc(db: &Connection) -> Result<(), Box<dyn Error>> {
+	let mut stmt = db.prepare(
+		"SELECT rowid, time, expires_at FROM reservations WHERE time NOT LIKE '%Z' OR expires_at NOT LIKE '%Z'",
+	)?;
+	let rows: Vec<_> = stmt
+		.query_map([], extract_row!(i64 String String))?
+		.collect::<Result<_, _>>()?;
+	drop(stmt);
+	// local times are interpreted with the current offset of the server
+	let to_utc = |time: &str| {
+		parse_timestamp(time)
+			.map(|x| x.format(UTC_TIME_FORMAT).to_string())
+			.unwrap_or_else(|| time.to_owned())
+	};
+	for (rowid, time, expires_at) in rows {
+		db.execute(
+			"UPDATE reservations SET time = ?1, expires_at = ?2 WHERE rowid = ?3",
+			params![to_utc(&time), to_utc(&expires_at), rowid],
+		)?;
 	}
+	Ok(())
+}
 
-	pub fn last_update(&self) -> Result<Option<String>, Box<dyn Error>> {
-		Ok(self.db.query_row("SELECT MAX(last_updated) FROM pulls", [], |row| {
-			row.get::<_, Option<String>>(0)
-		})?)
+/// Reduce the full PR JSON stored by older versions to `StoredPr`, reclaiming the space afterwards.
+fn strip_stored_data(db: &Connection) -> Result<(), Box<dyn Error>> {
+	let mut stripped = 0;
+	for (table, id) in [("pulls", "id"), ("departures", "pull_id")] {
+		// only full payloads have a head, compressed rows were stripped before
+		let mut stmt = db.prepare(&format!(
+			"SELECT repo, {id}, data FROM {table} WHERE json_valid(data) AND json_type(data, '$.head') IS NOT NULL"
+		))?;
+		let rows: Vec<_> = stmt
+			.query_map([], extract_row!(String i64 String))?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+		if rows.is_empty() {
+			continue;
+		}
+		tracing::info!("stripping the stored data of {} rows in {table}", rows.len());
+		let tx = db.unchecked_transaction()?;
+		for (repo, pull_id, data) in rows {
+			let data = serde_json::to_string(&serde_json::from_str::<StoredPr>(&data)?)?;
+			tx.execute(
+				&format!("UPDATE {table} SET data = ?1 WHERE repo = ?2 AND {id} = ?3"),
+				params![data, repo, pull_id],
+			)?;
+			stripped += 1;
+		}
+		tx.commit()?;
 	}
+	if stripped > 0 {
+		db.execute("VACUUM", [])?;
+	}
+	Ok(())
+}
 
-	pub fn transaction(&mut self) -> Result<Transaction, Box<dyn Error>> {
-		Ok(self.db.transaction()?)
+/// Key the tables of older versions by repository and PR number, assigning their rows to `repo`.
+/// Also drops reservations of untracked PRs and `reserved_by` values without reservation.
+/// Runs in the migration transaction, with foreign keys disabled.
+/// The rebuilt tables keep all columns of the old ones, including those added by `add_column`.
+fn repository_keys(db: &Connection, repo: &str) -> Result<(), Box<dyn Error>> {
+	let tables = [
+		("pulls", "PRIMARY KEY (repo, id)", ""),
+		(
+			"reservations",
+			"PRIMARY KEY (repo, id),
+			FOREIGN KEY (repo, id) REFERENCES pulls(repo, id) ON DELETE CASCADE",
+			"WHERE id IN (SELECT id FROM pulls)",
+		),
+		("hidden", "PRIMARY KEY (repo, pull_id, hidden_by)", ""),
+		("report_inclusions", "PRIMARY KEY (report, repo, pull_id, week)", ""),
+		("departures", "PRIMARY KEY (repo, pull_id)", ""),
+		("expired_reservations", "", ""),
+		("reservation_log", "", ""),
+	];
+
+	for (table, keys, condition) in tables {
+		let mut stmt = db.prepare("SELECT name, type, \"notnull\", dflt_value FROM pragma_table_info(?1)")?;
+		let columns: Vec<_> = stmt
+			.query_map(params![table], extract_row!(String String bool Option<String>))?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+		if columns.iter().any(|(name, ..)| name == "repo") {
+			continue;
+		}
+		tracing::info!("keying {table} by repository");
+		let mut definition = vec!["repo TEXT NOT NULL".to_owned()];
+		for (name, column_type, not_null, default) in &columns {
+			let mut column = format!("{name} {column_type}");
+			if *not_null {
+				column += " NOT NULL";
+			}
+			if let Some(default) = default {
+				column += &format!(" DEFAULT {default}");
+			}
+			definition.push(column);
+		}
+		if !keys.is_empty() {
+			definition.push(keys.to_owned());
+		}
+		let names = columns.iter().map(|(name, ..)| &**name).collect::<Vec<_>>().join(", ");
+		db.execute(
+			&format!("CREATE TABLE {table}_new({}) STRICT", definition.join(",\n")),
+			[],
+		)?;
+		db.execute(
+			&format!("INSERT INTO {table}_new (repo, {names}) SELECT ?1, {names} FROM {table} {condition}"),
+			params![repo],
+		)?;
+		db.execute(&format!("DROP TABLE {table}"), [])?;
+		db.execute(&format!("ALTER TABLE {table}_new RENAME TO {table}"), [])?;
+	}
+	db.execute(
+		"UPDATE pulls SET reserved_by = NULL
+		WHERE reserved_by IS NOT NULL AND (repo, id) NOT IN (SELECT repo, id FROM reservations)",
+		[],
+	)?;
+	Ok(())
+}
+
+/// Add a column to a table created by an older version, returning whether it was missing.
+fn add_column(db: &Connection, table: &str, column: &str, definition: &str) -> Result<bool, Box<dyn Error>> {
+	let exists = db.query_row(
+		"SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+		params![table, column],
+		|row| row.get::<_, usize>(0),
+	)? > 0;
+	if exists {
+		return Ok(false);
+	}
+	tracing::info!("adding column {table}.{column}");
+	db.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"), [])?;
+	Ok(true)
+}
+
+/// The fields of a GitHub PR that are kept in `pulls.data` and `departures.data`.
+/// Same names and shapes as in octocrab's `PullRequest`, which can be read as this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredPr {
+	pub number: u64,
+	pub title: Option<String>,
+	pub user: Option<StoredUser>,
+	pub labels: Option<Vec<StoredLabel>>,
+	pub milestone: Option<StoredMilestone>,
+	pub requested_reviewers: Option<Vec<StoredUser>>,
+	pub requested_teams: Option<Vec<StoredTeam>>,
+	pub created_at: Option<DateTime<Utc>>,
+	pub updated_at: Option<DateTime<Utc>>,
+	pub closed_at: Option<DateTime<Utc>>,
+	pub merged_at: Option<DateTime<Utc>>,
+	pub state: Option<IssueState>,
+	pub draft: Option<bool>,
+	pub base: Option<StoredBase>,
+	pub head: Option<StoredHead>,
+	pub html_url: Option<String>,
+	/// Only known for PRs fetched individually.
+	pub changed_files: Option<u64>,
+	pub additions: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredUser {
+	pub login: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredLabel {
+	pub name: String,
+	pub color: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMilestone {
+	pub title: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTeam {
+	pub slug: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredBase {
+	#[serde(rename = "ref")]
+	pub ref_field: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredHead {
+	pub sha: String,
+}
+
+impl From<&PullRequest> for StoredPr {
+	fn from(pr: &PullRequest) -> Self {
+		let user = |login: &str| StoredUser {
+			login: login.to_owned(),
+		};
+		Self {
+			number: pr.number,
+			title: pr.title.clone(),
+			user: pr.user.as_ref().map(|x| user(&x.login)),
+			labels: pr.labels.as_ref().map(|labels| {
+				labels
+					.iter()
+					.map(|x| StoredLabel {
+						name: x.name.clone(),
+						color: x.color.clone(),
+					})
+					.collect()
+			}),
+			milestone: pr
+				.milestone
+				.as_ref()
+				.map(|x| StoredMilestone { title: x.title.clone() }),
+			requested_reviewers: pr
+				.requested_reviewers
+				.as_ref()
+				.map(|x| x.iter().map(|x| user(&x.login)).collect()),
+			requested_teams: pr
+				.requested_teams
+				.as_ref()
+				.map(|x| x.iter().map(|x| StoredTeam { slug: x.slug.clone() }).collect()),
+			created_at: pr.created_at,
+			updated_at: pr.updated_at,
+			closed_at: pr.closed_at,
+			merged_at: pr.merged_at,
+			state: pr.state.clone(),
+			draft: pr.draft,
+			base: Some(StoredBase {
+				ref_field: pr.base.ref_field.clone(),
+			}),
+			head: Some(StoredHead {
+				sha: pr.head.sha.clone(),
+			}),
+			html_url: pr.html_url.as_ref().map(|x| x.to_string()),
+			changed_files: pr.changed_files,
+			additions: pr.additions,
+		}
 	}
 }
 
 #[derive(Clone)]
 pub struct PR {
-	inner: PullRequest,
+	inner: StoredPr,
+	/// `owner/name` of the repository.
+	pub repo: String,
 	pub category: Option<String>,
+	/// UTC, only set by `get_pulls`.
+	pub category_since: Option<String>,
+	/// Approvals according to the stored reviews, only set by `get_pulls` if they were fetched.
+	pub approvals: Option<usize>,
+	/// UTC, only set by `get_pulls`.
+	pub first_seen: Option<String>,
+	/// Only set by `get_pulls`.
+	pub author_association: Option<String>,
+}
+
+impl PR {
+	pub fn new(repo: String, inner: StoredPr, category: Option<String>) -> Self {
+		Self {
+			inner,
+			repo,
+			category,
+			category_since: None,
+			approvals: None,
+			first_seen: None,
+			author_association: None,
+		}
+	}
+
+	/// Number of approvals, from the stored reviews or else the `12.approvals:` labels.
+	/// An approval by a package maintainer counts twice.
+	pub fn approval_score(&self) -> usize {
+		let labels = self.labels.as_deref().unwrap_or(&[]);
+		let mut approvals = self.approvals.unwrap_or_else(|| {
+			if labels.iter().any(|x| x.name == "12.approvals: 3+") {
+				3
+			} else if labels.iter().any(|x| x.name == "12.approvals: 2") {
+				2
+			} else if labels.iter().any(|x| x.name == "12.approvals: 1") {
+				1
+			} else {
+				0
+			}
+		});
+		if labels.iter().any(|x| x.name == "12.approved-by: package-maintainer") {
+			approvals += 1;
+		}
+		approvals
+	}
+
+	/// Title of the PR, a placeholder if it was stored without one.
+	pub fn title(&self) -> &str {
+		self.inner.title.as_deref().unwrap_or("(no title)")
+	}
+
+	/// Time of the last update, falling back to when the dashboard first saw the PR (or the epoch)
+	/// if it was stored without one.
+	pub fn updated_at(&self) -> DateTime<Utc> {
+		self.inner
+			.updated_at
+			.unwrap_or_else(|| self.first_seen.as_deref().map(parse_utc).unwrap_or_default())
+	}
+
+	/// Whether the stored data lacks fields that are replaced by placeholders when rendering.
+	pub fn is_incomplete(&self) -> bool {
+		self.inner.title.is_none() || self.inner.updated_at.is_none()
+	}
+
+	/// Whether the author has not contributed to the repository before.
+	pub fn is_first_timer(&self) -> bool {
+		self.author_association
+			.as_deref()
+			.is_some_and(|x| FIRST_TIMER_ASSOCIATIONS.contains(&x))
+	}
+
+	/// Logins of requested reviewers, team requests are prefixed with `@org/`.
+	pub fn requested_reviewer_names(&self) -> Vec<String> {
+		let owner = self.repo.split('/').next().unwrap_or_default();
+		let mut names: Vec<_> = self
+			.requested_reviewers
+			.as_deref()
+			.unwrap_or_default()
+			.iter()
+			.map(|x| x.login.clone())
+			.collect();
+		for team in self.requested_teams.as_deref().unwrap_or_default() {
+			names.push(format!("@{owner}/{}", team.slug));
+		}
+		names
+	}
 }
 
 impl Deref for PR {
-	type Target = PullRequest;
+	type Target = StoredPr;
 
 	fn deref(&self) -> &Self::Target {
 		&self.inner
@@ -75,84 +1169,1247 @@ impl DerefMut for PR {
 	}
 }
 
+/// Author associations of first-time contributors. `NONE` is an author without any previous contribution.
+pub const FIRST_TIMER_ASSOCIATIONS: [&str; 3] = ["FIRST_TIME_CONTRIBUTOR", "FIRST_TIMER", "NONE"];
+
+/// Whether `get_pulls` sorts by number of approvals instead of last update time.
+pub fn sorts_by_approvals(category: Option<&str>, tweak_sort: bool) -> bool {
+	tweak_sort && category != Some(NEEDS_MERGER)
+}
+
+/// Split a `;`-separated label filter into its labels.
+/// Fails with a message for the client if a label contains an unsupported character.
+pub fn split_label_filter(filter: &str) -> Result<Vec<&str>, String> {
+	let mut labels = vec![];
+	for label in filter.split(';') {
+		if let Some(offender) = label
+			.chars()
+			.find(|x| !x.is_ascii_alphanumeric() && !matches!(x, '.' | ' ' | '-' | '_' | ':' | '/' | '(' | ')'))
+		{
+			return Err(format!("invalid character in label filter: {offender:?}"));
+		}
+		if label.is_empty() {
+			continue;
+		}
+		labels.push(label);
+	}
+	labels.sort();
+	labels.dedup();
+	Ok(labels)
+}
+
+/// Builder for queries on the pulls table.
+/// Every condition is added together with its parameters, so they can't get out of sync.
+#[derive(Clone, Default)]
+pub struct PullQuery {
+	conditions: Vec<String>,
+	params: Vec<Value>,
+	category: Option<String>,
+	tweak_sort: bool,
+	limit: Option<u64>,
+}
+
+impl PullQuery {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Apply the filters shared by the dashboard and the reserve endpoint.
+	/// Fails with a message for the client if a parameter is invalid.
+	pub fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+		let mut query = Self::new()
+			.labels_all(params.get("filter").map(|x| &**x).unwrap_or_default())?
+			.exclude_labels(params.get("exclude").map(|x| &**x).unwrap_or_default())?;
+		if let Some(milestone) = params.get("milestone").filter(|x| !x.is_empty()) {
+			query = query.milestone(milestone);
+		}
+		if let Some(effort) = params.get("effort").filter(|x| !x.is_empty()) {
+			query = query.effort(effort);
+		}
+		if let Some(repo) = params.get("repo").filter(|x| !x.is_empty()) {
+			query = query.repo(repo);
+		}
+		if params.get("first-timers").is_some_and(|x| x == "true") {
+			query = query.first_timers();
+		}
+		if let Some(waiting) = params.get("waiting-longer-than").filter(|x| !x.is_empty()) {
+			let waiting = parse_duration(waiting).ok_or_else(|| format!("invalid waiting-longer-than: {waiting:?}"))?;
+			query = query.first_seen_before(&(Utc::now() - waiting));
+		}
+		Ok(query)
+	}
+
+	fn condition(mut self, condition: &str, params: impl IntoIterator<Item = Value>) -> Self {
+		self.conditions.push(condition.to_owned());
+		self.params.extend(params);
+		self
+	}
+
+	/// `None` and `"New"` select uncategorized PRs.
+	pub fn category(mut self, category: Option<&str>) -> Self {
+		match category.filter(|x| *x != "New") {
+			Some(category) => {
+				self.category = Some(category.to_owned());
+				self.condition("category = ?", [Value::from(category.to_owned())])
+			},
+			None => {
+				self.category = None;
+				self.condition("category IS NULL", [])
+			},
+		}
+	}
+
+	/// Reserved PRs that go back to this category once the reservation ends, `None` and `"New"` for
+	/// uncategorized PRs.
+	pub fn reserved_from(self, category: Option<&str>) -> Self {
+		let reserved = self.condition(
+			"category = ? AND reserved_by IS NOT NULL",
+			[Value::from(AWAITING_REVIEWER.to_owned())],
+		);
+		match category.filter(|x| *x != "New") {
+			Some(category) => reserved.condition("prev_category = ?", [Value::from(category.to_owned())]),
+			None => reserved.condition("prev_category IS NULL", []),
+		}
+	}
+
+	/// Require all labels of a `;`-separated filter, fails if the filter is invalid.
+	pub fn labels_all(mut self, filter: &str) -> Result<Self, String> {
+		for label in split_label_filter(filter)? {
+			self = self.condition(
+				"pull_data(data, data_compressed) LIKE ?",
+				[Value::from(format!("%{label}%"))],
+			);
+		}
+		Ok(self)
+	}
+
+	/// Exclude PRs with any label of a `;`-separated filter, fails if the filter is invalid.
+	pub fn exclude_labels(mut self, filter: &str) -> Result<Self, String> {
+		for label in split_label_filter(filter)? {
+			self = self.condition(
+				"pull_data(data, data_compressed) NOT LIKE ?",
+				[Value::from(format!("%{label}%"))],
+			);
+		}
+		Ok(self)
+	}
+
+	/// PRs of one repository (`owner/name`).
+	pub fn repo(self, repo: &str) -> Self {
+		self.condition("repo = ?", [Value::from(repo.to_owned())])
+	}
+
+	pub fn only_unreserved(self) -> Self {
+		self.condition("reserved_by IS NULL", [])
+	}
+
+	/// Exclude PRs hidden by this viewer.
+	pub fn not_hidden_for(self, viewer: &str) -> Self {
+		self.condition(
+			"(repo, id) NOT IN (SELECT repo, pull_id FROM hidden WHERE hidden_by = ?)",
+			[Value::from(viewer.to_owned())],
+		)
+	}
+
+	/// `"none"` selects PRs without milestone.
+	pub fn milestone(self, milestone: &str) -> Self {
+		if milestone == "none" {
+			self.condition("milestone IS NULL", [])
+		} else {
+			self.condition("milestone = ?", [Value::from(milestone.to_owned())])
+		}
+	}
+
+	/// `"unknown"` selects PRs without effort estimate.
+	pub fn effort(self, effort: &str) -> Self {
+		if effort == "unknown" {
+			self.condition("effort IS NULL", [])
+		} else {
+			self.condition("effort = ?", [Value::from(effort.to_owned())])
+		}
+	}
+
+	/// PRs opened at or after the given time.
+	pub fn created_since(self, time: &DateTime<Utc>) -> Self {
+		self.condition(
+			"json_extract(pull_data(data, data_compressed), '$.created_at') >= ?",
+			[Value::from(time.format(UTC_TIME_FORMAT).to_string())],
+		)
+	}
+
+	/// PRs of first-time contributors.
+	pub fn first_timers(self) -> Self {
+		let associations = FIRST_TIMER_ASSOCIATIONS.map(|x| format!("'{x}'")).join(", ");
+		self.condition(&format!("author_association IN ({associations})"), [])
+	}
+
+	/// PRs the dashboard first saw before the given time.
+	pub fn first_seen_before(self, time: &DateTime<Utc>) -> Self {
+		self.condition(
+			"first_seen < ?",
+			[Value::from(time.format(UTC_TIME_FORMAT).to_string())],
+		)
+	}
+
+	/// PRs that entered their current (non-New) category at or after the given time.
+	pub fn category_since(self, time: &DateTime<Utc>) -> Self {
+		self.condition(
+			"category IS NOT NULL AND category_since >= ?",
+			[Value::from(time.format(UTC_TIME_FORMAT).to_string())],
+		)
+	}
+
+	/// Sort by number of approvals instead of last update (not applied to NeedsMerger).
+	pub fn tweak_sort(mut self, tweak_sort: bool) -> Self {
+		self.tweak_sort = tweak_sort;
+		self
+	}
+
+	pub fn limit(mut self, limit: u64) -> Self {
+		self.limit = Some(limit);
+		self
+	}
+
+	/// PRs after the last one of the previous page, in the order of `select_sql`.
+	pub fn after(self, cursor: &PageCursor) -> Self {
+		self.condition(
+			"(last_updated, repo, id) > (?, ?, ?)",
+			[
+				Value::from(cursor.last_updated.clone()),
+				Value::from(cursor.repo.clone()),
+				Value::from(cursor.id as i64),
+			],
+		)
+	}
+
+	/// Whether any condition restricts the selected PRs.
+	pub fn is_filtered(&self) -> bool {
+		!self.conditions.is_empty()
+	}
+
+	pub fn sorts_by_approvals(&self) -> bool {
+		sorts_by_approvals(self.category.as_deref(), self.tweak_sort)
+	}
+
+	pub fn where_clause(&self) -> String {
+		let mut sql = "WHERE 1=1".to_owned();
+		for condition in &self.conditions {
+			sql += " AND ";
+			sql += condition;
+		}
+		sql
+	}
+
+	pub fn params(&self) -> ParamsFromIter<std::slice::Iter<'_, Value>> {
+		params_from_iter(self.params.iter())
+	}
+
+	/// Statement selecting the given columns of open PRs, in order of last update.
+	/// PRs updated at the same time are ordered by repository and number, so that pages don't overlap.
+	pub fn select_sql(&self, columns: &str) -> String {
+		let mut sql = format!(
+			"SELECT {columns} FROM pulls {} AND state = 'open' ORDER BY last_updated ASC, repo ASC, id ASC",
+			self.where_clause()
+		);
+		if let Some(limit) = self.limit {
+			sql += &format!(" LIMIT {limit}");
+		}
+		sql
+	}
+
+	pub fn count_sql(&self) -> String {
+		format!("SELECT COUNT(*) FROM pulls {} AND state = 'open'", self.where_clause())
+	}
+
+	pub fn count_by_category_sql(&self) -> String {
+		format!(
+			"SELECT category, COUNT(*) FROM pulls {} AND state = 'open' GROUP BY category",
+			self.where_clause()
+		)
+	}
+}
+
+/// Position in the list of PRs ordered by `select_sql`, see `PullQuery::after`.
+/// Unlike an offset, pages stay consistent when PRs are updated or closed in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageCursor {
+	/// `UTC_TIME_FORMAT`, as in `pulls.last_updated`.
+	pub last_updated: String,
+	pub repo: String,
+	pub id: u64,
+}
+
+impl PageCursor {
+	/// Opaque form for a query parameter.
+	pub fn encode(&self) -> String {
+		hex::encode(format!("{}\n{}\n{}", self.last_updated, self.repo, self.id))
+	}
+
+	pub fn decode(cursor: &str) -> Option<Self> {
+		let cursor = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+		let mut parts = cursor.splitn(3, '\n');
+		Some(Self {
+			last_updated: parts.next()?.to_owned(),
+			repo: parts.next()?.to_owned(),
+			id: parts.next()?.parse().ok()?,
+		})
+	}
+}
+
+/// Number of open PRs per category, see `CommonQueries::count_by_category`.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CategoryCounts {
+	/// Without a category.
+	pub new: usize,
+	pub awaiting_author: usize,
+	pub needs_reviewer: usize,
+	pub awaiting_reviewer: usize,
+	pub needs_merger: usize,
+	pub stale: usize,
+	pub needs_eval: usize,
+	/// Categories unknown to this version.
+	pub other: usize,
+}
+
+impl CategoryCounts {
+	/// Count of a category, `None` for new PRs.
+	pub fn get(&self, category: Option<&str>) -> usize {
+		let mut counts = *self;
+		*counts.get_mut(category)
+	}
+
+	fn get_mut(&mut self, category: Option<&str>) -> &mut usize {
+		match category {
+			None => &mut self.new,
+			Some(x) if x == AWAITING_AUTHOR => &mut self.awaiting_author,
+			Some(x) if x == NEEDS_REVIEWER => &mut self.needs_reviewer,
+			Some(x) if x == AWAITING_REVIEWER => &mut self.awaiting_reviewer,
+			Some(x) if x == NEEDS_MERGER => &mut self.needs_merger,
+			Some(x) if x == STALE => &mut self.stale,
+			Some(x) if x == NEEDS_EVAL => &mut self.needs_eval,
+			Some(_) => &mut self.other,
+		}
+	}
+
+	/// Each count with the name of its category, as shown on the dashboard.
+	pub fn entries(&self) -> [(&'static str, usize); 8] {
+		[
+			("New", self.new),
+			(AWAITING_AUTHOR, self.awaiting_author),
+			(NEEDS_REVIEWER, self.needs_reviewer),
+			(AWAITING_REVIEWER, self.awaiting_reviewer),
+			(NEEDS_MERGER, self.needs_merger),
+			(STALE, self.stale),
+			(NEEDS_EVAL, self.needs_eval),
+			("Other", self.other),
+		]
+	}
+
+	pub fn total(&self) -> usize {
+		self.new
+			+ self.awaiting_author
+			+ self.needs_reviewer
+			+ self.awaiting_reviewer
+			+ self.needs_merger
+			+ self.stale
+			+ self.needs_eval
+			+ self.other
+	}
+}
+
+/// The fields of an open PR stored as columns, read without deserializing its data.
+#[derive(Serialize)]
+pub struct PrSummary {
+	pub repo: String,
+	pub number: u64,
+	pub title: Option<String>,
+	/// `UTC_TIME_FORMAT`.
+	pub updated: String,
+	pub category: Option<String>,
+	/// `UTC_TIME_FORMAT`.
+	pub category_since: Option<String>,
+	pub labels: Vec<StoredLabel>,
+}
+
+impl PrSummary {
+	/// Cursor of the page after this PR.
+	pub fn cursor(&self) -> PageCursor {
+		PageCursor {
+			last_updated: self.updated.clone(),
+			repo: self.repo.clone(),
+			id: self.number,
+		}
+	}
+}
+
+/// A PR that was closed or merged on GitHub.
+pub struct Departure {
+	pub repo: String,
+	pub id: u64,
+	pub title: String,
+	pub merged: bool,
+	/// UTC
+	pub time: String,
+}
+
+pub struct ExpiredReservation {
+	pub repo: String,
+	pub id: u64,
+	/// `None` if the PR is no longer tracked.
+	pub title: Option<String>,
+	pub reserved_by: Option<String>,
+	/// UTC
+	pub time: String,
+}
+
 pub trait CommonQueries {
-	fn get_pulls(
+	fn get_pulls(&self, query: &PullQuery) -> Result<Vec<PR>, Box<dyn Error>>;
+
+	/// Like `get_pulls`, but only the summaries, always in order of last update.
+	fn get_pull_summaries(&self, query: &PullQuery) -> Result<Vec<PrSummary>, Box<dyn Error>>;
+
+	/// Number of PRs selected by `query`, ignoring its limit.
+	fn count_pulls(&self, query: &PullQuery) -> Result<usize, Box<dyn Error>>;
+
+	/// Number of PRs selected by `query` per category, ignoring its limit and category.
+	fn count_by_category(&self, query: &PullQuery) -> Result<CategoryCounts, Box<dyn Error>>;
+
+	/// Departures since the given time. The query may only filter by labels and repository.
+	fn get_departures(&self, query: &PullQuery, since: &DateTime<Utc>) -> Result<Vec<Departure>, Box<dyn Error>>;
+
+	/// Expired reservations since the given time, of PRs still matching the query.
+	fn get_expired_reservations(
 		&self,
-		category: Option<&str>,
-		filter_query: &str,
-		exclude: &str,
-		only_not_reserved: bool,
-		tweak_sort: bool,
-		limit: u64,
-	) -> Result<Vec<PR>, Box<dyn Error>>;
+		query: &PullQuery,
+		since: &DateTime<Utc>,
+	) -> Result<Vec<ExpiredReservation>, Box<dyn Error>>;
+
+	/// Problems that should never happen, but were left behind by crashes of older versions.
+	fn check_consistency(&self) -> Result<ConsistencyReport, Box<dyn Error>>;
+
+	/// Open PRs whose data fails to decompress or deserialize.
+	fn broken_rows(&self) -> Result<Vec<BrokenRow>, Box<dyn Error>>;
 }
 
 impl<'conn> CommonQueries for Transaction<'conn> {
-	fn get_pulls(
-		&self,
-		category: Option<&str>,
-		filter_query: &str,
-		exclude: &str,
-		only_not_reserved: bool,
-		mut tweak_sort: bool,
-		limit: u64,
-	) -> Result<Vec<PR>, Box<dyn Error>> {
-		if category == Some(NEEDS_MERGER) {
-			tweak_sort = false;
-		}
-		let sql_filter = construct_sql_filter(filter_query, exclude);
-		let reserved_filter = if only_not_reserved {
-			"AND reserved_by IS NULL"
-		} else {
-			""
-		};
-		let new = category.map(|x| x == "New").unwrap_or(true);
-		let qual = if new { "IS NULL" } else { "= ?1" };
-		let cat = if new { "" } else { category.as_ref().unwrap() };
-
-		let mut query = self.prepare(&format!(
-			"SELECT data, category
-			FROM pulls
-			WHERE
-			category {qual}
-			{sql_filter}
-			{reserved_filter}
-			ORDER BY last_updated ASC LIMIT {limit}"
-		))?;
-		println!("query = {query:?}");
-		let params = if cat != "" { params![cat] } else { params![] };
-		let rows = query.query_map(params, extract_row!(String Option<String>))?;
+	fn get_pulls(&self, query: &PullQuery) -> Result<Vec<PR>, Box<dyn Error>> {
+		let mut stmt = self.prepare(&query.select_sql(&format!(
+			"repo, pull_data(data, data_compressed), category, category_since, first_seen, author_association,
+			CASE WHEN reviews_synced IS NOT NULL THEN {} END, id",
+			approvals_sql()
+		)))?;
+		let rows = stmt.query_map(
+			query.params(),
+			extract_row!(String Option<String> Option<String> Option<String> String Option<String> Option<usize> u64),
+		)?;
 		let mut prs: Vec<PR> = vec![];
 		for data in rows {
 			let data = data?;
-			let pr = data.0;
-			let cat = data.1;
-			prs.push(PR {
-				inner: serde_json::from_str(&pr)?,
-				category: cat,
-			});
+			// fails to decompress, listed by `broken_rows`
+			let Some(pr) = data.1 else {
+				continue;
+			};
+			let pr = match serde_json::from_str(&pr) {
+				Ok(pr) => pr,
+				Err(err) => {
+					// listed by `broken_rows` as well
+					tracing::error!("skipping {}#{}, its data is unreadable: {err}", data.0, data.7);
+					continue;
+				},
+			};
+			let cat = data.2;
+			let mut pr = PR::new(data.0, pr, cat);
+			pr.category_since = data.3;
+			pr.first_seen = Some(data.4);
+			pr.author_association = data.5;
+			pr.approvals = data.6;
+			prs.push(pr);
 		}
-		if tweak_sort {
-			// sort by: number of approvals, last updated time
+		if query.sorts_by_approvals() {
+			// sort by: number of approvals, first-time contributors first, last updated time
 			let now = Utc::now();
-			prs.sort_unstable_by_key(|x| {
-				let mut approvals = 0;
-				let labels = x.labels.as_deref().unwrap_or(&[]);
-				if labels.iter().any(|x| x.name == "12.approvals: 1") {
-					approvals = 1;
-				}
-				if labels.iter().any(|x| x.name == "12.approvals: 2") {
-					approvals = 2;
+			prs.sort_unstable_by_key(|x| (x.approval_score(), !x.is_first_timer(), Reverse(now - x.updated_at())));
+		}
+		Ok(prs)
+	}
+
+	fn get_pull_summaries(&self, query: &PullQuery) -> Result<Vec<PrSummary>, Box<dyn Error>> {
+		let mut stmt = self.prepare(&query.select_sql(
+			"repo, id, title, last_updated, category, category_since,
+			json_extract(pull_data(data, data_compressed), '$.labels')",
+		))?;
+		let rows = stmt.query_map(
+			query.params(),
+			extract_row!(String u64 Option<String> String Option<String> Option<String> Option<String>),
+		)?;
+		let mut summaries = vec![];
+		for row in rows {
+			let (repo, number, title, updated, category, category_since, labels) = row?;
+			summaries.push(PrSummary {
+				repo,
+				number,
+				title,
+				updated,
+				category,
+				category_since,
+				labels: labels
+					.map(|x| serde_json::from_str(&x))
+					.transpose()?
+					.unwrap_or_default(),
+			});
+		}
+		Ok(summaries)
+	}
+
+	fn count_pulls(&self, query: &PullQuery) -> Result<usize, Box<dyn Error>> {
+		Ok(self.query_row(&query.count_sql(), query.params(), |row| row.get(0))?)
+	}
+
+	fn count_by_category(&self, query: &PullQuery) -> Result<CategoryCounts, Box<dyn Error>> {
+		let mut stmt = self.prepare(&query.count_by_category_sql())?;
+		let mut counts = CategoryCounts::default();
+		for row in stmt.query_map(query.params(), extract_row!(Option<String> usize))? {
+			let (category, count) = row?;
+			*counts.get_mut(category.as_deref()) += count;
+		}
+		Ok(counts)
+	}
+
+	fn get_departures(&self, query: &PullQuery, since: &DateTime<Utc>) -> Result<Vec<Departure>, Box<dyn Error>> {
+		let mut params = query.params.clone();
+		params.push(Value::from(since.format(UTC_TIME_FORMAT).to_string()));
+		// departures are never compressed, the label conditions read `data_compressed` like in `pulls`
+		let mut stmt = self.prepare(&format!(
+			"SELECT repo, pull_id, json_extract(data, '$.title'), merged, time
+			FROM (SELECT *, NULL AS data_compressed FROM departures)
+			{} AND time >= ?
+			ORDER BY time ASC",
+			query.where_clause()
+		))?;
+		let rows = stmt
+			.query_map(
+				params_from_iter(params),
+				extract_row!(String u64 Option<String> bool String),
+			)?
+			.map(|x| {
+				x.map(|(repo, id, title, merged, time)| Departure {
+					repo,
+					id,
+					title: title.unwrap_or_default(),
+					merged,
+					time,
+				})
+			})
+			.collect::<Result<_, _>>()?;
+		Ok(rows)
+	}
+
+	fn get_expired_reservations(
+		&self,
+		query: &PullQuery,
+		since: &DateTime<Utc>,
+	) -> Result<Vec<ExpiredReservation>, Box<dyn Error>> {
+		let mut sql = "SELECT repo, pull_id,
+				(SELECT title FROM pulls WHERE pulls.repo = expired_reservations.repo AND id = pull_id),
+				reserved_by, time
+			FROM expired_reservations
+			WHERE time >= ?"
+			.to_owned();
+		let mut params = vec![Value::from(since.format(UTC_TIME_FORMAT).to_string())];
+		if query.is_filtered() {
+			sql += &format!(
+				" AND (repo, pull_id) IN (SELECT repo, id FROM pulls {})",
+				query.where_clause()
+			);
+			params.extend(query.params.iter().cloned());
+		}
+		sql += " ORDER BY time ASC";
+		let mut stmt = self.prepare(&sql)?;
+		let rows = stmt
+			.query_map(
+				params_from_iter(params),
+				extract_row!(String u64 Option<String> Option<String> String),
+			)?
+			.map(|x| {
+				x.map(|(repo, id, title, reserved_by, time)| ExpiredReservation {
+					repo,
+					id,
+					title,
+					reserved_by,
+					time,
+				})
+			})
+			.collect::<Result<_, _>>()?;
+		Ok(rows)
+	}
+
+	fn check_consistency(&self) -> Result<ConsistencyReport, Box<dyn Error>> {
+		let mut stmt = self.prepare(
+			"SELECT repo, id, reserved_by FROM reservations
+			WHERE (repo, id) NOT IN (SELECT repo, id FROM pulls WHERE state = 'open')",
+		)?;
+		let orphan_reservations = stmt
+			.query_map([], |row| {
+				Ok(OrphanReservation {
+					repo: row.get(0)?,
+					id: row.get(1)?,
+					reserved_by: row.get(2)?,
+				})
+			})?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+		let mut stmt = self.prepare(
+			"SELECT pulls.repo, pulls.id, pulls.reserved_by, reservations.reserved_by FROM pulls
+			LEFT JOIN reservations ON reservations.repo = pulls.repo AND reservations.id = pulls.id
+			WHERE pulls.state = 'open' AND pulls.reserved_by IS NOT reservations.reserved_by",
+		)?;
+		let dangling_reserved_by = stmt
+			.query_map([], |row| {
+				Ok(ReservedByMismatch {
+					repo: row.get(0)?,
+					id: row.get(1)?,
+					reserved_by: row.get(2)?,
+					reservation: row.get(3)?,
+				})
+			})?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+		let mut stmt =
+			self.prepare("SELECT repo, id, category FROM pulls WHERE state = 'open' AND category IS NOT NULL")?;
+		let mut invalid_categories = vec![];
+		for row in stmt.query_map([], extract_row!(String u64 String))? {
+			let (repo, id, category) = row?;
+			if !category::CATEGORIES.contains(&&*category) {
+				invalid_categories.push(InvalidCategory { repo, id, category });
+			}
+		}
+		drop(stmt);
+		Ok(ConsistencyReport {
+			orphan_reservations,
+			dangling_reserved_by,
+			invalid_categories,
+			broken_rows: self.broken_rows()?,
+		})
+	}
+
+	fn broken_rows(&self) -> Result<Vec<BrokenRow>, Box<dyn Error>> {
+		let mut stmt = self.prepare("SELECT repo, id, data, data_compressed FROM pulls WHERE state = 'open'")?;
+		let mut rows = stmt.query([])?;
+		let mut broken = vec![];
+		while let Some(row) = rows.next()? {
+			let data = match row.get::<_, Option<Vec<u8>>>(3)? {
+				Some(compressed) => decompress_data(&compressed).map_err(|err| format!("decompression failed: {err}")),
+				None => Ok(row.get(2)?),
+			};
+			let error = match data {
+				Ok(data) => serde_json::from_str::<StoredPr>(&data).err().map(|err| err.to_string()),
+				Err(err) => Some(err),
+			};
+			if let Some(error) = error {
+				broken.push(BrokenRow {
+					repo: row.get(0)?,
+					id: row.get(1)?,
+					error,
+				});
+			}
+		}
+		Ok(broken)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Barrier};
+
+	use chrono::{Duration, TimeZone};
+	use itertools::Itertools;
+
+	use super::*;
+
+	fn memory_db() -> DB {
+		DB::new_with_path(IN_MEMORY).unwrap()
+	}
+
+	/// Database file of a test, removed together with its WAL at the end of the test.
+	struct TempDb(String);
+
+	impl TempDb {
+		fn new(name: &str) -> Self {
+			let path = env::temp_dir().join(format!("pr-dashboard-{name}-{}.db", std::process::id()));
+			let db = Self(path.to_str().unwrap().to_owned());
+			db.remove();
+			db
+		}
+
+		fn remove(&self) {
+			for suffix in ["", "-wal", "-shm"] {
+				let _ = std::fs::remove_file(format!("{}{suffix}", self.0));
+			}
+		}
+	}
+
+	impl Drop for TempDb {
+		fn drop(&mut self) {
+			self.remove();
+		}
+	}
+
+	/// The dashboard lists a category with `pulls_category`, skipping hidden PRs with `hidden_by`,
+	/// and the reservations of a viewer are found with `pulls_reserved_by`.
+	#[test]
+	fn queries_use_the_indexes() {
+		let mut db = memory_db();
+		let tx = db.transaction().unwrap();
+		let plan = |sql: &str, params: &[Value]| -> String {
+			let mut stmt = tx.prepare(&format!("EXPLAIN QUERY PLAN {sql}")).unwrap();
+			let details = stmt
+				.query_map(params_from_iter(params), |row| row.get::<_, String>(3))
+				.unwrap()
+				.collect::<Result<Vec<_>, _>>()
+				.unwrap();
+			details.join("\n")
+		};
+
+		let query = PullQuery::new()
+			.category(Some(NEEDS_REVIEWER))
+			.only_unreserved()
+			.not_hidden_for("viewer");
+		let dashboard = plan(&query.select_sql("repo, id"), &query.params);
+		assert!(
+			dashboard.contains("USING INDEX pulls_category (category=?)"),
+			"{dashboard}"
+		);
+		assert!(dashboard.contains("USING INDEX hidden_by (hidden_by=?)"), "{dashboard}");
+		let held = plan(
+			"SELECT repo, id FROM pulls WHERE reserved_by = ?1",
+			&[Value::from("viewer".to_owned())],
+		);
+		assert!(held.contains("USING INDEX pulls_reserved_by (reserved_by=?)"), "{held}");
+	}
+
+	/// The rewritten timestamps sort like the old ones did, also across day and year boundaries.
+	#[test]
+	fn rfc3339_times_keep_the_order() {
+		let db = memory_db();
+		let times = [
+			"2024-01-10 09:00:00",
+			"2023-12-31 23:59:59",
+			"2024-01-01 00:00:00",
+			"2024-01-09 23:00:00",
+			"2024-01-01 00:00:00",
+			"2024-02-01 00:00:00",
+			"2024-01-01 00:00:01",
+		];
+		for (id, time) in times.iter().enumerate() {
+			db.db
+				.execute_batch(&format!(
+					"INSERT INTO pulls (repo, id, author, last_updated, data) VALUES ('o/r', {id}, 'a', '{time}', '{{}}');
+					INSERT INTO departures (repo, pull_id, data, merged, time) VALUES ('o/r', {id}, '{{}}', 0, '{time}');
+					INSERT INTO hidden (repo, pull_id, hidden_by, time) VALUES ('o/r', {id}, 'viewer', '{time}');"
+				))
+				.unwrap();
+		}
+		let tables = [
+			("pulls", "id", "last_updated"),
+			("departures", "pull_id", "time"),
+			("hidden", "pull_id", "time"),
+		];
+		let order = || {
+			tables.map(|(table, id, time)| values(&db.db, &format!("SELECT {id} FROM {table} ORDER BY {time}, {id}")))
+		};
+		let before = order();
+		assert_eq!(before[0], [1, 2, 4, 6, 3, 0, 5].map(|x| vec![Value::Integer(x)]));
+
+		rfc3339_times(&db.db).unwrap();
+		assert_eq!(order(), before);
+		for (table, _, time) in tables {
+			let old = values(
+				&db.db,
+				&format!("SELECT {time} FROM {table} WHERE {time} NOT LIKE '____-__-__T__:__:__Z'"),
+			);
+			assert!(old.is_empty(), "{table}: {old:?}");
+		}
+	}
+
+	#[test]
+	fn pages_split_equal_update_times() {
+		let mut db = memory_db();
+		let tx = db.transaction().unwrap();
+		let rows = [
+			("o/r", 1, "2024-01-01T00:00:00Z"),
+			("a/b", 5, "2024-01-02T00:00:00Z"),
+			("o/r", 2, "2024-01-02T00:00:00Z"),
+			("o/r", 3, "2024-01-02T00:00:00Z"),
+			("a/b", 4, "2024-01-02T00:00:00Z"),
+			("o/r", 6, "2024-01-03T00:00:00Z"),
+		];
+		for (repo, id, updated) in rows {
+			tx.execute(
+				"INSERT INTO pulls (repo, id, author, last_updated, data) VALUES (?1, ?2, 'a', ?3, ?4)",
+				params![repo, id, updated, serde_json::json!({ "number": id }).to_string()],
+			)
+			.unwrap();
+		}
+		let ids = |pulls: Vec<PrSummary>| pulls.into_iter().map(|x| (x.repo, x.number)).collect::<Vec<_>>();
+		let all = ids(tx.get_pull_summaries(&PullQuery::new()).unwrap());
+		let expected = [("o/r", 1), ("a/b", 4), ("a/b", 5), ("o/r", 2), ("o/r", 3), ("o/r", 6)];
+		assert_eq!(all, expected.map(|(repo, id)| (repo.to_owned(), id)));
+
+		// every page size puts a boundary between PRs updated at the same time
+		for size in 1..=4 {
+			let mut pages = vec![];
+			let mut cursor: Option<String> = None;
+			loop {
+				let mut query = PullQuery::new().limit(size);
+				if let Some(cursor) = &cursor {
+					query = query.after(&PageCursor::decode(cursor).unwrap());
 				}
-				if labels.iter().any(|x| x.name == "12.approvals: 3+") {
-					approvals = 3;
+				let page = tx.get_pull_summaries(&query).unwrap();
+				let Some(last) = page.last() else {
+					break;
+				};
+				cursor = Some(last.cursor().encode());
+				pages.extend(ids(page));
+			}
+			assert_eq!(pages, all, "page size {size}");
+		}
+	}
+
+	// one writer waits for the other within the busy timeout, instead of failing with `database is locked`
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn pool_connections_write_at_once() {
+		let file = TempDb::new("concurrent-writes");
+		let pool = Arc::new(DbPool::new_with_path(&file.0, 2).unwrap());
+		let started = Arc::new(Barrier::new(2));
+		let writers: Vec<_> = (0..2)
+			.map(|writer: i64| {
+				let pool = pool.clone();
+				let started = started.clone();
+				tokio::spawn(async move {
+					pool.run(move |db: &mut DB| {
+						if writer == 1 {
+							// starts writing while the other connection holds the write lock
+							started.wait();
+						}
+						let tx = db.transaction()?;
+						for id in 0..50 {
+							tx.execute(
+								"INSERT INTO pulls (repo, id, author, last_updated, data) VALUES ('o/r', ?1, 'a', '', '{}')",
+								params![writer * 100 + id],
+							)?;
+						}
+						if writer == 0 {
+							started.wait();
+							std::thread::sleep(std::time::Duration::from_millis(100));
+						}
+						tx.commit()?;
+						Ok(())
+					})
+					.await
+					.map_err(|err| err.to_string())
+				})
+			})
+			.collect();
+		for writer in writers {
+			writer.await.unwrap().unwrap();
+		}
+
+		assert_eq!(pool.idle.lock().unwrap().len(), 2);
+		let count = pool
+			.run(|db: &mut DB| {
+				Ok(db
+					.db
+					.query_row("SELECT count(*) FROM pulls", [], |row| row.get::<_, i64>(0))?)
+			})
+			.await
+			.unwrap();
+		assert_eq!(count, 100);
+	}
+
+	/// All columns of every table, by table.
+	fn columns(db: &Connection) -> Vec<(String, Vec<String>)> {
+		let mut stmt = db
+			.prepare(
+				"SELECT m.name, p.name FROM sqlite_master m, pragma_table_info(m.name) p
+				WHERE m.type = 'table' ORDER BY m.name, p.name",
+			)
+			.unwrap();
+		let rows = stmt
+			.query_map([], extract_row!(String String))
+			.unwrap()
+			.collect::<Result<Vec<_>, _>>()
+			.unwrap();
+		rows.into_iter()
+			.chunk_by(|x| x.0.clone())
+			.into_iter()
+			.map(|(table, columns)| (table, columns.map(|x| x.1).collect()))
+			.collect()
+	}
+
+	/// Values of the selected columns of every row.
+	fn values(db: &Connection, sql: &str) -> Vec<Vec<Value>> {
+		let mut stmt = db.prepare(sql).unwrap();
+		let count = stmt.column_count();
+		stmt.query_map([], |row| (0..count).map(|i| row.get::<_, Value>(i)).collect())
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap()
+	}
+
+	/// A database written by the first version, before the schema was versioned.
+	#[test]
+	fn upgrades_unversioned_database() {
+		let file = TempDb::new("unversioned");
+		let old = Connection::open(&file.0).unwrap();
+		old.execute_batch(
+			"CREATE TABLE pulls(
+				id INTEGER NOT NULL PRIMARY KEY,
+				author TEXT NOT NULL,
+				last_updated TEXT NOT NULL,
+				data TEXT NOT NULL,
+				category TEXT,
+				reserved_by TEXT
+			) STRICT;
+			CREATE TABLE reservations(
+				id INTEGER NOT NULL PRIMARY KEY,
+				time TEXT NOT NULL
+			) STRICT;",
+		)
+		.unwrap();
+		// the full payload of GitHub, as stored back then
+		let data = serde_json::json!({
+			"number": 1,
+			"title": "foo: init at 1.0",
+			"body": "Adds foo.",
+			"author_association": "FIRST_TIME_CONTRIBUTOR",
+			"user": { "login": "alice" },
+			"labels": [{ "name": "8.has: package (new)", "color": "ffffff" }],
+			"milestone": { "title": "25.05" },
+			"updated_at": "2024-01-02T03:04:05Z",
+			"head": { "sha": "abc" },
+			"base": { "ref": "master" },
+		});
+		old.execute(
+			"INSERT INTO pulls VALUES (1, 'alice', '2024-01-02 03:04:05', ?1, 'NeedsReviewer', '10.0.0.1')",
+			params![data.to_string()],
+		)
+		.unwrap();
+		// reserved, but the reservation was lost
+		old.execute(
+			"INSERT INTO pulls VALUES (2, 'bob', '2024-01-03 00:00:00', '{\"number\":2,\"title\":\"bar\"}', NULL, '10.0.0.2')",
+			[],
+		)
+		.unwrap();
+		old.execute_batch(
+			"INSERT INTO reservations VALUES (1, '2024-01-02 03:10:00');
+			INSERT INTO reservations VALUES (3, '2024-01-02 03:10:00');",
+		)
+		.unwrap();
+		drop(old);
+
+		let mut db = DB::new_with_path(&file.0).unwrap();
+		assert_eq!(columns(&db.db), columns(&memory_db().db));
+		let version: usize = db
+			.db
+			.pragma_query_value(None, "user_version", |row| row.get(0))
+			.unwrap();
+		assert_eq!(version, MIGRATIONS.len());
+
+		let repo = Value::from(configured_repos()[0].clone());
+		let text = |x: &str| Value::from(x.to_owned());
+		assert_eq!(
+			values(
+				&db.db,
+				"SELECT repo, id, author, last_updated, category, reserved_by, milestone, effort, category_since,
+				prev_category, state, closed_at, first_seen, body, author_association, category_locked, title
+				FROM pulls ORDER BY id"
+			),
+			[
+				vec![
+					repo.clone(),
+					Value::Integer(1),
+					text("alice"),
+					text("2024-01-02T03:04:05Z"),
+					text(NEEDS_REVIEWER),
+					text("10.0.0.1"),
+					text("25.05"),
+					Value::Null,
+					text("2024-01-02T03:04:05Z"),
+					Value::Null,
+					text("open"),
+					Value::Null,
+					text("2024-01-02T03:04:05Z"),
+					text("Adds foo."),
+					text("FIRST_TIME_CONTRIBUTOR"),
+					Value::Integer(0),
+					text("foo: init at 1.0"),
+				],
+				vec![
+					repo.clone(),
+					Value::Integer(2),
+					text("bob"),
+					text("2024-01-03T00:00:00Z"),
+					Value::Null,
+					Value::Null,
+					Value::Null,
+					Value::Null,
+					Value::Null,
+					Value::Null,
+					text("open"),
+					Value::Null,
+					text("2024-01-03T00:00:00Z"),
+					Value::Null,
+					Value::Null,
+					Value::Integer(0),
+					text("bar"),
+				],
+			]
+		);
+		// reservations were stored in local time
+		let utc = |x: &str| text(&parse_timestamp(x).unwrap().format(UTC_TIME_FORMAT).to_string());
+		assert_eq!(
+			values(
+				&db.db,
+				"SELECT repo, id, time, reserved_by, expires_at, note, notify, notified FROM reservations"
+			),
+			[vec![
+				repo,
+				Value::Integer(1),
+				utc("2024-01-02 03:10:00"),
+				text("10.0.0.1"),
+				utc("2024-01-02 04:10:00"),
+				Value::Null,
+				Value::Null,
+				Value::Integer(0),
+			]]
+		);
+
+		// the stored data was reduced to `StoredPr` and can still be read
+		let tx = db.transaction().unwrap();
+		let pulls = tx.get_pulls(&PullQuery::new().category(Some(NEEDS_REVIEWER))).unwrap();
+		assert_eq!(pulls.len(), 1);
+		assert_eq!(pulls[0].title(), "foo: init at 1.0");
+		assert_eq!(pulls[0].head.as_ref().unwrap().sha, "abc");
+		assert!(tx.check_consistency().unwrap().is_consistent());
+	}
+
+	#[test]
+	fn departures_by_label() {
+		let mut db = memory_db();
+		let tx = db.transaction().unwrap();
+		let data = r#"{"number":1,"title":"foo: init","labels":[{"name":"8.has: package (new)","color":"ffffff"}]}"#;
+		for (id, data) in [(1, data), (2, r#"{"number":2,"title":"bar: 1.0 -> 1.1","labels":[]}"#)] {
+			tx.execute(
+				"INSERT INTO departures (repo, pull_id, data, merged, time) VALUES ('o/r', ?1, ?2, 1, ?3)",
+				params![id, data, Utc::now().format(UTC_TIME_FORMAT).to_string()],
+			)
+			.unwrap();
+		}
+		let since = Utc::now() - Duration::hours(1);
+
+		let query = PullQuery::new().labels_all("8.has: package (new)").unwrap().repo("o/r");
+		let departures = tx.get_departures(&query, &since).unwrap();
+		assert_eq!(departures.iter().map(|x| x.id).collect::<Vec<_>>(), [1]);
+
+		let query = PullQuery::new().exclude_labels("8.has: package (new)").unwrap();
+		let departures = tx.get_departures(&query, &since).unwrap();
+		assert_eq!(departures.iter().map(|x| x.id).collect::<Vec<_>>(), [2]);
+	}
+
+	#[test]
+	fn from_params_rejects_invalid_waiting_time() {
+		let params = HashMap::from([("waiting-longer-than".to_owned(), "abc".to_owned())]);
+		assert!(PullQuery::from_params(&params).is_err());
+		let params = HashMap::from([("waiting-longer-than".to_owned(), "3d".to_owned())]);
+		assert!(PullQuery::from_params(&params).unwrap().is_filtered());
+	}
+
+	#[test]
+	fn from_params_rejects_invalid_label_filter() {
+		let params = HashMap::from([("filter".to_owned(), "6.topic: rust;100%".to_owned())]);
+		assert_eq!(
+			PullQuery::from_params(&params).err().as_deref(),
+			Some("invalid character in label filter: '%'")
+		);
+		let params = HashMap::from([("exclude".to_owned(), "a\"b".to_owned())]);
+		assert!(PullQuery::from_params(&params).is_err());
+		let params = HashMap::from([("filter".to_owned(), ";6.topic: rust;;".to_owned())]);
+		assert!(PullQuery::from_params(&params).unwrap().is_filtered());
+	}
+
+	#[test]
+	fn get_pulls_skips_broken_rows() {
+		let mut db = memory_db();
+		let tx = db.transaction().unwrap();
+		for (id, data) in [
+			(1, r#"{"number":1,"title":"foo: init"}"#),
+			(2, "{"),
+			(3, r#"{"number":"3"}"#),
+		] {
+			tx.execute(
+				"INSERT INTO pulls (repo, id, author, last_updated, data) VALUES ('o/r', ?1, 'a', '2024-01-01T00:00:00Z', ?2)",
+				params![id, data],
+			)
+			.unwrap();
+		}
+		let pulls = tx.get_pulls(&PullQuery::new()).unwrap();
+		assert_eq!(pulls.iter().map(|x| x.number).collect::<Vec<_>>(), [1]);
+		let broken = tx.broken_rows().unwrap();
+		assert_eq!(broken.iter().map(|x| x.id).collect::<Vec<_>>(), [2, 3]);
+	}
+
+	#[test]
+	fn pull_query_sql() {
+		let query = PullQuery::new()
+			.category(Some(NEEDS_REVIEWER))
+			.labels_all("8.has: package (new);6.topic: rust")
+			.unwrap()
+			.milestone("none")
+			.repo("o/r")
+			.limit(10);
+		assert_eq!(
+			query.select_sql("id"),
+			"SELECT id FROM pulls WHERE 1=1 AND category = ? \
+			AND pull_data(data, data_compressed) LIKE ? AND pull_data(data, data_compressed) LIKE ? \
+			AND milestone IS NULL AND repo = ? \
+			AND state = 'open' ORDER BY last_updated ASC, repo ASC, id ASC LIMIT 10"
+		);
+		let text = |x: &str| Value::from(x.to_owned());
+		assert_eq!(
+			query.params,
+			[
+				text(NEEDS_REVIEWER),
+				text("%6.topic: rust%"),
+				text("%8.has: package (new)%"),
+				text("o/r"),
+			]
+		);
+		assert!(query.is_filtered());
+		assert!(!PullQuery::new().is_filtered());
+		assert_eq!(
+			PullQuery::new().category(Some("New")).count_sql(),
+			"SELECT COUNT(*) FROM pulls WHERE 1=1 AND category IS NULL AND state = 'open'"
+		);
+	}
+
+	/// Every combination of filters is valid SQL, with a parameter for each placeholder,
+	/// and selects the PRs selected by each of its filters. The counts per category agree with it.
+	#[test]
+	fn pull_query_combinations() {
+		let mut db = memory_db();
+		let tx = db.transaction().unwrap();
+		for id in 1..=8 {
+			let mut labels = vec![];
+			if id % 3 == 0 {
+				labels.push(serde_json::json!({ "name": "8.has: package (new)", "color": "ffffff" }));
+			}
+			if id % 5 == 0 {
+				labels.push(serde_json::json!({ "name": "6.topic: python", "color": "ffffff" }));
+			}
+			let data = serde_json::json!({
+				"number": id,
+				"title": format!("pkg{id}"),
+				"labels": labels,
+				"created_at": format!("2024-01-{id:02}T00:00:00Z"),
+			});
+			// pairs of PRs updated at the same time
+			let updated = format!("2024-01-{:02}T00:00:00Z", 10 + id / 2);
+			let category = [None, Some(NEEDS_REVIEWER), Some(AWAITING_AUTHOR)][id % 3];
+			tx.execute(
+				"INSERT INTO pulls (repo, id, author, last_updated, data, category, category_since, first_seen,
+					reserved_by, milestone, effort, author_association, state)
+				VALUES (?1, ?2, 'a', ?3, ?4, ?5, ?6, ?3, ?7, ?8, ?9, ?10, ?11)",
+				params![
+					if id % 2 == 1 { "o/r" } else { "NixOS/nixpkgs" },
+					id,
+					updated,
+					data.to_string(),
+					category,
+					category.map(|_| &updated),
+					(id % 4 == 1).then_some("10.0.0.1"),
+					(id % 2 == 0).then_some("25.05"),
+					(id % 3 == 1).then_some("small"),
+					if id % 2 == 1 {
+						"FIRST_TIME_CONTRIBUTOR"
+					} else {
+						"MEMBER"
+					},
+					if id == 8 { "closed" } else { "open" },
+				],
+			)
+			.unwrap();
+			if id % 3 == 2 {
+				tx.execute(
+					"INSERT INTO hidden (repo, pull_id, hidden_by, time)
+					SELECT repo, id, 'viewer', last_updated FROM pulls WHERE id = ?1",
+					[id],
+				)
+				.unwrap();
+			}
+		}
+
+		let time = |day: u32| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+		let cursor = PageCursor {
+			last_updated: "2024-01-11T00:00:00Z".to_owned(),
+			repo: "NixOS/nixpkgs".to_owned(),
+			id: 2,
+		};
+		let filters: Vec<Box<dyn Fn(PullQuery) -> PullQuery>> = vec![
+			Box::new(|x| x.category(Some(NEEDS_REVIEWER))),
+			Box::new(|x| x.labels_all("8.has: package (new)").unwrap()),
+			Box::new(|x| x.exclude_labels("6.topic: python").unwrap()),
+			Box::new(|x| x.repo("o/r")),
+			Box::new(|x| x.only_unreserved()),
+			Box::new(|x| x.not_hidden_for("viewer")),
+			Box::new(|x| x.milestone("25.05")),
+			Box::new(|x| x.effort("unknown")),
+			Box::new(move |x| x.created_since(&time(3))),
+			Box::new(|x| x.first_timers()),
+			Box::new(move |x| x.first_seen_before(&time(13))),
+			Box::new(move |x| x.category_since(&time(12))),
+			Box::new(move |x| x.after(&cursor)),
+		];
+		let select = |query: &PullQuery| -> Vec<(String, i64)> {
+			let mut stmt = tx.prepare(&query.select_sql("repo, id")).unwrap();
+			assert_eq!(
+				stmt.parameter_count(),
+				query.params.len(),
+				"{}",
+				query.select_sql("repo, id")
+			);
+			stmt.query_map(query.params(), extract_row!(String i64))
+				.unwrap()
+				.collect::<Result<_, _>>()
+				.unwrap()
+		};
+		let single: Vec<_> = filters.iter().map(|filter| select(&filter(PullQuery::new()))).collect();
+		assert!(single.iter().all(|x| !x.is_empty() && x.len() < 7), "{single:?}");
+
+		for combination in 0..1u32 << filters.len() {
+			let mut query = PullQuery::new();
+			let mut expected = select(&query);
+			for (i, filter) in filters.iter().enumerate() {
+				if combination & (1 << i) != 0 {
+					query = filter(query);
+					expected.retain(|x| single[i].contains(x));
 				}
-				if labels.iter().any(|x| x.name == "12.approved-by: package-maintainer") {
-					approvals += 1;
+			}
+			assert_eq!(select(&query), expected, "{}", query.where_clause());
+			assert_eq!(tx.count_pulls(&query).unwrap(), expected.len());
+			let counts = tx.count_by_category(&query).unwrap();
+			assert_eq!(counts.total(), expected.len());
+			// each count matches the listing of its category, checked for up to two filters to keep this fast
+			if combination.count_ones() > 2 {
+				continue;
+			}
+			for (category, count) in counts.entries() {
+				if category == "Other" {
+					assert_eq!(count, 0);
+					continue;
 				}
-				(approvals, Reverse(now - x.updated_at.unwrap()))
-			});
+				let listed = select(&query.clone().category(Some(category)));
+				assert_eq!(count, listed.len(), "{category}: {}", query.where_clause());
+			}
 		}
-		Ok(prs)
 	}
 }
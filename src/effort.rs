@@ -0,0 +1,354 @@
+use std::{env, error::Error, fmt, str::FromStr};
+
+use rusqlite::types::Value;
+
+use crate::database::{has_label_sql, StoredPr};
+
+/// Rough review effort bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Effort {
+	S,
+	M,
+	L,
+	XL,
+}
+
+impl fmt::Display for Effort {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Effort::S => "S",
+			Effort::M => "M",
+			Effort::L => "L",
+			Effort::XL => "XL",
+		})
+	}
+}
+
+impl FromStr for Effort {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"S" => Ok(Effort::S),
+			"M" => Ok(Effort::M),
+			"L" => Ok(Effort::L),
+			"XL" => Ok(Effort::XL),
+			_ => Err(format!("invalid effort bucket: {s:?}")),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub enum Signal {
+	/// PR has a label with exactly this name.
+	Label(String),
+	/// Title contains this text (case-insensitive).
+	TitleContains(String),
+	/// PR was opened by this user.
+	Author(String),
+	/// At least this many files changed (only known for PRs fetched individually).
+	ChangedFiles(u64),
+	/// At least this many lines added (only known for PRs fetched individually).
+	Additions(u64),
+}
+
+impl Signal {
+	fn matches(&self, pr: &StoredPr) -> bool {
+		match self {
+			Signal::Label(name) => pr.labels.as_deref().unwrap_or_default().iter().any(|x| x.name == *name),
+			Signal::TitleContains(text) => pr
+				.title
+				.as_deref()
+				.map(|x| x.to_lowercase().contains(&text.to_lowercase()))
+				.unwrap_or(false),
+			Signal::Author(login) => pr.user.as_ref().map(|x| x.login == *login).unwrap_or(false),
+			Signal::ChangedFiles(count) => pr.changed_files.map(|x| x >= *count).unwrap_or(false),
+			Signal::Additions(count) => pr.additions.map(|x| x >= *count).unwrap_or(false),
+		}
+	}
+
+	/// SQL condition equivalent to `matches` for a PR in `pulls`, binding its value to `params`.
+	fn sql(&self, params: &mut Vec<Value>) -> String {
+		let (sql, value) = match self {
+			Signal::Label(name) => return has_label_sql(std::slice::from_ref(name), params),
+			Signal::TitleContains(text) => (
+				"instr(lower(json_extract(pull_data(pulls.data, pulls.data_compressed), '$.title')), lower(?)) > 0",
+				Value::from(text.clone()),
+			),
+			Signal::Author(login) => (
+				"json_extract(pull_data(pulls.data, pulls.data_compressed), '$.user.login') = ?",
+				Value::from(login.clone()),
+			),
+			Signal::ChangedFiles(count) => (
+				"json_extract(pull_data(pulls.data, pulls.data_compressed), '$.changed_files') >= ?",
+				Value::from(*count as i64),
+			),
+			Signal::Additions(count) => (
+				"json_extract(pull_data(pulls.data, pulls.data_compressed), '$.additions') >= ?",
+				Value::from(*count as i64),
+			),
+		};
+		params.push(value);
+		sql.to_owned()
+	}
+}
+
+impl fmt::Display for Signal {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Signal::Label(name) => write!(f, "label {name:?}"),
+			Signal::TitleContains(text) => write!(f, "title contains {text:?}"),
+			Signal::Author(login) => write!(f, "author {login}"),
+			Signal::ChangedFiles(count) => write!(f, "{count}+ files changed"),
+			Signal::Additions(count) => write!(f, "{count}+ lines added"),
+		}
+	}
+}
+
+impl FromStr for Signal {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let Some((kind, value)) = s.split_once(':') else {
+			return Err(format!("invalid effort signal: {s:?}"));
+		};
+		let value = value.trim().to_owned();
+		let count = || {
+			value
+				.parse::<u64>()
+				.map_err(|e| format!("invalid effort signal {s:?}: {e}"))
+		};
+		match kind.trim() {
+			"label" => Ok(Signal::Label(value.clone())),
+			"title" => Ok(Signal::TitleContains(value.clone())),
+			"author" => Ok(Signal::Author(value.clone())),
+			"files" => Ok(Signal::ChangedFiles(count()?)),
+			"additions" => Ok(Signal::Additions(count()?)),
+			_ => Err(format!("invalid effort signal kind: {kind:?}")),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct EffortRule {
+	pub signal: Signal,
+	pub effort: Effort,
+}
+
+fn rule(signal: Signal, effort: Effort) -> EffortRule {
+	EffortRule { signal, effort }
+}
+
+/// Rules tuned for nixpkgs.
+pub fn default_rules() -> Vec<EffortRule> {
+	let mut rules = vec![];
+	for (label, effort) in [
+		("10.rebuild-linux: 1", Effort::S),
+		("10.rebuild-linux: 1-10", Effort::S),
+		("10.rebuild-linux: 11-100", Effort::M),
+		("10.rebuild-linux: 101-500", Effort::L),
+		("10.rebuild-linux: 501-1000", Effort::L),
+		("10.rebuild-linux: 501+", Effort::XL),
+		("10.rebuild-linux: 1001-2500", Effort::XL),
+		("10.rebuild-linux: 2501-5000", Effort::XL),
+		("10.rebuild-linux: 5001+", Effort::XL),
+	] {
+		rules.push(rule(Signal::Label(label.to_owned()), effort));
+	}
+	rules.push(rule(Signal::Author("r-ryantm".to_owned()), Effort::S));
+	rules.push(rule(Signal::TitleContains("init at".to_owned()), Effort::L));
+	rules.push(rule(Signal::ChangedFiles(20), Effort::L));
+	rules.push(rule(Signal::ChangedFiles(100), Effort::XL));
+	rules.push(rule(Signal::Additions(500), Effort::L));
+	rules
+}
+
+/// Load rules from `PR_DASHBOARD_EFFORT_RULES`, formatted as `kind:value=BUCKET` entries separated by `;`.
+/// Example: `label:10.rebuild-linux: 1-10=S;title:init at=L;files:100=XL`
+pub fn load_rules() -> Result<Vec<EffortRule>, Box<dyn Error>> {
+	let Ok(config) = env::var("PR_DASHBOARD_EFFORT_RULES") else {
+		return Ok(default_rules());
+	};
+	parse_rules(&config)
+}
+
+fn parse_rules(config: &str) -> Result<Vec<EffortRule>, Box<dyn Error>> {
+	let mut rules = vec![];
+	for entry in config.split(';').filter(|x| !x.trim().is_empty()) {
+		let Some((signal, effort)) = entry.rsplit_once('=') else {
+			return Err(format!("invalid effort rule: {entry:?}").into());
+		};
+		rules.push(rule(signal.parse()?, effort.trim().parse()?));
+	}
+	Ok(rules)
+}
+
+pub struct Estimate<'a> {
+	/// `None` if no signal applied.
+	pub effort: Option<Effort>,
+	pub signals: Vec<&'a Signal>,
+}
+
+impl Estimate<'_> {
+	pub fn bucket(&self) -> String {
+		self.effort
+			.map(|x| x.to_string())
+			.unwrap_or_else(|| "unknown".to_owned())
+	}
+
+	pub fn describe_signals(&self) -> String {
+		self.signals
+			.iter()
+			.map(|x| x.to_string())
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+}
+
+/// SQL expression equivalent to `estimate(..).effort` for a PR in `pulls`, binding the rules' values to `params`.
+pub fn sql(rules: &[EffortRule], params: &mut Vec<Value>) -> String {
+	let mut sql = "CASE".to_owned();
+	// the largest bucket wins, so it is checked first
+	for effort in [Effort::XL, Effort::L, Effort::M, Effort::S] {
+		let conditions: Vec<_> = rules
+			.iter()
+			.filter(|x| x.effort == effort)
+			.map(|x| x.signal.sql(params))
+			.collect();
+		if conditions.is_empty() {
+			continue;
+		}
+		sql += &format!(" WHEN {} THEN ?", conditions.join(" OR "));
+		params.push(Value::from(effort.to_string()));
+	}
+	if sql == "CASE" {
+		return "NULL".to_owned();
+	}
+	sql + " END"
+}
+
+/// The largest bucket of all matching rules wins.
+pub fn estimate<'a>(rules: &'a [EffortRule], pr: &StoredPr) -> Estimate<'a> {
+	let mut effort = None;
+	let mut signals = vec![];
+	for rule in rules {
+		if rule.signal.matches(pr) {
+			effort = effort.max(Some(rule.effort));
+			signals.push(&rule.signal);
+		}
+	}
+	Estimate { effort, signals }
+}
+
+#[cfg(test)]
+mod tests {
+	use rusqlite::params_from_iter;
+
+	use super::*;
+	use crate::database::{DB, IN_MEMORY};
+
+	fn pr(title: &str, author: &str, labels: &[&str], changed_files: Option<u64>, additions: Option<u64>) -> StoredPr {
+		let labels: Vec<_> = labels
+			.iter()
+			.map(|x| serde_json::json!({"name": x, "color": "ffffff"}))
+			.collect();
+		serde_json::from_value(serde_json::json!({
+			"number": 1,
+			"title": title,
+			"user": {"login": author},
+			"labels": labels,
+			"changed_files": changed_files,
+			"additions": additions,
+		}))
+		.unwrap()
+	}
+
+	fn cases() -> Vec<(StoredPr, &'static str)> {
+		vec![
+			(pr("foo: 1.0 -> 1.1", "someone", &[], None, None), "unknown"),
+			(
+				pr("foo: 1.0 -> 1.1", "someone", &["10.rebuild-linux: 1-10"], None, None),
+				"S",
+			),
+			(pr("foo: 1.0 -> 1.1", "r-ryantm", &[], None, None), "S"),
+			(
+				pr("foo: 1.0 -> 1.1", "someone", &["10.rebuild-linux: 11-100"], None, None),
+				"M",
+			),
+			(pr("foo: init at 1.0", "someone", &[], None, None), "L"),
+			(pr("Foo: Init At 1.0", "someone", &[], None, None), "L"),
+			(pr("foo: 1.0 -> 1.1", "someone", &[], Some(20), None), "L"),
+			(pr("foo: 1.0 -> 1.1", "someone", &[], Some(19), Some(499)), "unknown"),
+			(pr("foo: 1.0 -> 1.1", "someone", &[], None, Some(500)), "L"),
+			(pr("foo: 1.0 -> 1.1", "someone", &[], Some(100), None), "XL"),
+			// the largest bucket wins
+			(
+				pr("foo: init at 1.0", "r-ryantm", &["10.rebuild-linux: 1-10"], None, None),
+				"L",
+			),
+			(
+				pr("foo: 1.0 -> 1.1", "r-ryantm", &["10.rebuild-linux: 5001+"], None, None),
+				"XL",
+			),
+			// other labels are no signal
+			(
+				pr("foo: 1.0 -> 1.1", "someone", &["6.topic: python"], None, None),
+				"unknown",
+			),
+		]
+	}
+
+	#[test]
+	fn default_rules_table() {
+		let rules = default_rules();
+		for (pr, expected) in cases() {
+			let estimate = estimate(&rules, &pr);
+			assert_eq!(estimate.bucket(), expected, "{pr:?}: {}", estimate.describe_signals());
+			assert_eq!(estimate.signals.is_empty(), expected == "unknown");
+		}
+	}
+
+	#[test]
+	fn sql_agrees_with_estimate() {
+		let rules = default_rules();
+		let mut db = DB::new_with_path(IN_MEMORY).unwrap();
+		let tx = db.transaction().unwrap();
+		let cases = cases();
+		for (id, (pr, _)) in cases.iter().enumerate() {
+			tx.execute(
+				"INSERT INTO pulls (repo, id, author, last_updated, data) VALUES ('o/r', ?1, 'a', '2024-01-01T00:00:00Z', ?2)",
+				rusqlite::params![id, serde_json::to_string(pr).unwrap()],
+			)
+			.unwrap();
+		}
+		let mut params = vec![];
+		let query = format!("SELECT {} FROM pulls ORDER BY id", sql(&rules, &mut params));
+		let buckets: Vec<Option<String>> = tx
+			.prepare(&query)
+			.unwrap()
+			.query_map(params_from_iter(params), |row| row.get(0))
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap();
+		for ((_, expected), bucket) in cases.iter().zip(buckets) {
+			assert_eq!(bucket.as_deref().unwrap_or("unknown"), *expected);
+		}
+		assert_eq!(sql(&[], &mut vec![]), "NULL");
+	}
+
+	#[test]
+	fn parse_rule_config() {
+		let rules = parse_rules("label:10.rebuild-linux: 1-10=S; title:init at=L;files:100=XL;").unwrap();
+		assert_eq!(rules.len(), 3);
+		assert!(matches!(&rules[0].signal, Signal::Label(x) if x == "10.rebuild-linux: 1-10"));
+		assert_eq!(rules[0].effort, Effort::S);
+		assert!(matches!(rules[2].signal, Signal::ChangedFiles(100)));
+		assert_eq!(rules[2].effort, Effort::XL);
+
+		assert!(parse_rules("label:foo").is_err());
+		assert!(parse_rules("label:foo=XXL").is_err());
+		assert!(parse_rules("files:many=L").is_err());
+		assert!(parse_rules("size:1=L").is_err());
+		assert!(parse_rules("").unwrap().is_empty());
+	}
+}
@@ -0,0 +1,192 @@
+use std::env;
+
+use chrono::{Duration, Utc};
+
+use crate::parse_utc;
+
+/// When the data is considered recent enough to hand out PRs.
+#[derive(Debug, Clone, Copy)]
+pub struct FreshnessPolicy {
+	/// Maximum time since the last successful update.
+	pub max_age: Duration,
+	/// Run an update when reserving from stale data, instead of refusing.
+	pub auto_refresh: bool,
+}
+
+impl FreshnessPolicy {
+	/// Load the policy from `PR_DASHBOARD_MAX_DATA_AGE_HOURS` (default 6) and `PR_DASHBOARD_AUTO_REFRESH`.
+	pub fn from_env() -> Self {
+		let hours = env::var("PR_DASHBOARD_MAX_DATA_AGE_HOURS")
+			.map(|x| x.parse().expect("invalid PR_DASHBOARD_MAX_DATA_AGE_HOURS"))
+			.unwrap_or(6);
+		let auto_refresh = env::var("PR_DASHBOARD_AUTO_REFRESH")
+			.map(|x| x == "1" || x == "true")
+			.unwrap_or(false);
+		Self {
+			max_age: Duration::hours(hours),
+			auto_refresh,
+		}
+	}
+
+	/// Evaluate the policy for the time of the last successful update (`UTC_TIME_FORMAT`).
+	pub fn evaluate(&self, last_sync: Option<&str>) -> Verdict {
+		let age = last_sync.filter(|x| !x.is_empty()).map(|x| Utc::now() - parse_utc(x));
+		Verdict {
+			last_sync: last_sync.map(|x| x.to_owned()),
+			age,
+			caught_up: age.map(|x| x <= self.max_age).unwrap_or(false),
+		}
+	}
+}
+
+pub struct Verdict {
+	pub last_sync: Option<String>,
+	/// `None` if no update succeeded yet.
+	pub age: Option<Duration>,
+	pub caught_up: bool,
+}
+
+impl Verdict {
+	pub fn describe_age(&self) -> String {
+		match self.age {
+			Some(age) if age.num_hours() >= 48 => format!("{} days", age.num_days()),
+			Some(age) if age.num_minutes() >= 120 => format!("{} hours", age.num_hours()),
+			Some(age) => format!("{} minutes", age.num_minutes()),
+			None => "unknown".to_owned(),
+		}
+	}
+
+	/// Explanation shown when reserving is refused.
+	pub fn stale_message(&self) -> String {
+		match self.last_sync.as_deref() {
+			Some(time) => format!(
+				"data is stale: last successful update {} ago ({time}), trigger an update with POST /update-prs",
+				self.describe_age()
+			),
+			None => "no successful update yet, trigger one with POST /update-prs".to_owned(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::StatusCode;
+	use rusqlite::params;
+
+	use super::*;
+	use crate::database::DB;
+	use crate::tests::{pull_json, seed, serve, serve_state, FakeGithub};
+	use crate::UTC_TIME_FORMAT;
+
+	const POLICY: FreshnessPolicy = FreshnessPolicy {
+		max_age: Duration::hours(6),
+		auto_refresh: false,
+	};
+
+	fn ago(age: Duration) -> String {
+		(Utc::now() - age).format(UTC_TIME_FORMAT).to_string()
+	}
+
+	#[test]
+	fn evaluate() {
+		let fresh = POLICY.evaluate(Some(&ago(Duration::minutes(30))));
+		assert!(fresh.caught_up);
+		assert_eq!(fresh.describe_age(), "30 minutes");
+
+		let stale = POLICY.evaluate(Some(&ago(Duration::hours(7))));
+		assert!(!stale.caught_up);
+		assert_eq!(stale.describe_age(), "7 hours");
+		assert!(
+			stale
+				.stale_message()
+				.starts_with("data is stale: last successful update 7 hours ago"),
+			"{}",
+			stale.stale_message()
+		);
+		assert_eq!(POLICY.evaluate(Some(&ago(Duration::days(3)))).describe_age(), "3 days");
+
+		for never in [None, Some("")] {
+			let verdict = POLICY.evaluate(never);
+			assert!(!verdict.caught_up);
+			assert_eq!(verdict.age, None);
+			assert_eq!(verdict.describe_age(), "unknown");
+		}
+		assert_eq!(
+			POLICY.evaluate(None).stale_message(),
+			"no successful update yet, trigger one with POST /update-prs"
+		);
+	}
+
+	async fn set_last_success(state: &crate::AppState, time: Option<String>) {
+		state
+			.db
+			.run(move |db: &mut DB| {
+				let tx = db.transaction()?;
+				tx.execute("DELETE FROM sync_state WHERE key = 'last_success'", [])?;
+				if let Some(time) = time {
+					tx.execute(
+						"INSERT INTO sync_state (key, value) VALUES ('last_success', ?1)",
+						params![time],
+					)?;
+				}
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn reserving_requires_recent_data() {
+		let (state, base) = serve().await;
+		seed(&state).await;
+		let client = reqwest::Client::new();
+		let reserve = || async {
+			let response = client
+				.post(format!("{base}/reserve-pr?pr=1&as=tester"))
+				.send()
+				.await
+				.unwrap();
+			(response.status(), response.text().await.unwrap())
+		};
+
+		set_last_success(&state, Some(ago(Duration::hours(7)))).await;
+		let (status, body) = reserve().await;
+		assert_eq!(status, StatusCode::CONFLICT);
+		assert!(
+			body.starts_with("data is stale: last successful update 7 hours ago"),
+			"{body}"
+		);
+
+		set_last_success(&state, None).await;
+		let (status, body) = reserve().await;
+		assert_eq!(status, StatusCode::CONFLICT);
+		assert!(body.starts_with("no successful update yet"), "{body}");
+
+		set_last_success(&state, Some(ago(Duration::minutes(5)))).await;
+		let (status, body) = reserve().await;
+		assert_eq!(status, StatusCode::OK, "{body}");
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn stale_data_is_refreshed_before_reserving() {
+		let github = FakeGithub::new(vec![pull_json(1, "open", "2024-01-01T00:00:00Z", &[])]);
+		let mut state = github.serve().await;
+		state.freshness = FreshnessPolicy {
+			auto_refresh: true,
+			..POLICY
+		};
+		let base = serve_state(state.clone()).await;
+		assert!(!state.freshness().await.unwrap().caught_up);
+
+		let response = reqwest::Client::new()
+			.post(format!("{base}/reserve-pr?pr=1&as=tester"))
+			.send()
+			.await
+			.unwrap();
+		let status = response.status();
+		assert_eq!(status, StatusCode::OK, "{}", response.text().await.unwrap());
+		assert!(state.freshness().await.unwrap().caught_up);
+	}
+}
@@ -0,0 +1,899 @@
+use std::{
+	env,
+	error::Error,
+	fmt,
+	future::Future,
+	sync::{Arc, Mutex as StdMutex},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{
+	header::{AUTHORIZATION, ETAG, IF_NONE_MATCH, RETRY_AFTER, USER_AGENT},
+	HeaderMap, HeaderValue, StatusCode, Uri,
+};
+use chrono::{DateTime, Utc};
+use hyper_http_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_util::{
+	client::legacy::{connect::HttpConnector, Client as HyperClient},
+	rt::TokioExecutor,
+};
+use octocrab::{
+	auth::AppAuth,
+	models::{pulls::PullRequest, AppId, InstallationId, Rate},
+	service::middleware::{base_uri::BaseUriLayer, extra_headers::ExtraHeadersLayer, retry::RetryConfig},
+	AuthState, FromResponse, Octocrab, Page,
+};
+use tokio::{fs, sync::RwLock};
+use tower::{timeout::TimeoutLayer, ServiceBuilder};
+
+use crate::UTC_TIME_FORMAT;
+
+/// Maximum length of the error message kept for decode errors.
+const DECODE_SAMPLE_LENGTH: usize = 500;
+/// Delay before the first retry, doubled for every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the exponential part of the retry delay.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Minimum wait after hitting a rate limit without a `Retry-After` header,
+/// GitHub asks to wait at least a minute for secondary rate limits then.
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+/// How long the remaining requests of a client are trusted when picking one, instead of asking again.
+const RATE_LIMIT_TTL: Duration = Duration::from_secs(30);
+
+/// API of github.com, unless `GITHUB_API_BASE` is set.
+const DEFAULT_API_BASE: &str = "https://api.github.com";
+/// Time to establish a connection to GitHub or the proxy.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time for a whole GitHub request, so a stalled connection fails instead of hanging the update.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Variables configuring authentication as a GitHub App installation.
+const APP_VARIABLES: [&str; 3] = [
+	"GITHUB_APP_ID",
+	"GITHUB_APP_PRIVATE_KEY_FILE",
+	"GITHUB_APP_INSTALLATION_ID",
+];
+
+/// Where the credentials of a client come from, kept to rebuild the client.
+#[derive(Debug, Clone)]
+enum Credentials {
+	Pat(String),
+	/// Read on every rebuild, so tokens rotated on disk are picked up.
+	PatFile(String),
+	App,
+	/// A client built by the caller, which can't be rebuilt.
+	Fixed,
+}
+
+impl fmt::Display for Credentials {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Pat(_) => f.write_str("personal access token"),
+			Self::PatFile(file) => write!(f, "token file {file}"),
+			Self::App => f.write_str("GitHub App"),
+			Self::Fixed => f.write_str("fixed client"),
+		}
+	}
+}
+
+/// Credentials from `GITHUB_PATS`, `GITHUB_PAT`, `GITHUB_PAT_FILE` (comma-separated for several)
+/// or the GitHub App variables, in this order.
+fn credentials_from_env() -> Result<Vec<Credentials>, Box<dyn Error>> {
+	let list = |name: &str| -> Vec<String> {
+		env::var(name)
+			.unwrap_or_default()
+			.split(',')
+			.map(|x| x.trim().to_owned())
+			.filter(|x| !x.is_empty())
+			.collect()
+	};
+	let pats = list("GITHUB_PATS");
+	if !pats.is_empty() {
+		return Ok(pats.into_iter().map(Credentials::Pat).collect());
+	}
+	if let Ok(pat) = env::var("GITHUB_PAT") {
+		return Ok(vec![Credentials::Pat(pat)]);
+	}
+	let files = list("GITHUB_PAT_FILE");
+	if !files.is_empty() {
+		return Ok(files.into_iter().map(Credentials::PatFile).collect());
+	}
+	let missing: Vec<_> = APP_VARIABLES.into_iter().filter(|x| env::var(x).is_err()).collect();
+	if missing.len() == APP_VARIABLES.len() {
+		return Err(format!(
+			"no GitHub credentials configured: set GITHUB_PATS, GITHUB_PAT, GITHUB_PAT_FILE or {}",
+			APP_VARIABLES.join(" + ")
+		)
+		.into());
+	}
+	if !missing.is_empty() {
+		return Err(format!("incomplete GitHub App configuration, missing {}", missing.join(", ")).into());
+	}
+	Ok(vec![Credentials::App])
+}
+
+/// GitHub API base URL, `GITHUB_API_BASE` for GitHub Enterprise instances.
+fn api_base() -> Result<Uri, Box<dyn Error>> {
+	let base = env::var("GITHUB_API_BASE")
+		.ok()
+		.filter(|x| !x.is_empty())
+		.unwrap_or_else(|| DEFAULT_API_BASE.to_owned());
+	Ok(base.parse().map_err(|_| format!("invalid GITHUB_API_BASE: {base:?}"))?)
+}
+
+/// Proxy for requests to `base`, from `HTTPS_PROXY` or `HTTP_PROXY` (also lowercase)
+/// unless `NO_PROXY` excludes the host of `base`.
+fn proxy_for(base: &Uri) -> Result<Option<Uri>, Box<dyn Error>> {
+	let var = |names: [&str; 2]| {
+		names
+			.into_iter()
+			.find_map(|x| env::var(x).ok().filter(|x| !x.is_empty()))
+	};
+	let proxy = if base.scheme_str() == Some("http") {
+		var(["HTTP_PROXY", "http_proxy"])
+	} else {
+		var(["HTTPS_PROXY", "https_proxy"])
+	};
+	let Some(proxy) = proxy else {
+		return Ok(None);
+	};
+	let host = base.host().unwrap_or_default();
+	let excluded = var(["NO_PROXY", "no_proxy"])
+		.unwrap_or_default()
+		.split(',')
+		.map(|x| x.trim().trim_start_matches('.'))
+		.filter(|x| !x.is_empty())
+		.any(|x| x == "*" || host == x || host.ends_with(&format!(".{x}")));
+	if excluded {
+		return Ok(None);
+	}
+	let proxy = if proxy.contains("://") {
+		proxy
+	} else {
+		format!("http://{proxy}")
+	};
+	Ok(Some(
+		proxy.parse().map_err(|_| format!("invalid proxy URL: {proxy:?}"))?,
+	))
+}
+
+/// Host and port of the proxy, without credentials, for the log.
+fn describe_proxy(proxy: &Uri) -> String {
+	match (proxy.host(), proxy.port_u16()) {
+		(Some(host), Some(port)) => format!("{host}:{port}"),
+		(Some(host), None) => host.to_owned(),
+		_ => proxy.to_string(),
+	}
+}
+
+async fn app_key() -> Result<(AppId, jsonwebtoken::EncodingKey), Box<dyn Error>> {
+	let app_id = parse_id("GITHUB_APP_ID")?;
+	let key = fs::read(env::var("GITHUB_APP_PRIVATE_KEY_FILE")?).await?;
+	Ok((AppId(app_id), jsonwebtoken::EncodingKey::from_rsa_pem(&key)?))
+}
+
+fn parse_id(name: &str) -> Result<u64, Box<dyn Error>> {
+	Ok(env::var(name)?.parse().map_err(|_| format!("invalid {name}"))?)
+}
+
+/// What a client authenticates with, read from `Credentials`.
+enum Secret {
+	Token(String),
+	App(AppId, jsonwebtoken::EncodingKey),
+}
+
+/// Build a GitHub client from a personal access token or as a GitHub App installation,
+/// connecting through the configured proxy.
+async fn build_client(credentials: &Credentials) -> Result<Octocrab, Box<dyn Error>> {
+	// read everything first, the builders are not `Send` and must not be held across an await
+	let secret = match credentials {
+		Credentials::Pat(pat) => Secret::Token(pat.clone()),
+		Credentials::PatFile(file) => Secret::Token(fs::read_to_string(file).await?.trim().to_owned()),
+		Credentials::App => {
+			let (app_id, key) = app_key().await?;
+			Secret::App(app_id, key)
+		},
+		Credentials::Fixed => return Err("a fixed client can't be rebuilt".into()),
+	};
+	let base = api_base()?;
+	let proxy = proxy_for(&base)?;
+	let gh = match proxy {
+		None => {
+			// retried by `GithubPool::with_retry` with backoff, octocrab would retry 5xx right away
+			let gh = octocrab::OctocrabBuilder::default()
+				.base_uri(base)?
+				.add_retry_config(RetryConfig::None)
+				.set_connect_timeout(Some(CONNECT_TIMEOUT))
+				.set_read_timeout(Some(REQUEST_TIMEOUT));
+			match secret {
+				Secret::Token(token) => gh.personal_token(token).build()?,
+				Secret::App(app_id, key) => gh.app(app_id, key).build()?,
+			}
+		},
+		Some(proxy) => {
+			let mut http = HttpConnector::new();
+			http.set_connect_timeout(Some(CONNECT_TIMEOUT));
+			let connector = ProxyConnector::from_proxy(http, Proxy::new(Intercept::All, proxy))?;
+			let client = HyperClient::builder(TokioExecutor::new()).build(connector);
+			// the proxy may accept the connection and then stall, so the whole request is bounded
+			let client = ServiceBuilder::new()
+				.layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+				.service(client);
+			let mut headers = vec![(USER_AGENT, HeaderValue::from_static("octocrab"))];
+			let auth = match secret {
+				Secret::Token(token) => {
+					headers.push((AUTHORIZATION, format!("Bearer {token}").parse()?));
+					AuthState::None
+				},
+				Secret::App(app_id, key) => AuthState::App(AppAuth { app_id, key }),
+			};
+			octocrab::OctocrabBuilder::new_empty()
+				.with_service(client)
+				.with_layer(&BaseUriLayer::new(base))
+				.with_layer(&ExtraHeadersLayer::new(Arc::new(headers)))
+				.with_auth(auth)
+				.build()?
+		},
+	};
+	match credentials {
+		// the installation token is fetched on demand and renewed by octocrab when it expires
+		Credentials::App => Ok(gh.installation(InstallationId(parse_id("GITHUB_APP_INSTALLATION_ID")?))?),
+		_ => Ok(gh),
+	}
+}
+
+struct PooledClient {
+	credentials: Credentials,
+	gh: RwLock<Octocrab>,
+	/// Not handed out before this time, after it hit a rate limit.
+	cooldown_until: StdMutex<Option<Instant>>,
+	/// Remaining core requests at the last check, and when it was made.
+	remaining: StdMutex<Option<(usize, Instant)>>,
+}
+
+impl PooledClient {
+	fn new(credentials: Credentials, gh: Octocrab) -> Self {
+		Self {
+			credentials,
+			gh: RwLock::new(gh),
+			cooldown_until: StdMutex::new(None),
+			remaining: StdMutex::new(None),
+		}
+	}
+
+	/// Remaining core requests, asking GitHub only if the last check is older than `RATE_LIMIT_TTL`.
+	/// Unknown counts as none left.
+	async fn remaining(&self, gh: &Octocrab) -> usize {
+		if let Some((remaining, checked)) = *self.remaining.lock().unwrap() {
+			if checked.elapsed() < RATE_LIMIT_TTL {
+				return remaining;
+			}
+		}
+		let remaining = rate_limit(gh).await.map(|x| x.remaining).unwrap_or(0);
+		*self.remaining.lock().unwrap() = Some((remaining, Instant::now()));
+		remaining
+	}
+}
+
+/// A client handed out by the pool, with its index for logging.
+pub struct Client {
+	pub index: usize,
+	pub gh: Octocrab,
+}
+
+/// GitHub clients, one per configured token.
+pub struct GithubPool {
+	clients: Vec<PooledClient>,
+}
+
+impl GithubPool {
+	pub async fn from_env() -> Result<Self, Box<dyn Error>> {
+		let mut clients = vec![];
+		for credentials in credentials_from_env()? {
+			let gh = build_client(&credentials).await?;
+			clients.push(PooledClient::new(credentials, gh));
+		}
+		let base = api_base()?;
+		match proxy_for(&base)? {
+			Some(proxy) => tracing::info!(
+				"using {} GitHub client(s) for {base} via proxy {}",
+				clients.len(),
+				describe_proxy(&proxy)
+			),
+			None => tracing::info!("using {} GitHub client(s) for {base} without proxy", clients.len()),
+		}
+		Ok(Self { clients })
+	}
+
+	/// A pool of a single client built by the caller, e.g. one pointing at a local server.
+	/// It is kept as is when a reload is requested.
+	pub fn with_client(gh: Octocrab) -> Self {
+		Self {
+			clients: vec![PooledClient::new(Credentials::Fixed, gh)],
+		}
+	}
+
+	/// All clients, for reporting their rate limits.
+	pub async fn all(&self) -> Vec<Client> {
+		let mut all = vec![];
+		for (index, client) in self.clients.iter().enumerate() {
+			all.push(Client {
+				index,
+				gh: client.gh.read().await.clone(),
+			});
+		}
+		all
+	}
+
+	/// The client with the most remaining requests, skipping clients that recently hit a rate limit.
+	/// The remaining requests are cached for `RATE_LIMIT_TTL`.
+	pub async fn pick(&self) -> Client {
+		if self.clients.len() > 1 {
+			let now = Instant::now();
+			let mut best: Option<(usize, Client)> = None;
+			for (index, client) in self.clients.iter().enumerate() {
+				if client.cooldown_until.lock().unwrap().is_some_and(|x| x > now) {
+					continue;
+				}
+				let gh = client.gh.read().await.clone();
+				let remaining = client.remaining(&gh).await;
+				if best.as_ref().is_none_or(|(most, _)| remaining > *most) {
+					best = Some((remaining, Client { index, gh }));
+				}
+			}
+			if let Some((_, client)) = best {
+				return client;
+			}
+		}
+		// a single client, or all of them are cooling down
+		Client {
+			index: 0,
+			gh: self.clients[0].gh.read().await.clone(),
+		}
+	}
+
+	/// Keep a client out of rotation for a while after it hit a rate limit,
+	/// as long as GitHub asked for if it sent `Retry-After`.
+	fn cool_down(&self, index: usize, retry_after: Option<Duration>) {
+		let delay = retry_after.unwrap_or(RATE_LIMIT_DELAY);
+		let client = &self.clients[index];
+		*client.cooldown_until.lock().unwrap() = Some(Instant::now() + delay);
+		// the cached count is outdated, ask again once the cooldown is over
+		*client.remaining.lock().unwrap() = None;
+	}
+
+	/// Re-read the credentials of a client (a token may have been rotated on disk) and swap in a new client.
+	pub async fn reload(&self, index: usize) {
+		let client = &self.clients[index];
+		// the boxed error is not `Send`, so it must not be held across the lock below
+		let result = build_client(&client.credentials).await.map_err(|err| err.to_string());
+		match result {
+			Ok(gh) => {
+				*client.gh.write().await = gh;
+				tracing::info!("reloaded GitHub client {index} ({})", client.credentials);
+			},
+			Err(err) => tracing::error!("failed to reload GitHub client {index}: {err}"),
+		}
+	}
+
+	/// Run a GitHub request, retrying transient failures up to `retries` times with exponential backoff.
+	/// After a rate limit the request moves to another client if there is one.
+	/// Auth failures reload the client before the error is returned.
+	/// A `Retry-After` is honored if the request reports it, see `list_pulls_page`.
+	pub async fn with_retry<T, E, F, Fut>(
+		&self,
+		retries: u32,
+		client: &mut Client,
+		mut request: F,
+	) -> Result<T, GithubError>
+	where
+		F: FnMut(Octocrab) -> Fut,
+		Fut: Future<Output = Result<T, E>>,
+		E: Into<GithubError>,
+	{
+		let mut attempt = 0;
+		loop {
+			attempt += 1;
+			let mut err = match request(client.gh.clone()).await {
+				Ok(x) => return Ok(x),
+				Err(err) => err.into(),
+			};
+			err.attempts = attempt;
+			if err.kind == GithubErrorKind::Auth {
+				self.reload(client.index).await;
+			}
+			if !err.kind.is_transient() || attempt > retries {
+				return Err(err);
+			}
+			if err.kind == GithubErrorKind::RateLimit && self.clients.len() > 1 {
+				let limited = client.index;
+				self.cool_down(limited, err.retry_after);
+				*client = self.pick().await;
+				if client.index != limited {
+					tracing::debug!(
+						"GitHub client {limited} is rate limited, retry {attempt} of {retries} with client {}",
+						client.index
+					);
+					continue;
+				}
+			}
+			let delay = backoff(attempt, err.kind, err.retry_after, jitter());
+			tracing::debug!("GitHub request failed, retry {attempt} of {retries} in {delay:?}: {err}");
+			tokio::time::sleep(delay).await;
+		}
+	}
+}
+
+/// How updates treat a nearly exhausted GitHub rate limit.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitPolicy {
+	/// Remaining core API requests below which updates stop fetching.
+	pub floor: usize,
+	/// Sleep until the limit resets, instead of ending the update early.
+	pub wait: bool,
+}
+
+impl RateLimitPolicy {
+	/// Load the policy from `PR_DASHBOARD_RATE_LIMIT_FLOOR` (default 100) and `PR_DASHBOARD_RATE_LIMIT_WAIT`.
+	pub fn from_env() -> Self {
+		let floor = env::var("PR_DASHBOARD_RATE_LIMIT_FLOOR")
+			.map(|x| x.parse().expect("invalid PR_DASHBOARD_RATE_LIMIT_FLOOR"))
+			.unwrap_or(100);
+		let wait = env::var("PR_DASHBOARD_RATE_LIMIT_WAIT")
+			.map(|x| x == "1" || x == "true")
+			.unwrap_or(false);
+		Self { floor, wait }
+	}
+
+	/// Check the remaining budget before making requests. Below the floor this sleeps until the
+	/// limit resets, or returns why the caller should stop. Returns the remaining requests if known.
+	pub async fn check(&self, gh: &Octocrab) -> Result<Option<usize>, String> {
+		let rate = match rate_limit(gh).await {
+			Ok(rate) => rate,
+			Err(err) => {
+				tracing::warn!("failed to query the GitHub rate limit: {err}");
+				return Ok(None);
+			},
+		};
+		if rate.remaining >= self.floor {
+			return Ok(Some(rate.remaining));
+		}
+		let reset = DateTime::from_timestamp(rate.reset as i64, 0).unwrap_or_default();
+		if !self.wait {
+			tracing::warn!("{} GitHub requests left until {reset}, stopping", rate.remaining);
+			return Err(format!(
+				"only {} GitHub requests left, the limit resets at {}",
+				rate.remaining,
+				reset.format(UTC_TIME_FORMAT)
+			));
+		}
+		let delay = (reset - Utc::now()).to_std().unwrap_or_default() + Duration::from_secs(1);
+		tracing::info!("{} GitHub requests left, waiting {delay:?}", rate.remaining);
+		tokio::time::sleep(delay).await;
+		Ok(Some(rate.remaining))
+	}
+}
+
+/// Current budget of the core API. Querying it does not count against the limit.
+pub async fn rate_limit(gh: &Octocrab) -> Result<Rate, GithubError> {
+	Ok(gh.ratelimit().get().await?.resources.core)
+}
+
+/// One page of the PR listing of `repo`, most recently updated first.
+/// `None` if GitHub answered `304 Not Modified` for the given ETag, these responses are free.
+/// Otherwise the page is returned with its new ETag.
+/// Errors carry the `Retry-After` of the response, octocrab drops it when parsing the error.
+pub async fn list_pulls_page(
+	gh: &Octocrab,
+	repo: &str,
+	state: &str,
+	page: u32,
+	per_page: u8,
+	etag: Option<&str>,
+) -> Result<Option<(Page<PullRequest>, Option<String>)>, GithubError> {
+	let mut headers = HeaderMap::new();
+	if let Some(etag) = etag.and_then(|x| HeaderValue::from_str(x).ok()) {
+		headers.insert(IF_NONE_MATCH, etag);
+	}
+	let url = format!("/repos/{repo}/pulls?state={state}&sort=updated&direction=desc&per_page={per_page}&page={page}");
+	let response = gh._get_with_headers(url, Some(headers)).await?;
+	if response.status() == StatusCode::NOT_MODIFIED {
+		return Ok(None);
+	}
+	let retry_after = retry_after(response.headers());
+	// parsed the same way as octocrab's own `list().send()`
+	let response = octocrab::map_github_error(response).await.map_err(|err| GithubError {
+		retry_after,
+		..err.into()
+	})?;
+	let etag = response
+		.headers()
+		.get(ETAG)
+		.and_then(|x| x.to_str().ok())
+		.map(|x| x.to_owned());
+	Ok(Some((Page::from_response(response).await?, etag)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GithubErrorKind {
+	/// Token revoked or lacking permissions: needs operator action.
+	Auth,
+	/// Primary or secondary rate limit.
+	RateLimit,
+	/// GitHub returned a 5xx.
+	ServerError,
+	/// Connection failures and timeouts.
+	Network,
+	/// The response did not match the expected schema.
+	Decode,
+	Other,
+}
+
+impl GithubErrorKind {
+	/// Whether retrying the request later may succeed.
+	pub fn is_transient(self) -> bool {
+		matches!(self, Self::RateLimit | Self::ServerError | Self::Network)
+	}
+}
+
+impl fmt::Display for GithubErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Auth => "auth",
+			Self::RateLimit => "rate-limit",
+			Self::ServerError => "server-error",
+			Self::Network => "network",
+			Self::Decode => "decode",
+			Self::Other => "other",
+		})
+	}
+}
+
+pub fn classify(err: &octocrab::Error) -> GithubErrorKind {
+	match err {
+		octocrab::Error::GitHub { source, .. } => classify_status(source.status_code, &source.message),
+		octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. } => GithubErrorKind::Network,
+		octocrab::Error::Serde { .. } | octocrab::Error::Json { .. } => GithubErrorKind::Decode,
+		_ => GithubErrorKind::Other,
+	}
+}
+
+fn classify_status(status: StatusCode, message: &str) -> GithubErrorKind {
+	if status == StatusCode::TOO_MANY_REQUESTS
+		|| (status == StatusCode::FORBIDDEN && message.to_lowercase().contains("rate limit"))
+	{
+		GithubErrorKind::RateLimit
+	} else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+		GithubErrorKind::Auth
+	} else if status.is_server_error() {
+		GithubErrorKind::ServerError
+	} else {
+		GithubErrorKind::Other
+	}
+}
+
+/// A classified error from the GitHub API.
+#[derive(Debug)]
+pub struct GithubError {
+	pub kind: GithubErrorKind,
+	pub source: octocrab::Error,
+	/// Truncated error description, kept for decode errors to debug schema changes.
+	pub sample: Option<String>,
+	/// Number of requests made before giving up.
+	pub attempts: u32,
+	/// How long GitHub asked to wait before the next request, if it said so.
+	pub retry_after: Option<Duration>,
+}
+
+impl From<octocrab::Error> for GithubError {
+	fn from(source: octocrab::Error) -> Self {
+		let kind = classify(&source);
+		let sample = (kind == GithubErrorKind::Decode).then(|| {
+			let mut sample = source.to_string();
+			if sample.len() > DECODE_SAMPLE_LENGTH {
+				let mut end = DECODE_SAMPLE_LENGTH;
+				while !sample.is_char_boundary(end) {
+					end -= 1;
+				}
+				sample.truncate(end);
+			}
+			sample
+		});
+		Self {
+			kind,
+			source,
+			sample,
+			attempts: 1,
+			retry_after: None,
+		}
+	}
+}
+
+impl fmt::Display for GithubError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "GitHub {} error: {}", self.kind, self.source)?;
+		if self.attempts > 1 {
+			write!(f, " (after {} attempts)", self.attempts)?;
+		}
+		Ok(())
+	}
+}
+
+impl Error for GithubError {}
+
+/// Delay before retry number `attempt` (starting at 1).
+/// `jitter` in `0.0..1.0` adds up to half of the exponential delay on top.
+/// Never shorter than `retry_after`, or than `RATE_LIMIT_DELAY` for rate limits without it.
+pub fn backoff(attempt: u32, kind: GithubErrorKind, retry_after: Option<Duration>, jitter: f64) -> Duration {
+	let exponential = RETRY_BASE_DELAY
+		.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+		.min(RETRY_MAX_DELAY);
+	let delay = exponential + exponential.mul_f64(jitter.clamp(0.0, 1.0) / 2.0);
+	match retry_after {
+		Some(retry_after) => delay.max(retry_after),
+		None if kind == GithubErrorKind::RateLimit => delay.max(RATE_LIMIT_DELAY),
+		None => delay,
+	}
+}
+
+/// The `Retry-After` header, either in seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+	let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+	if let Ok(secs) = value.parse() {
+		return Some(Duration::from_secs(secs));
+	}
+	let date = DateTime::parse_from_rfc2822(value).ok()?;
+	Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default())
+}
+
+/// Pseudo-random value in `0.0..1.0`, good enough to spread out retries.
+pub fn jitter() -> f64 {
+	let nanos = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|x| x.subsec_nanos())
+		.unwrap_or(0);
+	nanos as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	#[test]
+	fn backoff_bounds() {
+		for attempt in 1..=20 {
+			let exponential = RETRY_BASE_DELAY
+				.saturating_mul(2u32.saturating_pow(attempt - 1))
+				.min(RETRY_MAX_DELAY);
+			for jitter in [0.0, 0.5, 0.999] {
+				let delay = backoff(attempt, GithubErrorKind::ServerError, None, jitter);
+				assert!(delay >= exponential, "attempt {attempt}: {delay:?}");
+				assert!(delay <= exponential.mul_f64(1.5), "attempt {attempt}: {delay:?}");
+				assert!(delay <= RETRY_MAX_DELAY.mul_f64(1.5), "attempt {attempt}: {delay:?}");
+			}
+		}
+		assert_eq!(backoff(1, GithubErrorKind::Network, None, 0.0), RETRY_BASE_DELAY);
+		assert_eq!(backoff(2, GithubErrorKind::Network, None, 0.0), RETRY_BASE_DELAY * 2);
+		// out of range jitter is clamped
+		assert_eq!(
+			backoff(1, GithubErrorKind::Network, None, 7.0),
+			RETRY_BASE_DELAY.mul_f64(1.5)
+		);
+		assert_eq!(backoff(1, GithubErrorKind::Network, None, -1.0), RETRY_BASE_DELAY);
+	}
+
+	#[test]
+	fn backoff_honors_retry_after() {
+		assert_eq!(backoff(1, GithubErrorKind::RateLimit, None, 0.0), RATE_LIMIT_DELAY);
+		let retry_after = Some(Duration::from_secs(5));
+		assert_eq!(
+			backoff(1, GithubErrorKind::RateLimit, retry_after, 0.0),
+			Duration::from_secs(5)
+		);
+		assert_eq!(
+			backoff(1, GithubErrorKind::ServerError, retry_after, 0.0),
+			Duration::from_secs(5)
+		);
+		// a short Retry-After does not undercut the exponential delay
+		assert_eq!(
+			backoff(5, GithubErrorKind::RateLimit, Some(Duration::from_secs(1)), 0.0),
+			RETRY_BASE_DELAY * 16
+		);
+	}
+
+	#[test]
+	fn retry_after_header() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(retry_after(&headers), None);
+		headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+		assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+		headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+		assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+		let later = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+		headers.insert(RETRY_AFTER, HeaderValue::from_str(&later).unwrap());
+		let delay = retry_after(&headers).unwrap();
+		assert!(delay > Duration::from_secs(3500) && delay <= Duration::from_secs(3600));
+		headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+		assert_eq!(retry_after(&headers), None);
+	}
+
+	#[test]
+	fn classify_statuses() {
+		for (status, message, kind) in [
+			(401, "Bad credentials", GithubErrorKind::Auth),
+			(403, "Resource not accessible by integration", GithubErrorKind::Auth),
+			(
+				403,
+				"API rate limit exceeded for installation ID 1.",
+				GithubErrorKind::RateLimit,
+			),
+			(
+				403,
+				"You have exceeded a secondary rate limit.",
+				GithubErrorKind::RateLimit,
+			),
+			(429, "Too Many Requests", GithubErrorKind::RateLimit),
+			(500, "Server Error", GithubErrorKind::ServerError),
+			(502, "Bad Gateway", GithubErrorKind::ServerError),
+			(404, "Not Found", GithubErrorKind::Other),
+			(422, "Validation Failed", GithubErrorKind::Other),
+		] {
+			let status = StatusCode::from_u16(status).unwrap();
+			assert_eq!(classify_status(status, message), kind, "{status} {message}");
+		}
+		assert!(GithubErrorKind::RateLimit.is_transient());
+		assert!(GithubErrorKind::ServerError.is_transient());
+		assert!(GithubErrorKind::Network.is_transient());
+		assert!(!GithubErrorKind::Auth.is_transient());
+		assert!(!GithubErrorKind::Decode.is_transient());
+		assert!(!GithubErrorKind::Other.is_transient());
+	}
+
+	/// A fake GitHub answering `GET /repos/o/r/pulls/{status}` with that status,
+	/// and with a PR that doesn't match the schema for 200. Counts the requests.
+	async fn fake_github() -> (Octocrab, Arc<AtomicUsize>) {
+		let requests = Arc::new(AtomicUsize::new(0));
+		let counter = requests.clone();
+		let app = axum::Router::new().route(
+			"/repos/o/r/pulls/{status}",
+			axum::routing::get(move |axum::extract::Path(status): axum::extract::Path<u16>| {
+				counter.fetch_add(1, Ordering::Relaxed);
+				async move {
+					let status = StatusCode::from_u16(status).unwrap();
+					let body = match status.as_u16() {
+						200 => r#"{"number":"one"}"#,
+						403 => r#"{"message":"API rate limit exceeded for user ID 1."}"#,
+						_ => r#"{"message":"something failed"}"#,
+					};
+					(status, body)
+				}
+			}),
+		);
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let base = format!("http://{}", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+		let gh = Octocrab::builder()
+			.base_uri(base)
+			.unwrap()
+			.add_retry_config(RetryConfig::None)
+			.build()
+			.unwrap();
+		(gh, requests)
+	}
+
+	#[tokio::test]
+	async fn classify_responses() {
+		let (gh, _) = fake_github().await;
+		for (status, kind) in [
+			(401, GithubErrorKind::Auth),
+			(403, GithubErrorKind::RateLimit),
+			(429, GithubErrorKind::RateLimit),
+			(503, GithubErrorKind::ServerError),
+			(404, GithubErrorKind::Other),
+			(200, GithubErrorKind::Decode),
+		] {
+			let err = GithubError::from(gh.pulls("o", "r").get(status).await.unwrap_err());
+			assert_eq!(err.kind, kind, "{status}: {err}");
+			assert_eq!(err.sample.is_some(), kind == GithubErrorKind::Decode, "{status}: {err}");
+		}
+
+		let closed = Octocrab::builder()
+			.base_uri("http://127.0.0.1:9")
+			.unwrap()
+			.build()
+			.unwrap();
+		let err = GithubError::from(closed.pulls("o", "r").get(1).await.unwrap_err());
+		assert_eq!(err.kind, GithubErrorKind::Network, "{err}");
+	}
+
+	// the clock advances by itself while the retry sleeps
+	#[tokio::test(start_paused = true)]
+	async fn retries_only_transient_errors() {
+		let (gh, requests) = fake_github().await;
+		let pool = GithubPool::with_client(gh);
+		let mut client = pool.pick().await;
+
+		// auth failures reload the client and are not retried
+		let err = pool
+			.with_retry(3, &mut client, |gh| async move { gh.pulls("o", "r").get(401).await })
+			.await
+			.unwrap_err();
+		assert_eq!((err.kind, err.attempts), (GithubErrorKind::Auth, 1));
+		assert_eq!(requests.swap(0, Ordering::Relaxed), 1);
+
+		let err = pool
+			.with_retry(3, &mut client, |gh| async move { gh.pulls("o", "r").get(404).await })
+			.await
+			.unwrap_err();
+		assert_eq!((err.kind, err.attempts), (GithubErrorKind::Other, 1));
+		assert_eq!(requests.swap(0, Ordering::Relaxed), 1);
+
+		// one retry after `RETRY_BASE_DELAY`
+		let start = tokio::time::Instant::now();
+		let err = pool
+			.with_retry(1, &mut client, |gh| async move { gh.pulls("o", "r").get(500).await })
+			.await
+			.unwrap_err();
+		assert!(start.elapsed() >= RETRY_BASE_DELAY, "{:?}", start.elapsed());
+		assert_eq!((err.kind, err.attempts), (GithubErrorKind::ServerError, 2));
+		assert!(err.to_string().contains("after 2 attempts"), "{err}");
+		assert_eq!(requests.swap(0, Ordering::Relaxed), 2);
+	}
+
+	/// A fake GitHub reporting `remaining` core requests, counting the rate limit queries.
+	async fn fake_rate_limit(remaining: usize) -> (Octocrab, Arc<AtomicUsize>) {
+		let requests = Arc::new(AtomicUsize::new(0));
+		let counter = requests.clone();
+		let app = axum::Router::new().route(
+			"/rate_limit",
+			axum::routing::get(move || {
+				counter.fetch_add(1, Ordering::Relaxed);
+				let rate =
+					serde_json::json!({ "limit": 5000, "used": 5000 - remaining, "remaining": remaining, "reset": 0 });
+				async move { axum::Json(serde_json::json!({ "resources": { "core": rate, "search": rate }, "rate": rate })) }
+			}),
+		);
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let base = format!("http://{}", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+		let gh = Octocrab::builder().base_uri(base).unwrap().build().unwrap();
+		(gh, requests)
+	}
+
+	#[tokio::test]
+	async fn pick_caches_the_rate_limit() {
+		let (low, low_requests) = fake_rate_limit(10).await;
+		let (high, high_requests) = fake_rate_limit(20).await;
+		let pool = GithubPool {
+			clients: vec![
+				PooledClient::new(Credentials::Fixed, low),
+				PooledClient::new(Credentials::Fixed, high),
+			],
+		};
+		let requests = || {
+			(
+				low_requests.load(Ordering::Relaxed),
+				high_requests.load(Ordering::Relaxed),
+			)
+		};
+
+		assert_eq!(pool.pick().await.index, 1);
+		assert_eq!(requests(), (1, 1));
+		assert_eq!(pool.pick().await.index, 1);
+		assert_eq!(requests(), (1, 1));
+
+		// only the outdated count is checked again
+		*pool.clients[1].remaining.lock().unwrap() = Some((20, Instant::now() - RATE_LIMIT_TTL));
+		assert_eq!(pool.pick().await.index, 1);
+		assert_eq!(requests(), (1, 2));
+
+		// a rate limited client is skipped and checked again after its cooldown
+		pool.cool_down(1, None);
+		assert_eq!(pool.pick().await.index, 0);
+		*pool.clients[1].cooldown_until.lock().unwrap() = None;
+		assert_eq!(pool.pick().await.index, 1);
+		assert_eq!(requests(), (1, 3));
+	}
+}
@@ -0,0 +1,147 @@
+use std::env;
+
+use crate::database::StoredLabel;
+
+/// Order in which labels are shown: labels matching earlier prefixes come first,
+/// sorted alphabetically within a prefix. Other labels follow alphabetically.
+#[derive(Debug, Clone)]
+pub struct LabelOrder {
+	pub prefixes: Vec<String>,
+}
+
+impl Default for LabelOrder {
+	/// nixpkgs labels are numbered like `10.rebuild-linux: 1-10`.
+	fn default() -> Self {
+		Self {
+			prefixes: (0..20).map(|x| format!("{x}.")).collect(),
+		}
+	}
+}
+
+impl LabelOrder {
+	/// Load the prefixes from `PR_DASHBOARD_LABEL_ORDER`, separated by `;`.
+	/// Example: `1.;2.;6.topic;10.;12.`
+	pub fn load() -> Self {
+		let Ok(config) = env::var("PR_DASHBOARD_LABEL_ORDER") else {
+			return Self::default();
+		};
+		Self {
+			prefixes: config
+				.split(';')
+				.filter(|x| !x.is_empty())
+				.map(|x| x.to_owned())
+				.collect(),
+		}
+	}
+
+	fn bucket(&self, name: &str) -> usize {
+		self.prefixes
+			.iter()
+			.position(|x| name.starts_with(&**x))
+			.unwrap_or(self.prefixes.len())
+	}
+}
+
+/// Sort labels for display. The sort is stable, so equal names keep their order.
+pub fn sort_labels(labels: &mut [StoredLabel], order: &LabelOrder) {
+	labels.sort_by(|a, b| {
+		order
+			.bucket(&a.name)
+			.cmp(&order.bucket(&b.name))
+			.then_with(|| a.name.cmp(&b.name))
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn labels(names: &[(&str, &str)]) -> Vec<StoredLabel> {
+		names
+			.iter()
+			.map(|(name, color)| StoredLabel {
+				name: (*name).to_owned(),
+				color: (*color).to_owned(),
+			})
+			.collect()
+	}
+
+	fn sorted(names: &[&str], order: &LabelOrder) -> Vec<String> {
+		let mut labels = labels(&names.iter().map(|x| (*x, "ffffff")).collect::<Vec<_>>());
+		sort_labels(&mut labels, order);
+		labels.into_iter().map(|x| x.name).collect()
+	}
+
+	#[test]
+	fn nixpkgs_order() {
+		let order = LabelOrder::default();
+		assert_eq!(
+			sorted(
+				&[
+					"backport release-24.05",
+					"12.approvals: 1",
+					"10.rebuild-linux: 1-10",
+					"6.topic: python",
+					"10.rebuild-darwin: 0",
+					"2.status: merge conflict",
+					"1.severity: security",
+					"8.has: package (new)",
+					"awaiting_changes",
+				],
+				&order
+			),
+			[
+				"1.severity: security",
+				"2.status: merge conflict",
+				"6.topic: python",
+				"8.has: package (new)",
+				"10.rebuild-darwin: 0",
+				"10.rebuild-linux: 1-10",
+				"12.approvals: 1",
+				"awaiting_changes",
+				"backport release-24.05",
+			]
+		);
+	}
+
+	#[test]
+	fn configured_order() {
+		let order = LabelOrder {
+			prefixes: vec!["priority".to_owned(), "bug".to_owned(), "6.topic".to_owned()],
+		};
+		assert_eq!(
+			sorted(
+				&[
+					"documentation",
+					"bug: crash",
+					"6.topic: rust",
+					"priority: high",
+					"bug",
+					"6.severity: low",
+					"area: ui",
+				],
+				&order
+			),
+			[
+				"priority: high",
+				"bug",
+				"bug: crash",
+				"6.topic: rust",
+				"6.severity: low",
+				"area: ui",
+				"documentation",
+			]
+		);
+		// without prefixes, the order is alphabetical
+		let order = LabelOrder { prefixes: vec![] };
+		assert_eq!(sorted(&["b", "10.a", "a", "1.a"], &order), ["1.a", "10.a", "a", "b"]);
+	}
+
+	#[test]
+	fn stable_for_equal_names() {
+		let mut sorted = labels(&[("b", "1"), ("a", "1"), ("b", "2"), ("a", "2"), ("b", "3")]);
+		sort_labels(&mut sorted, &LabelOrder::default());
+		let sorted: Vec<_> = sorted.iter().map(|x| (&*x.name, &*x.color)).collect();
+		assert_eq!(sorted, [("a", "1"), ("a", "2"), ("b", "1"), ("b", "2"), ("b", "3")]);
+	}
+}
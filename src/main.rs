@@ -1,55 +1,73 @@
 use std::any::Any;
-use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::num::ParseIntError;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Instant, SystemTime};
 
 use axum::extract::{RawQuery, Request};
-use axum::http::StatusCode;
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{Extension, Router};
+use axum::{Extension, Json, Router};
 use axum_client_ip::{ClientIp, ClientIpSource};
-use database::DB;
-use octocrab::Octocrab;
-use tokio::fs;
-use tokio::sync::{Mutex, RwLock};
+use cache::Caches;
+use category::CategoryRules;
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use database::{DbPool, DB};
+use effort::EffortRule;
+use freshness::{FreshnessPolicy, Verdict};
+use github::{GithubError, GithubPool, RateLimitPolicy};
+use labels::LabelOrder;
+use scheduler::ScheduledRun;
+use tokio::sync::{watch, Mutex, MutexGuard, RwLock};
 use tower_http::catch_panic::CatchPanicLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+mod cache;
+mod category;
 mod database;
+mod effort;
+mod freshness;
+mod github;
+mod labels;
+mod notify;
 mod route;
+mod scheduler;
 
 use route::*;
 
+/// Attempts of `try_lock_update` while the `update_lock` is held by something other than a pass.
+const UPDATE_LOCK_ATTEMPTS: usize = 50;
+const UPDATE_LOCK_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Format of displayed timestamps, in the configured time zone.
 pub static TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Format of stored timestamps (RFC 3339 in UTC), which sort chronologically as text.
+pub static UTC_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
 
 pub static AWAITING_AUTHOR: &str = "AwaitingAuthor";
 pub static NEEDS_REVIEWER: &str = "NeedsReviewer";
 pub static AWAITING_REVIEWER: &str = "AwaitingReviewer";
 pub static NEEDS_MERGER: &str = "NeedsMerger";
+/// AwaitingAuthor PRs without any update for `PR_DASHBOARD_STALE_DAYS`.
+pub static STALE: &str = "Stale";
+/// PRs the evaluator didn't label in time, see `category::EvalCheck`.
+pub static NEEDS_EVAL: &str = "NeedsEval";
 
-thread_local! {
-	static DATABASE: RefCell<Option<DB>> = RefCell::new(None);
-}
-
+/// Run a closure taking `&mut DB` with a connection of the pool in `AppState`, see `DbPool::run`.
 #[macro_export]
 macro_rules! with_db {
-	($code:expr) => {
-		crate::DATABASE.with(|db| {
-			let mut db = db.borrow_mut();
-			if db.is_none() {
-				*db = Some(DB::new().unwrap());
-			}
-			let db: &mut DB = db.as_mut().unwrap();
-			let result: Result<_, Box<dyn std::error::Error>> = $code(db);
-			result
-		})
+	($state:expr, $code:expr) => {
+		$state.db.run($code).await
 	};
 }
 
@@ -80,15 +98,19 @@ async fn real_main() -> Result<(), Box<dyn Error>> {
 		.with(tracing_subscriber::fmt::layer())
 		.init();
 
-	let gh = octocrab::OctocrabBuilder::default();
-	let gh = if let Ok(pat) = env::var("GITHUB_PAT") {
-		gh.personal_token(pat)
-	} else if let Ok(file) = env::var("GITHUB_PAT_FILE") {
-		gh.personal_token(fs::read_to_string(file).await?)
-	} else {
-		panic!("no GITHUB_PAT / GITHUB_PAT_FILE configured");
-	}
-	.build()?;
+	let gh = github::GithubPool::from_env().await?;
+
+	let db = DbPool::new(
+		env::var("PR_DASHBOARD_DB_CONNECTIONS")
+			.map(|x| {
+				x.parse()
+					.ok()
+					.filter(|x| *x > 0)
+					.expect("invalid PR_DASHBOARD_DB_CONNECTIONS")
+			})
+			.unwrap_or(4),
+	)?;
+	let state = AppState::from_env(db, gh)?;
 
 	// Categories
 	// Awaiting changes
@@ -100,22 +122,24 @@ async fn real_main() -> Result<(), Box<dyn Error>> {
 	// Routes
 	// GET /: main dashboard
 	// POST /update-prs: fetch new data from GH
+	// POST /update-pr: refresh a single PR
 	// POST /reserve-pr: claim PR
-	let app = Router::new()
-		.route("/", get(root))
-		.route("/update-prs", post(update_prs))
-		.route("/housekeep-prs", post(housekeep_prs))
-		.route("/reserve-pr", post(reserve_pr))
-		.route("/list-reservations", get(list_reservations))
-		.route("/extend-reservations", post(extend_reservations))
-		.route("/robots.txt", get(robots_txt))
-		.layer(middleware::from_fn(log_time))
-		.layer(ip_extractor())
-		.layer(CatchPanicLayer::custom(handle_panic))
-		.with_state(AppState {
-			update_lock: Arc::new(Mutex::new(())),
-			gh: Arc::new(RwLock::new(gh)),
-		});
+	// POST /release-pr: give up claimed PR
+	// POST /hide-pr: hide PR from own dashboard
+	// POST /webhook: apply a single PR change pushed by GitHub
+	let app = build_router(state.clone());
+
+	let update_on_start = env::var("PR_DASHBOARD_UPDATE_ON_START")
+		.map(|x| x == "1" || x == "true")
+		.unwrap_or(false);
+	// served concurrently, the dashboard shows the progress meanwhile
+	if update_on_start && !state.freshness().await?.caught_up {
+		scheduler::spawn_initial_sync(state.clone());
+	}
+	if let Some(interval) = state.update_interval {
+		scheduler::spawn(state.clone(), interval);
+	}
+	cache::spawn_janitor(state.caches.clone());
 
 	let port = env::var("PORT")
 		.map(|x| x.parse::<u16>().expect("invalid port"))
@@ -128,6 +152,62 @@ async fn real_main() -> Result<(), Box<dyn Error>> {
 	Ok(())
 }
 
+/// All routes of the dashboard, with the logging and panic handling layers.
+pub fn build_router(state: AppState) -> Router {
+	Router::new()
+		.route("/", get(root))
+		.route("/update-prs", post(update_prs))
+		.route("/update-pr", post(update_pr))
+		.route("/update-reviews", post(update_reviews))
+		.route("/housekeep-prs", post(housekeep_prs))
+		.route("/expire-reservations", post(expire_reservations))
+		.route("/reserve-pr", post(reserve_pr))
+		.route("/release-pr", post(release_pr))
+		.route("/list-reservations", get(list_reservations))
+		.route("/my-reservations", get(my_reservations))
+		.route("/extend-reservations", post(extend_reservations))
+		.route("/extend-reservation", post(extend_reservation))
+		.route("/annotate-reservation", post(annotate_reservation))
+		.route("/set-category", post(set_category))
+		.route("/hide-pr", post(hide_pr))
+		.route("/unhide-pr", post(unhide_pr))
+		.route("/hidden", get(list_hidden))
+		.route("/stats", get(stats))
+		.route("/api/stats", get(api_stats))
+		.route("/api/forecast", get(forecast))
+		.route("/api/pulls", get(api_pulls))
+		.route("/api/pr/{id}/history", get(pr_history))
+		.route("/admin/merge-viewers", post(merge_viewers))
+		.route("/admin/drift", get(drift))
+		.route("/admin/backup", post(backup))
+		.route("/admin/maintenance", post(maintenance))
+		.route("/admin/consistency", get(consistency))
+		.route("/admin/caches", get(caches))
+		.route("/admin/backup/download", get(download_backup))
+		.route("/broken-rows", get(broken_rows))
+		.route("/compress-data", post(compress_data))
+		.route("/pr", get(pr_detail_redirect))
+		.route("/pr/{id}", get(pr_detail))
+		.route("/pr/{owner}/{name}/{id}", get(pr_detail_in_repo))
+		.route("/sitemap.xml", get(sitemap))
+		.route("/reports/stale-mergeable", get(stale_mergeable))
+		.route("/status", get(status))
+		.route("/last-update-status", get(last_update_status))
+		.route("/rate-limit", get(rate_limit))
+		.route("/update-progress", get(update_progress))
+		.route("/changes", get(changes))
+		.route("/changes.atom", get(changes_atom))
+		.route("/reservation-history", get(reservation_history))
+		.route("/reservation-stats", get(reservation_stats))
+		.route("/outcomes", get(outcomes))
+		.route("/webhook", post(webhook))
+		.route("/robots.txt", get(robots_txt))
+		.layer(middleware::from_fn(log_time))
+		.layer(ip_extractor())
+		.layer(CatchPanicLayer::custom(handle_panic))
+		.with_state(state)
+}
+
 #[cfg(feature = "proxy")]
 fn ip_extractor() -> Extension<ClientIpSource> {
 	ClientIpSource::RightmostXForwardedFor.into_extension()
@@ -162,48 +242,439 @@ async fn log_time(ClientIp(ip): ClientIp, RawQuery(query): RawQuery, req: Reques
 
 #[derive(Clone)]
 pub struct AppState {
+	/// Connections to the database, used through `with_db!`.
+	pub db: Arc<DbPool>,
 	pub update_lock: Arc<Mutex<()>>,
-	pub gh: Arc<RwLock<Octocrab>>,
-}
-
-pub fn construct_sql_filter(filter_query: &str, exclude: &str) -> String {
-	println!("construct {exclude:?}");
-	let mut filter = "".to_owned();
-	let mut labels = vec![];
-	let mut labels_exclude = vec![];
-	for (label, incl) in filter_query
-		.split(';')
-		.map(|x| (x, true))
-		.chain(exclude.split(';').map(|x| (x, false)))
-	{
-		if let Some(offender) = label
-			.chars()
-			.find(|x| !x.is_ascii_alphanumeric() && !matches!(x, '.' | ' ' | '-' | '_' | ':' | '/' | '(' | ')'))
-		{
-			panic!("invalid character in label filter: {offender:?}");
-		}
-		if label == "" {
-			continue;
-		}
-		if incl {
-			labels.push(label);
+	/// Start of the update or housekeeping pass holding the `update_lock`, if any.
+	pub update_started: Arc<StdMutex<Option<Instant>>>,
+	/// Progress of the last or running update, for `/update-progress`.
+	pub update_progress: Arc<watch::Sender<UpdateProgress>>,
+	pub gh: Arc<GithubPool>,
+	pub admin_token: Option<String>,
+	pub effort_rules: Arc<Vec<EffortRule>>,
+	/// Label and review based categorization, in priority order.
+	pub category_rules: Arc<CategoryRules>,
+	/// Public URL of the dashboard, used for absolute links.
+	pub base_url: Option<String>,
+	/// Days a PR may wait in NeedsMerger before it is listed in the stale report.
+	pub merger_sla_days: i64,
+	/// Days without update after which an AwaitingAuthor PR is moved to Stale.
+	pub stale_days: i64,
+	/// Days category changes are kept.
+	pub history_days: i64,
+	/// Days the snapshots of the category counts are kept in full, older ones are reduced to one per day.
+	pub snapshot_days: i64,
+	pub merger_report_count: usize,
+	pub default_tz: Tz,
+	pub freshness: FreshnessPolicy,
+	pub rate_limit: RateLimitPolicy,
+	pub label_order: Arc<LabelOrder>,
+	/// Default duration of a reservation.
+	pub reservation_ttl: Duration,
+	/// Longest duration that can be requested with `/reserve-pr?duration=`.
+	pub reservation_max_ttl: Duration,
+	/// Number of PRs a single reserver may hold at once.
+	pub max_reservations: usize,
+	/// How often a transient GitHub failure is retried.
+	pub github_retries: u32,
+	/// Most listing pages fetched per repository in one update.
+	pub max_pages: u32,
+	/// PRs per listing page, at most 100.
+	pub page_size: u8,
+	/// zstd level of the stored PR data, uncompressed if not set.
+	pub compression_level: Option<i32>,
+	/// Directory of the backups made by `/admin/backup`.
+	pub backup_dir: PathBuf,
+	/// Number of backups kept, older ones are deleted after a new backup.
+	pub backup_keep: usize,
+	/// Client for reservation notifications.
+	pub http: reqwest::Client,
+	/// Tracked GitHub repositories (`owner/name`), the first one is the default.
+	pub repos: Arc<Vec<String>>,
+	/// Shared secret of the GitHub webhook, `/webhook` is disabled without it.
+	pub webhook_secret: Option<String>,
+	/// Interval of the built-in update and housekeeping runs, if enabled.
+	pub update_interval: Option<std::time::Duration>,
+	pub last_scheduled_run: Arc<RwLock<Option<ScheduledRun>>>,
+	/// Set while the startup sync of `PR_DASHBOARD_UPDATE_ON_START` runs.
+	pub initial_sync: Arc<AtomicBool>,
+	pub caches: Arc<Caches>,
+}
+
+impl AppState {
+	/// Settings from the environment, the defaults for those not set.
+	pub fn from_env(db: DbPool, gh: GithubPool) -> Result<Self, Box<dyn Error>> {
+		let default_tz = match env::var("PR_DASHBOARD_DEFAULT_TZ") {
+			Ok(x) => x
+				.parse()
+				.map_err(|err| format!("invalid PR_DASHBOARD_DEFAULT_TZ {x:?}: {err}"))?,
+			Err(_) => Tz::UTC,
+		};
+		Ok(Self {
+			db: Arc::new(db),
+			update_lock: Arc::new(Mutex::new(())),
+			update_started: Arc::new(StdMutex::new(None)),
+			update_progress: Arc::new(watch::Sender::new(UpdateProgress::default())),
+			gh: Arc::new(gh),
+			admin_token: env::var("PR_DASHBOARD_ADMIN_TOKEN").ok().filter(|x| !x.is_empty()),
+			effort_rules: Arc::new(effort::load_rules()?),
+			category_rules: Arc::new(category::load_rules()?),
+			base_url: env::var("PR_DASHBOARD_BASE_URL")
+				.ok()
+				.map(|x| x.trim_end_matches('/').to_owned()),
+			merger_sla_days: env::var("PR_DASHBOARD_MERGER_SLA_DAYS")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_MERGER_SLA_DAYS"))
+				.unwrap_or(30),
+			stale_days: env::var("PR_DASHBOARD_STALE_DAYS")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_STALE_DAYS"))
+				.unwrap_or(90),
+			history_days: env::var("PR_DASHBOARD_HISTORY_DAYS")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_HISTORY_DAYS"))
+				.unwrap_or(365),
+			snapshot_days: env::var("PR_DASHBOARD_SNAPSHOT_DAYS")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_SNAPSHOT_DAYS"))
+				.unwrap_or(14),
+			merger_report_count: env::var("PR_DASHBOARD_MERGER_REPORT_COUNT")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_MERGER_REPORT_COUNT"))
+				.unwrap_or(10),
+			default_tz,
+			freshness: FreshnessPolicy::from_env(),
+			rate_limit: RateLimitPolicy::from_env(),
+			label_order: Arc::new(LabelOrder::load()),
+			reservation_ttl: env::var("PR_DASHBOARD_RESERVATION_TTL")
+				.map(|x| parse_duration(&x).expect("invalid PR_DASHBOARD_RESERVATION_TTL"))
+				.unwrap_or(Duration::hours(1)),
+			reservation_max_ttl: env::var("PR_DASHBOARD_RESERVATION_MAX_TTL")
+				.map(|x| parse_duration(&x).expect("invalid PR_DASHBOARD_RESERVATION_MAX_TTL"))
+				.unwrap_or(Duration::weeks(1)),
+			max_reservations: env::var("PR_DASHBOARD_MAX_RESERVATIONS")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_MAX_RESERVATIONS"))
+				.unwrap_or(5),
+			github_retries: env::var("PR_DASHBOARD_GITHUB_RETRIES")
+				.map(|x| x.parse().expect("invalid PR_DASHBOARD_GITHUB_RETRIES"))
+				.unwrap_or(3),
+			max_pages: env::var("PR_DASHBOARD_MAX_PAGES")
+				.map(|x| {
+					x.parse()
+						.ok()
+						.filter(|x| *x > 0)
+						.expect("invalid PR_DASHBOARD_MAX_PAGES")
+				})
+				.unwrap_or(400),
+			page_size: env::var("PR_DASHBOARD_PAGE_SIZE")
+				.map(|x| {
+					x.parse()
+						.ok()
+						.filter(|x| (1..=100).contains(x))
+						.expect("invalid PR_DASHBOARD_PAGE_SIZE")
+				})
+				.unwrap_or(100),
+			compression_level: env::var("PR_DASHBOARD_COMPRESS")
+				.is_ok_and(|x| x == "1" || x == "true")
+				.then(|| {
+					env::var("PR_DASHBOARD_COMPRESS_LEVEL")
+						.map(|x| {
+							x.parse()
+								.ok()
+								.filter(|x| zstd::compression_level_range().contains(x))
+								.expect("invalid PR_DASHBOARD_COMPRESS_LEVEL")
+						})
+						.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL)
+				}),
+			backup_dir: env::var("PR_DASHBOARD_BACKUP_DIR")
+				.unwrap_or_else(|_| "./backups".to_owned())
+				.into(),
+			backup_keep: env::var("PR_DASHBOARD_BACKUP_KEEP")
+				.map(|x| {
+					x.parse()
+						.ok()
+						.filter(|x| *x > 0)
+						.expect("invalid PR_DASHBOARD_BACKUP_KEEP")
+				})
+				.unwrap_or(7),
+			http: reqwest::Client::builder()
+				.timeout(std::time::Duration::from_secs(10))
+				.build()?,
+			repos: Arc::new(configured_repos()),
+			webhook_secret: env::var("GITHUB_WEBHOOK_SECRET").ok().filter(|x| !x.is_empty()),
+			update_interval: scheduler::interval_from_env(),
+			last_scheduled_run: Arc::new(RwLock::new(None)),
+			initial_sync: Arc::new(AtomicBool::new(false)),
+			caches: Arc::new(Caches::from_env()),
+		})
+	}
+
+	/// Timezone for displayed times, from the `tz` query parameter or the configured default.
+	pub fn timezone(&self, params: &HashMap<String, String>) -> Result<Tz, AppError> {
+		match params.get("tz").filter(|x| !x.is_empty()) {
+			Some(tz) => tz
+				.parse()
+				.map_err(|_| AppError::new(StatusCode::BAD_REQUEST, format!("invalid timezone: {tz:?}"))),
+			None => Ok(self.default_tz),
+		}
+	}
+
+	/// Public URL of the dashboard, guessed from the Host header if not configured.
+	pub fn base_url(&self, headers: &HeaderMap) -> String {
+		match self.base_url.as_deref() {
+			Some(base_url) => base_url.to_owned(),
+			None => {
+				let host = headers
+					.get(header::HOST)
+					.and_then(|x| x.to_str().ok())
+					.unwrap_or("localhost");
+				format!("http://{host}")
+			},
+		}
+	}
+
+	/// Repository of a request about a single PR, from `?repo=` or the default repository.
+	pub fn repo_param(&self, params: &HashMap<String, String>) -> Result<String, AppError> {
+		match params.get("repo").filter(|x| !x.is_empty()) {
+			Some(repo) if self.repos.contains(repo) => Ok(repo.clone()),
+			Some(repo) => Err(AppError::new(
+				StatusCode::BAD_REQUEST,
+				format!("repository is not tracked: {repo:?}"),
+			)),
+			None => Ok(self.repos[0].clone()),
+		}
+	}
+
+	/// Path the dashboard is served under, from the configured base URL. Empty if it is served at the root.
+	pub fn base_path(&self) -> &str {
+		let Some(base_url) = self.base_url.as_deref() else {
+			return "";
+		};
+		let host = base_url.split_once("://").map(|x| x.1).unwrap_or(base_url);
+		host.find('/').map(|i| &host[i..]).unwrap_or_default()
+	}
+
+	/// Scheme and host of the public URL, for absolute links to the paths returned by `permalink`.
+	pub fn origin(&self, headers: &HeaderMap) -> String {
+		let mut base_url = self.base_url(headers);
+		base_url.truncate(base_url.len() - self.base_path().len());
+		base_url
+	}
+
+	/// Canonical dashboard URL of the detail page of a PR, below the `base_path`: `/pr/<number>`,
+	/// or `/pr/<owner>/<name>/<number>` if several repositories are tracked.
+	pub fn permalink(&self, repo: &str, id: u64) -> String {
+		if self.repos.len() > 1 {
+			format!("{}/pr/{repo}/{id}", self.base_path())
 		} else {
-			labels_exclude.push(label);
+			format!("{}/pr/{id}", self.base_path())
+		}
+	}
+
+	/// Evaluate the freshness policy against the last successful update.
+	pub async fn freshness(&self) -> Result<Verdict, AppError> {
+		let last_sync = with_db!(self, |db: &mut DB| db.last_sync())?;
+		Ok(self.freshness.evaluate(last_sync.as_deref()))
+	}
+
+	/// Check the `Authorization: Bearer <token>` header against the configured admin token.
+	/// Admin routes are disabled if no token is configured.
+	pub fn is_admin(&self, headers: &HeaderMap) -> bool {
+		let Some(token) = self.admin_token.as_deref() else {
+			return false;
+		};
+		headers
+			.get(header::AUTHORIZATION)
+			.and_then(|x| x.to_str().ok())
+			.and_then(|x| x.strip_prefix("Bearer "))
+			.map(|x| x == token)
+			.unwrap_or(false)
+	}
+
+	/// Take the `update_lock` for an update or housekeeping pass, queueing behind a running one.
+	pub async fn lock_update(&self) -> UpdateGuard<'_> {
+		UpdateGuard::new(self.update_lock.lock().await, &self.update_started)
+	}
+
+	/// Take the `update_lock` for an update or housekeeping pass,
+	/// or answer `409 Conflict` if such a pass is already running.
+	/// Never queues behind another pass, which would only repeat its work.
+	pub async fn try_lock_update(&self) -> Result<UpdateGuard<'_>, Response> {
+		for _ in 0..UPDATE_LOCK_ATTEMPTS {
+			if let Ok(lock) = self.update_lock.try_lock() {
+				return Ok(UpdateGuard::new(lock, &self.update_started));
+			}
+			let started = *self.update_started.lock().unwrap();
+			if let Some(started) = started {
+				let body = serde_json::json!({
+					"error": "an update is already in progress",
+					"running_secs": started.elapsed().as_secs(),
+				});
+				return Err((StatusCode::CONFLICT, Json(body)).into_response());
+			}
+			// otherwise the lock is only held briefly, e.g. by a reservation
+			tokio::time::sleep(UPDATE_LOCK_RETRY_DELAY).await;
 		}
+		let body = serde_json::json!({ "error": "the database is busy, try again later" });
+		Err((StatusCode::CONFLICT, Json(body)).into_response())
+	}
+}
+
+/// Holds the `update_lock` during an update or housekeeping pass, so concurrent passes can be rejected.
+pub struct UpdateGuard<'a> {
+	_lock: MutexGuard<'a, ()>,
+	started: &'a StdMutex<Option<Instant>>,
+}
+
+impl<'a> UpdateGuard<'a> {
+	fn new(lock: MutexGuard<'a, ()>, started: &'a StdMutex<Option<Instant>>) -> Self {
+		*started.lock().unwrap() = Some(Instant::now());
+		Self { _lock: lock, started }
+	}
+}
+
+impl Drop for UpdateGuard<'_> {
+	fn drop(&mut self) {
+		*self.started.lock().unwrap() = None;
 	}
-	labels.sort();
-	labels.dedup();
-	for label in labels {
-		filter += &format!("AND data LIKE '%{label}%'");
+}
+
+/// GitHub URL of a PR.
+pub fn pr_url(repo: &str, id: u64) -> String {
+	format!("https://github.com/{repo}/pull/{id}")
+}
+
+/// Repositories to track, from `PR_DASHBOARD_REPOS` (comma-separated `owner/name`)
+/// or `GITHUB_OWNER` and `GITHUB_REPO`. Defaults to NixOS/nixpkgs.
+pub fn configured_repos() -> Vec<String> {
+	if let Ok(repos) = env::var("PR_DASHBOARD_REPOS") {
+		let repos: Vec<_> = repos
+			.split(',')
+			.map(|x| x.trim())
+			.filter(|x| !x.is_empty())
+			.map(|x| x.to_owned())
+			.collect();
+		for repo in &repos {
+			match repo.split_once('/') {
+				Some((owner, name)) if !owner.is_empty() && !name.is_empty() && !name.contains('/') => {},
+				_ => panic!("invalid repository in PR_DASHBOARD_REPOS: {repo:?}"),
+			}
+		}
+		if !repos.is_empty() {
+			return repos;
+		}
 	}
-	for label in labels_exclude {
-		filter += &format!("AND data NOT LIKE '%{label}%'");
+	let owner = env::var("GITHUB_OWNER").unwrap_or_else(|_| "NixOS".to_owned());
+	let name = env::var("GITHUB_REPO").unwrap_or_else(|_| "nixpkgs".to_owned());
+	vec![format!("{owner}/{name}")]
+}
+
+/// Convert a stored timestamp (see `parse_timestamp`) for display in the given timezone.
+pub fn format_timestamp(time: &str, tz: &Tz) -> String {
+	parse_timestamp(time)
+		.map(|x| x.with_timezone(tz).format(TIME_FORMAT).to_string())
+		.unwrap_or_else(|| time.to_owned())
+}
+
+/// Parse an RFC 3339 timestamp, or a local time in `TIME_FORMAT`, or a local date.
+pub fn parse_timestamp(time: &str) -> Option<DateTime<Utc>> {
+	if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+		return Some(time.with_timezone(&Utc));
 	}
-	filter
+	let time = NaiveDateTime::parse_from_str(time, TIME_FORMAT)
+		.or_else(|_| NaiveDate::parse_from_str(time, "%Y-%m-%d").map(|x| x.and_time(NaiveTime::MIN)))
+		.ok()?;
+	Local
+		.from_local_datetime(&time)
+		.earliest()
+		.map(|x| x.with_timezone(&Utc))
+}
+
+/// Parse a stored UTC timestamp in `UTC_TIME_FORMAT`, or in `TIME_FORMAT` as written by older versions.
+pub fn parse_utc(time: &str) -> DateTime<Utc> {
+	DateTime::parse_from_rfc3339(time)
+		.map(|x| x.with_timezone(&Utc))
+		.or_else(|_| NaiveDateTime::parse_from_str(time, TIME_FORMAT).map(|x| x.and_utc()))
+		.unwrap_or_default()
+}
+
+/// Parse `?since=`, a duration before now or a timestamp, defaulting to `default`.
+pub fn parse_since(params: &HashMap<String, String>, default: &str) -> Result<DateTime<Utc>, String> {
+	let now = Utc::now();
+	let since = params.get("since").map(|x| &**x).unwrap_or(default);
+	let since = match parse_duration(since) {
+		Some(duration) => now - duration,
+		None => parse_timestamp(since).ok_or_else(|| format!("invalid since: {since:?}"))?,
+	};
+	if since > now {
+		return Err("since is in the future".to_owned());
+	}
+	Ok(since)
+}
+
+/// Whether the client asked for JSON, by `Accept: application/json` or `?format=json`.
+pub fn wants_json(params: &HashMap<String, String>, headers: &HeaderMap) -> bool {
+	params.get("format").is_some_and(|x| x == "json")
+		|| headers
+			.get(header::ACCEPT)
+			.and_then(|x| x.to_str().ok())
+			.is_some_and(|x| x.contains("application/json"))
+}
+
+/// Parse a duration like `90m`, `4h` or `2d`.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+	let duration = duration.trim();
+	let unit = duration.chars().last()?;
+	let value: i64 = duration[..duration.len() - unit.len_utf8()].parse().ok()?;
+	if value < 0 {
+		return None;
+	}
+	match unit {
+		's' => Duration::try_seconds(value),
+		'm' => Duration::try_minutes(value),
+		'h' => Duration::try_hours(value),
+		'd' => Duration::try_days(value),
+		'w' => Duration::try_weeks(value),
+		_ => None,
+	}
+}
+
+/// Identity of the requesting viewer, used for reservations and hidden PRs.
+pub async fn viewer_identity(state: &AppState, ip: IpAddr) -> Result<String, AppError> {
+	Ok(with_db!(state, |db: &mut DB| db.resolve_viewer(&format!("{ip}")))?)
+}
+
+/// Identity used for reservations: the `as` query parameter or the `X-Reserver` header.
+/// Falls back to the viewer identity, so clients that don't send a name keep working.
+pub async fn reserver_identity(
+	state: &AppState,
+	ip: IpAddr,
+	params: &HashMap<String, String>,
+	headers: &HeaderMap,
+) -> Result<String, AppError> {
+	let name = params
+		.get("as")
+		.map(|x| &**x)
+		.or_else(|| headers.get("x-reserver").and_then(|x| x.to_str().ok()))
+		.map(|x| x.trim())
+		.filter(|x| !x.is_empty());
+	let Some(name) = name else {
+		return viewer_identity(state, ip).await;
+	};
+	if name.len() > 64 || name.chars().any(|x| x.is_control()) {
+		return Err(AppError::new(StatusCode::BAD_REQUEST, "invalid reserver name"));
+	}
+	Ok(with_db!(state, |db: &mut DB| db.resolve_viewer(name))?)
 }
 
 pub struct AppError {
 	inner: Box<dyn Error>,
+	status: StatusCode,
+}
+
+impl AppError {
+	pub fn new(status: StatusCode, msg: impl Into<String>) -> Self {
+		let msg: String = msg.into();
+		Self {
+			inner: msg.into(),
+			status,
+		}
+	}
 }
 
 macro_rules! impl_from {
@@ -212,26 +683,68 @@ macro_rules! impl_from {
 			fn from(value: $type) -> Self {
 				Self {
 					inner: Box::new(value),
+					status: StatusCode::INTERNAL_SERVER_ERROR,
 				}
 			}
 		}
 	};
 }
-impl_from!(octocrab::Error);
 impl_from!(serde_json::Error);
 impl_from!(ParseIntError);
 impl_from!(std::io::Error);
+impl_from!(chrono::ParseError);
+impl_from!(serde_urlencoded::ser::Error);
 
 impl From<Box<dyn Error>> for AppError {
 	fn from(value: Box<dyn Error>) -> Self {
-		Self { inner: value }
+		Self {
+			inner: value,
+			status: StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+}
+
+impl From<GithubError> for AppError {
+	fn from(value: GithubError) -> Self {
+		Self {
+			inner: Box::new(value),
+			status: StatusCode::BAD_GATEWAY,
+		}
+	}
+}
+
+impl From<octocrab::Error> for AppError {
+	fn from(value: octocrab::Error) -> Self {
+		GithubError::from(value).into()
+	}
+}
+
+impl fmt::Display for AppError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.inner)
+	}
+}
+
+impl fmt::Debug for AppError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {:?}", self.status, self.inner)
+	}
+}
+
+impl Error for AppError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		Some(&*self.inner)
 	}
 }
 
 impl IntoResponse for AppError {
 	fn into_response(self) -> Response {
-		let msg = format!("{:?}", self.inner);
-		(StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
+		let msg = if self.status == StatusCode::INTERNAL_SERVER_ERROR {
+			format!("{:?}", self.inner)
+		} else {
+			format!("{}", self.inner)
+		};
+		(self.status, msg).into_response()
 	}
 }
 
@@ -246,3 +759,466 @@ fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
 	};
 	(StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
 }
+
+#[cfg(test)]
+mod tests {
+	use axum::extract::{Path, Query};
+	use octocrab::{service::middleware::retry::RetryConfig, Octocrab};
+	use rusqlite::params;
+
+	use super::*;
+	use crate::database::{CommonQueries, IN_MEMORY};
+
+	/// State with an in-memory database and a GitHub client pointing at a closed port,
+	/// so nothing reaches GitHub.
+	pub(crate) fn test_state() -> AppState {
+		let db = DbPool::new_with_path(IN_MEMORY, 1).unwrap();
+		let gh = Octocrab::builder()
+			.base_uri("http://127.0.0.1:9")
+			.unwrap()
+			.build()
+			.unwrap();
+		AppState::from_env(db, GithubPool::with_client(gh)).unwrap()
+	}
+
+	/// A user as GitHub sends it.
+	pub(crate) fn user_json(login: &str) -> serde_json::Value {
+		let url = format!("https://api.github.com/users/{login}");
+		serde_json::json!({
+			"login": login,
+			"id": 1,
+			"node_id": "U_1",
+			"avatar_url": "https://avatars.githubusercontent.com/u/1",
+			"gravatar_id": "",
+			"url": url,
+			"html_url": format!("https://github.com/{login}"),
+			"followers_url": format!("{url}/followers"),
+			"following_url": format!("{url}/following"),
+			"gists_url": format!("{url}/gists"),
+			"starred_url": format!("{url}/starred"),
+			"subscriptions_url": format!("{url}/subscriptions"),
+			"organizations_url": format!("{url}/orgs"),
+			"repos_url": format!("{url}/repos"),
+			"events_url": format!("{url}/events"),
+			"received_events_url": format!("{url}/received_events"),
+			"type": "User",
+			"site_admin": false,
+		})
+	}
+
+	/// A PR of NixOS/nixpkgs as GitHub sends it, `state` being `open` or `closed`.
+	pub(crate) fn pull_json(number: u64, state: &str, updated_at: &str, labels: &[&str]) -> serde_json::Value {
+		let labels: Vec<_> = labels
+			.iter()
+			.enumerate()
+			.map(|(i, name)| {
+				serde_json::json!({
+					"id": i,
+					"node_id": format!("L_{i}"),
+					"url": format!("https://api.github.com/repos/NixOS/nixpkgs/labels/{i}"),
+					"name": name,
+					"color": "ffffff",
+					"default": false,
+				})
+			})
+			.collect();
+		let closed_at = (state == "closed").then_some(updated_at);
+		serde_json::json!({
+			"url": format!("https://api.github.com/repos/NixOS/nixpkgs/pulls/{number}"),
+			"id": number,
+			"number": number,
+			"state": state,
+			"locked": false,
+			"maintainer_can_modify": false,
+			"title": format!("pkg{number}: 1.0 -> 1.1"),
+			"user": user_json("someone"),
+			"labels": labels,
+			"created_at": updated_at,
+			"updated_at": updated_at,
+			"closed_at": closed_at,
+			"merged_at": null,
+			"draft": false,
+			"author_association": "CONTRIBUTOR",
+			"html_url": format!("https://github.com/NixOS/nixpkgs/pull/{number}"),
+			"head": { "label": "someone:pkg", "ref": "pkg", "sha": format!("head{number}") },
+			"base": { "label": "NixOS:master", "ref": "master", "sha": "base" },
+		})
+	}
+
+	/// A fake GitHub serving the PRs in `pulls` for every repository, tests change them in between requests.
+	/// The listing answers `304 Not Modified` for a current ETag, the rate limit is unknown.
+	#[derive(Clone, Default)]
+	pub(crate) struct FakeGithub {
+		/// PRs as sent by GitHub, see `pull_json`.
+		pub pulls: Arc<StdMutex<Vec<serde_json::Value>>>,
+		/// Page and `If-None-Match` header of every listing request.
+		pub listings: Arc<StdMutex<Vec<(u32, Option<String>)>>>,
+		/// Listing requests wait while a test holds this.
+		pub gate: Arc<Mutex<()>>,
+	}
+
+	impl FakeGithub {
+		pub(crate) fn new(pulls: Vec<serde_json::Value>) -> Self {
+			Self {
+				pulls: Arc::new(StdMutex::new(pulls)),
+				..Default::default()
+			}
+		}
+
+		/// Serve on a local port, returning `test_state` with a client for it.
+		pub(crate) async fn serve(&self) -> AppState {
+			let list = self.clone();
+			let single = self.clone();
+			let app = Router::new()
+				.route(
+					"/repos/{owner}/{name}/pulls",
+					get(
+						move |Query(params): Query<HashMap<String, String>>, headers: HeaderMap| async move {
+							let _gate = list.gate.lock().await;
+							list.listing(&params, &headers)
+						},
+					),
+				)
+				.route(
+					"/repos/{owner}/{name}/pulls/{number}",
+					get(move |Path((_, _, number)): Path<(String, String, u64)>| async move {
+						let pulls = single.pulls.lock().unwrap();
+						match pulls.iter().find(|x| x["number"] == number) {
+							Some(pr) => Json(pr.clone()).into_response(),
+							None => (
+								StatusCode::NOT_FOUND,
+								Json(serde_json::json!({ "message": "Not Found" })),
+							)
+								.into_response(),
+						}
+					}),
+				);
+			let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+			let base = format!("http://{}", listener.local_addr().unwrap());
+			tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+			let gh = Octocrab::builder()
+				.base_uri(base)
+				.unwrap()
+				.add_retry_config(RetryConfig::None)
+				.build()
+				.unwrap();
+			let mut state = test_state();
+			state.gh = Arc::new(GithubPool::with_client(gh));
+			state.github_retries = 0;
+			state
+		}
+
+		/// A page of the listing, most recently updated first.
+		fn listing(&self, params: &HashMap<String, String>, headers: &HeaderMap) -> Response {
+			let param = |name: &str| params.get(name).and_then(|x| x.parse::<usize>().ok());
+			let (page, per_page) = (param("page").unwrap_or(1), param("per_page").unwrap_or(30));
+			let if_none_match = headers
+				.get(header::IF_NONE_MATCH)
+				.and_then(|x| x.to_str().ok())
+				.map(|x| x.to_owned());
+			self.listings.lock().unwrap().push((page as u32, if_none_match.clone()));
+			let mut pulls: Vec<_> = self
+				.pulls
+				.lock()
+				.unwrap()
+				.iter()
+				.filter(|x| {
+					params
+						.get("state")
+						.is_none_or(|state| state == "all" || x["state"] == **state)
+				})
+				.cloned()
+				.collect();
+			pulls.sort_by(|a, b| b["updated_at"].as_str().cmp(&a["updated_at"].as_str()));
+			let pulls: Vec<_> = pulls.into_iter().skip((page - 1) * per_page).take(per_page).collect();
+			let body = serde_json::to_string(&pulls).unwrap();
+			let mut hasher = std::hash::DefaultHasher::new();
+			std::hash::Hash::hash(&body, &mut hasher);
+			let etag = format!("\"{:x}\"", std::hash::Hasher::finish(&hasher));
+			if if_none_match.as_deref() == Some(&*etag) {
+				return StatusCode::NOT_MODIFIED.into_response();
+			}
+			(
+				[
+					(header::ETAG, etag),
+					(header::CONTENT_TYPE, "application/json".to_owned()),
+				],
+				body,
+			)
+				.into_response()
+		}
+	}
+
+	/// Serve the dashboard with `test_state` on a local port.
+	pub(crate) async fn serve() -> (AppState, String) {
+		let state = test_state();
+		let base = serve_state(state.clone()).await;
+		(state, base)
+	}
+
+	/// Serve the dashboard with this state on a local port, returns its URL.
+	pub(crate) async fn serve_state(state: AppState) -> String {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let app = build_router(state);
+		tokio::spawn(async move {
+			axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+				.await
+				.unwrap();
+		});
+		format!("http://{addr}")
+	}
+
+	/// Two open PRs needing a reviewer, with a successful update just now.
+	pub(crate) async fn seed(state: &AppState) {
+		let now = Utc::now().format(UTC_TIME_FORMAT).to_string();
+		state
+			.db
+			.run(move |db: &mut DB| {
+				let tx = db.transaction()?;
+				for (id, title) in [(1, "alpha: init at 1.0"), (2, "beta: 1.0 -> 1.1")] {
+					let data = serde_json::json!({
+						"url": "",
+						"id": id,
+						"number": id,
+						"title": title,
+						"labels": [],
+						"created_at": now,
+						"updated_at": now,
+					});
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, title, category, category_since, first_seen)
+						VALUES ('NixOS/nixpkgs', ?1, 'someone', ?2, ?3, ?4, ?5, ?2, ?2)",
+						params![id, now, data.to_string(), title, NEEDS_REVIEWER],
+					)?;
+				}
+				tx.execute(
+					"INSERT INTO sync_state (key, value) VALUES ('last_success', ?1)",
+					params![now],
+				)?;
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn reserved_pr_leaves_the_dashboard() {
+		let (state, base) = serve().await;
+		seed(&state).await;
+		let client = reqwest::Client::new();
+
+		let page = client.get(format!("{base}/")).send().await.unwrap();
+		assert_eq!(page.status(), StatusCode::OK);
+		let page = page.text().await.unwrap();
+		assert!(page.contains("alpha: init at 1.0"), "{page}");
+		assert!(page.contains("beta: 1.0 -&#62; 1.1"), "{page}");
+
+		let reserved = client
+			.post(format!("{base}/reserve-pr?pr=1&as=tester"))
+			.header(header::ACCEPT, "application/json")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(reserved.status(), StatusCode::OK);
+		let reserved: serde_json::Value = reserved.json().await.unwrap();
+		assert_eq!(reserved["reserved"]["number"], 1);
+
+		let page = client
+			.get(format!("{base}/"))
+			.send()
+			.await
+			.unwrap()
+			.text()
+			.await
+			.unwrap();
+		assert!(!page.contains("alpha: init at 1.0"), "{page}");
+		assert!(page.contains("beta: 1.0 -&#62; 1.1"), "{page}");
+
+		// the same PR can't be reserved twice
+		let again = client
+			.post(format!("{base}/reserve-pr?pr=1&as=other"))
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(again.status(), StatusCode::CONFLICT);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn pr_without_title_and_update_time() {
+		let (state, base) = serve().await;
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				tx.execute(
+					"INSERT INTO pulls (repo, id, author, last_updated, data, category)
+					VALUES ('NixOS/nixpkgs', 3, 'someone', '2024-01-01T00:00:00Z', '{\"number\":3}', ?1)",
+					params![NEEDS_REVIEWER],
+				)?;
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+		let page = reqwest::get(format!("{base}/")).await.unwrap();
+		assert_eq!(page.status(), StatusCode::OK);
+		let page = page.text().await.unwrap();
+		assert!(page.contains("(no title)"), "{page}");
+		assert!(page.contains("pr-incomplete"), "{page}");
+	}
+
+	#[test]
+	fn timestamps_across_dst() {
+		let tz: Tz = "Europe/Berlin".parse().unwrap();
+		// the clocks jump from 02:00 to 03:00 at 01:00 UTC
+		assert_eq!(format_timestamp("2024-03-31T00:30:00Z", &tz), "2024-03-31 01:30:00");
+		assert_eq!(format_timestamp("2024-03-31T01:30:00Z", &tz), "2024-03-31 03:30:00");
+		// and back from 03:00 to 02:00, so this hour is shown twice
+		assert_eq!(format_timestamp("2024-10-27T00:30:00Z", &tz), "2024-10-27 02:30:00");
+		assert_eq!(format_timestamp("2024-10-27T01:30:00Z", &tz), "2024-10-27 02:30:00");
+		assert_eq!(format_timestamp("2024-10-27T02:30:00Z", &tz), "2024-10-27 03:30:00");
+		assert_eq!(
+			format_timestamp("2024-10-27T01:30:00Z", &Tz::UTC),
+			"2024-10-27 01:30:00"
+		);
+	}
+
+	/// New PRs sorted by update are grouped by the day of their last update in the requested timezone,
+	/// with the offset in effect at that time.
+	#[tokio::test(flavor = "multi_thread")]
+	async fn days_follow_the_timezone_across_dst() {
+		let (state, base) = serve().await;
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				for (id, updated_at) in [
+					(1, "2024-03-30T22:30:00Z"),
+					(2, "2024-03-30T23:30:00Z"),
+					(3, "2024-03-31T22:30:00Z"),
+					(4, "2024-10-26T22:30:00Z"),
+					(5, "2024-10-27T22:30:00Z"),
+				] {
+					let data = serde_json::json!({
+						"number": id,
+						"title": format!("pkg{id}"),
+						"labels": [],
+						"updated_at": updated_at,
+					});
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, title)
+						VALUES ('NixOS/nixpkgs', ?1, 'someone', ?2, ?3, ?4)",
+						params![id, updated_at, data.to_string(), format!("pkg{id}")],
+					)?;
+				}
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+		let days = |tz: &'static str| {
+			let base = base.clone();
+			async move {
+				let page = reqwest::get(format!("{base}/?sort=updated&tz={tz}"))
+					.await
+					.unwrap()
+					.text()
+					.await
+					.unwrap();
+				let mut days: Vec<_> = page
+					.split(r#"<h3 class="pr-day">"#)
+					.skip(1)
+					.map(|x| x.split_once("</h3>").unwrap().0.to_owned())
+					.collect();
+				days.sort();
+				days
+			}
+		};
+		// summer time in Berlin is two hours ahead, winter time one
+		assert_eq!(
+			days("Europe/Berlin").await,
+			["2024-03-30 (1)", "2024-03-31 (1)", "2024-04-01 (1)", "2024-10-27 (2)"]
+		);
+		assert_eq!(
+			days("UTC").await,
+			["2024-03-30 (2)", "2024-03-31 (1)", "2024-10-26 (1)", "2024-10-27 (1)"]
+		);
+	}
+
+	/// Reserving, releasing, updates and housekeeping keep `reservations` and `pulls.reserved_by` in step.
+	#[tokio::test(flavor = "multi_thread")]
+	async fn reservations_stay_consistent() {
+		const EVALUATED: &str = "10.rebuild-linux: 1-10";
+		let github = FakeGithub::new(
+			(1..=4)
+				.map(|id| pull_json(id, "open", "2024-01-01T00:00:00Z", &[EVALUATED]))
+				.collect(),
+		);
+		let state = github.serve().await;
+		let base = serve_state(state.clone()).await;
+		let client = reqwest::Client::new();
+		let post = |path: String| {
+			let request = client
+				.post(format!("{base}{path}"))
+				.header(header::ACCEPT, "application/json");
+			async move { request.send().await.unwrap().status() }
+		};
+		let reserved = || {
+			state.db.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				let report = tx.check_consistency()?;
+				assert!(report.is_consistent(), "{report:?}");
+				let mut stmt = tx.prepare(
+					"SELECT pulls.id, pulls.reserved_by FROM pulls JOIN reservations USING (repo, id) ORDER BY id",
+				)?;
+				let rows: Vec<(i64, String)> = stmt
+					.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+					.collect::<Result<_, _>>()?;
+				Ok(rows)
+			})
+		};
+		let held = |rows: &[(i64, &str)]| rows.iter().map(|(id, x)| (*id, x.to_string())).collect::<Vec<_>>();
+
+		run_update(&state, false, None).await.unwrap();
+		assert_eq!(reserved().await.unwrap(), []);
+
+		for (id, reserver) in [(1, "alice"), (2, "bob"), (3, "alice")] {
+			assert_eq!(post(format!("/reserve-pr?pr={id}&as={reserver}")).await, StatusCode::OK);
+		}
+		assert_eq!(
+			reserved().await.unwrap(),
+			held(&[(1, "alice"), (2, "bob"), (3, "alice")])
+		);
+
+		assert_eq!(post("/release-pr?id=1&as=alice".to_owned()).await, StatusCode::OK);
+		assert_eq!(
+			post("/release-pr?id=2&as=alice".to_owned()).await,
+			StatusCode::FORBIDDEN
+		);
+		assert_eq!(reserved().await.unwrap(), held(&[(2, "bob"), (3, "alice")]));
+
+		// a reserved PR is closed
+		github.pulls.lock().unwrap()[1] = pull_json(2, "closed", "2024-01-02T00:00:00Z", &[EVALUATED]);
+		run_update(&state, false, None).await.unwrap();
+		assert_eq!(reserved().await.unwrap(), held(&[(3, "alice")]));
+
+		run_housekeep(&state, false, &HousekeepScope::full()).await.unwrap();
+		assert_eq!(reserved().await.unwrap(), held(&[(3, "alice")]));
+
+		// bob takes any free PR, then everything is released
+		assert_eq!(
+			post("/reserve-pr?category=NeedsReviewer&as=bob".to_owned()).await,
+			StatusCode::OK
+		);
+		let rows = reserved().await.unwrap();
+		assert_eq!(rows.len(), 2);
+		let (id, _) = rows.iter().find(|x| x.1 == "bob").unwrap();
+		assert!([1, 4].contains(id), "{rows:?}");
+		assert_eq!(post(format!("/release-pr?id={id}&as=bob")).await, StatusCode::OK);
+		assert_eq!(post("/release-pr?id=3&as=alice".to_owned()).await, StatusCode::OK);
+		assert_eq!(reserved().await.unwrap(), []);
+	}
+}
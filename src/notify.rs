@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use axum::http::StatusCode;
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+use crate::{parse_timestamp, AppError};
+
+/// Maximum length of a notification URL.
+const MAX_URL_LENGTH: usize = 512;
+
+/// Minutes before the expiry of a reservation when its holder is warned.
+pub const WARNING_MINUTES: i64 = 10;
+
+/// The `?notify=` parameter, which must be an https URL.
+pub fn notify_url(params: &HashMap<String, String>) -> Result<Option<String>, AppError> {
+	let Some(url) = params.get("notify").filter(|x| !x.is_empty()) else {
+		return Ok(None);
+	};
+	if url.len() > MAX_URL_LENGTH {
+		return Err(AppError::new(
+			StatusCode::BAD_REQUEST,
+			format!("notify URL is longer than {MAX_URL_LENGTH} characters"),
+		));
+	}
+	match Url::parse(url) {
+		Ok(parsed) if parsed.scheme() == "https" && parsed.host().is_some() => Ok(Some(url.clone())),
+		_ => Err(AppError::new(StatusCode::BAD_REQUEST, "notify must be an https URL")),
+	}
+}
+
+/// Payload posted to the notification URL of a reservation.
+#[derive(Debug, Serialize)]
+pub struct Notification {
+	#[serde(skip)]
+	pub url: String,
+	/// `expiring` or `expired`.
+	pub event: &'static str,
+	/// `owner/name` of the repository.
+	pub repo: String,
+	pub pr: u64,
+	pub title: Option<String>,
+	pub expires_at: Option<String>,
+}
+
+impl Notification {
+	pub fn new(
+		url: String,
+		event: &'static str,
+		repo: String,
+		pr: u64,
+		title: Option<String>,
+		expires_at: &str,
+	) -> Self {
+		Self {
+			url,
+			event,
+			repo,
+			pr,
+			title,
+			expires_at: parse_timestamp(expires_at).map(|x| x.to_rfc3339()),
+		}
+	}
+}
+
+/// Deliver the notifications in the background. Failures are only logged.
+pub fn send(client: &Client, notifications: Vec<Notification>) {
+	for notification in notifications {
+		let client = client.clone();
+		tokio::spawn(async move {
+			let res = client
+				.post(&notification.url)
+				.json(&notification)
+				.send()
+				.await
+				.and_then(|x| x.error_for_status());
+			if let Err(err) = res {
+				tracing::warn!(
+					"failed to notify about PR {}#{}: {err}",
+					notification.repo,
+					notification.pr
+				);
+			}
+		});
+	}
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use axum_client_ip::ClientIp;
+use rusqlite::{params, OptionalExtension};
+
+use crate::{database::DB, reserver_identity, with_db, AppError, AppState};
+
+/// Maximum number of characters of a reservation note.
+const MAX_NOTE_LENGTH: usize = 500;
+
+/// The `?note=` parameter, `None` if missing or empty.
+pub fn reservation_note(params: &HashMap<String, String>) -> Result<Option<String>, AppError> {
+	let Some(note) = params.get("note").map(|x| x.trim()).filter(|x| !x.is_empty()) else {
+		return Ok(None);
+	};
+	if note.chars().count() > MAX_NOTE_LENGTH {
+		return Err(AppError::new(
+			StatusCode::BAD_REQUEST,
+			format!("note is longer than {MAX_NOTE_LENGTH} characters"),
+		));
+	}
+	Ok(Some(note.to_owned()))
+}
+
+/// Set or clear (empty `?note=`) the note of a reservation. Only the holder may do this.
+pub async fn annotate_reservation(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let id: i64 = params.get("id").expect("malformed request, requires id").parse()?;
+	let repo = state.repo_param(&params)?;
+	let note = reservation_note(&params)?;
+	let reserver = reserver_identity(&state, ip, &params, &headers).await?;
+
+	let lock = state.update_lock.lock().await;
+
+	let result = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let holder = tx
+			.query_row(
+				"SELECT reserved_by FROM reservations WHERE repo = ?1 AND id = ?2",
+				params![repo, id],
+				|row| row.get::<_, Option<String>>(0),
+			)
+			.optional()?;
+		let Some(holder) = holder else {
+			return Ok((StatusCode::NOT_FOUND, format!("PR {id} is not reserved")));
+		};
+		if holder.as_deref() != Some(&*reserver) {
+			return Ok((StatusCode::FORBIDDEN, format!("PR {id} is reserved by someone else")));
+		}
+		tx.execute(
+			"UPDATE reservations SET note = ?1 WHERE repo = ?2 AND id = ?3",
+			params![note, repo, id],
+		)?;
+		tx.commit()?;
+		Ok((StatusCode::OK, format!("updated note of PR {id}")))
+	})?;
+
+	drop(lock);
+
+	Ok(result.into_response())
+}
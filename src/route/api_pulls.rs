@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	Json,
+};
+use serde::Serialize;
+
+use crate::{
+	category,
+	database::{CommonQueries, PageCursor, PrSummary, PullQuery, DB},
+	with_db, AppError, AppState,
+};
+
+/// Largest page of `/api/pulls`.
+const MAX_PAGE_SIZE: u64 = 500;
+
+#[derive(Serialize)]
+pub struct PullPage {
+	/// Number of PRs matching the filters, on all pages.
+	total: usize,
+	pulls: Vec<PrSummary>,
+	/// `after` parameter of the next page, if there is one.
+	next: Option<String>,
+}
+
+/// Open PRs in order of last update, a page of `limit` (default 50) at a time.
+/// Takes the dashboard filters and `category`, the next page is requested with `after`.
+pub async fn api_pulls(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<PullPage>, AppError> {
+	let bad_request = |msg: String| AppError::new(StatusCode::BAD_REQUEST, msg);
+	let mut query = PullQuery::from_params(&params).map_err(bad_request)?;
+	if let Some(category) = params.get("category").filter(|x| !x.is_empty()) {
+		if category != "New" && !category::CATEGORIES.contains(&&**category) {
+			return Err(bad_request(format!(
+				"unknown category {category:?}, expected New or one of {:?}",
+				category::CATEGORIES
+			)));
+		}
+		query = query.category(Some(category.as_str()));
+	}
+	let limit = match params.get("limit").filter(|x| !x.is_empty()) {
+		Some(limit) => limit
+			.parse()
+			.ok()
+			.filter(|x| (1..=MAX_PAGE_SIZE).contains(x))
+			.ok_or_else(|| bad_request(format!("invalid limit {limit:?}, expected 1 to {MAX_PAGE_SIZE}")))?,
+		None => 50,
+	};
+	let after = match params.get("after").filter(|x| !x.is_empty()) {
+		Some(after) => Some(PageCursor::decode(after).ok_or_else(|| bad_request(format!("invalid cursor {after:?}")))?),
+		None => None,
+	};
+
+	let (total, mut pulls) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let total = tx.count_pulls(&query)?;
+		let mut page = query.clone();
+		if let Some(after) = &after {
+			page = page.after(after);
+		}
+		// one more to know whether there is a next page
+		let pulls = tx.get_pull_summaries(&page.limit(limit + 1))?;
+		Ok((total, pulls))
+	})?;
+	let next = if pulls.len() as u64 > limit {
+		pulls.truncate(limit as usize);
+		pulls.last().map(|x| x.cursor().encode())
+	} else {
+		None
+	};
+	Ok(Json(PullPage { total, pulls, next }))
+}
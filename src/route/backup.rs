@@ -0,0 +1,108 @@
+use std::{
+	error::Error,
+	path::{Path, PathBuf},
+};
+
+use axum::{
+	body::Body,
+	extract::State,
+	http::{header, HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use crate::{database::DB, with_db, AppError, AppState};
+
+/// Backups are named `pr-dashboard-<UTC time>.db`, so that they sort by age.
+const BACKUP_PREFIX: &str = "pr-dashboard-";
+const BACKUP_SUFFIX: &str = ".db";
+
+#[derive(Serialize)]
+pub struct BackupSummary {
+	pub path: PathBuf,
+	/// In bytes.
+	pub size: u64,
+	/// Older backups deleted to keep `PR_DASHBOARD_BACKUP_KEEP`.
+	pub pruned: Vec<PathBuf>,
+}
+
+/// Copy the live database to a new file in `PR_DASHBOARD_BACKUP_DIR`, then delete the oldest backups.
+pub async fn backup(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	fs::create_dir_all(&state.backup_dir).await?;
+	let name = format!("{BACKUP_PREFIX}{}{BACKUP_SUFFIX}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+	let path = state.backup_dir.join(&name);
+	// renamed when complete, so that an interrupted backup is never the latest one
+	let partial = state.backup_dir.join(format!("{name}.partial"));
+	tracing::info!("backup: writing {}", path.display());
+	with_db!(state, |db: &mut DB| db.backup_to(&partial))?;
+	fs::rename(&partial, &path).await?;
+	let size = fs::metadata(&path).await?.len();
+
+	let backups = list_backups(&state.backup_dir).await?;
+	let excess = backups.len().saturating_sub(state.backup_keep);
+	let mut pruned = vec![];
+	for old in backups.into_iter().take(excess) {
+		fs::remove_file(&old).await?;
+		pruned.push(old);
+	}
+	tracing::info!(
+		"backup: wrote {size} bytes to {}, pruned {}",
+		path.display(),
+		pruned.len()
+	);
+
+	Ok(Json(BackupSummary { path, size, pruned }).into_response())
+}
+
+/// The latest backup, as an attachment.
+pub async fn download_backup(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let latest = if fs::try_exists(&state.backup_dir).await? {
+		list_backups(&state.backup_dir).await?.pop()
+	} else {
+		None
+	};
+	let Some(path) = latest else {
+		return Ok((
+			StatusCode::NOT_FOUND,
+			"no backup yet, create one with POST /admin/backup",
+		)
+			.into_response());
+	};
+	let file = fs::File::open(&path).await?;
+	let size = file.metadata().await?.len();
+	let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+	Ok((
+		[
+			(header::CONTENT_TYPE, "application/vnd.sqlite3".to_owned()),
+			(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{name}\"")),
+			(header::CONTENT_LENGTH, size.to_string()),
+		],
+		Body::from_stream(ReaderStream::new(file)),
+	)
+		.into_response())
+}
+
+/// Complete backups in `dir`, oldest first.
+async fn list_backups(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+	let mut backups = vec![];
+	let mut entries = fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		let name = entry.file_name();
+		let name = name.to_string_lossy();
+		if name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX) {
+			backups.push(entry.path());
+		}
+	}
+	backups.sort();
+	Ok(backups)
+}
@@ -0,0 +1,16 @@
+use axum::{extract::State, Json};
+
+use crate::{
+	database::{BrokenRow, CommonQueries, DB},
+	with_db, AppError, AppState,
+};
+
+/// Open PRs whose stored data fails to deserialize, skipped by housekeeping.
+/// Each can be deleted, or fetched again with `/update-pr?id=`.
+pub async fn broken_rows(State(state): State<AppState>) -> Result<Json<Vec<BrokenRow>>, AppError> {
+	let rows = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		tx.broken_rows()
+	})?;
+	Ok(Json(rows))
+}
@@ -0,0 +1,16 @@
+use axum::{
+	extract::State,
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+
+use crate::{AppError, AppState};
+
+/// Size, capacity and eviction counts of the in-memory caches.
+pub async fn caches(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	Ok(Json(state.caches.stats()).into_response())
+}
@@ -0,0 +1,237 @@
+use std::borrow::Cow;
+
+use chrono_tz::Tz;
+
+use crate::{database::PR, effort, labels::sort_labels, parse_utc, pr_url, AppError, AppState, TIME_FORMAT};
+
+/// Maximum number of characters of a title shown on a card.
+const TITLE_LENGTH: usize = 120;
+
+pub struct Card {
+	/// Day of the last update, in the displayed timezone.
+	pub date: String,
+	pub html: String,
+}
+
+/// Render a PR as a dashboard card.
+/// `label_href` computes the link of a label, `actions` is placed at the end of the card.
+pub fn render_card(
+	state: &AppState,
+	pr: &mut PR,
+	tz: &Tz,
+	label_href: impl Fn(&str) -> Result<String, AppError>,
+	actions: &str,
+) -> Result<Card, AppError> {
+	let reviewer_names = pr.requested_reviewer_names();
+	let estimate = effort::estimate(&state.effort_rules, pr);
+	let effort_tag = format!(
+		r#"<span class="pr-effort" title="{}">{}</span> "#,
+		askama_escape::escape(&estimate.describe_signals(), askama_escape::Html),
+		estimate.bucket()
+	);
+	let repo = pr.repo.clone();
+	let waiting_since = pr
+		.first_seen
+		.as_deref()
+		.map(|x| {
+			let date = parse_utc(x).with_timezone(tz).format("%Y-%m-%d").to_string();
+			format!(r#"<span class="pr-waiting" title="first seen by the dashboard">waiting since {date}</span> "#)
+		})
+		.unwrap_or_default();
+	let first_timer = if pr.is_first_timer() {
+		r#"<span class="pr-first-timer">first-time contributor</span> "#
+	} else {
+		""
+	};
+	let incomplete = if pr.is_incomplete() {
+		tracing::warn!(
+			"card: {}#{} is stored without a title or update time",
+			pr.repo,
+			pr.number
+		);
+		r#"<span class="pr-incomplete" title="stored without a title or update time, shown with placeholders">⚠</span> "#
+	} else {
+		""
+	};
+	let last_updated = pr.updated_at().with_timezone(tz).format(TIME_FORMAT).to_string();
+	let full_title = pr.title();
+	// truncate before escaping, so entities are never cut in half
+	let title = askama_escape::escape(&truncate_text(full_title, TITLE_LENGTH), askama_escape::Html).to_string();
+	let full_title = askama_escape::escape(full_title, askama_escape::Html).to_string();
+	let data = &mut **pr;
+	let date = &last_updated[0..10];
+	let id = data.number;
+
+	if let Some(labels) = data.labels.as_mut() {
+		sort_labels(labels, &state.label_order);
+	}
+
+	let mut labels = String::new();
+	for label in data.labels.as_deref().unwrap_or_default() {
+		// white for dark labels
+		let rgb_sum = usize::from_str_radix(&label.color[0..2], 16)?
+			+ usize::from_str_radix(&label.color[2..4], 16)?
+			+ usize::from_str_radix(&label.color[4..6], 16)?;
+		let text_color = if rgb_sum > 128 * 3 { "000000" } else { "ffffff" };
+		let href_filter = label_href(&label.name)?;
+		labels += &format!(
+			r#"<a href="{href_filter}" class="pr-label" style="background-color: #{}; color: #{}">{}</a> "#,
+			label.color,
+			text_color,
+			askama_escape::escape(&label.name, askama_escape::Html)
+		);
+	}
+
+	let milestone_chip = data
+		.milestone
+		.as_ref()
+		.map(|x| {
+			format!(
+				r#"<span class="pr-milestone">{}</span> "#,
+				askama_escape::escape(&x.title, askama_escape::Html)
+			)
+		})
+		.unwrap_or_default();
+
+	let mut reviewers = String::new();
+	for name in reviewer_names.iter().take(3) {
+		reviewers += &format!(
+			r#"<span class="pr-reviewer">{}</span> "#,
+			askama_escape::escape(name, askama_escape::Html)
+		);
+	}
+	if reviewer_names.len() > 3 {
+		reviewers += &format!(r#"<span class="pr-reviewer">+{}</span> "#, reviewer_names.len() - 3);
+	}
+	if !reviewers.is_empty() {
+		reviewers = format!("<br>{reviewers}");
+	}
+
+	let details = state.permalink(&repo, id);
+	let github_url = pr_url(&repo, id);
+	let repo_name = repo.split_once('/').map(|x| x.1).unwrap_or(&repo);
+	let html = format!(
+		r#"<div class="pr" data-repo="{repo}" data-pr="{id}">
+		<span class="pr-header">{repo_name} <a href="{github_url}">#{id}</a> <a href="{details}">details</a></span>
+		<span class="pr-date">{incomplete}{date}</span>
+		<br>
+		<span class="pr-title" title="{full_title}">{title}</span>
+		<br>
+		{effort_tag}{first_timer}{waiting_since}{milestone_chip}{labels}
+		{reviewers}
+		{actions}
+		</div>"#
+	);
+	Ok(Card {
+		date: date.to_owned(),
+		html,
+	})
+}
+
+/// Shorten a text to at most `max` characters (plus ellipsis), preferably at a word boundary.
+pub fn truncate_text(title: &str, max: usize) -> Cow<'_, str> {
+	let Some((end, _)) = title.char_indices().nth(max) else {
+		return Cow::Borrowed(title);
+	};
+	let cut = &title[..end];
+	let cut = match cut.rfind(char::is_whitespace) {
+		Some(space) if space > 0 => &cut[..space],
+		_ => cut,
+	};
+	Cow::Owned(format!("{}…", cut.trim_end()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_state;
+
+	fn escaped(title: &str, max: usize) -> String {
+		askama_escape::escape(&truncate_text(title, max), askama_escape::Html).to_string()
+	}
+
+	#[test]
+	fn short_titles_are_kept() {
+		assert!(matches!(truncate_text("foo: 1.0 -> 1.1", 15), Cow::Borrowed(_)));
+		assert_eq!(truncate_text("", 10), "");
+	}
+
+	#[test]
+	fn cut_at_word_boundary() {
+		assert_eq!(
+			truncate_text("python3Packages.foo: 1.0 -> 1.1", 26),
+			"python3Packages.foo: 1.0…"
+		);
+		assert_eq!(
+			truncate_text("python3Packages.foo: 1.0 -> 1.1", 24),
+			"python3Packages.foo:…"
+		);
+		assert_eq!(truncate_text("a b c d e", 4), "a b…");
+		// no whitespace to cut at
+		assert_eq!(truncate_text("abcdefghij", 4), "abcd…");
+		assert_eq!(truncate_text(" abcdefghij", 4), " abc…");
+	}
+
+	#[test]
+	fn entities_are_never_cut() {
+		// the cut falls right after `&` and `<`, which are escaped afterwards as a whole
+		assert_eq!(escaped("foo & bar & baz", 6), "foo &#38;…");
+		assert_eq!(escaped("foo&bar", 4), "foo&#38;…");
+		assert_eq!(escaped("a<b>c<d>e", 2), "a&#60;…");
+		assert_eq!(escaped("x&&&&&&&&&", 3), "x&#38;&#38;…");
+		for max in 0..16 {
+			let title = escaped("<&>\"' <&>\"' <&>", max);
+			// every entity is complete
+			for (i, _) in title.match_indices('&') {
+				let entity = title[i + 1..].split_once(';').map(|x| x.0).unwrap_or_default();
+				assert!(
+					entity.starts_with('#') && entity[1..].chars().all(|x| x.is_ascii_digit()),
+					"{title}"
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn multibyte_at_boundary() {
+		// `ü`, `→` and the emoji are several bytes, the cut counts characters
+		assert_eq!(truncate_text("müü", 2), "mü…");
+		assert_eq!(truncate_text("a→b→c", 2), "a→…");
+		assert_eq!(truncate_text("foo 🦀🦀🦀", 5), "foo…");
+		assert_eq!(truncate_text("🦀🦀🦀", 2), "🦀🦀…");
+		let title = "ä".repeat(200);
+		assert_eq!(truncate_text(&title, TITLE_LENGTH).chars().count(), TITLE_LENGTH + 1);
+	}
+
+	fn render(pr: &mut PR) -> Card {
+		render_card(&test_state(), pr, &Tz::UTC, |_| Ok(String::new()), "").unwrap()
+	}
+
+	#[tokio::test]
+	async fn missing_title_and_update_time() {
+		let mut pr = PR::new("o/r".to_owned(), serde_json::from_str(r#"{"number":7}"#).unwrap(), None);
+		assert!(pr.is_incomplete());
+		let card = render(&mut pr);
+		assert_eq!(card.date, "1970-01-01");
+		assert!(
+			card.html.contains(r#"title="(no title)">(no title)</span>"#),
+			"{}",
+			card.html
+		);
+		assert!(card.html.contains("pr-incomplete"), "{}", card.html);
+
+		// the first sighting stands in for the update time
+		pr.first_seen = Some("2024-03-01T12:00:00Z".to_owned());
+		assert_eq!(render(&mut pr).date, "2024-03-01");
+	}
+
+	#[tokio::test]
+	async fn complete_pr() {
+		let data = r#"{"number":7,"title":"foo: 1.0 -> 1.1","updated_at":"2024-05-02T10:00:00Z","labels":[]}"#;
+		let mut pr = PR::new("o/r".to_owned(), serde_json::from_str(data).unwrap(), None);
+		assert!(!pr.is_incomplete());
+		let card = render(&mut pr);
+		assert_eq!(card.date, "2024-05-02");
+		assert!(!card.html.contains("pr-incomplete"), "{}", card.html);
+	}
+}
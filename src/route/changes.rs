@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{header, HeaderMap, StatusCode},
+	response::{Html, IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+
+use crate::{
+	database::{CommonQueries, PullQuery, DB},
+	parse_since, parse_utc, pr_url, with_db, AppError, AppState, TIME_FORMAT,
+};
+
+struct Entry {
+	id: u64,
+	title: String,
+	detail: String,
+	time: DateTime<Utc>,
+	link: String,
+}
+
+struct Changes {
+	since: DateTime<Utc>,
+	sections: Vec<(&'static str, &'static str, Vec<Entry>)>,
+}
+
+/// New PRs, category changes, departures and expired reservations since `?since=`
+/// (a duration like `24h` or a timestamp), scoped to the label filter and `?repo=`.
+pub async fn changes(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let tz = state.timezone(&params)?;
+	let since = match parse_since(&params, "24h") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	let changes = collect_changes(&state, &params, since).await?;
+
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>Changes</title>";
+	html += &format!(
+		"<h1>Changes since {}</h1>",
+		changes.since.with_timezone(&tz).format(TIME_FORMAT)
+	);
+	for (_, heading, entries) in &changes.sections {
+		html += &format!("<h2>{heading} ({})</h2>", entries.len());
+		if entries.is_empty() {
+			html += "<p>Nothing.</p>";
+			continue;
+		}
+		html += "<ul>";
+		for entry in entries {
+			html += &format!(
+				"<li>{} <a href='{}'>#{}</a> {} ({})</li>",
+				entry.time.with_timezone(&tz).format(TIME_FORMAT),
+				entry.link,
+				entry.id,
+				askama_escape::escape(&entry.title, askama_escape::Html),
+				askama_escape::escape(&entry.detail, askama_escape::Html)
+			);
+		}
+		html += "</ul>";
+	}
+
+	Ok(Html(html).into_response())
+}
+
+/// Atom feed of `/changes`, with the same parameters.
+pub async fn changes_atom(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let since = match parse_since(&params, "24h") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	let changes = collect_changes(&state, &params, since).await?;
+	let base_url = state.base_url(&headers);
+	let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+
+	let mut entries: Vec<_> = changes
+		.sections
+		.iter()
+		.flat_map(|(kind, heading, entries)| entries.iter().map(move |x| (*kind, *heading, x)))
+		.collect();
+	entries.sort_by_key(|x| std::cmp::Reverse(x.2.time));
+	let updated = entries.first().map(|x| x.2.time).unwrap_or(changes.since);
+
+	let mut xml = String::new();
+	xml += r#"<?xml version="1.0" encoding="utf-8"?>"#;
+	xml += r#"<feed xmlns="http://www.w3.org/2005/Atom">"#;
+	xml += &format!("<title>{} PR changes</title>", escape(&state.repos.join(", ")));
+	xml += &format!("<id>{base_url}/changes</id>");
+	xml += &format!("<updated>{}</updated>", updated.to_rfc3339());
+	xml += &format!("<link rel='self' href='{base_url}/changes.atom'/>");
+	for (kind, heading, entry) in entries {
+		let link = if entry.link.starts_with('/') {
+			format!("{}{}", state.origin(&headers), entry.link)
+		} else {
+			entry.link.clone()
+		};
+		xml += "<entry>";
+		xml += &format!("<title>{heading}: #{} {}</title>", entry.id, escape(&entry.title));
+		xml += &format!(
+			"<id>{base_url}/changes#{kind}-{}-{}</id>",
+			entry.id,
+			entry.time.timestamp()
+		);
+		xml += &format!("<updated>{}</updated>", entry.time.to_rfc3339());
+		xml += &format!("<link href='{}'/>", escape(&link));
+		xml += &format!("<summary>{}</summary>", escape(&entry.detail));
+		xml += "</entry>";
+	}
+	xml += "</feed>";
+
+	Ok(([(header::CONTENT_TYPE, "application/atom+xml")], xml).into_response())
+}
+
+async fn collect_changes(
+	state: &AppState,
+	params: &HashMap<String, String>,
+	since: DateTime<Utc>,
+) -> Result<Changes, AppError> {
+	let now = Utc::now();
+
+	let mut query = PullQuery::new()
+		.labels_all(params.get("filter").map(|x| &**x).unwrap_or_default())
+		.and_then(|query| query.exclude_labels(params.get("exclude").map(|x| &**x).unwrap_or_default()))
+		.map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err))?;
+	if let Some(repo) = params.get("repo").filter(|x| !x.is_empty()) {
+		query = query.repo(repo);
+	}
+
+	let (new, changed, departures, expired) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let new = tx.get_pulls(&query.clone().created_since(&since))?;
+		let changed = tx.get_pull_summaries(&query.clone().category_since(&since))?;
+		let departures = tx.get_departures(&query, &since)?;
+		let expired = tx.get_expired_reservations(&query, &since)?;
+		Ok((new, changed, departures, expired))
+	})?;
+
+	let new = new
+		.into_iter()
+		.map(|pr| Entry {
+			id: pr.number,
+			title: pr.title().to_owned(),
+			detail: format!("opened by {}", pr.user.as_ref().map(|x| &*x.login).unwrap_or("?")),
+			time: pr.created_at.unwrap_or(now),
+			link: state.permalink(&pr.repo, pr.number),
+		})
+		.collect();
+	let changed = changed
+		.into_iter()
+		.map(|pr| Entry {
+			id: pr.number,
+			title: pr.title.unwrap_or_default(),
+			detail: format!("now in {}", pr.category.as_deref().unwrap_or("New")),
+			time: pr.category_since.as_deref().map(parse_utc).unwrap_or(now),
+			link: state.permalink(&pr.repo, pr.number),
+		})
+		.collect();
+	let departures = departures
+		.into_iter()
+		.map(|x| Entry {
+			id: x.id,
+			title: x.title,
+			detail: if x.merged { "merged" } else { "closed" }.to_owned(),
+			time: parse_utc(&x.time),
+			link: pr_url(&x.repo, x.id),
+		})
+		.collect();
+	let expired = expired
+		.into_iter()
+		.map(|x| Entry {
+			id: x.id,
+			title: x.title.unwrap_or_default(),
+			detail: format!("reserved by {}", x.reserved_by.as_deref().unwrap_or("?")),
+			time: parse_utc(&x.time),
+			link: state.permalink(&x.repo, x.id),
+		})
+		.collect();
+
+	Ok(Changes {
+		since,
+		sections: vec![
+			("new", "New PRs", new),
+			("category", "Changed category", changed),
+			("departed", "Merged or closed", departures),
+			("expired", "Expired reservations", expired),
+		],
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Duration;
+	use rusqlite::params;
+
+	use super::*;
+	use crate::{tests::test_state, NEEDS_MERGER, NEEDS_REVIEWER, UTC_TIME_FORMAT};
+
+	/// A day of activity: PRs opened and moved, one merged and one reservation expired, each also once before.
+	async fn seed(state: &AppState) {
+		let now = Utc::now();
+		let ago = move |hours: i64| (now - Duration::hours(hours)).format(UTC_TIME_FORMAT).to_string();
+		state
+			.db
+			.run(move |db: &mut DB| {
+				let tx = db.transaction()?;
+				for (id, created, category, category_since) in [
+					// opened today, categorized right away
+					(1, 2, Some(NEEDS_REVIEWER), Some(2)),
+					(2, 72, Some(NEEDS_MERGER), Some(5)),
+					(3, 72, Some(NEEDS_REVIEWER), Some(72)),
+					(4, 72, None, None),
+				] {
+					let data = serde_json::json!({
+						"url": "",
+						"id": id,
+						"number": id,
+						"title": format!("pr {id}"),
+						"labels": [],
+						"created_at": ago(created),
+						"updated_at": ago(created),
+					});
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, title, category, category_since, first_seen)
+						VALUES ('NixOS/nixpkgs', ?1, 'someone', ?2, ?3, ?4, ?5, ?6, ?2)",
+						params![
+							id,
+							ago(created),
+							data.to_string(),
+							format!("pr {id}"),
+							category,
+							category_since.map(ago)
+						],
+					)?;
+				}
+				for (id, merged, time) in [(5, true, 1), (6, false, 48)] {
+					tx.execute(
+						"INSERT INTO departures (repo, pull_id, data, merged, time) VALUES ('NixOS/nixpkgs', ?1, ?2, ?3, ?4)",
+						params![id, serde_json::json!({ "title": format!("pr {id}") }).to_string(), merged, ago(time)],
+					)?;
+				}
+				for (id, time) in [(3, 4), (2, 30)] {
+					tx.execute(
+						"INSERT INTO expired_reservations (repo, pull_id, reserved_by, time) VALUES ('NixOS/nixpkgs', ?1, 'alice', ?2)",
+						params![id, ago(time)],
+					)?;
+				}
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+	}
+
+	fn sections(changes: &Changes) -> Vec<(&'static str, Vec<u64>)> {
+		changes
+			.sections
+			.iter()
+			.map(|(kind, _, entries)| (*kind, entries.iter().map(|x| x.id).collect()))
+			.collect()
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn sections_of_the_last_day() {
+		let state = test_state();
+		seed(&state).await;
+		let since = Utc::now() - Duration::hours(24);
+
+		let changes = collect_changes(&state, &HashMap::new(), since).await.unwrap();
+		assert_eq!(
+			sections(&changes),
+			[
+				("new", vec![1]),
+				("category", vec![2, 1]),
+				("departed", vec![5]),
+				("expired", vec![3]),
+			]
+		);
+		let expired = &changes.sections[3].2[0];
+		assert_eq!(expired.title, "pr 3");
+		assert_eq!(expired.detail, "reserved by alice");
+		assert_eq!(changes.sections[2].2[0].detail, "merged");
+
+		let params = HashMap::from([("repo".to_owned(), "NixOS/other".to_owned())]);
+		let changes = collect_changes(&state, &params, since).await.unwrap();
+		assert!(
+			changes.sections.iter().all(|x| x.2.is_empty()),
+			"{:?}",
+			sections(&changes)
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn validates_since() {
+		let state = test_state();
+		let request = |since: &str| {
+			let state = state.clone();
+			let params = HashMap::from([("since".to_owned(), since.to_owned())]);
+			async move {
+				let response = changes(State(state), Query(params)).await.unwrap();
+				let status = response.status();
+				let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+				(status, String::from_utf8(body.to_vec()).unwrap())
+			}
+		};
+
+		let future = (Utc::now() + Duration::days(1)).to_rfc3339();
+		for (since, expected) in [
+			("yesterday", "invalid since: \"yesterday\""),
+			("-2h", "invalid since: \"-2h\""),
+			(&*future, "since is in the future"),
+		] {
+			assert_eq!(request(since).await, (StatusCode::BAD_REQUEST, expected.to_owned()));
+		}
+		for since in ["90m", "2d", "2024-01-01", "2024-01-01T12:00:00Z"] {
+			assert_eq!(request(since).await.0, StatusCode::OK, "{since}");
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn rejects_invalid_label_filter() {
+		let state = test_state();
+		let params = HashMap::from([("filter".to_owned(), "100%".to_owned())]);
+		let response = changes(State(state), Query(params)).await.into_response();
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	}
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use rusqlite::params;
+
+use crate::{database::DB, wants_json, with_db, AppError, AppState};
+
+/// Rows converted per transaction, so that updates can write in between.
+const DEFAULT_BATCH: usize = 500;
+
+/// Convert the stored data of all PRs to the representation selected by `PR_DASHBOARD_COMPRESS`:
+/// compress the uncompressed rows, or decompress the compressed rows if compression is disabled.
+/// Rows that fail to decompress are left alone, see `/broken-rows`.
+/// The file only shrinks after a `VACUUM`.
+pub async fn compress_data(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let batch = match params.get("batch").filter(|x| !x.is_empty()) {
+		Some(batch) => match batch.parse::<usize>() {
+			Ok(x) if x > 0 => x,
+			_ => return Ok((StatusCode::BAD_REQUEST, format!("invalid batch: {batch:?}")).into_response()),
+		},
+		None => DEFAULT_BATCH,
+	};
+	let level = state.compression_level;
+
+	let mut converted = 0;
+	loop {
+		let count = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			let count = match level {
+				Some(level) => tx.execute(
+					"UPDATE pulls SET data_compressed = compress_data(data, ?1), data = ''
+					WHERE (repo, id) IN (SELECT repo, id FROM pulls WHERE data_compressed IS NULL LIMIT ?2)",
+					params![level, batch],
+				)?,
+				None => tx.execute(
+					"UPDATE pulls SET data = pull_data(data, data_compressed), data_compressed = NULL
+					WHERE (repo, id) IN (SELECT repo, id FROM pulls
+						WHERE data_compressed IS NOT NULL AND pull_data(data, data_compressed) IS NOT NULL LIMIT ?1)",
+					params![batch],
+				)?,
+			};
+			tx.commit()?;
+			Ok(count)
+		})?;
+		converted += count;
+		if count < batch {
+			break;
+		}
+	}
+	tracing::info!("compress-data: converted {converted} rows, compression level {level:?}");
+
+	if wants_json(&params, &headers) {
+		return Ok(Json(serde_json::json!({ "compressed": level.is_some(), "converted": converted })).into_response());
+	}
+	let action = if level.is_some() { "compressed" } else { "decompressed" };
+	Ok(format!("{action} the data of {converted} PRs").into_response())
+}
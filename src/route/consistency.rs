@@ -0,0 +1,230 @@
+use std::{collections::HashMap, error::Error};
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Transaction};
+use serde::Serialize;
+
+use crate::{
+	database::{restore_category, CommonQueries, ConsistencyReport, DB},
+	with_db, AppError, AppState, AWAITING_REVIEWER, END_RESERVATION_LOG, RELEASE_PULLS, UTC_TIME_FORMAT,
+};
+
+#[derive(Serialize)]
+struct ConsistencyResponse {
+	#[serde(flatten)]
+	report: ConsistencyReport,
+	consistent: bool,
+	/// Number of problems fixed with `?repair=true`.
+	repaired: usize,
+}
+
+/// Check reservations, categories and stored data for problems left behind by crashes.
+/// With `?repair=true`, the mechanical problems are fixed in the same transaction.
+pub async fn consistency(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let repair = params.get("repair").is_some_and(|x| x == "true");
+
+	let _lock = state.update_lock.lock().await;
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	let (report, repaired) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let report = tx.check_consistency()?;
+		if !repair {
+			return Ok((report, 0));
+		}
+		let repaired = repair_consistency(&tx, &report, &time)?;
+		tx.commit()?;
+		Ok((report, repaired))
+	})?;
+	if repaired > 0 {
+		tracing::info!("consistency: repaired {repaired} problems ({})", report.summary());
+	}
+
+	Ok(Json(ConsistencyResponse {
+		consistent: report.is_consistent(),
+		report,
+		repaired,
+	})
+	.into_response())
+}
+
+/// Fix the problems of `report` that have a mechanical fix, returns their number.
+/// Orphan reservations are ended, the reservation decides who holds a PR, invalid categories become New.
+pub fn repair_consistency(tx: &Transaction, report: &ConsistencyReport, time: &str) -> Result<usize, Box<dyn Error>> {
+	let mut repaired = 0;
+	for orphan in &report.orphan_reservations {
+		tx.execute(
+			"DELETE FROM reservations WHERE repo = ?1 AND id = ?2",
+			params![orphan.repo, orphan.id],
+		)?;
+		tx.execute(END_RESERVATION_LOG, params![orphan.repo, orphan.id, time, "closed"])?;
+		repaired += 1;
+	}
+	for mismatch in &report.dangling_reserved_by {
+		if let Some(holder) = &mismatch.reservation {
+			tx.execute(
+				"UPDATE pulls SET reserved_by = ?3 WHERE repo = ?1 AND id = ?2",
+				params![mismatch.repo, mismatch.id, holder],
+			)?;
+		} else {
+			tx.execute(RELEASE_PULLS, params![mismatch.repo, mismatch.id])?;
+			restore_category(
+				tx,
+				&mismatch.repo,
+				mismatch.id as i64,
+				AWAITING_REVIEWER,
+				time,
+				"repaired",
+			)?;
+			tx.execute(
+				END_RESERVATION_LOG,
+				params![mismatch.repo, mismatch.id, time, "repaired"],
+			)?;
+		}
+		repaired += 1;
+	}
+	for invalid in &report.invalid_categories {
+		tx.execute(
+			"INSERT INTO category_history (repo, pull_id, from_category, to_category, changed_at, reason)
+			VALUES (?1, ?2, ?3, NULL, ?4, 'repaired')",
+			params![invalid.repo, invalid.id, invalid.category, time],
+		)?;
+		tx.execute(
+			"UPDATE pulls SET category = NULL, category_since = ?3, category_locked = 0 WHERE repo = ?1 AND id = ?2",
+			params![invalid.repo, invalid.id, time],
+		)?;
+		repaired += 1;
+	}
+	Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::header;
+
+	use super::*;
+	use crate::{extract_row, tests::test_state, NEEDS_REVIEWER};
+
+	const TIME: &str = "2024-01-01T00:00:00Z";
+
+	/// One problem of each kind, next to a consistent PR 1.
+	async fn state() -> AppState {
+		let mut state = test_state();
+		state.admin_token = Some("secret".to_owned());
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				for id in 1..=6 {
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, category) VALUES ('o/r', ?1, 'a', ?2, ?3, ?4)",
+						params![id, TIME, serde_json::json!({ "number": id }).to_string(), NEEDS_REVIEWER],
+					)?;
+				}
+				tx.execute_batch(&format!(
+					"-- orphan reservation of a closed PR
+					UPDATE pulls SET state = 'closed' WHERE id = 2;
+					INSERT INTO reservations (repo, id, time, reserved_by) VALUES ('o/r', 2, '{TIME}', 'alice');
+					-- reserved without a reservation
+					UPDATE pulls SET category = '{AWAITING_REVIEWER}', prev_category = '{NEEDS_REVIEWER}', reserved_by = 'alice'
+						WHERE id = 3;
+					-- reserved by someone other than the holder of the reservation
+					UPDATE pulls SET category = '{AWAITING_REVIEWER}', prev_category = '{NEEDS_REVIEWER}', reserved_by = 'alice'
+						WHERE id = 4;
+					INSERT INTO reservations (repo, id, time, reserved_by) VALUES ('o/r', 4, '{TIME}', 'bob');
+					UPDATE pulls SET category = 'Bogus' WHERE id = 5;
+					UPDATE pulls SET data = 'not json' WHERE id = 6;"
+				))?;
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+		state
+	}
+
+	async fn check(state: &AppState, repair: bool) -> serde_json::Value {
+		let mut headers = HeaderMap::new();
+		headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+		let params = HashMap::from([("repair".to_owned(), repair.to_string())]);
+		let response = consistency(State(state.clone()), Query(params), headers).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+		serde_json::from_slice(&body).unwrap()
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn reports_and_repairs_each_kind() {
+		let state = state().await;
+		let report = check(&state, false).await;
+		assert_eq!(
+			report["orphan_reservations"],
+			serde_json::json!([{ "repo": "o/r", "id": 2, "reserved_by": "alice" }])
+		);
+		assert_eq!(
+			report["dangling_reserved_by"],
+			serde_json::json!([
+				{ "repo": "o/r", "id": 3, "reserved_by": "alice", "reservation": null },
+				{ "repo": "o/r", "id": 4, "reserved_by": "alice", "reservation": "bob" },
+			])
+		);
+		assert_eq!(
+			report["invalid_categories"],
+			serde_json::json!([{ "repo": "o/r", "id": 5, "category": "Bogus" }])
+		);
+		assert_eq!(report["broken_rows"][0]["id"], 6);
+		assert_eq!(report["broken_rows"].as_array().unwrap().len(), 1);
+		assert_eq!(
+			(report["consistent"].clone(), report["repaired"].clone()),
+			(false.into(), 0.into())
+		);
+		// only checked
+		assert_eq!(check(&state, false).await, report);
+
+		let repaired = check(&state, true).await;
+		assert_eq!(repaired["repaired"], 4);
+		// broken rows have to be fetched again
+		let after = check(&state, false).await;
+		for kind in ["orphan_reservations", "dangling_reserved_by", "invalid_categories"] {
+			assert_eq!(after[kind], serde_json::json!([]), "{kind}");
+		}
+		assert_eq!(after["broken_rows"], report["broken_rows"]);
+
+		let rows = state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				let mut stmt =
+					tx.prepare("SELECT id, category, reserved_by FROM pulls WHERE id IN (3, 4, 5) ORDER BY id")?;
+				let rows = stmt
+					.query_map([], extract_row!(u64 Option<String> Option<String>))?
+					.collect::<Result<Vec<_>, _>>()?;
+				Ok(rows)
+			})
+			.await
+			.unwrap();
+		let owned = |x: &str| Some(x.to_owned());
+		assert_eq!(
+			rows,
+			[
+				(3, owned(NEEDS_REVIEWER), None),
+				(4, owned(AWAITING_REVIEWER), owned("bob")),
+				(5, None, None),
+			]
+		);
+	}
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::Utc;
+use itertools::Itertools;
+use octocrab::models::IssueState;
+use rusqlite::{params, params_from_iter};
+
+use crate::{
+	database::{StoredPr, DB},
+	extract_row, pull_row, remove_pull, with_db, AppError, AppState, RECORD_DEPARTURE, RECORD_OUTCOME, UPSERT_PULL,
+	UTC_TIME_FORMAT,
+};
+
+/// Maximum sample size per group, to stay within the GitHub rate limit.
+const MAX_SAMPLE: usize = 50;
+
+/// Compare a sample of stored PRs against GitHub and repair mismatches.
+/// Samples `?n=` random PRs plus the `n` PRs that most recently changed category.
+pub async fn drift(
+	State(state): State<AppState>,
+	headers: HeaderMap,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let n = params
+		.get("n")
+		.map(|x| x.parse::<usize>())
+		.transpose()?
+		.unwrap_or(10)
+		.min(MAX_SAMPLE);
+
+	let _lock = state.update_lock.lock().await;
+
+	let stored: Vec<_> = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(
+			"SELECT repo, id, data FROM (SELECT repo, id, pull_data(data, data_compressed) AS data FROM pulls
+				WHERE state = 'open' ORDER BY RANDOM() LIMIT ?1)
+			UNION
+			SELECT repo, id, data FROM (SELECT repo, id, pull_data(data, data_compressed) AS data FROM pulls
+				WHERE state = 'open' AND category_since IS NOT NULL
+				ORDER BY category_since DESC LIMIT ?1)",
+		)?;
+		let rows = stmt
+			.query_map(params![n], extract_row!(String u64 Option<String>))?
+			.map(Result::unwrap)
+			.collect();
+		Ok(rows)
+	})?;
+
+	let mut mismatches = vec![];
+	let mut repairs = vec![];
+	let mut departures = vec![];
+	let client = state.gh.pick().await;
+	tracing::debug!("drift: using GitHub client {}", client.index);
+	for (repo, id, data) in &stored {
+		let (Some((owner, name)), Some(data)) = (repo.split_once('/'), data) else {
+			continue;
+		};
+		let stored: StoredPr = serde_json::from_str(data)?;
+		let live = client.gh.pulls(owner, name).get(*id).await?;
+		let diff = compare(&stored, &StoredPr::from(&live));
+		if diff.is_empty() {
+			continue;
+		}
+		mismatches.push(serde_json::json!({
+			"repo": repo,
+			"id": id,
+			"fields": diff
+				.iter()
+				.map(|(field, before, after)| serde_json::json!({
+					"field": field,
+					"before": before,
+					"after": after,
+				}))
+				.collect::<Vec<_>>(),
+		}));
+		if live.state == Some(IssueState::Closed) {
+			departures.push((
+				repo,
+				*id as i64,
+				serde_json::to_string(&StoredPr::from(&live))?,
+				live.merged_at.is_some(),
+			));
+		} else if let Some(row) = pull_row(&state, repo, &live)? {
+			repairs.push(row);
+		}
+	}
+
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		for row in &repairs {
+			tx.execute(UPSERT_PULL, params_from_iter(row.iter()))?;
+		}
+		for (repo, id, data, merged) in &departures {
+			tx.execute(RECORD_DEPARTURE, params![repo, id, data, merged, time])?;
+			tx.execute(RECORD_OUTCOME, params![repo, id, data, merged, time])?;
+			remove_pull(&tx, repo, *id, &time)?;
+		}
+		tx.commit()?;
+		Ok(())
+	})?;
+
+	let drift_rate = if stored.is_empty() {
+		0.0
+	} else {
+		mismatches.len() as f64 / stored.len() as f64
+	};
+	if drift_rate > 0.0 {
+		tracing::warn!(
+			"drift: {} of {} sampled PRs differ from GitHub",
+			mismatches.len(),
+			stored.len()
+		);
+	}
+
+	Ok(Json(serde_json::json!({
+		"sampled": stored.len(),
+		"drift_rate": drift_rate,
+		"repaired": repairs.len(),
+		"removed": departures.len(),
+		"mismatches": mismatches,
+	}))
+	.into_response())
+}
+
+/// Fields relevant for categorization that differ: (field, stored, live).
+fn compare(stored: &StoredPr, live: &StoredPr) -> Vec<(&'static str, String, String)> {
+	let labels = |pr: &StoredPr| {
+		pr.labels
+			.as_deref()
+			.unwrap_or_default()
+			.iter()
+			.map(|x| &x.name)
+			.sorted()
+			.join(", ")
+	};
+	let fields = [
+		("state", format!("{:?}", stored.state), format!("{:?}", live.state)),
+		("labels", labels(stored), labels(live)),
+		("draft", format!("{:?}", stored.draft), format!("{:?}", live.draft)),
+		(
+			"updated_at",
+			format!("{:?}", stored.updated_at),
+			format!("{:?}", live.updated_at),
+		),
+	];
+	fields.into_iter().filter(|x| x.1 != x.2).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::header;
+
+	use super::*;
+	use crate::{
+		route::run_update,
+		tests::{pull_json, FakeGithub},
+	};
+
+	const STORED: &str = "2024-01-01T00:00:00Z";
+	const LIVE: &str = "2024-01-02T00:00:00Z";
+
+	/// Stores PRs 1 and 2, then changes them on the fake GitHub without the dashboard noticing.
+	async fn drifted(change: impl FnOnce(&mut [serde_json::Value])) -> (AppState, serde_json::Value) {
+		let github = FakeGithub::new(vec![
+			pull_json(1, "open", STORED, &["6.topic: python"]),
+			pull_json(2, "open", STORED, &[]),
+		]);
+		let mut state = github.serve().await;
+		state.admin_token = Some("secret".to_owned());
+		run_update(&state, false, None).await.unwrap();
+		change(&mut github.pulls.lock().unwrap());
+
+		let mut headers = HeaderMap::new();
+		headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+		let response = drift(State(state.clone()), headers, Query(HashMap::new()))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+		(state, serde_json::from_slice(&body).unwrap())
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn repairs_stale_labels() {
+		let (state, report) = drifted(|pulls| pulls[0] = pull_json(1, "open", LIVE, &["6.topic: rust"])).await;
+		assert_eq!(report["sampled"], 2);
+		assert_eq!(report["drift_rate"], 0.5);
+		assert_eq!(
+			(report["repaired"].clone(), report["removed"].clone()),
+			(1.into(), 0.into())
+		);
+		assert_eq!(
+			report["mismatches"],
+			serde_json::json!([{
+				"repo": "NixOS/nixpkgs",
+				"id": 1,
+				"fields": [
+					{ "field": "labels", "before": "6.topic: python", "after": "6.topic: rust" },
+					{ "field": "updated_at", "before": format!("Some({STORED})"), "after": format!("Some({LIVE})") },
+				],
+			}])
+		);
+
+		let labels = state
+			.db
+			.run(|db: &mut DB| {
+				let labels = db.transaction()?.query_row(
+					"SELECT json_extract(pull_data(data, data_compressed), '$.labels[0].name') FROM pulls WHERE id = 1",
+					[],
+					|row| row.get::<_, String>(0),
+				)?;
+				Ok(labels)
+			})
+			.await
+			.unwrap();
+		assert_eq!(labels, "6.topic: rust");
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn removes_missed_closes() {
+		let (state, report) = drifted(|pulls| {
+			pulls[1] = pull_json(2, "closed", LIVE, &[]);
+			pulls[1]["merged_at"] = LIVE.into();
+		})
+		.await;
+		assert_eq!(
+			(report["repaired"].clone(), report["removed"].clone()),
+			(0.into(), 1.into())
+		);
+		assert_eq!(report["mismatches"][0]["id"], 2);
+		assert_eq!(report["mismatches"][0]["fields"][0]["field"], "state");
+
+		let (open, departures) = state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				let open = tx.query_row("SELECT group_concat(id) FROM pulls WHERE state = 'open'", [], |row| {
+					row.get::<_, String>(0)
+				})?;
+				let departures = tx.query_row("SELECT pull_id, merged FROM departures", [], extract_row!(u64 bool))?;
+				Ok((open, departures))
+			})
+			.await
+			.unwrap();
+		assert_eq!(open, "1");
+		assert_eq!(departures, (2, true));
+	}
+}
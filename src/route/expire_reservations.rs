@@ -0,0 +1,25 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::HeaderMap,
+	response::{IntoResponse, Response},
+	Json,
+};
+
+use crate::{notify, run_expiry, wants_json, AppError, AppState};
+
+/// End expired reservations without a full housekeeping pass.
+pub async fn expire_reservations(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let (expired, notifications) = run_expiry(&state).await?;
+	notify::send(&state.http, notifications);
+
+	if wants_json(&params, &headers) {
+		return Ok(Json(serde_json::json!({ "expired": expired })).into_response());
+	}
+	Ok(format!("expired {expired} reservations").into_response())
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use axum_client_ip::ClientIp;
+use chrono::Duration;
+use rusqlite::{params, OptionalExtension};
+
+use crate::{database::DB, parse_duration, reserver_identity, with_db, AppError, AppState};
+
+/// Extend the reservations of the requesting reserver by `?duration=` (one week by default).
+/// Admins can extend all reservations with `?all=true`.
+pub async fn extend_reservations(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let all = params.get("all").map(|x| x == "true").unwrap_or(false);
+	if all && !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let reserver = reserver_identity(&state, ip, &params, &headers).await?;
+	let duration = duration_param(&params, "duration", Duration::weeks(1))?;
+	let modifier = format!("+{} seconds", duration.num_seconds());
+	let rows = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let rows = if all {
+			tx.execute(
+				"UPDATE reservations
+				SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, ?1), notified = 0",
+				params![modifier],
+			)?
+		} else {
+			tx.execute(
+				"UPDATE reservations
+				SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, ?1), notified = 0
+				WHERE reserved_by = ?2",
+				params![modifier, reserver],
+			)?
+		};
+		tx.commit()?;
+		Ok(rows)
+	})?;
+	Ok(format!("extended {rows} reservations").into_response())
+}
+
+/// Extend one reservation of the requesting reserver by `?by=` (the default reservation duration if absent).
+pub async fn extend_reservation(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let id: i64 = params.get("id").expect("malformed request, requires id").parse()?;
+	let repo = state.repo_param(&params)?;
+	let by = duration_param(&params, "by", state.reservation_ttl)?;
+	let modifier = format!("+{} seconds", by.num_seconds());
+	let reserver = reserver_identity(&state, ip, &params, &headers).await?;
+
+	let lock = state.update_lock.lock().await;
+
+	let result = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let holder = tx
+			.query_row(
+				"SELECT reserved_by FROM reservations WHERE repo = ?1 AND id = ?2",
+				params![repo, id],
+				|row| row.get::<_, Option<String>>(0),
+			)
+			.optional()?;
+		let Some(holder) = holder else {
+			return Ok((StatusCode::NOT_FOUND, format!("PR {id} is not reserved")));
+		};
+		if holder.as_deref() != Some(&*reserver) {
+			return Ok((StatusCode::FORBIDDEN, format!("PR {id} is reserved by someone else")));
+		}
+		let expires_at = tx.query_row(
+			"UPDATE reservations
+			SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, ?1), notified = 0
+			WHERE repo = ?2 AND id = ?3 RETURNING expires_at",
+			params![modifier, repo, id],
+			|row| row.get::<_, String>(0),
+		)?;
+		tx.commit()?;
+		Ok((StatusCode::OK, format!("PR {id} reserved until {expires_at}")))
+	})?;
+
+	drop(lock);
+
+	Ok(result.into_response())
+}
+
+fn duration_param(params: &HashMap<String, String>, key: &str, default: Duration) -> Result<Duration, AppError> {
+	let Some(duration) = params.get(key) else {
+		return Ok(default);
+	};
+	parse_duration(duration)
+		.ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, format!("invalid duration: {duration:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::header;
+	use chrono::{DateTime, Utc};
+
+	use super::*;
+	use crate::{
+		parse_utc,
+		tests::{seed, serve_state, test_state},
+		UTC_TIME_FORMAT,
+	};
+
+	/// Dashboard with the seeded PRs 1 and 2 reserved by alice and bob, returns its URL.
+	async fn reserved_by_alice_and_bob() -> (AppState, String) {
+		let mut state = test_state();
+		state.admin_token = Some("secret".to_owned());
+		seed(&state).await;
+		let base = serve_state(state.clone()).await;
+		for (id, reserver) in [(1, "alice"), (2, "bob")] {
+			let response = reqwest::Client::new()
+				.post(format!("{base}/reserve-pr?pr={id}&as={reserver}"))
+				.send()
+				.await
+				.unwrap();
+			assert_eq!(response.status(), StatusCode::OK);
+		}
+		(state, base)
+	}
+
+	/// Expiry of the reservations of PRs 1 and 2.
+	async fn expiries(state: &AppState) -> Vec<DateTime<Utc>> {
+		let expiries: Vec<String> = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			let mut stmt = tx.prepare("SELECT expires_at FROM reservations ORDER BY id")?;
+			let rows = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+			Ok(rows)
+		})
+		.unwrap();
+		expiries.iter().map(|x| parse_utc(x)).collect()
+	}
+
+	async fn post(base: &str, path: &str, admin: bool) -> (StatusCode, String) {
+		let mut request = reqwest::Client::new().post(format!("{base}{path}"));
+		if admin {
+			request = request.header(header::AUTHORIZATION, "Bearer secret");
+		}
+		let response = request.send().await.unwrap();
+		(response.status(), response.text().await.unwrap())
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn extends_only_own_reservations() {
+		let (state, base) = reserved_by_alice_and_bob().await;
+		let before = expiries(&state).await;
+
+		let response = post(&base, "/extend-reservations?as=alice&duration=1d", false).await;
+		assert_eq!(response, (StatusCode::OK, "extended 1 reservations".to_owned()));
+		let after = expiries(&state).await;
+		assert_eq!(after, [before[0] + Duration::days(1), before[1]]);
+
+		// everyone's, only for admins
+		let response = post(&base, "/extend-reservations?as=alice&all=true", false).await;
+		assert_eq!(response, (StatusCode::FORBIDDEN, "admin token required".to_owned()));
+		assert_eq!(expiries(&state).await, after);
+		let response = post(&base, "/extend-reservations?all=true&duration=1h", true).await;
+		assert_eq!(response, (StatusCode::OK, "extended 2 reservations".to_owned()));
+		assert_eq!(
+			expiries(&state).await,
+			[after[0] + Duration::hours(1), after[1] + Duration::hours(1)]
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn extends_a_single_reservation() {
+		let (state, base) = reserved_by_alice_and_bob().await;
+		let before = expiries(&state).await;
+
+		let (status, body) = post(&base, "/extend-reservation?id=1&as=alice&by=2h", false).await;
+		assert_eq!(status, StatusCode::OK);
+		let extended = before[0] + Duration::hours(2);
+		assert_eq!(
+			body,
+			format!("PR 1 reserved until {}", extended.format(UTC_TIME_FORMAT))
+		);
+		assert_eq!(expiries(&state).await, [extended, before[1]]);
+
+		let response = post(&base, "/extend-reservation?id=2&as=alice", false).await;
+		assert_eq!(
+			response,
+			(StatusCode::FORBIDDEN, "PR 2 is reserved by someone else".to_owned())
+		);
+		let response = post(&base, "/extend-reservation?id=3&as=alice", false).await;
+		assert_eq!(response, (StatusCode::NOT_FOUND, "PR 3 is not reserved".to_owned()));
+		let (status, _) = post(&base, "/extend-reservation?id=1&as=alice&by=soon", false).await;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+		assert_eq!(expiries(&state).await, [extended, before[1]]);
+	}
+}
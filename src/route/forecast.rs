@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::{DateTime, Utc};
+
+use crate::{
+	database::{PullQuery, DB},
+	extract_row, parse_timestamp, with_db, AppError, AppState, TIME_FORMAT,
+};
+
+/// Number of past days used to estimate the inflow rate.
+const HISTORY_DAYS: i64 = 14;
+
+pub async fn forecast(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let category = params.get("category").map(|x| &**x).unwrap_or("New");
+	let Some(at) = params.get("at") else {
+		return Ok((StatusCode::BAD_REQUEST, "malformed request, requires at").into_response());
+	};
+	let Some(at) = parse_timestamp(at) else {
+		return Ok((StatusCode::BAD_REQUEST, format!("invalid timestamp: {at:?}")).into_response());
+	};
+	let now = Utc::now();
+	if at < now {
+		return Ok((StatusCode::BAD_REQUEST, "target time is in the past").into_response());
+	}
+
+	let mut filters = PullQuery::new()
+		.labels_all(params.get("filter").map(|x| &**x).unwrap_or_default())
+		.and_then(|query| query.exclude_labels(params.get("exclude").map(|x| &**x).unwrap_or_default()))
+		.map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err))?;
+	if let Some(repo) = params.get("repo").filter(|x| !x.is_empty()) {
+		filters = filters.repo(repo);
+	}
+	let query = filters.clone().category(Some(category));
+	// reserved PRs are in AwaitingReviewer until the reservation ends
+	let reserved = filters.reserved_from(Some(category));
+
+	let (available, expiries, created) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let available = tx.query_row(
+			&query.clone().only_unreserved().select_sql("COUNT(*)"),
+			query.params(),
+			|row| row.get::<_, usize>(0),
+		)?;
+
+		let mut stmt = tx.prepare(&format!(
+			"SELECT expires_at FROM reservations WHERE (repo, id) IN (SELECT repo, id FROM pulls {})",
+			reserved.where_clause()
+		))?;
+		let expiries: Vec<_> = stmt
+			.query_map(reserved.params(), extract_row!(String))?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+
+		let mut stmt =
+			tx.prepare(&query.select_sql("json_extract(pull_data(data, data_compressed), '$.created_at')"))?;
+		let created: Vec<_> = stmt
+			.query_map(query.params(), extract_row!(Option<String>))?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+
+		Ok((available, expiries, created))
+	})?;
+	let projection = project(now, at, available, &expiries, &created);
+
+	let summary = format!(
+		"By {at}, between {} and {} unreserved PRs are expected in {category} (currently {available}, {} reservations expiring).",
+		projection.min,
+		projection.max,
+		projection.expiring,
+		at = at.format(TIME_FORMAT)
+	);
+
+	Ok(Json(serde_json::json!({
+		"category": category,
+		"at": at.to_rfc3339(),
+		"available": available,
+		"expiring_reservations": projection.expiring,
+		"inflow_per_day": projection.rate,
+		"inflow_stddev": projection.stddev,
+		"projected": projection.expected,
+		"min": projection.min,
+		"max": projection.max,
+		"summary": summary,
+		"assumptions": [
+			format!("inflow is the number of PRs still in this category created per day over the last {HISTORY_DAYS} days"),
+			"PRs leaving the category (reviewed, merged, closed) are not tracked and assumed to be zero",
+			"reservations are not extended and nobody reserves PRs until the target time",
+		],
+	}))
+	.into_response())
+}
+
+#[derive(Debug, PartialEq)]
+struct Projection {
+	/// Reservations ending by the target time.
+	expiring: usize,
+	rate: f64,
+	stddev: f64,
+	expected: usize,
+	min: usize,
+	max: usize,
+}
+
+/// Project the number of available PRs at `at`: the `available` ones now, those whose reservation
+/// expires by then, and the inflow estimated from the creation times of the PRs in the category.
+/// The bounds use one standard deviation of the daily inflow.
+fn project(
+	now: DateTime<Utc>,
+	at: DateTime<Utc>,
+	available: usize,
+	expiries: &[String],
+	created: &[Option<String>],
+) -> Projection {
+	let expiring = expiries
+		.iter()
+		.filter_map(|x| parse_timestamp(x))
+		.filter(|x| *x <= at)
+		.count();
+
+	let mut per_day = [0usize; HISTORY_DAYS as usize];
+	for created in created.iter().flatten() {
+		let Ok(created) = DateTime::parse_from_rfc3339(created) else {
+			continue;
+		};
+		let days_ago = (now - created.with_timezone(&Utc)).num_days();
+		if (0..HISTORY_DAYS).contains(&days_ago) {
+			per_day[days_ago as usize] += 1;
+		}
+	}
+	let n = per_day.len() as f64;
+	let rate = per_day.iter().sum::<usize>() as f64 / n;
+	let variance = per_day.iter().map(|&x| (x as f64 - rate).powi(2)).sum::<f64>() / n;
+	let stddev = variance.sqrt();
+
+	let days = (at - now).num_minutes() as f64 / (24.0 * 60.0);
+	let base = (available + expiring) as f64;
+	Projection {
+		expiring,
+		rate,
+		stddev,
+		expected: (base + rate * days).round() as usize,
+		min: (base + (rate - stddev).max(0.0) * days).floor() as usize,
+		max: (base + (rate + stddev) * days).ceil() as usize,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::body::to_bytes;
+	use chrono::{Duration, TimeZone};
+	use rusqlite::params;
+
+	use super::*;
+	use crate::{tests::test_state, AWAITING_REVIEWER, NEEDS_MERGER, NEEDS_REVIEWER, UTC_TIME_FORMAT};
+
+	fn format(time: DateTime<Utc>) -> String {
+		time.format(UTC_TIME_FORMAT).to_string()
+	}
+
+	#[test]
+	fn bounds_follow_the_inflow_variance() {
+		let now = Utc.with_ymd_and_hms(2024, 3, 7, 12, 0, 0).unwrap();
+		let at = now + Duration::days(2);
+		// two PRs every other day: one per day, give or take one
+		let created: Vec<_> = (0..HISTORY_DAYS)
+			.filter(|x| x % 2 == 0)
+			.flat_map(|x| vec![Some(format(now - Duration::days(x) - Duration::hours(1))); 2])
+			.collect();
+		let projection = project(now, at, 3, &[], &created);
+		assert_eq!(
+			projection,
+			Projection {
+				expiring: 0,
+				rate: 1.0,
+				stddev: 1.0,
+				expected: 5,
+				min: 3,
+				max: 7,
+			}
+		);
+
+		// a steady inflow is certain, PRs outside of the history or without a valid time don't count
+		let mut created: Vec<_> = (0..HISTORY_DAYS)
+			.map(|x| Some(format(now - Duration::days(x))))
+			.collect();
+		created.extend([
+			Some(format(now - Duration::days(HISTORY_DAYS))),
+			Some(format(now + Duration::days(1))),
+			Some("yesterday".to_owned()),
+			None,
+		]);
+		let projection = project(now, now + Duration::hours(36), 0, &[], &created);
+		assert_eq!((projection.rate, projection.stddev), (1.0, 0.0));
+		assert_eq!((projection.min, projection.expected, projection.max), (1, 2, 2));
+
+		// the lower bound doesn't go below the PRs available now
+		let created = vec![Some(format(now)); 14];
+		let projection = project(now, now + Duration::days(7), 4, &[], &created);
+		assert_eq!((projection.rate, projection.stddev), (1.0, 13.0f64.sqrt()));
+		assert_eq!((projection.min, projection.expected, projection.max), (4, 11, 37));
+	}
+
+	#[test]
+	fn reservations_ending_in_time_count() {
+		let now = Utc.with_ymd_and_hms(2024, 3, 7, 12, 0, 0).unwrap();
+		let at = now + Duration::hours(2);
+		let expiries = [
+			format(now - Duration::minutes(5)),
+			format(now + Duration::hours(1)),
+			format(at),
+			format(at + Duration::minutes(1)),
+			"never".to_owned(),
+		];
+		let projection = project(now, at, 2, &expiries, &[]);
+		assert_eq!(projection.expiring, 3);
+		assert_eq!((projection.min, projection.expected, projection.max), (5, 5, 5));
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn counts_reservations_of_the_category() {
+		let state = test_state();
+		let now = Utc::now();
+		let expires_at = format(now + Duration::hours(1));
+		state
+			.db
+			.run(move |db: &mut DB| {
+				let tx = db.transaction()?;
+				// two available, one reserved out of the category and one reserved out of another
+				for (id, category, prev_category) in [
+					(1, NEEDS_REVIEWER, None),
+					(2, NEEDS_REVIEWER, None),
+					(3, AWAITING_REVIEWER, Some(NEEDS_REVIEWER)),
+					(4, AWAITING_REVIEWER, Some(NEEDS_MERGER)),
+				] {
+					let data = serde_json::json!({ "number": id, "created_at": format(now) });
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, category, prev_category, reserved_by)
+						VALUES ('NixOS/nixpkgs', ?1, 'a', ?2, ?3, ?4, ?5, ?6)",
+						params![
+							id,
+							format(now),
+							data.to_string(),
+							category,
+							prev_category,
+							prev_category.map(|_| "tester")
+						],
+					)?;
+					if prev_category.is_some() {
+						tx.execute(
+							"INSERT INTO reservations (repo, id, time, reserved_by, expires_at)
+							VALUES ('NixOS/nixpkgs', ?1, ?2, 'tester', ?3)",
+							params![id, format(now), expires_at],
+						)?;
+					}
+				}
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+
+		let params = HashMap::from([
+			("category".to_owned(), NEEDS_REVIEWER.to_owned()),
+			("at".to_owned(), format(now + Duration::days(1))),
+		]);
+		let response = forecast(State(state), Query(params)).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let body: serde_json::Value =
+			serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+		assert_eq!(body["available"], 2);
+		assert_eq!(body["expiring_reservations"], 1);
+		assert!(body["min"].as_u64().unwrap() >= 3, "{body}");
+	}
+}
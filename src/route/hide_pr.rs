@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use axum::extract::{Query, State};
+use axum_client_ip::ClientIp;
+use chrono::Utc;
+use rusqlite::params;
+
+use crate::{database::DB, viewer_identity, with_db, AppError, AppState, UTC_TIME_FORMAT};
+
+pub async fn hide_pr(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+) -> Result<String, AppError> {
+	let id: i64 = params.get("id").expect("malformed request, requires id").parse()?;
+	let repo = state.repo_param(&params)?;
+	let viewer = viewer_identity(&state, ip).await?;
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	let rows = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let rows = tx.execute(
+			"INSERT INTO hidden
+			(repo, pull_id, hidden_by, time)
+			VALUES (?4, ?1, ?2, ?3)
+			ON CONFLICT DO NOTHING",
+			params![id, viewer, time, repo],
+		)?;
+		tx.commit()?;
+		Ok(rows)
+	})?;
+	Ok(format!("hid {rows} PRs"))
+}
+
+pub async fn unhide_pr(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+) -> Result<String, AppError> {
+	let id: i64 = params.get("id").expect("malformed request, requires id").parse()?;
+	let repo = state.repo_param(&params)?;
+	let viewer = viewer_identity(&state, ip).await?;
+
+	let rows = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let rows = tx.execute(
+			"DELETE FROM hidden WHERE repo = ?3 AND pull_id = ?1 AND hidden_by = ?2",
+			params![id, viewer, repo],
+		)?;
+		tx.commit()?;
+		Ok(rows)
+	})?;
+	Ok(format!("unhid {rows} PRs"))
+}
@@ -1,124 +1,940 @@
-use std::rc::Rc;
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use std::{
+	collections::{BTreeMap, HashMap},
+	error::Error,
+	time::Instant,
+};
 
-use axum::extract::State;
-use chrono::{Local, NaiveDateTime};
-use octocrab::models::pulls::PullRequest;
-use rusqlite::params;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, params_from_iter, types::Value, Transaction};
+use serde::Serialize;
 
 use crate::{
-	database::DB, extract_row, with_db, AppError, AppState, AWAITING_AUTHOR, NEEDS_MERGER, NEEDS_REVIEWER, TIME_FORMAT,
+	category,
+	database::{restore_category, BrokenRow, CommonQueries, PullQuery, DB, LABEL_SET_SQL},
+	effort, extract_row,
+	notify::{self, Notification},
+	wants_json, with_db, AppError, AppState, AWAITING_AUTHOR, AWAITING_REVIEWER, END_RESERVATION_LOG, NEEDS_EVAL,
+	RELEASE_PULLS, STALE, UTC_TIME_FORMAT,
 };
 
-pub async fn housekeep_prs(State(state): State<AppState>) -> Result<&'static str, AppError> {
-	let update_lock = state.update_lock.lock().await;
+/// Days a closed PR is kept as a tombstone.
+const TOMBSTONE_DAYS: i64 = 90;
+/// PRs listed per category transition in the housekeeping summary.
+const TRANSITION_EXAMPLES: usize = 5;
 
-	with_db!(|db: &mut DB| {
-		let tx = db.transaction()?;
+/// Category change made by `categorize_pulls`. For reserved PRs, this is the category they get
+/// once the reservation ends.
+pub struct Transition {
+	pub from: Option<String>,
+	pub to: Option<String>,
+	/// Number of PRs with this change.
+	pub count: usize,
+	/// Up to `TRANSITION_EXAMPLES` of these PRs, as `owner/name#number`.
+	pub examples: Vec<String>,
+}
 
-		let mut query = tx.prepare("SELECT id, data, category FROM pulls")?;
-		let pulls: Vec<_> = query
-			.query_map([], extract_row!(usize String Option<String>))?
-			.map(Result::unwrap)
-			.collect();
+impl Transition {
+	/// Whether the PRs lost a category no rule applies to anymore.
+	pub fn is_demotion(&self) -> bool {
+		self.to.is_none()
+	}
+}
+
+/// Update the effort estimate and the label-based category of a single PR.
+pub fn categorize_pull(
+	tx: &Transaction,
+	state: &AppState,
+	repo: &str,
+	id: i64,
+	update_time: &str,
+) -> Result<(), Box<dyn Error>> {
+	categorize_pulls(tx, state, Some(&[(repo.to_owned(), id)]), update_time)?;
+	Ok(())
+}
+
+/// Update the effort estimates and the label-based categories of all open PRs, or of the given ones.
+/// Each step is one statement over all PRs. PRs whose data is not valid JSON are skipped.
+pub fn categorize_pulls(
+	tx: &Transaction,
+	state: &AppState,
+	pulls: Option<&[(String, i64)]>,
+	update_time: &str,
+) -> Result<Vec<Transition>, Box<dyn Error>> {
+	let mut selected = "state = 'open' AND json_valid(pull_data(data, data_compressed))".to_owned();
+	tx.execute("DROP TABLE IF EXISTS temp.selected", [])?;
+	if let Some(pulls) = pulls {
+		// a table instead of bound values, which are limited in number
+		tx.execute(
+			"CREATE TEMP TABLE selected (repo TEXT NOT NULL, id INTEGER NOT NULL)",
+			[],
+		)?;
+		let mut insert = tx.prepare("INSERT INTO temp.selected (repo, id) VALUES (?1, ?2)")?;
+		for (repo, id) in pulls {
+			insert.execute(params![repo, id])?;
+		}
+		drop(insert);
+		selected += " AND (repo, id) IN (SELECT repo, id FROM temp.selected)";
+	}
+
+	// manually set categories are kept until the labels change
+	let unlocked = tx.execute(
+		&format!(
+			"UPDATE pulls SET category_locked = 0, locked_labels = NULL
+			WHERE {selected} AND category_locked AND locked_labels IS NOT {LABEL_SET_SQL}"
+		),
+		[],
+	)?;
+	if unlocked > 0 {
+		tracing::info!("housekeep: unlocked {unlocked} manually categorized PRs whose labels changed");
+	}
+
+	// 0. Keep the effort estimates in sync with the configured rules
+	let mut params = vec![];
+	let effort = effort::sql(&state.effort_rules, &mut params);
+	tx.execute(
+		&format!(
+			"UPDATE pulls SET effort = estimate.effort
+			FROM (SELECT repo, id, {effort} AS effort FROM pulls WHERE {selected}) AS estimate
+			WHERE estimate.repo = pulls.repo AND estimate.id = pulls.id AND pulls.effort IS NOT estimate.effort"
+		),
+		params_from_iter(params),
+	)?;
+
+	// 1. Mark PRs based on labels, and reviews if they were fetched
+	// 2. Set aside PRs the author abandoned, any update brings them back
+	let stale_before = (Utc::now() - Duration::days(state.stale_days))
+		.format(UTC_TIME_FORMAT)
+		.to_string();
+	// 3. Flag PRs the evaluator never got to, unless a rule applies
+	let rules = &state.category_rules.rules;
+	let mut params = vec![
+		Value::from(STALE.to_owned()),
+		Value::from(NEEDS_EVAL.to_owned()),
+		Value::from(AWAITING_AUTHOR.to_owned()),
+		Value::from(stale_before),
+	];
+	let category = category::lookup_sql(rules, "rule", |x| &x.category, &mut params);
+	let reason = category::lookup_sql(rules, "rule", |x| x.name(), &mut params);
+	params.push(Value::from(AWAITING_REVIEWER.to_owned()));
+	let rule = category::sql(rules, &mut params);
+	let waiting_eval = state.category_rules.needs_eval.sql(&mut params);
+	tx.execute("DROP TABLE IF EXISTS temp.recategorized", [])?;
+	// reserved PRs stay in AwaitingReviewer, the category applies once the reservation ends
+	tx.execute(
+		&format!(
+			"CREATE TEMP TABLE recategorized AS
+			SELECT repo, id, reserved, old_category,
+				CASE WHEN stale THEN ?
+					WHEN new_category IS NULL AND waiting_eval THEN ?
+					ELSE new_category END AS new_category,
+				CASE WHEN stale THEN 'stale'
+					WHEN new_category IS NULL AND waiting_eval THEN 'needs eval'
+					ELSE coalesce(reason, 'no rule') END AS reason
+			FROM (
+				SELECT *, new_category IS ? AND last_updated < ? AS stale
+				FROM (
+					SELECT repo, id, reserved, last_updated, waiting_eval,
+						CASE WHEN reserved THEN prev_category ELSE category END AS old_category,
+						{category} AS new_category,
+						{reason} AS reason
+					FROM (
+						SELECT repo, id, category, prev_category, last_updated,
+							reserved_by IS NOT NULL AND category IS ? AS reserved,
+							{rule} AS rule,
+							{waiting_eval} AS waiting_eval
+						FROM pulls WHERE {selected} AND NOT category_locked
+					)
+				)
+			)"
+		),
+		params_from_iter(params),
+	)?;
+	// if no rule matches, a category set by the rules is cleared: a PR whose merge conflict was resolved
+	// doesn't stay in AwaitingAuthor
+	let managed: Vec<_> = category::RULE_CATEGORIES
+		.iter()
+		.chain([&STALE, &NEEDS_EVAL])
+		.map(|x| Value::from((*x).to_owned()))
+		.collect();
+	tx.execute(
+		&format!(
+			"DELETE FROM temp.recategorized WHERE old_category IS new_category
+			OR (new_category IS NULL AND (old_category IS NULL OR old_category NOT IN ({})))",
+			vec!["?"; managed.len()].join(", ")
+		),
+		params_from_iter(managed),
+	)?;
+
+	let mut query = tx.prepare(
+		"SELECT old_category, new_category, COUNT(*), group_concat(CASE WHEN n <= ?1 THEN repo || '#' || id END, ' ')
+		FROM (
+			SELECT *, row_number() OVER (PARTITION BY old_category, new_category ORDER BY repo, id) AS n
+			FROM temp.recategorized
+		)
+		GROUP BY old_category, new_category",
+	)?;
+	let transitions = query
+		.query_map([TRANSITION_EXAMPLES], |row| {
+			Ok(Transition {
+				from: row.get(0)?,
+				to: row.get(1)?,
+				count: row.get(2)?,
+				examples: row.get::<_, String>(3)?.split(' ').map(|x| x.to_owned()).collect(),
+			})
+		})?
+		.collect::<Result<_, _>>()?;
+	drop(query);
+	tx.execute(
+		"INSERT INTO category_history (repo, pull_id, from_category, to_category, changed_at, reason)
+		SELECT repo, id, old_category, new_category, ?1, reason FROM temp.recategorized WHERE NOT reserved",
+		params![update_time],
+	)?;
+	tx.execute(
+		"UPDATE pulls SET category = r.new_category, category_since = ?1
+		FROM temp.recategorized AS r
+		WHERE r.repo = pulls.repo AND r.id = pulls.id AND NOT r.reserved",
+		params![update_time],
+	)?;
+	tx.execute(
+		"UPDATE pulls SET prev_category = r.new_category
+		FROM temp.recategorized AS r
+		WHERE r.repo = pulls.repo AND r.id = pulls.id AND r.reserved",
+		[],
+	)?;
+	tx.execute("DROP TABLE temp.recategorized", [])?;
+	tx.execute("DROP TABLE IF EXISTS temp.selected", [])?;
+	Ok(transitions)
+}
 
-		for (id, data, category) in pulls {
-			let data: PullRequest = serde_json::from_str(&data)?;
-			let labels = data.labels.as_deref().unwrap_or_default();
-			// 1. Mark new PRs as ready for review if ofborg labeled them!
-			let ofborg_evaled = labels.iter().any(|x| x.name.starts_with("10."));
-			// 2. Mark PRs based on labels
-			let await_author =
-				labels.iter().map(|x| &x.name).any(|x| {
-					x == "awaiting_changes" || x == "2.status: merge conflict" || x == "2.status: needs-changes"
-				}) || data.draft.unwrap_or(false);
-			let need_merger = labels.iter().map(|x| &x.name).any(|x| {
-				x == "needs_merger"
-					|| x == "awaiting_merger"
-					|| x == "12.approvals: 3+"
-					|| x == "12.approved-by: package-maintainer"
+/// Subset of the PRs a housekeeping pass recategorizes, all open PRs by default.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HousekeepScope {
+	/// Only PRs in this category, `New` for uncategorized PRs.
+	pub category: Option<String>,
+	/// Only these PRs, by repository and number.
+	pub ids: Option<Vec<(String, u64)>>,
+	/// End expired reservations, always done by a full pass.
+	pub reservations: bool,
+}
+
+impl HousekeepScope {
+	/// Everything, as done by the scheduler.
+	pub fn full() -> Self {
+		HousekeepScope {
+			reservations: true,
+			..Default::default()
+		}
+	}
+
+	/// Read the `category`, `ids` and `reservations` parameters.
+	/// `ids` are `owner/name#number`, or numbers of PRs in `repo` (the first of `repos` by default).
+	fn from_params(params: &HashMap<String, String>, repos: &[String]) -> Result<Self, String> {
+		let category = params.get("category").filter(|x| !x.is_empty());
+		if let Some(category) = category {
+			if category != "New" && !category::CATEGORIES.contains(&&**category) {
+				return Err(format!(
+					"unknown category {category:?}, expected New or one of {:?}",
+					category::CATEGORIES
+				));
+			}
+		}
+		let default_repo = params.get("repo").filter(|x| !x.is_empty()).unwrap_or(&repos[0]);
+		let ids = match params.get("ids").filter(|x| !x.is_empty()) {
+			Some(ids) => Some(
+				ids.split(',')
+					.map(|x| {
+						let (repo, id) = x.trim().rsplit_once('#').unwrap_or((default_repo, x.trim()));
+						if !repos.iter().any(|x| x == repo) {
+							return Err(format!("repository is not tracked: {repo:?}"));
+						}
+						let id = id.parse().map_err(|_| format!("invalid PR number in ids: {x:?}"))?;
+						Ok((repo.to_owned(), id))
+					})
+					.collect::<Result<Vec<_>, _>>()?,
+			),
+			None => None,
+		};
+		let scope = HousekeepScope {
+			category: category.cloned(),
+			ids,
+			reservations: params.get("reservations").is_some_and(|x| x == "true"),
+		};
+		if scope.is_full() {
+			return Ok(Self::full());
+		}
+		Ok(scope)
+	}
+
+	/// Whether all open PRs are recategorized.
+	pub fn is_full(&self) -> bool {
+		self.category.is_none() && self.ids.is_none()
+	}
+
+	/// The open PRs in this scope except the `broken` ones, `None` for all of them.
+	fn select(&self, tx: &Transaction, broken: &[BrokenRow]) -> Result<Option<Vec<(String, i64)>>, rusqlite::Error> {
+		if self.is_full() && broken.is_empty() {
+			return Ok(None);
+		}
+		let mut conditions = vec!["state = 'open'".to_owned()];
+		let mut params = vec![];
+		if !broken.is_empty() {
+			let broken: Vec<_> = broken.iter().map(|x| (&x.repo, x.id)).collect();
+			conditions.push(
+				"(repo, id) NOT IN (SELECT json_extract(value, '$[0]'), json_extract(value, '$[1]') FROM json_each(?))"
+					.to_owned(),
+			);
+			params.push(Value::from(serde_json::to_string(&broken).unwrap()));
+		}
+		if let Some(category) = &self.category {
+			conditions.push("category IS ?".to_owned());
+			params.push(match &**category {
+				"New" => Value::Null,
+				x => Value::from(x.to_owned()),
 			});
-			let need_reviewer = ofborg_evaled;
-
-			if await_author {
-				if category.as_deref() != Some(AWAITING_AUTHOR) {
-					let res = tx.execute(
-						"UPDATE pulls
-						SET category = ?1
-						WHERE id = ?2",
-						params![AWAITING_AUTHOR, id],
-					);
-					if let Err(err) = res {
-						tracing::warn!("error during pr housekeep: {:?}", err);
-					}
-				}
-			} else if need_merger {
-				if category.as_deref() != Some(NEEDS_MERGER) {
-					let res = tx.execute(
-						"UPDATE pulls
-						SET category = ?1
-						WHERE id = ?2",
-						params![NEEDS_MERGER, id],
-					);
-					if let Err(err) = res {
-						tracing::warn!("error during pr housekeep: {:?}", err);
-					}
-				}
-			} else if need_reviewer {
-				if category.as_deref() != Some(NEEDS_REVIEWER) {
-					let res = tx.execute(
-						"UPDATE pulls
-						SET category = ?1
-						WHERE id = ?2",
-						params![NEEDS_REVIEWER, id],
-					);
-					if let Err(err) = res {
-						tracing::warn!("error during pr housekeep: {:?}", err);
-					}
-				}
+		}
+		if let Some(ids) = &self.ids {
+			conditions.push(
+				"(repo, id) IN (SELECT json_extract(value, '$[0]'), json_extract(value, '$[1]') FROM json_each(?))"
+					.to_owned(),
+			);
+			params.push(Value::from(serde_json::to_string(ids).unwrap()));
+		}
+		let mut query = tx.prepare(&format!(
+			"SELECT repo, id FROM pulls WHERE {}",
+			conditions.join(" AND ")
+		))?;
+		let pulls = query
+			.query_map(params_from_iter(params), extract_row!(String i64))?
+			.collect::<Result<_, _>>()?;
+		Ok(Some(pulls))
+	}
+}
+
+/// Outcome of a housekeeping pass, returned as JSON if requested.
+/// After an update with `housekeep=true`, only the transitions, demotions and duration are set.
+#[derive(Debug, Default, Serialize)]
+pub struct HousekeepSummary {
+	/// Number of PRs per category change, like `null→NeedsReviewer`.
+	pub transitions: BTreeMap<String, usize>,
+	/// Some PRs per category change, as `owner/name#number`.
+	pub examples: BTreeMap<String, Vec<String>>,
+	/// Nothing was changed, this is what housekeeping would do.
+	pub dry_run: bool,
+	/// PRs that lost a category no rule applies to anymore.
+	pub demoted: usize,
+	pub reservations_expired: usize,
+	/// Open PRs skipped by the categorization, because their stored data fails to deserialize.
+	pub parse_errors: usize,
+	/// These PRs, also listed by `/broken-rows`.
+	pub broken_rows: Vec<BrokenRow>,
+	/// Open PRs left alone, because their category was set with `/set-category`.
+	pub locked: usize,
+	/// Set if only some PRs were recategorized.
+	pub scope: Option<HousekeepScope>,
+	pub duration_ms: u64,
+	/// Reservation notifications to send once the update lock is released.
+	#[serde(skip)]
+	pub notifications: Vec<Notification>,
+}
+
+impl HousekeepSummary {
+	fn add_transitions(&mut self, transitions: Vec<Transition>) {
+		for transition in transitions {
+			if transition.is_demotion() {
+				self.demoted += transition.count;
 			}
+			let name = |x: &Option<String>| x.as_deref().unwrap_or("null").to_owned();
+			let key = format!("{}→{}", name(&transition.from), name(&transition.to));
+			*self.transitions.entry(key.clone()).or_default() += transition.count;
+			let examples = self.examples.entry(key).or_default();
+			examples.extend(transition.examples);
+			examples.truncate(TRANSITION_EXAMPLES);
+		}
+	}
+}
+
+pub async fn housekeep_prs(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let scope = HousekeepScope::from_params(&params, &state.repos)
+		.map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err))?;
+	let update_lock = if params.get("wait").is_some_and(|x| x == "true") {
+		state.lock_update().await
+	} else {
+		match state.try_lock_update().await {
+			Ok(x) => x,
+			Err(busy) => return Ok(busy),
 		}
-		drop(query);
+	};
+	let dry_run = params.get("dry-run").is_some_and(|x| x == "true");
+	let mut summary = run_housekeep(&state, dry_run, &scope).await?;
+	drop(update_lock);
 
-		let mut query = tx.prepare("SELECT id, time FROM reservations")?;
-		let reservations: Vec<_> = query
-			.query_map([], extract_row!(usize String))?
-			.map(Result::unwrap)
-			.collect();
-		let mut pulls_to_unreserve = vec![];
+	notify::send(&state.http, std::mem::take(&mut summary.notifications));
+
+	if dry_run || wants_json(&params, &headers) {
+		return Ok(Json(summary).into_response());
+	}
+	Ok("done".into_response())
+}
+
+/// Record the number of open PRs per category, and reduce the snapshots older than `full_days`
+/// to the last one of each day.
+pub fn snapshot_counts(tx: &Transaction, now_utc: DateTime<Utc>, full_days: i64) -> Result<(), Box<dyn Error>> {
+	let taken_at = now_utc.format(UTC_TIME_FORMAT).to_string();
+	let counts = tx.count_by_category(&PullQuery::new())?;
+	for (category, count) in counts.entries() {
+		tx.execute(
+			"INSERT INTO count_snapshots (taken_at, category, count) VALUES (?1, ?2, ?3)
+			ON CONFLICT DO UPDATE SET count = ?3",
+			params![taken_at, category, count],
+		)?;
+	}
+	let full_start = (now_utc - Duration::days(full_days))
+		.format(UTC_TIME_FORMAT)
+		.to_string();
+	tx.execute(
+		"DELETE FROM count_snapshots WHERE taken_at < ?1
+		AND (filter, category, taken_at) NOT IN (
+			SELECT filter, category, MAX(taken_at) FROM count_snapshots WHERE taken_at < ?1
+			GROUP BY filter, category, substr(taken_at, 1, 10)
+		)",
+		params![full_start],
+	)?;
+	Ok(())
+}
+
+/// Warn holders of reservations about to expire, and end the expired reservations, giving their PRs
+/// back the category they had before. Returns the number of ended reservations and the notifications to send.
+pub fn end_expired_reservations(
+	tx: &Transaction,
+	now_utc: DateTime<Utc>,
+) -> Result<(usize, Vec<Notification>), Box<dyn Error>> {
+	let now = now_utc.format(UTC_TIME_FORMAT).to_string();
+	let update_time = now_utc.format(UTC_TIME_FORMAT).to_string();
+	let mut notifications = vec![];
+
+	// warn holders shortly before their reservation expires
+	let warn_before = (now_utc + Duration::minutes(notify::WARNING_MINUTES))
+		.format(UTC_TIME_FORMAT)
+		.to_string();
+	let mut query = tx.prepare(
+		"UPDATE reservations SET notified = 1
+		WHERE notify IS NOT NULL AND notified = 0 AND expires_at >= ?1 AND expires_at < ?2
+		RETURNING repo, id, notify, expires_at,
+			(SELECT title FROM pulls
+			WHERE pulls.repo = reservations.repo AND pulls.id = reservations.id)",
+	)?;
+	let expiring: Vec<_> = query
+		.query_map(
+			params![now, warn_before],
+			extract_row!(String u64 String String Option<String>),
+		)?
+		.collect::<Result<_, _>>()?;
+	drop(query);
+	for (repo, id, url, expires_at, title) in expiring {
+		notifications.push(Notification::new(url, "expiring", repo, id, title, &expires_at));
+	}
+
+	let mut query = tx.prepare(
+		"SELECT repo, id, notify, expires_at,
+			(SELECT title FROM pulls
+			WHERE pulls.repo = reservations.repo AND pulls.id = reservations.id)
+		FROM reservations WHERE expires_at < ?1",
+	)?;
+	let expired: Vec<_> = query
+		.query_map(
+			params![now],
+			extract_row!(String i64 Option<String> String Option<String>),
+		)?
+		.collect::<Result<_, _>>()?;
+	drop(query);
+
+	let count = expired.len();
+	for (repo, id, url, expires_at, title) in expired {
+		tracing::debug!("housekeep: remove reservation of {repo}#{id}");
+		if let Some(url) = url {
+			notifications.push(Notification::new(
+				url,
+				"expired",
+				repo.clone(),
+				id as u64,
+				title,
+				&expires_at,
+			));
+		}
+		tx.execute(
+			"INSERT INTO expired_reservations
+			(repo, pull_id, reserved_by, time)
+			SELECT repo, id, reserved_by, ?3 FROM reservations WHERE repo = ?1 AND id = ?2",
+			params![repo, id, update_time],
+		)?;
+		tx.execute(
+			"DELETE FROM reservations WHERE repo = ?1 AND id = ?2",
+			params![repo, id],
+		)?;
+		tx.execute(RELEASE_PULLS, params![repo, id])?;
+		restore_category(tx, &repo, id, AWAITING_REVIEWER, &update_time, "expired")?;
+		tx.execute(END_RESERVATION_LOG, params![repo, id, update_time, "expired"])?;
+	}
+	Ok((count, notifications))
+}
 
-		let now = Local::now().naive_local();
-		for (id, time) in reservations {
-			let time = NaiveDateTime::parse_from_str(&time, TIME_FORMAT)?;
-			if (now - time).num_hours() >= 1 {
-				pulls_to_unreserve.push(id);
+/// End expired reservations on their own, without the rest of housekeeping.
+/// The caller sends the returned notifications.
+pub async fn run_expiry(state: &AppState) -> Result<(usize, Vec<Notification>), AppError> {
+	let (count, notifications) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let res = end_expired_reservations(&tx, Utc::now())?;
+		tx.commit()?;
+		Ok(res)
+	})?;
+	if count > 0 {
+		tracing::info!("expired {count} reservations");
+	}
+	Ok((count, notifications))
+}
+
+/// Recategorize the PRs inserted or updated by an update. The caller holds the `update_lock`.
+pub async fn categorize_updated(state: &AppState, pulls: &[(String, i64)]) -> Result<HousekeepSummary, AppError> {
+	let started = Instant::now();
+	let update_time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	let transitions = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let transitions = categorize_pulls(&tx, state, Some(pulls), &update_time)?;
+		tx.commit()?;
+		Ok(transitions)
+	})?;
+	let mut summary = HousekeepSummary::default();
+	summary.add_transitions(transitions);
+	summary.duration_ms = started.elapsed().as_millis() as u64;
+	Ok(summary)
+}
+
+/// End expired reservations and recategorize the PRs in `scope`. The caller holds the `update_lock`
+/// and sends the returned notifications.
+/// A `dry_run` makes the same decisions, but rolls them back and returns no notifications.
+pub async fn run_housekeep(
+	state: &AppState,
+	dry_run: bool,
+	scope: &HousekeepScope,
+) -> Result<HousekeepSummary, AppError> {
+	let started = Instant::now();
+	let now_utc = Utc::now();
+	let update_time = now_utc.format(UTC_TIME_FORMAT).to_string();
+
+	let mut summary = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut summary = HousekeepSummary {
+			scope: (!scope.is_full()).then(|| scope.clone()),
+			..Default::default()
+		};
+
+		// end expired reservations first, so the labels take precedence over the restored categories.
+		// A scoped pass only does so if asked to
+		let mut notifications = vec![];
+		if scope.reservations {
+			(summary.reservations_expired, notifications) = end_expired_reservations(&tx, now_utc)?;
+		}
+
+		// a single unreadable PR must not abort the pass, it is skipped until deleted or fetched again
+		match tx.check_consistency() {
+			Ok(report) => {
+				tracing::info!("housekeep: consistency: {}", report.summary());
+				summary.broken_rows = report.broken_rows;
+			},
+			Err(err) => tracing::warn!("error during pr housekeep: {:?}", err),
+		}
+		for row in &summary.broken_rows {
+			tracing::error!("housekeep: skipping {}#{}: {}", row.repo, row.id, row.error);
+		}
+		summary.parse_errors = summary.broken_rows.len();
+		let selected = scope.select(&tx, &summary.broken_rows)?;
+		// the history rows must not be committed without the category changes they record
+		let transitions = categorize_pulls(&tx, state, selected.as_deref(), &update_time)?;
+		summary.add_transitions(transitions);
+		match tx.query_row(
+			"SELECT COUNT(*) FROM pulls WHERE state = 'open' AND category_locked",
+			[],
+			|row| row.get(0),
+		) {
+			Ok(count) => summary.locked = count,
+			Err(err) => tracing::warn!("error during pr housekeep: {:?}", err),
+		}
+		if summary.demoted > 0 {
+			tracing::info!(
+				"housekeep: demoted {} PRs whose category no longer applies",
+				summary.demoted
+			);
+		}
+
+		// keep the change history for a month
+		let history_start = (now_utc - Duration::days(30)).format(UTC_TIME_FORMAT).to_string();
+		for table in ["departures", "expired_reservations"] {
+			let res = tx.execute(&format!("DELETE FROM {table} WHERE time < ?1"), params![history_start]);
+			if let Err(err) = res {
+				tracing::warn!("error during pr housekeep: {:?}", err);
 			}
 		}
-		drop(query);
 
-		tracing::debug!("housekeep: remove reservations for {pulls_to_unreserve:?}");
+		// category changes are kept longer, for PR_DASHBOARD_HISTORY_DAYS
+		let category_history_start = (now_utc - Duration::days(state.history_days))
+			.format(UTC_TIME_FORMAT)
+			.to_string();
+		let res = tx.execute(
+			"DELETE FROM category_history WHERE changed_at < ?1",
+			params![category_history_start],
+		);
+		if let Err(err) = res {
+			tracing::warn!("error during pr housekeep: {:?}", err);
+		}
+
+		// tombstones of closed PRs are kept for a while, in case they are reopened
+		let tombstone_start = (now_utc - Duration::days(TOMBSTONE_DAYS))
+			.format(UTC_TIME_FORMAT)
+			.to_string();
+		match tx.execute(
+			"DELETE FROM pulls WHERE state = 'closed' AND closed_at < ?1",
+			params![tombstone_start],
+		) {
+			Ok(count) if count > 0 => tracing::info!("housekeep: purged {count} closed PRs"),
+			Ok(_) => {},
+			Err(err) => tracing::warn!("error during pr housekeep: {:?}", err),
+		}
 
-		let ids = Rc::new(
-			pulls_to_unreserve
-				.iter()
-				.copied()
-				.map(|x| x as i64)
-				.map(rusqlite::types::Value::from)
-				.collect::<Vec<_>>(),
+		// safety net for reservations of PRs that are no longer tracked
+		let res = tx.execute(
+			"UPDATE reservation_log SET released_at = ?1, outcome = 'closed'
+			WHERE released_at IS NULL AND (repo, pull_id) NOT IN (SELECT repo, id FROM pulls WHERE state = 'open')",
+			params![update_time],
 		);
-		let mut query = tx.prepare("DELETE FROM reservations WHERE id IN rarray(?1)")?;
-		query.execute(params![ids])?;
-		drop(query);
-		let mut query = tx.prepare("UPDATE pulls SET reserved_by = NULL WHERE id IN rarray(?1)")?;
-		query.execute(params![ids])?;
-		drop(query);
+		if let Err(err) = res {
+			tracing::warn!("error during pr housekeep: {:?}", err);
+		}
+		match tx.execute(
+			"DELETE FROM reservations WHERE (repo, id) NOT IN (SELECT repo, id FROM pulls WHERE state = 'open')",
+			[],
+		) {
+			Ok(count) if count > 0 => tracing::info!("housekeep: removed {count} orphaned reservations"),
+			Ok(_) => {},
+			Err(err) => tracing::warn!("error during pr housekeep: {:?}", err),
+		}
+
+		// forget that purged PRs were hidden
+		let res = tx.execute(
+			"DELETE FROM hidden WHERE (repo, pull_id) NOT IN (SELECT repo, id FROM pulls)",
+			[],
+		);
+		if let Err(err) = res {
+			tracing::warn!("error during pr housekeep: {:?}", err);
+		}
+
+		// the backlog over time, for /stats
+		if let Err(err) = snapshot_counts(&tx, now_utc, state.snapshot_days) {
+			tracing::warn!("error during pr housekeep: {:?}", err);
+		}
 
-		if let Err(err) = tx.commit() {
-			tracing::warn!("error during pr housekeep: {err:?}");
+		if dry_run {
+			tracing::info!("housekeep: dry run, rolling back");
+			tx.rollback()?;
+			summary.dry_run = true;
+			return Ok(summary);
 		}
-		Ok(())
+		// nothing changed and the notifications are not marked as sent, the caller answers with an error
+		tx.commit()?;
+		summary.notifications = notifications;
+		Ok(summary)
 	})?;
+	summary.duration_ms = started.elapsed().as_millis() as u64;
+	Ok(summary)
+}
 
-	drop(update_lock);
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::{category::Reviews, tests::test_state, NEEDS_MERGER, NEEDS_REVIEWER};
+
+	/// Store an open PR with these labels, updated now.
+	fn insert_pull(tx: &Transaction, repo: &str, id: i64, labels: &[&str], category: Option<&str>) {
+		let labels: Vec<_> = labels
+			.iter()
+			.map(|x| serde_json::json!({ "name": x, "color": "ffffff" }))
+			.collect();
+		let now = Utc::now().format(UTC_TIME_FORMAT).to_string();
+		let data =
+			serde_json::json!({ "number": id, "title": format!("pkg{id}"), "labels": labels, "updated_at": now });
+		tx.execute(
+			"INSERT INTO pulls (repo, id, author, last_updated, data, category, first_seen) VALUES (?1, ?2, 'a', ?3, ?4, ?5, ?3)",
+			params![repo, id, now, data.to_string(), category],
+		)
+		.unwrap();
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn scope_ids_name_the_repository() {
+		let repos = ["NixOS/nixpkgs".to_owned(), "o/r".to_owned()];
+		let params = |ids: &str, repo: Option<&str>| {
+			let mut params = HashMap::from([("ids".to_owned(), ids.to_owned())]);
+			if let Some(repo) = repo {
+				params.insert("repo".to_owned(), repo.to_owned());
+			}
+			HousekeepScope::from_params(&params, &repos)
+		};
+		assert_eq!(
+			params("123", None).unwrap().ids.unwrap(),
+			[("NixOS/nixpkgs".to_owned(), 123)]
+		);
+		assert_eq!(
+			params("123", Some("o/r")).unwrap().ids.unwrap(),
+			[("o/r".to_owned(), 123)]
+		);
+		assert_eq!(
+			params("o/r#5, NixOS/nixpkgs#6", None).unwrap().ids.unwrap(),
+			[("o/r".to_owned(), 5), ("NixOS/nixpkgs".to_owned(), 6)]
+		);
+		assert!(params("other/repo#5", None).is_err());
+		assert!(params("5", Some("other/repo")).is_err());
+		assert!(params("o/r#x", None).is_err());
+
+		let mut state = test_state();
+		state.repos = Arc::new(repos.to_vec());
+		let scope = params("123", Some("o/r")).unwrap();
+		let selected = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			for repo in &repos {
+				insert_pull(&tx, repo, 123, &[], Some(NEEDS_REVIEWER));
+			}
+			Ok(scope.select(&tx, &[])?)
+		})
+		.unwrap();
+		assert_eq!(selected.unwrap(), [("o/r".to_owned(), 123)]);
+	}
+
+	/// Once the conflict is resolved, the category is computed from scratch.
+	#[tokio::test(flavor = "multi_thread")]
+	async fn conflict_label_disappears() {
+		const EVALUATED: &str = "10.rebuild-linux: 1-10";
+		const CONFLICT: &str = "2.status: merge conflict";
+		let state = test_state();
+		let labels = [
+			vec![EVALUATED, CONFLICT],
+			vec![EVALUATED, "12.approvals: 3+", CONFLICT],
+			vec![EVALUATED, CONFLICT],
+		];
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			for (id, labels) in (1..).zip(&labels) {
+				insert_pull(&tx, "o/r", id, labels, None);
+			}
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
+		let summary = run_housekeep(&state, false, &HousekeepScope::full()).await.unwrap();
+		assert_eq!(
+			summary.transitions,
+			BTreeMap::from([("null→AwaitingAuthor".to_owned(), 3)])
+		);
+
+		// the conflict is gone, PR 3 also lost its evaluation
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			for (id, labels) in [
+				(1, vec![EVALUATED]),
+				(2, vec![EVALUATED, "12.approvals: 3+"]),
+				(3, vec![]),
+			] {
+				let labels: Vec<_> = labels
+					.iter()
+					.map(|x| serde_json::json!({ "name": x, "color": "ffffff" }))
+					.collect();
+				tx.execute(
+					"UPDATE pulls SET data = json_set(data, '$.labels', json(?2)) WHERE id = ?1",
+					params![id, serde_json::to_string(&labels)?],
+				)?;
+			}
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
+		let summary = run_housekeep(&state, false, &HousekeepScope::full()).await.unwrap();
+		assert_eq!(
+			summary.transitions,
+			BTreeMap::from([
+				("AwaitingAuthor→NeedsMerger".to_owned(), 1),
+				("AwaitingAuthor→NeedsReviewer".to_owned(), 1),
+				("AwaitingAuthor→null".to_owned(), 1),
+			])
+		);
+		assert_eq!(summary.demoted, 1);
+		assert_eq!(summary.examples["AwaitingAuthor→null"], ["o/r#3"]);
+	}
+
+	/// Unreadable rows are reported and skipped, the other PRs are still categorized.
+	#[tokio::test(flavor = "multi_thread")]
+	async fn survives_garbage_data() {
+		let state = test_state();
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			for id in 1..=3 {
+				insert_pull(&tx, "o/r", id, &["10.rebuild-linux: 1-10"], None);
+			}
+			tx.execute("UPDATE pulls SET data = '{\"number\": [' WHERE id = 2", [])?;
+			tx.execute("UPDATE pulls SET data_compressed = X'00FF00FF' WHERE id = 3", [])?;
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
 
-	Ok("done")
+		let summary = run_housekeep(&state, false, &HousekeepScope::full()).await.unwrap();
+		assert_eq!(summary.parse_errors, 2);
+		let broken: Vec<_> = summary.broken_rows.iter().map(|x| x.id).collect();
+		assert_eq!(broken, [2, 3]);
+		assert!(
+			summary.broken_rows[1].error.starts_with("decompression failed"),
+			"{:?}",
+			summary.broken_rows
+		);
+
+		let categories = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			let mut stmt = tx.prepare("SELECT category FROM pulls ORDER BY id")?;
+			let categories = stmt
+				.query_map([], |row| row.get::<_, Option<String>>(0))?
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(categories)
+		})
+		.unwrap();
+		assert_eq!(categories, [Some(NEEDS_REVIEWER.to_owned()), None, None]);
+	}
+
+	/// A failed categorization commits nothing, in particular no history of the changes it didn't make.
+	#[tokio::test(flavor = "multi_thread")]
+	async fn failed_categorization_commits_nothing() {
+		let state = test_state();
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			insert_pull(&tx, "o/r", 1, &["10.rebuild-linux: 1-10"], None);
+			// fails after the history rows are written
+			tx.execute(
+				"CREATE TEMP TRIGGER fail BEFORE UPDATE OF category ON main.pulls
+				BEGIN SELECT RAISE(ABORT, 'category update failed'); END",
+				[],
+			)?;
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
+
+		assert!(run_housekeep(&state, false, &HousekeepScope::full()).await.is_err());
+		let (category, history): (Option<String>, i64) = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			Ok(tx.query_row(
+				"SELECT category, (SELECT COUNT(*) FROM category_history) FROM pulls",
+				[],
+				|row| Ok((row.get(0)?, row.get(1)?)),
+			)?)
+		})
+		.unwrap();
+		assert_eq!((category, history), (None, 0));
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn sql_categories_match_the_rules() {
+		const LABELS: [&str; 6] = [
+			"10.rebuild-linux: 1-10",
+			"2.status: merge conflict",
+			"awaiting_changes",
+			"12.approvals: 3+",
+			"needs_merger",
+			"6.topic: python",
+		];
+		/// States and commits of the reviews, `None` if they were never fetched.
+		const REVIEWS: [Option<&[(&str, &str)]>; 6] = [
+			None,
+			Some(&[]),
+			Some(&[("CHANGES_REQUESTED", "head")]),
+			Some(&[("APPROVED", "head"), ("APPROVED", "head"), ("APPROVED", "head")]),
+			// approvals of an earlier push don't count
+			Some(&[("APPROVED", "head"), ("APPROVED", "head"), ("APPROVED", "old")]),
+			Some(&[("APPROVED", "head"), ("CHANGES_REQUESTED", "old")]),
+		];
+		let state = test_state();
+		let now = Utc::now().format(UTC_TIME_FORMAT).to_string();
+		let mut fixture = vec![];
+		for subset in 0..1 << LABELS.len() {
+			let labels: Vec<_> = (0..LABELS.len())
+				.filter(|i| subset & (1 << i) != 0)
+				.map(|i| LABELS[i])
+				.collect();
+			for draft in [false, true] {
+				for reviews in REVIEWS {
+					// earlier categories set by the rules are replaced or cleared
+					let category = [None, Some(AWAITING_AUTHOR), Some(NEEDS_MERGER)][fixture.len() % 3];
+					fixture.push((labels.clone(), draft, reviews, category));
+				}
+			}
+		}
+
+		let categories: Vec<Option<String>> = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			for (id, (labels, draft, reviews, category)) in fixture.iter().enumerate() {
+				let labels: Vec<_> = labels
+					.iter()
+					.map(|x| serde_json::json!({ "name": x, "color": "ffffff" }))
+					.collect();
+				let data = serde_json::json!({
+					"number": id,
+					"title": format!("pkg{id}"),
+					"labels": labels,
+					"draft": draft,
+					"head": { "sha": "head" },
+					"created_at": now,
+					"updated_at": now,
+				});
+				tx.execute(
+					"INSERT INTO pulls (repo, id, author, last_updated, data, category, first_seen, reviews_synced)
+					VALUES ('NixOS/nixpkgs', ?1, 'a', ?2, ?3, ?4, ?2, ?5)",
+					params![id, now, data.to_string(), category, reviews.map(|_| &now)],
+				)?;
+				for (i, (state, commit)) in reviews.unwrap_or_default().iter().enumerate() {
+					tx.execute(
+						"INSERT INTO pull_reviews (repo, pull_id, reviewer, state, commit_id)
+						VALUES ('NixOS/nixpkgs', ?1, ?2, ?3, ?4)",
+						params![id, format!("reviewer{i}"), state, commit],
+					)?;
+				}
+			}
+			categorize_pulls(&tx, &state, None, &now)?;
+			let mut query = tx.prepare("SELECT category FROM pulls ORDER BY id")?;
+			let categories = query.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+			Ok(categories)
+		})
+		.unwrap();
+
+		assert_eq!(categories.len(), fixture.len());
+		for (id, ((labels, draft, reviews, _), category)) in fixture.iter().zip(categories).enumerate() {
+			let reviews = reviews.map(|reviews| Reviews {
+				approvals: reviews.iter().filter(|x| **x == ("APPROVED", "head")).count(),
+				changes_requested: reviews.iter().any(|x| x.0 == "CHANGES_REQUESTED"),
+			});
+			let expected = category::categorize(&state.category_rules.rules, labels, *draft, reviews.as_ref());
+			assert_eq!(
+				category.as_deref(),
+				expected,
+				"PR {id}: labels {labels:?}, draft {draft}, reviews {reviews:?}",
+				reviews = reviews.map(|x| (x.approvals, x.changes_requested))
+			);
+		}
+	}
 }
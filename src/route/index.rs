@@ -1,187 +1,311 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::atomic::Ordering};
 
-use axum::{extract::Query, http::StatusCode, response::Html};
-use octocrab::models::pulls::PullRequest;
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::Html,
+};
+use axum_client_ip::ClientIp;
+use itertools::Itertools;
 
 use crate::{
-	construct_sql_filter,
-	database::{CommonQueries, DB},
-	with_db, AppError, AWAITING_AUTHOR, AWAITING_REVIEWER, NEEDS_MERGER, NEEDS_REVIEWER, TIME_FORMAT,
+	database::{CommonQueries, PullQuery, DB},
+	render_card, viewer_identity, with_db, AppError, AppState, AWAITING_AUTHOR, NEEDS_MERGER, NEEDS_REVIEWER, STALE,
 };
 
 static INDEX: &'static str = include_str!("../../index.html");
 
-pub async fn root(Query(params): Query<HashMap<String, String>>) -> Result<(StatusCode, Html<String>), AppError> {
+/// The `<style>` element of the dashboard, for other pages showing cards.
+pub fn index_style() -> &'static str {
+	let start = INDEX.find("<style>").unwrap_or(0);
+	let end = INDEX.find("</style>").map(|x| x + "</style>".len()).unwrap_or(0);
+	&INDEX[start..end]
+}
+
+pub async fn root(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+) -> Result<(StatusCode, Html<String>), AppError> {
+	let viewer = viewer_identity(&state, ip).await?;
+	let tz = state.timezone(&params)?;
+	let tz_param = params.get("tz").filter(|x| !x.is_empty());
 	let filter = params.get("filter").map(|x| &**x);
 	let exclude_filter = params.get("exclude").map(|x| &**x).unwrap_or_default();
 	let limit = params
 		.get("limit")
 		.map(|x| x.parse().expect("bad limit parameter"))
 		.unwrap_or(50);
-	let filter_query = filter.unwrap_or_default();
-	let sql_filter = if let Some(filter_query) = filter {
-		construct_sql_filter(filter_query, exclude_filter)
-	} else {
-		"".to_owned()
-	};
+	let milestone = params.get("milestone").map(|x| &**x).filter(|x| *x != "");
+	let effort_filter = params.get("effort").map(|x| &**x).filter(|x| *x != "");
+	let first_timers = params.get("first-timers").is_some_and(|x| x == "true");
+	let waiting_filter = params.get("waiting-longer-than").map(|x| &**x).filter(|x| *x != "");
+	let repo_filter = params.get("repo").map(|x| &**x).filter(|x| *x != "");
+	let sort_updated = params.get("sort").map(|x| x == "updated").unwrap_or(false);
 	let mut filter = filter
 		.map(|x| x.split(';').filter(|x| *x != "").collect::<Vec<_>>())
 		.unwrap_or_default();
 	filter.sort();
 	filter.dedup();
 
-	let (counts, pulls) = with_db!(|db: &mut DB| {
+	let verdict = state.freshness().await?;
+	let base_query = PullQuery::from_params(&params).map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err))?;
+	let filter_active = base_query.is_filtered();
+
+	let (counts, unfiltered_counts, pulls) = with_db!(state, |db: &mut DB| {
 		let tx = db.transaction()?;
 
-		let mut query = tx.prepare(&format!(
-			"SELECT category, COUNT(*) FROM pulls WHERE 1=1 {sql_filter} GROUP BY category"
-		))?;
-		let counts: Vec<_> = query
-			.query_map([], |row| {
-				Ok((row.get::<_, Option<String>>(0)?, row.get::<_, usize>(1)?))
-			})?
-			.map(Result::unwrap)
-			.collect();
+		let counts = tx.count_by_category(&base_query)?;
+		let unfiltered_counts = if filter_active {
+			let mut unfiltered = PullQuery::new();
+			if let Some(repo) = repo_filter {
+				unfiltered = unfiltered.repo(repo);
+			}
+			Some(tx.count_by_category(&unfiltered)?)
+		} else {
+			None
+		};
 
 		let mut rows2 = vec![];
-		rows2.extend_from_slice(&tx.get_pulls(None, &filter_query, exclude_filter, true, true, limit)?);
-		for cat in [AWAITING_AUTHOR, NEEDS_REVIEWER, NEEDS_MERGER] {
-			rows2.extend_from_slice(&tx.get_pulls(Some(cat), &filter_query, exclude_filter, true, true, limit)?);
+		for cat in [
+			None,
+			Some(AWAITING_AUTHOR),
+			Some(NEEDS_REVIEWER),
+			Some(NEEDS_MERGER),
+			Some(STALE),
+		] {
+			let query = base_query
+				.clone()
+				.category(cat)
+				.only_unreserved()
+				.not_hidden_for(&viewer)
+				.tweak_sort(!sort_updated)
+				.limit(limit);
+			rows2.extend_from_slice(&tx.get_pulls(&query)?);
 		}
-		Ok((counts, rows2))
+		Ok((counts, unfiltered_counts, rows2))
 	})?;
-	let total: usize = counts.iter().map(|x| x.1).sum();
-	if total == 0 {
+	let total = counts.total();
+	let initial_sync = state.initial_sync.load(Ordering::Relaxed);
+	if total == 0 && !initial_sync {
 		return Ok((StatusCode::NOT_FOUND, Html(include_str!("../../404.html").to_owned())));
 	}
 
 	let mut prs_author = String::new();
-	let mut prs_new = String::new();
+	let mut prs_new = vec![];
 	let mut prs_need_review = String::new();
 	let mut prs_need_merger = String::new();
+	let mut prs_stale = String::new();
 
-	for mut pr in pulls {
-		let category = pr.category.clone();
-		let data: &mut PullRequest = &mut *pr;
-		let last_updated = data.updated_at.unwrap().format(TIME_FORMAT).to_string();
-		let title = data.title.as_deref().unwrap();
-		let title = askama_escape::escape(title, askama_escape::Html).to_string();
-		let date = &last_updated[0..10];
-		let id = data.number;
-
-		data.labels.as_mut().map(|x| {
-			x.sort_by_key(|x| {
-				if let Some((pre, _)) = x.name.split_once('.') {
-					if let Ok(number) = pre.parse::<usize>() {
-						number
-					} else {
-						20 + x.name.len()
-					}
-				} else {
-					20 + x.name.len()
-				}
-			})
-		});
-
-		let mut labels = String::new();
-		for label in data.labels.as_deref().unwrap_or_default() {
-			// white for dark labels
-			let rgb_sum = usize::from_str_radix(&label.color[0..2], 16)?
-				+ usize::from_str_radix(&label.color[2..4], 16)?
-				+ usize::from_str_radix(&label.color[4..6], 16)?;
-			let text_color = if rgb_sum > 128 * 3 { "000000" } else { "ffffff" };
-			let name = label.name.replace('+', "");
-			let mut href_filter = {
-				if filter.contains(&&*name) {
-					"javascript:void()".to_owned()
-				} else {
-					let mut filter = filter.clone();
-					filter.push(&name);
-					filter.sort();
-					filter.dedup();
-					format!("?filter={}", filter.join(";"))
-				}
-			};
-			if total == 1 {
-				href_filter = "javascript:void()".to_owned();
+	let label_href = |name: &str| -> Result<String, AppError> {
+		let name = name.replace('+', "");
+		let mut href_filter = {
+			if filter.contains(&&*name) {
+				"javascript:void()".to_owned()
+			} else {
+				let mut filter = filter.clone();
+				filter.push(&name);
+				filter.sort();
+				filter.dedup();
+				format!("?filter={}", filter.join(";"))
+			}
+		};
+		if total == 1 {
+			href_filter = "javascript:void()".to_owned();
+		}
+		if href_filter.starts_with('?') {
+			if limit != 50 {
+				href_filter = format!("?limit={limit}&{}", &href_filter[1..]);
 			}
-			if href_filter.starts_with('?') {
-				if limit != 50 {
-					href_filter = format!("?limit={limit}&{}", &href_filter[1..]);
-				}
-				if exclude_filter != "" {
-					href_filter += &format!("&exclude={}", exclude_filter);
-				}
+			if sort_updated {
+				href_filter += "&sort=updated";
+			}
+			if exclude_filter != "" {
+				href_filter += &format!("&exclude={}", exclude_filter);
+			}
+			if let Some(milestone) = milestone {
+				href_filter += &format!("&{}", serde_urlencoded::to_string([("milestone", milestone)])?);
+			}
+			if let Some(effort) = effort_filter {
+				href_filter += &format!("&{}", serde_urlencoded::to_string([("effort", effort)])?);
+			}
+			if first_timers {
+				href_filter += "&first-timers=true";
+			}
+			if let Some(waiting) = waiting_filter {
+				href_filter += &format!("&{}", serde_urlencoded::to_string([("waiting-longer-than", waiting)])?);
+			}
+			if let Some(repo) = repo_filter {
+				href_filter += &format!("&{}", serde_urlencoded::to_string([("repo", repo)])?);
+			}
+			if let Some(tz) = tz_param {
+				href_filter += &format!("&{}", serde_urlencoded::to_string([("tz", tz)])?);
 			}
-			labels += &format!(
-				r#"<a href="{href_filter}" class="pr-label" style="background-color: #{}; color: #{}">{}</a> "#,
-				label.color,
-				text_color,
-				askama_escape::escape(&label.name, askama_escape::Html)
-			);
 		}
-
-		let formatting = format!(
-			r#"<div class="pr">
-			<span class="pr-header">nixpkgs <a href="https://github.com/NixOS/nixpkgs/pull/{id}">#{id}</a></span>
-			<span class="pr-date">{date}</span>
-			<br>
-			<span class="pr-title">{title}</span>
-			<br>
-			{labels}
-			<button class="pr-hide">hide</button>
-			</div>"#
-		);
+		Ok(href_filter)
+	};
+	for mut pr in pulls {
+		let category = pr.category.clone();
+		let card = render_card(
+			&state,
+			&mut pr,
+			&tz,
+			&label_href,
+			r#"<button class="pr-hide">hide</button>"#,
+		)?;
+		let (date, formatting) = (card.date, card.html);
 		if category.is_none() {
-			prs_new += &formatting;
+			prs_new.push((date, formatting));
 		} else if category.as_deref() == Some(NEEDS_REVIEWER) {
 			prs_need_review += &formatting;
 		} else if category.as_deref() == Some(NEEDS_MERGER) {
 			prs_need_merger += &formatting;
 		} else if category.as_deref() == Some(AWAITING_AUTHOR) {
 			prs_author += &formatting;
+		} else if category.as_deref() == Some(STALE) {
+			prs_stale += &formatting;
 		}
 	}
 
-	let count_awaiting_author = counts
-		.iter()
-		.filter(|x| x.0.as_deref() == Some(AWAITING_AUTHOR))
-		.next()
-		.map(|x| x.1)
-		.unwrap_or(0);
-	let count_null = counts.iter().filter(|x| x.0 == None).next().map(|x| x.1).unwrap_or(0);
-	let count_needs_reviewer = counts
-		.iter()
-		.filter(|x| x.0.as_deref() == Some(NEEDS_REVIEWER))
-		.next()
-		.map(|x| x.1)
-		.unwrap_or(0);
-	let _count_awaiting_reviewer = counts
+	// group by day, unless sorted by something else
+	let group_new = !PullQuery::new()
+		.category(None)
+		.tweak_sort(!sort_updated)
+		.sorts_by_approvals();
+	let mut prs_new_html = String::new();
+	for (date, cards) in &prs_new.into_iter().chunk_by(|x| x.0.clone()) {
+		let cards: Vec<_> = cards.collect();
+		if group_new {
+			prs_new_html += &format!(r#"<h3 class="pr-day">{date} ({})</h3>"#, cards.len());
+		}
+		for (_, card) in cards {
+			prs_new_html += &card;
+		}
+	}
+
+	let mut unfiltered_link = vec![];
+	if limit != 50 {
+		unfiltered_link.push(("limit", limit.to_string()));
+	}
+	if sort_updated {
+		unfiltered_link.push(("sort", "updated".to_owned()));
+	}
+	if let Some(tz) = tz_param {
+		unfiltered_link.push(("tz", tz.clone()));
+	}
+	if let Some(repo) = repo_filter {
+		unfiltered_link.push(("repo", repo.to_owned()));
+	}
+	let unfiltered_link = format!("?{}", serde_urlencoded::to_string(unfiltered_link)?);
+	let filtered_out = |category: Option<&str>, anchor: &str| {
+		let Some(unfiltered_counts) = unfiltered_counts.as_ref() else {
+			return String::new();
+		};
+		let hidden = unfiltered_counts.get(category).saturating_sub(counts.get(category));
+		if hidden == 0 {
+			return String::new();
+		}
+		format!(r#" <a class="filtered-out" href="{unfiltered_link}#{anchor}">(+{hidden} filtered out)</a>"#)
+	};
+
+	let stale_banner = if initial_sync {
+		let loaded = with_db!(state, |db: &mut DB| db.count_pulls())?;
+		format!(r#"<div class="stale center">Initial sync in progress, {loaded} PRs loaded so far.</div>"#)
+	} else if verdict.caught_up {
+		String::new()
+	} else {
+		let action = if state.freshness.auto_refresh {
+			"The next reservation will update the data first."
+		} else {
+			"Reserving is disabled until the next update."
+		};
+		let age = match verdict.age {
+			Some(_) => format!("last successful update {} ago", verdict.describe_age()),
+			None => "no successful update yet".to_owned(),
+		};
+		format!(r#"<div class="stale center">Data is stale: {age}. {action}</div>"#)
+	};
+
+	let needs_eval_notice = if counts.needs_eval > 0 {
+		format!(
+			r#"<div class="stale center">{} PRs are still waiting for evaluation after {} hours.</div>"#,
+			counts.needs_eval, state.category_rules.needs_eval.hours
+		)
+	} else {
+		String::new()
+	};
+
+	let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+	let shown_repos = match repo_filter {
+		Some(repo) => vec![repo],
+		None => state.repos.iter().map(|x| &**x).collect(),
+	};
+	let repo_links = shown_repos
 		.iter()
-		.filter(|x| x.0.as_deref() == Some(AWAITING_REVIEWER))
-		.next()
-		.map(|x| x.1)
-		.unwrap_or(0);
-	let count_needs_merger = counts
+		.map(|x| format!(r#"<a href="https://github.com/{0}">{0}</a>"#, escape(x)))
+		.join(", ");
+	let repo_options = state
+		.repos
 		.iter()
-		.filter(|x| x.0.as_deref() == Some(NEEDS_MERGER))
-		.next()
-		.map(|x| x.1)
-		.unwrap_or(0);
-
+		.map(|x| format!("<option>{}</option>", escape(x)))
+		.join("");
 	let index = INDEX
-		.replace("$C1", &count_awaiting_author.to_string())
-		.replace("$C2", &count_null.to_string())
-		.replace("$C3", &count_needs_reviewer.to_string())
-		.replace("$C4", &count_needs_merger.to_string())
-		.replace("$RESERVE_FILTER", &format!("&filter={}", filter.join(";")))
+		.replace("$TITLE", &escape(&shown_repos.join(", ")))
+		.replace("$REPO_LINKS", &repo_links)
+		.replace("$REPO_OPTIONS", &repo_options)
+		.replace("$REPO_FILTER", &escape(repo_filter.unwrap_or_default()))
+		.replace("$STALE_BANNER", &stale_banner)
+		.replace("$NEEDS_EVAL_NOTICE", &needs_eval_notice)
+		.replace("$F1", &filtered_out(Some(AWAITING_AUTHOR), "awaiting-author"))
+		.replace("$F2", &filtered_out(None, "new"))
+		.replace("$F3", &filtered_out(Some(NEEDS_REVIEWER), "needs-reviewer"))
+		.replace("$F4", &filtered_out(Some(NEEDS_MERGER), "needs-merger"))
+		.replace("$F5", &filtered_out(Some(STALE), "stale"))
+		.replace("$C1", &counts.awaiting_author.to_string())
+		.replace("$C2", &counts.new.to_string())
+		.replace("$C3", &counts.needs_reviewer.to_string())
+		.replace("$C4", &counts.needs_merger.to_string())
+		.replace("$C5", &counts.stale.to_string())
+		.replace(
+			"$RESERVE_FILTER",
+			&format!(
+				"&filter={}&{}",
+				filter.join(";"),
+				serde_urlencoded::to_string([
+					("milestone", milestone.unwrap_or_default()),
+					("effort", effort_filter.unwrap_or_default()),
+					("waiting-longer-than", waiting_filter.unwrap_or_default()),
+					("first-timers", if first_timers { "true" } else { "" }),
+					("repo", repo_filter.unwrap_or_default()),
+				])?
+			),
+		)
 		.replace("$FILTER", &filter.join(";"))
 		.replace("$EXCLUDE_FILTER", &exclude_filter)
+		.replace(
+			"$TZ",
+			&askama_escape::escape(tz.name(), askama_escape::Html).to_string(),
+		)
+		.replace(
+			"$EFFORT",
+			&askama_escape::escape(effort_filter.unwrap_or_default(), askama_escape::Html).to_string(),
+		)
+		.replace(
+			"$WAITING",
+			&askama_escape::escape(waiting_filter.unwrap_or_default(), askama_escape::Html).to_string(),
+		)
+		.replace(
+			"$MILESTONE",
+			&askama_escape::escape(milestone.unwrap_or_default(), askama_escape::Html).to_string(),
+		)
 		.replace("$LIMIT", &limit.to_string())
+		.replace("$SORT_CHECKED", if sort_updated { "checked" } else { "" })
+		.replace("$FIRST_TIMERS_CHECKED", if first_timers { "checked" } else { "" })
 		.replace("$PRS_1", &prs_author)
-		.replace("$PRS_2", &prs_new)
+		.replace("$PRS_2", &prs_new_html)
 		.replace("$PRS_3", &prs_need_review)
-		.replace("$PRS_4", &prs_need_merger);
+		.replace("$PRS_4", &prs_need_merger)
+		.replace("$PRS_5", &prs_stale);
 
 	Ok((StatusCode::OK, Html(index)))
 }
@@ -0,0 +1,35 @@
+use axum::{extract::State, response::Html};
+use axum_client_ip::ClientIp;
+use rusqlite::params;
+
+use crate::{database::DB, extract_row, pr_url, viewer_identity, with_db, AppError, AppState};
+
+pub async fn list_hidden(State(state): State<AppState>, ClientIp(ip): ClientIp) -> Result<Html<String>, AppError> {
+	let mut html = String::new();
+	let viewer = viewer_identity(&state, ip).await?;
+
+	let results: Vec<_> = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare("SELECT repo, pull_id, time FROM hidden WHERE hidden_by = ?1 ORDER BY time DESC")?;
+		let rows = stmt
+			.query_map(params![viewer], extract_row!(String u64 String))?
+			.map(Result::unwrap)
+			.collect();
+		Ok(rows)
+	})?;
+
+	html += "<!DOCTYPE html>";
+	html += "<table><thead><td>ID</td><td>hidden at</td><td></td><tbody>";
+	for (repo, id, time) in results {
+		html += &format!(
+			"<tr><td><a href='{}'>{repo}#{id}</a></td><td>{time}</td><td><button class='unhide' data-repo='{repo}' data-pr='{id}'>unhide</button></td>",
+			pr_url(&repo, id)
+		);
+	}
+	html += "</tbody></table>";
+	html += "<script>";
+	html += "for (const button of document.querySelectorAll('button.unhide')) { button.addEventListener('click', (e) => { fetch('/unhide-pr?id=' + e.target.dataset.pr + '&repo=' + encodeURIComponent(e.target.dataset.repo), { 'method': 'POST' }).then(() => e.target.parentElement.parentElement.remove()); }); }";
+	html += "</script>";
+
+	Ok(Html(html))
+}
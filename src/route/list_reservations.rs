@@ -1,30 +1,362 @@
-use axum::response::Html;
+use std::collections::HashMap;
 
-use crate::{database::DB, extract_row, with_db, AppError};
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{Html, IntoResponse, Response},
+	Json,
+};
+use axum_client_ip::ClientIp;
+use chrono::{Duration, Utc};
+use chrono_tz::Tz;
+use rusqlite::params;
+use serde::Serialize;
 
-pub async fn list_reservations() -> Result<Html<String>, AppError> {
+use crate::{
+	database::{StoredPr, DB, PR},
+	extract_row, format_timestamp, index_style, parse_timestamp, parse_utc, pr_url, render_card, reserver_identity,
+	wants_json, with_db, AppError, AppState, TIME_FORMAT, UTC_TIME_FORMAT,
+};
+
+/// Entry of the JSON listing.
+#[derive(Serialize)]
+struct Reservation {
+	repo: String,
+	id: usize,
+	url: String,
+	/// `None` if the PR is no longer tracked.
+	title: Option<String>,
+	reserved_by: Option<String>,
+	reserved_at: Option<String>,
+	expires_at: Option<String>,
+	category: Option<String>,
+	note: Option<String>,
+}
+
+/// List all reservations, as HTML or as JSON if requested by
+/// `Accept: application/json` or `?format=json`.
+/// Sorted by `?sort=expiry` (default), `holder` or `id`, optionally only of `?holder=`.
+pub async fn list_reservations(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
 	let mut html = String::new();
+	let tz = state.timezone(&params)?;
+	let order = match params.get("sort").map(|x| &**x).unwrap_or("expiry") {
+		"expiry" => "reservations.expires_at, reservations.repo, reservations.id",
+		"holder" => "reservations.reserved_by, reservations.expires_at",
+		"id" => "reservations.repo, reservations.id",
+		sort => return Ok((StatusCode::BAD_REQUEST, format!("invalid sort: {sort:?}")).into_response()),
+	};
+	let holder = params.get("holder").filter(|x| !x.is_empty());
 
-	let results: Vec<_> = with_db!(|db: &mut DB| {
+	let results: Vec<_> = with_db!(state, |db: &mut DB| {
+		let holder = holder.map(|x| db.resolve_viewer(x)).transpose()?;
 		let tx = db.transaction()?;
-		let mut stmt = tx.prepare("SELECT id, time FROM reservations")?;
+		let mut stmt = tx.prepare(&format!(
+			"SELECT reservations.repo, reservations.id, reservations.time, reservations.expires_at,
+				reservations.reserved_by, reservations.note, pull_data(pulls.data, pulls.data_compressed), pulls.category
+			FROM reservations LEFT JOIN pulls ON pulls.repo = reservations.repo AND pulls.id = reservations.id
+			WHERE ?1 IS NULL OR reservations.reserved_by = ?1
+			ORDER BY {order}"
+		))?;
 		let rows = stmt
-			.query_map([], extract_row!(usize String))?
+			.query_map(
+				params![holder],
+				extract_row!(String usize String String Option<String> Option<String> Option<String> Option<String>),
+			)?
 			.map(Result::unwrap)
 			.collect();
 		Ok(rows)
 	})?;
 
+	if wants_json(&params, &headers) {
+		let rfc3339 = |x: &str| parse_timestamp(x).map(|x| x.to_rfc3339());
+		let mut reservations = vec![];
+		for (repo, id, time, expires_at, reserved_by, note, data, category) in results {
+			let pr: Option<StoredPr> = data.map(|x| serde_json::from_str(&x)).transpose()?;
+			reservations.push(Reservation {
+				url: pr_url(&repo, id as u64),
+				repo,
+				id,
+				category: pr.as_ref().map(|_| category.unwrap_or_else(|| "New".to_owned())),
+				title: pr.and_then(|x| x.title),
+				reserved_by,
+				reserved_at: rfc3339(&time),
+				expires_at: rfc3339(&expires_at),
+				note,
+			});
+		}
+		return Ok(Json(reservations).into_response());
+	}
+
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>Reservations</title>";
+	html += index_style();
+	html += "<button id='extend'>Extend mine by one week</button>";
+	// expired reservations are kept until the next housekeeping
+	let now = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	let (expired, active): (Vec<_>, Vec<_>) = results.into_iter().partition(|x| x.3 < now);
+	for (heading, results) in [
+		("Active", active),
+		("Expired, released by the next housekeeping", expired),
+	] {
+		if results.is_empty() {
+			continue;
+		}
+		html += &format!("<h2>{heading}</h2>");
+		html += "<table><thead><td>PR</td><td>category</td><td>reserved by</td><td>time</td><td>expires</td><td>note</td><td></td><tbody>";
+		for (repo, id, time, expires_at, reserved_by, note, data, category) in results {
+			let time = format_timestamp(&time, &tz);
+			let expires_at = format_timestamp(&expires_at, &tz);
+			let reserved_by = askama_escape::escape(reserved_by.as_deref().unwrap_or_default(), askama_escape::Html);
+			let note = askama_escape::escape(note.as_deref().unwrap_or_default(), askama_escape::Html);
+			let (card, category) = reservation_card(&state, &tz, &repo, id, data, category)?;
+			html += &format!(
+				"<tr><td>{card}</td><td>{category}</td><td>{reserved_by}</td><td>{time}</td><td>{expires_at}</td><td>{note}</td><td><button class='extend' data-repo='{repo}' data-pr='{id}'>extend</button> <button class='release' data-repo='{repo}' data-pr='{id}'>release</button></td>"
+			);
+		}
+		html += "</tbody></table>";
+	}
+	html += &reservation_script(&params)?;
+
+	Ok(Html(html).into_response())
+}
+
+/// The reserver's reservations with expiry countdowns, and their recently expired reservations.
+pub async fn my_reservations(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+) -> Result<Html<String>, AppError> {
+	let tz = state.timezone(&params)?;
+	let reserver = reserver_identity(&state, ip, &params, &headers).await?;
+	let expired_since = (Utc::now() - Duration::days(7)).format(UTC_TIME_FORMAT).to_string();
+
+	let (active, expired): (Vec<_>, Vec<_>) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(
+			"SELECT reservations.repo, reservations.id, reservations.expires_at, reservations.note,
+				pull_data(pulls.data, pulls.data_compressed), pulls.category
+			FROM reservations LEFT JOIN pulls ON pulls.repo = reservations.repo AND pulls.id = reservations.id
+			WHERE reservations.reserved_by = ?1
+			ORDER BY reservations.expires_at",
+		)?;
+		let active = stmt
+			.query_map(
+				params![reserver],
+				extract_row!(String usize String Option<String> Option<String> Option<String>),
+			)?
+			.map(Result::unwrap)
+			.collect();
+		drop(stmt);
+		let mut stmt = tx.prepare(
+			"SELECT repo, pull_id, title, released_at FROM reservation_log
+			WHERE reserved_by = ?1 AND outcome = 'expired' AND released_at >= ?2
+			ORDER BY released_at DESC",
+		)?;
+		let expired = stmt
+			.query_map(
+				params![reserver, expired_since],
+				extract_row!(String u64 Option<String> String),
+			)?
+			.map(Result::unwrap)
+			.collect();
+		Ok((active, expired))
+	})?;
+
+	let mut html = String::new();
 	html += "<!DOCTYPE html>";
-	html += "<button id='extend'>Extend all to one week</button>";
-	html += "<table><thead><td>ID</td><td>time</td><tbody>";
-	for (id, time) in results {
-		html += &format!("<tr><td>{id}</td><td>{time}</td>");
+	html += "<meta charset='utf-8'>";
+	html += "<title>My reservations</title>";
+	html += index_style();
+	html += &format!(
+		"<h1>Reservations of {}</h1>",
+		askama_escape::escape(&reserver, askama_escape::Html)
+	);
+	if active.is_empty() {
+		html += &format!(
+			"<p>You have no reservations. Reserve a PR from the <a href='/'>dashboard</a>, \
+			or with <code>curl -X POST '{}/reserve-pr?category=NeedsReviewer'</code> \
+			(or <code>?pr=NUMBER</code> for a specific PR).</p>",
+			askama_escape::escape(&state.base_url(&headers), askama_escape::Html)
+		);
+	} else {
+		let now = Utc::now();
+		html +=
+			"<table><thead><td>PR</td><td>category</td><td>expires</td><td>remaining</td><td>note</td><td></td><tbody>";
+		for (repo, id, expires_at, note, data, category) in active {
+			let note = askama_escape::escape(note.as_deref().unwrap_or_default(), askama_escape::Html);
+			let (card, category) = reservation_card(&state, &tz, &repo, id, data, category)?;
+			let remaining = parse_timestamp(&expires_at)
+				.map(|x| describe_remaining(x - now))
+				.unwrap_or_default();
+			let deadline = parse_timestamp(&expires_at)
+				.map(|x| x.timestamp_millis())
+				.unwrap_or_default();
+			let expires_at = format_timestamp(&expires_at, &tz);
+			html += &format!(
+				"<tr><td>{card}</td><td>{category}</td><td>{expires_at}</td><td class='remaining' data-deadline='{deadline}'>{remaining}</td><td class='note' data-repo='{repo}' data-pr='{id}'>{note}</td><td><button class='extend' data-repo='{repo}' data-pr='{id}'>extend</button> <button class='release' data-repo='{repo}' data-pr='{id}'>release</button> <button class='annotate' data-repo='{repo}' data-pr='{id}'>edit note</button></td>"
+			);
+		}
+		html += "</tbody></table>";
+	}
+
+	html += "<h2>Expired in the last week</h2>";
+	if expired.is_empty() {
+		html += "<p>Nothing.</p>";
+	} else {
+		html += "<ul>";
+		for (repo, id, title, released_at) in expired {
+			html += &format!(
+				"<li>{} <a href='{}'>{repo}#{id}</a> {}</li>",
+				parse_utc(&released_at).with_timezone(&tz).format(TIME_FORMAT),
+				pr_url(&repo, id),
+				askama_escape::escape(title.as_deref().unwrap_or_default(), askama_escape::Html)
+			);
+		}
+		html += "</ul>";
 	}
-	html += "</tbody></table>";
+
+	html += &reservation_script(&params)?;
+	// refresh the countdowns every minute
 	html += "<script>";
-	html += "document.getElementById('extend').addEventListener('click', (e) => { fetch('/extend-reservations', { 'method': 'POST' }); });";
+	html += "const countdown = () => { for (const cell of document.querySelectorAll('td.remaining')) { const minutes = Math.round((cell.dataset.deadline - Date.now()) / 60000); cell.innerText = minutes <= 0 ? 'expired' : minutes < 120 ? `${minutes} minutes` : `${Math.floor(minutes / 60)} hours`; } }; setInterval(countdown, 60000);";
 	html += "</script>";
 
 	Ok(Html(html))
 }
+
+/// Card of a reserved PR and its category, with a placeholder if the PR is no longer tracked.
+fn reservation_card(
+	state: &AppState,
+	tz: &Tz,
+	repo: &str,
+	id: usize,
+	data: Option<String>,
+	category: Option<String>,
+) -> Result<(String, String), AppError> {
+	let Some(data) = data else {
+		return Ok((
+			format!(
+				r#"<div class="pr"><a href="{}">{repo}#{id}</a> PR no longer tracked</div>"#,
+				pr_url(repo, id as u64)
+			),
+			String::new(),
+		));
+	};
+	let mut pr = PR::new(repo.to_owned(), serde_json::from_str(&data)?, category.clone());
+	let label_href = |name: &str| -> Result<String, AppError> {
+		Ok(format!(
+			"/?{}",
+			serde_urlencoded::to_string([("filter", name.replace('+', ""))])?
+		))
+	};
+	let card = render_card(state, &mut pr, tz, label_href, "")?;
+	Ok((card.html, category.unwrap_or_else(|| "New".to_owned())))
+}
+
+/// Time left until a reservation expires.
+fn describe_remaining(remaining: Duration) -> String {
+	match remaining {
+		x if x <= Duration::zero() => "expired".to_owned(),
+		x if x.num_minutes() >= 120 => format!("{} hours", x.num_hours()),
+		x => format!("{} minutes", x.num_minutes()),
+	}
+}
+
+/// Handlers for the extend and release buttons.
+fn reservation_script(params: &HashMap<String, String>) -> Result<String, AppError> {
+	// act as the reserver given in the page URL
+	let as_param = params
+		.get("as")
+		.map(|name| serde_urlencoded::to_string([("as", name)]))
+		.transpose()?
+		.unwrap_or_default();
+	let mut html = String::new();
+	html += "<script>";
+	html += &format!("document.getElementById('extend')?.addEventListener('click', (e) => {{ fetch('/extend-reservations?{as_param}', {{ 'method': 'POST' }}); }});");
+	html += &format!("for (const button of document.querySelectorAll('button.release')) {{ button.addEventListener('click', (e) => {{ fetch('/release-pr?id=' + e.target.dataset.pr + '&repo=' + encodeURIComponent(e.target.dataset.repo) + '&{as_param}', {{ 'method': 'POST' }}).then(resp => resp.text()).then(text => {{ e.target.parentElement.innerText = text; }}); }}); }}");
+	html += &format!("for (const button of document.querySelectorAll('button.annotate')) {{ button.addEventListener('click', (e) => {{ const note = prompt('Note'); if (note === null) {{ return; }} fetch('/annotate-reservation?id=' + e.target.dataset.pr + '&repo=' + encodeURIComponent(e.target.dataset.repo) + '&note=' + encodeURIComponent(note) + '&{as_param}', {{ 'method': 'POST' }}).then(resp => {{ if (resp.ok) {{ document.querySelector(`td.note[data-repo='${{e.target.dataset.repo}}'][data-pr='${{e.target.dataset.pr}}']`).innerText = note; }} return resp.text(); }}).then(text => {{ e.target.title = text; }}); }}); }}");
+	html += &format!("for (const button of document.querySelectorAll('button.extend')) {{ button.addEventListener('click', (e) => {{ fetch('/extend-reservation?id=' + e.target.dataset.pr + '&repo=' + encodeURIComponent(e.target.dataset.repo) + '&{as_param}', {{ 'method': 'POST' }}).then(resp => resp.text()).then(text => {{ e.target.parentElement.innerText = text; }}); }}); }}");
+	html += "</script>";
+	Ok(html)
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::header;
+	use chrono::DateTime;
+
+	use super::*;
+	use crate::{
+		tests::{seed, serve},
+		AWAITING_REVIEWER,
+	};
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn json_listing() {
+		let (state, base) = serve().await;
+		seed(&state).await;
+		let client = reqwest::Client::new();
+		for query in ["pr=1&as=alice&note=looking%20at%20it", "pr=2&as=bob&duration=30m"] {
+			let response = client.post(format!("{base}/reserve-pr?{query}")).send().await.unwrap();
+			assert_eq!(response.status(), StatusCode::OK);
+		}
+
+		let by_header = client
+			.get(format!("{base}/list-reservations"))
+			.header(header::ACCEPT, "application/json")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(by_header.status(), StatusCode::OK);
+		let listing: Vec<serde_json::Map<String, serde_json::Value>> = by_header.json().await.unwrap();
+		let by_param: Vec<serde_json::Map<String, serde_json::Value>> =
+			reqwest::get(format!("{base}/list-reservations?format=json"))
+				.await
+				.unwrap()
+				.json()
+				.await
+				.unwrap();
+		assert_eq!(listing, by_param);
+
+		// sorted by expiry, every field is present even if null
+		assert_eq!(listing.len(), 2);
+		let mut fields = [
+			"repo",
+			"id",
+			"url",
+			"title",
+			"reserved_by",
+			"reserved_at",
+			"expires_at",
+			"category",
+			"note",
+		];
+		fields.sort();
+		for entry in &listing {
+			let mut keys: Vec<_> = entry.keys().map(|x| &**x).collect();
+			keys.sort();
+			assert_eq!(keys, fields);
+		}
+		let (bob, alice) = (&listing[0], &listing[1]);
+		assert_eq!(bob["id"], 2);
+		assert_eq!(bob["url"], "https://github.com/NixOS/nixpkgs/pull/2");
+		assert_eq!(bob["reserved_by"], "bob");
+		assert_eq!(bob["note"], serde_json::Value::Null);
+
+		assert_eq!(alice["repo"], "NixOS/nixpkgs");
+		assert_eq!(alice["id"], 1);
+		assert_eq!(alice["title"], "alpha: init at 1.0");
+		assert_eq!(alice["category"], AWAITING_REVIEWER);
+		assert_eq!(alice["reserved_by"], "alice");
+		assert_eq!(alice["note"], "looking at it");
+		let time = |field: &str| DateTime::parse_from_rfc3339(alice[field].as_str().unwrap()).unwrap();
+		assert_eq!(time("expires_at") - time("reserved_at"), state.reservation_ttl);
+	}
+}
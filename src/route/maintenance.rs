@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+
+use crate::{database::DB, with_db, AppError, AppState};
+
+/// Vacuum, analyze and check the database, incrementally unless `?full=true`.
+/// Runs under the update lock and answers `409 Conflict` while an update is running, for a weekly cron job.
+pub async fn maintenance(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let full = params.get("full").is_some_and(|x| x == "true");
+	let update_lock = match state.try_lock_update().await {
+		Ok(x) => x,
+		Err(busy) => return Ok(busy),
+	};
+	let report = with_db!(state, |db: &mut DB| db.maintain(full))?;
+	drop(update_lock);
+
+	let reclaimed = report.size_before - report.size_after;
+	let healthy = report.integrity == ["ok"];
+	if healthy {
+		tracing::info!("maintenance: reclaimed {reclaimed} bytes, full vacuum: {full}");
+	} else {
+		tracing::error!("maintenance: integrity check failed: {:?}", report.integrity);
+	}
+	Ok(Json(serde_json::json!({
+		"full": full,
+		"reclaimed": reclaimed,
+		"size": report.size_after,
+		"healthy": healthy,
+		"integrity": report.integrity,
+	}))
+	.into_response())
+}
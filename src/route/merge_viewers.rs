@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::Utc;
+use rusqlite::params;
+
+use crate::{database::DB, with_db, AppError, AppState, UTC_TIME_FORMAT};
+
+pub async fn merge_viewers(
+	State(state): State<AppState>,
+	headers: HeaderMap,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let Some(keep) = params.get("keep").filter(|x| !x.is_empty()) else {
+		return Ok((StatusCode::BAD_REQUEST, "malformed request, requires keep").into_response());
+	};
+	let merge: Vec<_> = params
+		.get("merge")
+		.map(|x| x.split(',').filter(|x| !x.is_empty()).collect())
+		.unwrap_or_default();
+	if merge.is_empty() {
+		return Ok((StatusCode::BAD_REQUEST, "malformed request, requires merge").into_response());
+	}
+	let dry_run = params.get("dry-run").map(|x| x == "true").unwrap_or(false);
+
+	let _lock = state.update_lock.lock().await;
+
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	let report = with_db!(state, |db: &mut DB| {
+		let keep = db.resolve_viewer(keep)?;
+		if merge.iter().any(|x| *x == keep) {
+			return Ok(Err(format!("cannot merge {keep:?} into itself")));
+		}
+		let tx = db.transaction()?;
+
+		let mut moved = vec![];
+		for viewer in &merge {
+			// hidden: both viewers may have hidden the same PR, keep the earlier entry
+			let hidden_duplicates = tx.execute(
+				"UPDATE hidden SET time = MIN(time, (
+					SELECT other.time FROM hidden other
+					WHERE other.repo = hidden.repo AND other.pull_id = hidden.pull_id AND other.hidden_by = ?2
+				))
+				WHERE hidden_by = ?1 AND (repo, pull_id) IN (SELECT repo, pull_id FROM hidden WHERE hidden_by = ?2)",
+				params![keep, viewer],
+			)?;
+			tx.execute(
+				"DELETE FROM hidden
+				WHERE hidden_by = ?2 AND (repo, pull_id) IN (SELECT repo, pull_id FROM hidden WHERE hidden_by = ?1)",
+				params![keep, viewer],
+			)?;
+			let hidden = tx.execute(
+				"UPDATE hidden SET hidden_by = ?1 WHERE hidden_by = ?2",
+				params![keep, viewer],
+			)?;
+			// a PR has a single reserved_by, so there is nothing to deduplicate
+			let reservations = tx.execute(
+				"UPDATE pulls SET reserved_by = ?1 WHERE reserved_by = ?2",
+				params![keep, viewer],
+			)?;
+			tx.execute(
+				"UPDATE reservations SET reserved_by = ?1 WHERE reserved_by = ?2",
+				params![keep, viewer],
+			)?;
+			// the history, for the stats
+			let mut history = 0;
+			for (table, column) in [
+				("reservation_log", "reserved_by"),
+				("expired_reservations", "reserved_by"),
+				("pull_outcomes", "was_reserved_by"),
+			] {
+				history += tx.execute(
+					&format!("UPDATE {table} SET {column} = ?1 WHERE {column} = ?2"),
+					params![keep, viewer],
+				)?;
+			}
+			// future requests from the merged identity resolve to the kept viewer
+			let aliases = tx.execute(
+				"UPDATE viewer_aliases SET viewer = ?1 WHERE viewer = ?2",
+				params![keep, viewer],
+			)?;
+			tx.execute(
+				"INSERT INTO viewer_aliases
+				(alias, viewer, time)
+				VALUES (?1, ?2, ?3)
+				ON CONFLICT DO UPDATE SET viewer = ?2, time = ?3",
+				params![viewer, keep, time],
+			)?;
+			moved.push(serde_json::json!({
+				"viewer": viewer,
+				"hidden": hidden,
+				"hidden_duplicates": hidden_duplicates,
+				"reservations": reservations,
+				"history": history,
+				"aliases": aliases,
+			}));
+		}
+
+		if dry_run {
+			tx.rollback()?;
+		} else {
+			tx.commit()?;
+			tracing::info!("merged viewers {merge:?} into {keep:?}");
+		}
+		Ok(Ok(serde_json::json!({
+			"keep": keep,
+			"dry_run": dry_run,
+			"merged": moved,
+		})))
+	})?;
+
+	match report {
+		Ok(report) => Ok(Json(report).into_response()),
+		Err(msg) => Ok((StatusCode::CONFLICT, msg).into_response()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::to_bytes, http::header};
+	use rusqlite::Connection;
+
+	use super::*;
+	use crate::{database::CommonQueries, tests::test_state, viewer_identity, AWAITING_REVIEWER};
+
+	const EARLY: &str = "2024-01-01T00:00:00Z";
+	const LATE: &str = "2024-01-02T00:00:00Z";
+
+	/// Two identities of one person: `alice` and the IP address `10.0.0.2`, which holds a reservation.
+	async fn state() -> AppState {
+		let mut state = test_state();
+		state.admin_token = Some("secret".to_owned());
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				for id in 1..=3 {
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data) VALUES ('o/r', ?1, 'a', ?2, ?3)",
+						params![id, EARLY, serde_json::json!({ "number": id }).to_string()],
+					)?;
+				}
+				tx.execute_batch(&format!(
+					"UPDATE pulls SET category = '{AWAITING_REVIEWER}', reserved_by = '10.0.0.2' WHERE id = 1;
+					INSERT INTO reservations (repo, id, time, reserved_by, expires_at)
+						VALUES ('o/r', 1, '{EARLY}', '10.0.0.2', '2099-01-01T00:00:00Z');
+					INSERT INTO reservation_log (repo, pull_id, reserved_by, reserved_at)
+						VALUES ('o/r', 1, '10.0.0.2', '{EARLY}');
+					INSERT INTO expired_reservations (repo, pull_id, reserved_by, time)
+						VALUES ('o/r', 2, '10.0.0.2', '{EARLY}');
+					INSERT INTO pull_outcomes (repo, id, author, outcome, closed_at, was_reserved_by)
+						VALUES ('o/r', 4, 'a', 'merged', '{EARLY}', '10.0.0.2');
+					INSERT INTO hidden (repo, pull_id, hidden_by, time) VALUES
+						('o/r', 2, 'alice', '{LATE}'),
+						('o/r', 2, '10.0.0.2', '{EARLY}'),
+						('o/r', 3, 'alice', '{EARLY}'),
+						('o/r', 3, '10.0.0.2', '{LATE}'),
+						('o/r', 1, '10.0.0.2', '{LATE}');
+					INSERT INTO viewer_aliases (alias, viewer, time) VALUES
+						('alice-laptop', 'alice', '{EARLY}'),
+						('10.0.0.1', '10.0.0.2', '{EARLY}');"
+				))?;
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+		state
+	}
+
+	async fn merge(state: &AppState, params: &[(&str, &str)]) -> (StatusCode, String) {
+		let mut headers = HeaderMap::new();
+		headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+		let params = params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+		let response = merge_viewers(State(state.clone()), headers, Query(params))
+			.await
+			.unwrap();
+		let status = response.status();
+		let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+		(status, String::from_utf8(body.to_vec()).unwrap())
+	}
+
+	/// Every row naming a viewer, as `table column value` sorted by table and PR.
+	fn identities(db: &Connection) -> Vec<String> {
+		let mut rows = vec![];
+		for (table, id, column) in [
+			("pulls", "id", "reserved_by"),
+			("reservations", "id", "reserved_by"),
+			("reservation_log", "pull_id", "reserved_by"),
+			("expired_reservations", "pull_id", "reserved_by"),
+			("pull_outcomes", "id", "was_reserved_by"),
+			("hidden", "pull_id", "hidden_by || ' ' || time"),
+		] {
+			let mut stmt = db
+				.prepare(&format!(
+					"SELECT {id}, {column} FROM {table} WHERE {column} IS NOT NULL ORDER BY {id}, {column}"
+				))
+				.unwrap();
+			let values = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)));
+			rows.extend(values.unwrap().map(|x| {
+				let (id, value) = x.unwrap();
+				format!("{table} {id} {value}")
+			}));
+		}
+		let mut stmt = db
+			.prepare("SELECT alias, viewer FROM viewer_aliases ORDER BY alias")
+			.unwrap();
+		let aliases = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)));
+		rows.extend(aliases.unwrap().map(|x| {
+			let (alias, viewer) = x.unwrap();
+			format!("alias {alias} {viewer}")
+		}));
+		rows
+	}
+
+	async fn stored(state: &AppState) -> Vec<String> {
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				Ok(identities(&tx))
+			})
+			.await
+			.unwrap()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn repoints_every_table() {
+		let state = state().await;
+		let (status, body) = merge(&state, &[("keep", "alice-laptop"), ("merge", "10.0.0.2")]).await;
+		assert_eq!(status, StatusCode::OK, "{body}");
+		let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+		assert_eq!(
+			report,
+			serde_json::json!({
+				"keep": "alice",
+				"dry_run": false,
+				"merged": [{
+					"viewer": "10.0.0.2",
+					"hidden": 1,
+					"hidden_duplicates": 2,
+					"reservations": 1,
+					"history": 3,
+					"aliases": 1,
+				}],
+			})
+		);
+		// PRs hidden by both keep the earlier time
+		assert_eq!(
+			stored(&state).await,
+			[
+				"pulls 1 alice",
+				"reservations 1 alice",
+				"reservation_log 1 alice",
+				"expired_reservations 2 alice",
+				"pull_outcomes 4 alice",
+				&format!("hidden 1 alice {LATE}"),
+				&format!("hidden 2 alice {EARLY}"),
+				&format!("hidden 3 alice {EARLY}"),
+				"alias 10.0.0.1 alice",
+				"alias 10.0.0.2 alice",
+				"alias alice-laptop alice",
+			]
+		);
+		let report = state
+			.db
+			.run(|db: &mut DB| db.transaction()?.check_consistency())
+			.await
+			.unwrap();
+		assert!(report.is_consistent(), "{}", report.summary());
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn merged_identities_resolve_to_the_kept_viewer() {
+		let state = state().await;
+		assert_eq!(
+			viewer_identity(&state, "10.0.0.1".parse().unwrap()).await.unwrap(),
+			"10.0.0.2"
+		);
+		merge(&state, &[("keep", "alice"), ("merge", "10.0.0.2")]).await;
+		for ip in ["10.0.0.1", "10.0.0.2"] {
+			assert_eq!(viewer_identity(&state, ip.parse().unwrap()).await.unwrap(), "alice");
+		}
+		assert_eq!(
+			viewer_identity(&state, "10.0.0.3".parse().unwrap()).await.unwrap(),
+			"10.0.0.3"
+		);
+
+		// merging an alias of the kept viewer would merge it into itself
+		let (status, body) = merge(&state, &[("keep", "alice-laptop"), ("merge", "alice")]).await;
+		assert_eq!(status, StatusCode::CONFLICT);
+		assert!(body.contains("cannot merge \"alice\" into itself"), "{body}");
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn dry_run_changes_nothing() {
+		let state = state().await;
+		let before = stored(&state).await;
+		let (status, body) = merge(
+			&state,
+			&[("keep", "alice"), ("merge", "10.0.0.2,bob"), ("dry-run", "true")],
+		)
+		.await;
+		assert_eq!(status, StatusCode::OK, "{body}");
+		let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+		assert_eq!(report["dry_run"], true);
+		assert_eq!(report["merged"][0]["reservations"], 1);
+		assert_eq!(report["merged"][0]["history"], 3);
+		assert_eq!(report["merged"][1]["viewer"], "bob");
+		assert_eq!(report["merged"][1]["hidden"], 0);
+		assert_eq!(stored(&state).await, before);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn requires_the_admin_token() {
+		let state = state().await;
+		let params = HashMap::from([
+			("keep".to_owned(), "alice".to_owned()),
+			("merge".to_owned(), "10.0.0.2".to_owned()),
+		]);
+		let response = merge_viewers(State(state.clone()), HeaderMap::new(), Query(params))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let (status, _) = merge(&state, &[("keep", "alice")]).await;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+	}
+}
@@ -1,16 +1,78 @@
-mod extend_revervations;
+mod annotate_reservation;
+mod api_pulls;
+mod backup;
+mod broken_rows;
+mod caches;
+mod card;
+mod changes;
+mod compress_data;
+mod consistency;
+mod drift;
+mod expire_reservations;
+mod extend_reservations;
+mod forecast;
+mod hide_pr;
 mod housekeep_prs;
 mod index;
+mod list_hidden;
 mod list_reservations;
+mod maintenance;
+mod merge_viewers;
+mod outcomes;
+mod pr_detail;
+mod pr_history;
+mod rate_limit;
+mod release_pr;
+mod reservation_history;
 mod reserve_pr;
+mod set_category;
+mod sitemap;
+mod stale_mergeable;
+mod stats;
+mod status;
+mod update_pr;
+mod update_progress;
 mod update_prs;
+mod update_reviews;
+mod webhook;
 
-pub use extend_revervations::*;
+pub use annotate_reservation::*;
+pub use api_pulls::*;
+pub use backup::*;
+pub use broken_rows::*;
+pub use caches::*;
+pub use card::*;
+pub use changes::*;
+pub use compress_data::*;
+pub use consistency::*;
+pub use drift::*;
+pub use expire_reservations::*;
+pub use extend_reservations::*;
+pub use forecast::*;
+pub use hide_pr::*;
 pub use housekeep_prs::*;
 pub use index::*;
+pub use list_hidden::*;
 pub use list_reservations::*;
+pub use maintenance::*;
+pub use merge_viewers::*;
+pub use outcomes::*;
+pub use pr_detail::*;
+pub use pr_history::*;
+pub use rate_limit::*;
+pub use release_pr::*;
+pub use reservation_history::*;
 pub use reserve_pr::*;
+pub use set_category::*;
+pub use sitemap::*;
+pub use stale_mergeable::*;
+pub use stats::*;
+pub use status::*;
+pub use update_pr::*;
+pub use update_progress::*;
 pub use update_prs::*;
+pub use update_reviews::*;
+pub use webhook::*;
 
 pub async fn robots_txt() -> &'static str {
 	include_str!("robots.txt")
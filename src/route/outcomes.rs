@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{Html, IntoResponse, Response},
+	Json,
+};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{
+	database::DB, extract_row, parse_since, parse_utc, pr_url, wants_json, with_db, AppError, AppState, TIME_FORMAT,
+	UTC_TIME_FORMAT,
+};
+
+#[derive(Serialize)]
+struct Outcome {
+	repo: String,
+	id: u64,
+	title: Option<String>,
+	author: String,
+	/// `merged` or `closed`
+	outcome: String,
+	closed_at: String,
+	last_category: Option<String>,
+	was_reserved_by: Option<String>,
+}
+
+/// How tracked PRs ended since `?since=` (default 30 days), as HTML or JSON.
+pub async fn outcomes(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let tz = state.timezone(&params)?;
+	let since = match parse_since(&params, "30d") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	let since_param = since.format(UTC_TIME_FORMAT).to_string();
+
+	let rows: Vec<_> = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(
+			"SELECT repo, id, title, author, outcome, closed_at, last_category, was_reserved_by
+			FROM pull_outcomes
+			WHERE closed_at >= ?1
+			ORDER BY closed_at DESC",
+		)?;
+		let rows = stmt
+			.query_map(
+				params![since_param],
+				extract_row!(String u64 Option<String> String String String Option<String> Option<String>),
+			)?
+			.map(|x| {
+				x.map(
+					|(repo, id, title, author, outcome, closed_at, last_category, was_reserved_by)| Outcome {
+						repo,
+						id,
+						title,
+						author,
+						outcome,
+						closed_at,
+						last_category,
+						was_reserved_by,
+					},
+				)
+			})
+			.collect::<Result<_, _>>()?;
+		Ok(rows)
+	})?;
+
+	if wants_json(&params, &headers) {
+		let rows: Vec<_> = rows
+			.into_iter()
+			.map(|x| Outcome {
+				closed_at: parse_utc(&x.closed_at).to_rfc3339(),
+				..x
+			})
+			.collect();
+		return Ok(Json(rows).into_response());
+	}
+
+	let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+	let merged = rows.iter().filter(|x| x.outcome == "merged").count();
+	let merged_reserved = rows
+		.iter()
+		.filter(|x| x.outcome == "merged" && x.was_reserved_by.is_some())
+		.count();
+	let reserved = rows.iter().filter(|x| x.was_reserved_by.is_some()).count();
+
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>PR outcomes</title>";
+	html += &format!(
+		"<h1>PRs closed since {}</h1>",
+		since.with_timezone(&tz).format(TIME_FORMAT)
+	);
+	html += &format!(
+		"<p>{merged} of {} merged, {merged_reserved} of {reserved} reserved at the time.</p>",
+		rows.len()
+	);
+	html += "<table><thead><td>PR</td><td>title</td><td>author</td><td>outcome</td><td>closed</td><td>category</td><td>reserved by</td><tbody>";
+	for x in &rows {
+		html += &format!(
+			"<tr><td><a href='{}'>{}#{}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>",
+			pr_url(&x.repo, x.id),
+			x.repo,
+			x.id,
+			escape(x.title.as_deref().unwrap_or_default()),
+			escape(&x.author),
+			x.outcome,
+			parse_utc(&x.closed_at).with_timezone(&tz).format(TIME_FORMAT),
+			x.last_category.as_deref().unwrap_or("New"),
+			escape(x.was_reserved_by.as_deref().unwrap_or_default()),
+		);
+	}
+	html += "</tbody></table>";
+
+	Ok(Html(html).into_response())
+}
@@ -0,0 +1,447 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+	extract::{Path, Query, State},
+	http::{header, StatusCode},
+	response::{Html, IntoResponse, Response},
+};
+use rusqlite::{params, OptionalExtension};
+
+use crate::{
+	database::{category_history, StoredPr, DB},
+	effort, extract_row,
+	labels::sort_labels,
+	parse_utc, pr_url, truncate_text, with_db, AppError, AppState, TIME_FORMAT,
+};
+
+/// Characters of the description shown before it is expanded.
+const SNIPPET_LENGTH: usize = 300;
+
+/// Reduce markdown to its text: drops markup characters, HTML tags, images and link targets.
+/// The result still needs escaping.
+fn markdown_to_text(markdown: &str) -> String {
+	let mut text = String::new();
+	let mut chars = markdown.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'<' if chars
+				.peek()
+				.is_some_and(|x| x.is_ascii_alphabetic() || *x == '/' || *x == '!') =>
+			{
+				// HTML tags and comments
+				for c in chars.by_ref() {
+					if c == '>' {
+						break;
+					}
+				}
+			},
+			'!' if chars.peek() == Some(&'[') => {
+				// images: keep neither alt text nor target
+				for c in chars.by_ref() {
+					if c == ']' {
+						break;
+					}
+				}
+				skip_link_target(&mut chars);
+			},
+			']' => skip_link_target(&mut chars),
+			'[' | '*' | '`' | '#' | '>' | '~' => {},
+			c => text.push(c),
+		}
+	}
+	// collapse the blank lines left by removed markup
+	text.lines()
+		.map(|x| x.trim())
+		.filter(|x| !x.is_empty())
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// Skip `(target)` after the text of a link, the target may contain balanced parentheses.
+fn skip_link_target(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+	if chars.peek() != Some(&'(') {
+		return;
+	}
+	let mut depth = 0;
+	for c in chars.by_ref() {
+		match c {
+			'(' => depth += 1,
+			')' => depth -= 1,
+			_ => {},
+		}
+		if depth == 0 {
+			break;
+		}
+	}
+}
+
+/// Permanent redirect to the canonical permalink of a PR, keeping the query parameters besides `id` and `repo`.
+fn redirect_to_permalink(state: &AppState, repo: &str, id: u64, params: &HashMap<String, String>) -> Response {
+	let mut location = state.permalink(repo, id);
+	let params: BTreeMap<_, _> = params.iter().filter(|x| x.0 != "id" && x.0 != "repo").collect();
+	if !params.is_empty() {
+		location += "?";
+		location += &serde_urlencoded::to_string(params).unwrap_or_default();
+	}
+	(StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response()
+}
+
+/// Redirect the query-parameter form `/pr?id=123` to the canonical permalink.
+pub async fn pr_detail_redirect(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let Some(id) = params.get("id") else {
+		return Ok((StatusCode::BAD_REQUEST, "malformed request, requires id").into_response());
+	};
+	let id: u64 = id.parse()?;
+	let repo = state.repo_param(&params)?;
+	Ok(redirect_to_permalink(&state, &repo, id, &params))
+}
+
+/// Notice for a PR whose stored data is unreadable, as listed by `/broken-rows`.
+fn broken_row(state: &AppState, repo: &str, id: u64, error: &str) -> Html<String> {
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += &format!("<title>#{id}: unreadable</title>");
+	html += &format!("<link rel='canonical' href='{}'>", state.permalink(repo, id));
+	html += &format!("<h1><a href='{}'>{repo} #{id}</a></h1>", pr_url(repo, id));
+	html += &format!(
+		"<p>The stored data of this PR is unreadable: {}. It is skipped by the dashboard until it is fetched again with <code>POST /update-pr?id={id}</code>, see <a href='/broken-rows'>/broken-rows</a>.</p>",
+		askama_escape::escape(error, askama_escape::Html)
+	);
+	Html(html)
+}
+
+/// `/pr/<number>`, with `?repo=` for PRs of another repository in the form used before the permalinks
+/// named the repository.
+pub async fn pr_detail(
+	State(state): State<AppState>,
+	Path(id): Path<u64>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let repo = state.repo_param(&params)?;
+	if state.repos.len() > 1 || params.contains_key("repo") {
+		return Ok(redirect_to_permalink(&state, &repo, id, &params));
+	}
+	show_pr(&state, repo, id, &params).await
+}
+
+/// `/pr/<owner>/<name>/<number>`, canonical if several repositories are tracked.
+pub async fn pr_detail_in_repo(
+	State(state): State<AppState>,
+	Path((owner, name, id)): Path<(String, String, u64)>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let repo = format!("{owner}/{name}");
+	if !state.repos.contains(&repo) {
+		return Ok((StatusCode::NOT_FOUND, Html(include_str!("../../404.html").to_owned())).into_response());
+	}
+	if state.repos.len() == 1 || params.contains_key("repo") {
+		return Ok(redirect_to_permalink(&state, &repo, id, &params));
+	}
+	show_pr(&state, repo, id, &params).await
+}
+
+async fn show_pr(
+	state: &AppState,
+	repo: String,
+	id: u64,
+	params: &HashMap<String, String>,
+) -> Result<Response, AppError> {
+	let tz = state.timezone(params)?;
+
+	let (row, history) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let row = tx
+			.query_row(
+				"SELECT pull_data(data, data_compressed), category, reserved_by, body FROM pulls
+				WHERE repo = ?1 AND id = ?2 AND state = 'open'",
+				params![repo, id],
+				extract_row!(Option<String> Option<String> Option<String> Option<String>),
+			)
+			.optional()?;
+		let history = category_history(&tx, &repo, id as i64)?;
+		Ok((row, history))
+	})?;
+	let Some((data, category, reserved_by, body)) = row else {
+		return Ok((StatusCode::NOT_FOUND, Html(include_str!("../../404.html").to_owned())).into_response());
+	};
+	// NULL if the data fails to decompress
+	let data = data
+		.ok_or_else(|| "decompression failed".to_owned())
+		.and_then(|x| serde_json::from_str::<StoredPr>(&x).map_err(|err| err.to_string()));
+	let mut data = match data {
+		Ok(data) => data,
+		Err(error) => return Ok(broken_row(state, &repo, id, &error).into_response()),
+	};
+	if let Some(labels) = data.labels.as_mut() {
+		sort_labels(labels, &state.label_order);
+	}
+
+	let title = askama_escape::escape(data.title.as_deref().unwrap_or_default(), askama_escape::Html).to_string();
+	let author = data.user.as_ref().map(|x| x.login.clone()).unwrap_or_default();
+	let updated = data
+		.updated_at
+		.map(|x| x.with_timezone(&tz).format(TIME_FORMAT).to_string())
+		.unwrap_or_default();
+	let created = data
+		.created_at
+		.map(|x| x.with_timezone(&tz).format(TIME_FORMAT).to_string())
+		.unwrap_or_default();
+	let category = category.as_deref().unwrap_or("New");
+	let reserved = if reserved_by.is_some() { "yes" } else { "no" };
+	let estimate = effort::estimate(&state.effort_rules, &data);
+
+	let mut labels = String::new();
+	for label in data.labels.as_deref().unwrap_or_default() {
+		labels += &format!("<li>{}</li>", askama_escape::escape(&label.name, askama_escape::Html));
+	}
+
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += &format!("<title>#{id}: {title}</title>");
+	html += &format!("<link rel='canonical' href='{}'>", state.permalink(&repo, id));
+	html += &format!("<h1><a href='{}'>{repo} #{id}</a>: {title}</h1>", pr_url(&repo, id));
+	html += "<table>";
+	html += &format!(
+		"<tr><td>Author</td><td>{}</td></tr>",
+		askama_escape::escape(&author, askama_escape::Html)
+	);
+	html += &format!("<tr><td>Category</td><td>{category}</td></tr>");
+	html += &format!("<tr><td>Reserved</td><td>{reserved}</td></tr>");
+	html += &format!(
+		"<tr><td>Effort</td><td>{} {}</td></tr>",
+		estimate.bucket(),
+		askama_escape::escape(&estimate.describe_signals(), askama_escape::Html)
+	);
+	html += &format!("<tr><td>Created</td><td>{created}</td></tr>");
+	html += &format!("<tr><td>Last updated</td><td>{updated}</td></tr>");
+	html += "</table>";
+	html += &format!("<h2>Labels</h2><ul>{labels}</ul>");
+	let body = markdown_to_text(body.as_deref().unwrap_or_default());
+	if !body.is_empty() {
+		let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+		html += &format!(
+			"<h2>Description</h2><details><summary>{}</summary><p style='white-space: pre-wrap'>{}</p></details>",
+			escape(&truncate_text(&body, SNIPPET_LENGTH)),
+			escape(&body)
+		);
+	}
+
+	if !history.is_empty() {
+		html += "<h2>Category history</h2><ol>";
+		let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+		for change in &history {
+			let changed_at = parse_utc(&change.changed_at).with_timezone(&tz).format(TIME_FORMAT);
+			html += &format!(
+				"<li>{changed_at}: {} → {} ({})</li>",
+				escape(change.from.as_deref().unwrap_or("New")),
+				escape(change.to.as_deref().unwrap_or("New")),
+				escape(&change.reason)
+			);
+		}
+		html += "</ol>";
+	}
+
+	Ok(Html(html).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use axum::{body::to_bytes, http::HeaderMap};
+
+	use super::*;
+	use crate::tests::{serve_state, test_state};
+
+	#[test]
+	fn markdown_is_reduced_to_text() {
+		assert_eq!(
+			markdown_to_text("## Description\n\n**Bold** and `code`"),
+			"Description\nBold and code"
+		);
+		assert_eq!(
+			markdown_to_text("See [the issue](https://example.com/1) ![logo](x.png) here"),
+			"See the issue  here"
+		);
+		assert_eq!(markdown_to_text("<!-- template -->\n- [x] tested\n"), "- x tested");
+		assert_eq!(markdown_to_text("a < b && c > d"), "a < b && c  d");
+	}
+
+	#[test]
+	fn hostile_markup_is_dropped() {
+		for (markdown, text) in [
+			("<script>alert(1)</script>", "alert(1)"),
+			("<img src=x onerror=alert(1)>", ""),
+			("<scr<script>ipt>alert(1)</script>", "iptalert(1)"),
+			("[click](javascript:alert(1))", "click"),
+			("![x](javascript:alert(1)) <a href=\"javascript:alert(1)\">y</a>", "y"),
+			// unterminated tags and links swallow the rest instead of leaking markup
+			("text <script src=//evil", "text"),
+			("[a](javascript:", "a"),
+		] {
+			assert_eq!(markdown_to_text(markdown), text, "{markdown}");
+		}
+	}
+
+	async fn render(body: &str) -> String {
+		let state = test_state();
+		let body = body.to_owned();
+		state
+			.db
+			.run(move |db: &mut DB| {
+				let tx = db.transaction()?;
+				tx.execute(
+					"INSERT INTO pulls (repo, id, author, last_updated, data, body)
+					VALUES ('NixOS/nixpkgs', 5, 'a', '2024-01-01T00:00:00Z', '{\"number\":5,\"title\":\"foo\"}', ?1)",
+					params![body],
+				)?;
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+		let response = pr_detail(State(state), Path(5), Query(HashMap::new())).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn description_is_escaped() {
+		let html = render("a < b & \"c\" <b>bold</b> '><script>alert(1)</script> <<script>>x").await;
+		let description = &html[html.find("<h2>Description</h2>").unwrap()..];
+		assert!(!description.contains("<script"), "{description}");
+		assert!(!description.contains("<b>"), "{description}");
+		assert!(
+			description.contains("a &#60; b &#38; &#34;c&#34; bold &#39;"),
+			"{description}"
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn long_description_is_cut() {
+		let html = render(&"word ".repeat(10_000)).await;
+		let summary = &html[html.find("<summary>").unwrap() + 9..html.find("</summary>").unwrap()];
+		assert!(summary.chars().count() <= SNIPPET_LENGTH + 1, "{summary}");
+		assert!(summary.ends_with('…'));
+	}
+
+	/// `test_state` tracking these repositories, served under the path of `base_url`.
+	fn configured(repos: &[&str], base_url: Option<&str>) -> AppState {
+		let mut state = test_state();
+		state.repos = Arc::new(repos.iter().map(|x| (*x).to_owned()).collect());
+		state.base_url = base_url.map(|x| x.to_owned());
+		state
+	}
+
+	#[tokio::test]
+	async fn permalinks() {
+		let mut headers = HeaderMap::new();
+		headers.insert(header::HOST, "dash.local:8080".parse().unwrap());
+
+		let state = configured(&["NixOS/nixpkgs"], None);
+		assert_eq!(state.base_path(), "");
+		assert_eq!(state.origin(&headers), "http://dash.local:8080");
+		assert_eq!(state.permalink("NixOS/nixpkgs", 5), "/pr/5");
+
+		let state = configured(&["NixOS/nixpkgs"], Some("https://example.com"));
+		assert_eq!(state.base_path(), "");
+		assert_eq!(state.origin(&headers), "https://example.com");
+		assert_eq!(state.permalink("NixOS/nixpkgs", 5), "/pr/5");
+
+		let state = configured(&["NixOS/nixpkgs", "o/r"], Some("https://example.com/dashboard"));
+		assert_eq!(state.base_path(), "/dashboard");
+		assert_eq!(state.origin(&headers), "https://example.com");
+		assert_eq!(state.permalink("NixOS/nixpkgs", 5), "/dashboard/pr/NixOS/nixpkgs/5");
+		assert_eq!(state.permalink("o/r", 7), "/dashboard/pr/o/r/7");
+	}
+
+	/// Store `NixOS/nixpkgs#5` and `o/r#7`.
+	async fn seed(state: &AppState) {
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				for (repo, id) in [("NixOS/nixpkgs", 5), ("o/r", 7)] {
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data)
+						VALUES (?1, ?2, 'a', '2024-01-01T00:00:00Z', ?3)",
+						params![
+							repo,
+							id,
+							serde_json::json!({ "number": id, "title": "foo" }).to_string()
+						],
+					)?;
+				}
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+	}
+
+	/// Status and `Location` of a request to the dashboard served with this state.
+	async fn get(state: &AppState, path: &str) -> (StatusCode, Option<String>) {
+		let base = serve_state(state.clone()).await;
+		let client = reqwest::Client::builder()
+			.redirect(reqwest::redirect::Policy::none())
+			.build()
+			.unwrap();
+		let response = client.get(format!("{base}{path}")).send().await.unwrap();
+		let location = response
+			.headers()
+			.get(header::LOCATION)
+			.map(|x| x.to_str().unwrap().to_owned());
+		(response.status(), location)
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn old_urls_redirect_to_the_permalink() {
+		let moved = |x: &str| (StatusCode::MOVED_PERMANENTLY, Some(x.to_owned()));
+
+		let state = configured(&["NixOS/nixpkgs"], Some("https://example.com/dashboard"));
+		seed(&state).await;
+		assert_eq!(get(&state, "/pr/5").await, (StatusCode::OK, None));
+		assert_eq!(
+			get(&state, "/pr?id=5&tz=Europe/Berlin").await,
+			moved("/dashboard/pr/5?tz=Europe%2FBerlin")
+		);
+		assert_eq!(get(&state, "/pr/5?repo=NixOS/nixpkgs").await, moved("/dashboard/pr/5"));
+		assert_eq!(get(&state, "/pr/NixOS/nixpkgs/5").await, moved("/dashboard/pr/5"));
+		assert_eq!(get(&state, "/pr/o/r/7").await.0, StatusCode::NOT_FOUND);
+
+		let state = configured(&["NixOS/nixpkgs", "o/r"], None);
+		seed(&state).await;
+		assert_eq!(get(&state, "/pr/o/r/7").await, (StatusCode::OK, None));
+		assert_eq!(get(&state, "/pr/NixOS/nixpkgs/5").await, (StatusCode::OK, None));
+		assert_eq!(get(&state, "/pr/5").await, moved("/pr/NixOS/nixpkgs/5"));
+		assert_eq!(get(&state, "/pr/7?repo=o/r").await, moved("/pr/o/r/7"));
+		assert_eq!(get(&state, "/pr?id=7&repo=o/r").await, moved("/pr/o/r/7"));
+		assert_eq!(get(&state, "/pr/o/r/7?repo=o/r").await, moved("/pr/o/r/7"));
+		assert_eq!(get(&state, "/pr/other/repo/7").await.0, StatusCode::NOT_FOUND);
+		assert_eq!(get(&state, "/pr/7?repo=other/repo").await.0, StatusCode::BAD_REQUEST);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn canonical_link_names_the_repository() {
+		let state = configured(&["NixOS/nixpkgs", "o/r"], Some("https://example.com/dashboard"));
+		seed(&state).await;
+		let response = pr_detail_in_repo(
+			State(state),
+			Path(("o".to_owned(), "r".to_owned(), 7)),
+			Query(HashMap::new()),
+		)
+		.await
+		.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let html = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+		assert!(
+			html.contains("<link rel='canonical' href='/dashboard/pr/o/r/7'>"),
+			"{html}"
+		);
+	}
+}
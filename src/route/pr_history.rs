@@ -0,0 +1,25 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Path, Query, State},
+	Json,
+};
+
+use crate::{
+	database::{category_history, CategoryChange, DB},
+	with_db, AppError, AppState,
+};
+
+/// Category changes of a PR as JSON, oldest first.
+pub async fn pr_history(
+	State(state): State<AppState>,
+	Path(id): Path<u64>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<Vec<CategoryChange>>, AppError> {
+	let repo = state.repo_param(&params)?;
+	let changes = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		category_history(&tx, &repo, id as i64)
+	})?;
+	Ok(Json(changes))
+}
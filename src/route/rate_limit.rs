@@ -0,0 +1,41 @@
+use axum::{extract::State, Json};
+use chrono::DateTime;
+
+use crate::{github, AppError, AppState, UTC_TIME_FORMAT};
+
+/// Remaining GitHub API budget of every client and the configured floor for updates.
+/// The top-level numbers are totals over all clients.
+pub async fn rate_limit(State(state): State<AppState>) -> Result<Json<serde_json::Value>, AppError> {
+	let mut clients = vec![];
+	let (mut limit, mut used, mut remaining) = (0, 0, 0);
+	let mut reset: Option<u64> = None;
+	for client in state.gh.all().await {
+		let rate = github::rate_limit(&client.gh).await?;
+		limit += rate.limit;
+		used += rate.used;
+		remaining += rate.remaining;
+		reset = Some(reset.map_or(rate.reset, |x| x.min(rate.reset)));
+		clients.push(serde_json::json!({
+			"index": client.index,
+			"limit": rate.limit,
+			"used": rate.used,
+			"remaining": rate.remaining,
+			"reset": format_reset(rate.reset),
+		}));
+	}
+	Ok(Json(serde_json::json!({
+		"limit": limit,
+		"used": used,
+		"remaining": remaining,
+		"reset": reset.and_then(format_reset),
+		"clients": clients,
+		"policy": {
+			"floor": state.rate_limit.floor,
+			"wait": state.rate_limit.wait,
+		},
+	})))
+}
+
+fn format_reset(reset: u64) -> Option<String> {
+	DateTime::from_timestamp(reset as i64, 0).map(|x| x.format(UTC_TIME_FORMAT).to_string())
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use axum_client_ip::ClientIp;
+use chrono::Utc;
+use rusqlite::{params, OptionalExtension};
+
+use crate::{
+	database::{restore_category, DB},
+	reserver_identity, with_db, AppError, AppState, AWAITING_REVIEWER, END_RESERVATION_LOG, UTC_TIME_FORMAT,
+};
+
+/// Mark a PR as no longer reserved. Parameters: repo, id.
+/// Its category is restored separately with `restore_category`.
+pub static RELEASE_PULLS: &str = "UPDATE pulls SET reserved_by = NULL WHERE repo = ?1 AND id = ?2";
+
+pub async fn release_pr(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	ClientIp(ip): ClientIp,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let id: i64 = params.get("id").expect("malformed request, requires id").parse()?;
+	let repo = state.repo_param(&params)?;
+	let reserver = reserver_identity(&state, ip, &params, &headers).await?;
+
+	let lock = state.update_lock.lock().await;
+
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	let result = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let holder = tx
+			.query_row(
+				"SELECT reserved_by FROM pulls WHERE repo = ?1 AND id = ?2",
+				params![repo, id],
+				|row| row.get::<_, Option<String>>(0),
+			)
+			.optional()?
+			.flatten();
+		let Some(holder) = holder else {
+			return Ok((StatusCode::OK, format!("PR {id} is not reserved")));
+		};
+		if holder != reserver {
+			return Ok((StatusCode::FORBIDDEN, format!("PR {id} is reserved by someone else")));
+		}
+		tx.execute(
+			"DELETE FROM reservations WHERE repo = ?1 AND id = ?2",
+			params![repo, id],
+		)?;
+		tx.execute(RELEASE_PULLS, params![repo, id])?;
+		restore_category(&tx, &repo, id, AWAITING_REVIEWER, &time, "released")?;
+		tx.execute(END_RESERVATION_LOG, params![repo, id, time, "released"])?;
+		tx.commit()?;
+		Ok((StatusCode::OK, format!("released PR {id}")))
+	})?;
+
+	drop(lock);
+
+	Ok(result.into_response())
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{Html, IntoResponse, Response},
+	Json,
+};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{
+	database::DB, extract_row, parse_since, parse_utc, pr_url, wants_json, with_db, AppError, AppState, TIME_FORMAT,
+	UTC_TIME_FORMAT,
+};
+
+/// Record the end of the open reservation of a PR.
+/// Parameters: repo, id, time (UTC), outcome (`released`, `expired` or `closed`).
+pub static END_RESERVATION_LOG: &str = "UPDATE reservation_log
+	SET released_at = ?3, outcome = ?4
+	WHERE repo = ?1 AND pull_id = ?2 AND released_at IS NULL";
+
+#[derive(Serialize)]
+struct LogEntry {
+	repo: String,
+	pull_id: u64,
+	title: Option<String>,
+	reserved_by: Option<String>,
+	reserved_at: String,
+	/// `None` while the reservation is active.
+	released_at: Option<String>,
+	outcome: Option<String>,
+}
+
+/// Reservations made or ended since `?since=` (default 30 days), as HTML or JSON.
+pub async fn reservation_history(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let tz = state.timezone(&params)?;
+	let since = match parse_since(&params, "30d") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	let since_param = since.format(UTC_TIME_FORMAT).to_string();
+
+	let rows: Vec<_> = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(
+			"SELECT repo, pull_id, title, reserved_by, reserved_at, released_at, outcome
+			FROM reservation_log
+			WHERE reserved_at >= ?1 OR released_at IS NULL OR released_at >= ?1
+			ORDER BY reserved_at DESC",
+		)?;
+		let rows = stmt
+			.query_map(
+				params![since_param],
+				extract_row!(String u64 Option<String> Option<String> String Option<String> Option<String>),
+			)?
+			.map(Result::unwrap)
+			.collect();
+		Ok(rows)
+	})?;
+
+	if wants_json(&params, &headers) {
+		let entries: Vec<_> = rows
+			.into_iter()
+			.map(
+				|(repo, pull_id, title, reserved_by, reserved_at, released_at, outcome)| LogEntry {
+					repo,
+					pull_id,
+					title,
+					reserved_by,
+					reserved_at: parse_utc(&reserved_at).to_rfc3339(),
+					released_at: released_at.map(|x| parse_utc(&x).to_rfc3339()),
+					outcome,
+				},
+			)
+			.collect();
+		return Ok(Json(entries).into_response());
+	}
+
+	let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+	let format_time = |x: &str| parse_utc(x).with_timezone(&tz).format(TIME_FORMAT).to_string();
+
+	// reservations per reserver, by outcome
+	let mut totals: HashMap<&str, [usize; 4]> = HashMap::new();
+	for (_, _, _, reserved_by, _, _, outcome) in &rows {
+		let counts = totals.entry(reserved_by.as_deref().unwrap_or("?")).or_default();
+		let column = match outcome.as_deref() {
+			Some("released") => 1,
+			Some("expired") => 2,
+			Some("closed") => 3,
+			_ => 0,
+		};
+		counts[column] += 1;
+	}
+	let mut totals: Vec<_> = totals.into_iter().collect();
+	totals.sort_by_key(|(name, counts)| (std::cmp::Reverse(counts.iter().sum::<usize>()), *name));
+
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>Reservation history</title>";
+	html += &format!(
+		"<h1>Reservations since {}</h1>",
+		since.with_timezone(&tz).format(TIME_FORMAT)
+	);
+	html += "<table><thead><td>reserved by</td><td>active</td><td>released</td><td>expired</td><td>closed</td><tbody>";
+	for (name, [active, released, expired, closed]) in &totals {
+		html += &format!(
+			"<tr><td>{}</td><td>{active}</td><td>{released}</td><td>{expired}</td><td>{closed}</td>",
+			escape(name)
+		);
+	}
+	html += "</tbody></table>";
+
+	html += "<table><thead><td>PR</td><td>title</td><td>reserved by</td><td>reserved</td><td>ended</td><td>outcome</td><tbody>";
+	for (repo, pull_id, title, reserved_by, reserved_at, released_at, outcome) in &rows {
+		html += &format!(
+			"<tr><td><a href='{}'>{repo}#{pull_id}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>",
+			pr_url(repo, *pull_id),
+			escape(title.as_deref().unwrap_or_default()),
+			escape(reserved_by.as_deref().unwrap_or_default()),
+			format_time(reserved_at),
+			released_at.as_deref().map(format_time).unwrap_or_default(),
+			outcome.as_deref().unwrap_or("active"),
+		);
+	}
+	html += "</tbody></table>";
+
+	Ok(Html(html).into_response())
+}
+
+#[derive(Serialize)]
+struct Stats {
+	since: String,
+	holders: Vec<HolderStats>,
+	/// Average time from reservation to its end, for reservations ended since `since`.
+	average_hold_hours: Option<f64>,
+	released: usize,
+	expired: usize,
+	closed: usize,
+	/// Categories PRs were reserved from, most frequent first.
+	categories: Vec<(String, usize)>,
+}
+
+#[derive(Serialize)]
+struct HolderStats {
+	reserved_by: String,
+	active: usize,
+	/// Age of the oldest active reservation.
+	oldest_hours: f64,
+}
+
+/// Reservation throughput over the last 30 days (or `?since=`), as HTML or JSON.
+pub async fn reservation_stats(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let tz = state.timezone(&params)?;
+	let since = match parse_since(&params, "30d") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	let since_param = since.format(UTC_TIME_FORMAT).to_string();
+
+	let stats = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(
+			"SELECT COALESCE(reserved_by, '?'), COUNT(*), MAX(julianday('now') - julianday(time)) * 24
+			FROM reservations
+			GROUP BY 1
+			ORDER BY 2 DESC, 1",
+		)?;
+		let holders = stmt
+			.query_map([], extract_row!(String usize f64))?
+			.map(|x| {
+				x.map(|(reserved_by, active, oldest_hours)| HolderStats {
+					reserved_by,
+					active,
+					oldest_hours,
+				})
+			})
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+
+		let (average_hold_hours, released, expired, closed) = tx.query_row(
+			"SELECT AVG(julianday(released_at) - julianday(reserved_at)) * 24,
+				COUNT(*) FILTER (WHERE outcome = 'released'),
+				COUNT(*) FILTER (WHERE outcome = 'expired'),
+				COUNT(*) FILTER (WHERE outcome = 'closed')
+			FROM reservation_log WHERE released_at >= ?1",
+			params![since_param],
+			extract_row!(Option<f64> usize usize usize),
+		)?;
+
+		let mut stmt = tx.prepare(
+			"SELECT COALESCE(category, 'New'), COUNT(*)
+			FROM reservation_log WHERE reserved_at >= ?1
+			GROUP BY 1
+			ORDER BY 2 DESC, 1",
+		)?;
+		let categories = stmt
+			.query_map(params![since_param], extract_row!(String usize))?
+			.collect::<Result<_, _>>()?;
+		drop(stmt);
+
+		Ok(Stats {
+			since: since.to_rfc3339(),
+			holders,
+			average_hold_hours,
+			released,
+			expired,
+			closed,
+			categories,
+		})
+	})?;
+
+	if wants_json(&params, &headers) {
+		return Ok(Json(stats).into_response());
+	}
+
+	let escape = |x: &str| askama_escape::escape(x, askama_escape::Html).to_string();
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>Reservation statistics</title>";
+	html += &format!(
+		"<h1>Reservations since {}</h1>",
+		since.with_timezone(&tz).format(TIME_FORMAT)
+	);
+	html += "<h2>Active reservations</h2>";
+	html += "<table><thead><td>reserved by</td><td>active</td><td>oldest (hours)</td><tbody>";
+	for holder in &stats.holders {
+		html += &format!(
+			"<tr><td>{}</td><td>{}</td><td>{:.1}</td>",
+			escape(&holder.reserved_by),
+			holder.active,
+			holder.oldest_hours
+		);
+	}
+	html += "</tbody></table>";
+	html += "<h2>Ended reservations</h2>";
+	html += &format!(
+		"<p>{} released, {} expired unused, {} closed while reserved. Average hold: {}.</p>",
+		stats.released,
+		stats.expired,
+		stats.closed,
+		stats
+			.average_hold_hours
+			.map(|x| format!("{x:.1} hours"))
+			.unwrap_or_else(|| "unknown".to_owned())
+	);
+	html += "<h2>Reserved from</h2>";
+	html += "<table><thead><td>category</td><td>reservations</td><tbody>";
+	for (category, count) in &stats.categories {
+		html += &format!("<tr><td>{}</td><td>{count}</td>", escape(category));
+	}
+	html += "</tbody></table>";
+
+	Ok(Html(html).into_response())
+}
@@ -1,78 +1,503 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, error::Error};
 
-use axum::extract::{Query, State};
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
+};
 use axum_client_ip::ClientIp;
-use chrono::Local;
-use rusqlite::params;
+use chrono::Utc;
+use itertools::Itertools;
+use rusqlite::{params, OptionalExtension, Transaction};
+use serde::Serialize;
 
 use crate::{
-	database::{CommonQueries, DB},
-	extract_row, with_db, AppError, AppState, TIME_FORMAT,
+	database::{CommonQueries, PullQuery, DB, PR},
+	effort, extract_row, notify, parse_duration, parse_timestamp, pr_url, reservation_note, reserver_identity,
+	update_prs, viewer_identity, wants_json, with_db, AppError, AppState, AWAITING_REVIEWER, UTC_TIME_FORMAT,
 };
 
+/// Number of candidates that may be lost to concurrent reservations before giving up.
+const RESERVE_ATTEMPTS: u64 = 3;
+
+/// Label of PRs that can't be merged until the author rebases.
+const MERGE_CONFLICT: &str = "2.status: merge conflict";
+
+#[derive(Serialize)]
+struct ReserveResponse {
+	/// `None` if no PR was available.
+	reserved: Option<Reserved>,
+}
+
+/// Response of a bulk reservation with `?count=`.
+#[derive(Serialize)]
+struct BulkReserveResponse {
+	requested: usize,
+	count: usize,
+	reserved: Vec<Reserved>,
+}
+
+#[derive(Serialize)]
+struct Reserved {
+	repo: String,
+	number: u64,
+	url: String,
+	title: Option<String>,
+	author: Option<String>,
+	labels: Vec<String>,
+	category: String,
+	expires_at: Option<String>,
+}
+
+/// Reserve the given `?pr=` or the next PR of `?category=`, optionally with a `?note=`.
+/// `?repo=` selects the repository of `?pr=`, and restricts the category to one repository.
+/// An https URL given as `?notify=` is notified shortly before and at the expiry.
+/// Responds with the PR URL and details, or JSON if requested by `Accept: application/json`.
+/// With `?count=`, reserves up to that many PRs of the category and responds with their URLs.
+/// PRs with merge conflicts are only handed out by category with `?include-conflicts=true`.
 pub async fn reserve_pr(
 	State(state): State<AppState>,
 	Query(params): Query<HashMap<String, String>>,
 	ClientIp(ip): ClientIp,
-) -> Result<String, AppError> {
-	let cat = params.get("category").expect("malformed request, requires category");
-	let filter = params.get("filter");
-	let exclude = params.get("exclude").map(|x| &**x).unwrap_or_default();
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let number: Option<i64> = params.get("pr").map(|x| x.parse()).transpose()?;
+	let cat = params.get("category");
+	if number.is_none() && cat.is_none() {
+		return Ok((StatusCode::BAD_REQUEST, "malformed request, requires category or pr").into_response());
+	}
+	let repo = state.repo_param(&params)?;
+	let count: Option<usize> = params.get("count").map(|x| x.parse()).transpose()?;
+	if count == Some(0) {
+		return Ok((StatusCode::BAD_REQUEST, "count must be positive").into_response());
+	}
+
+	let mut verdict = state.freshness().await?;
+	if !verdict.caught_up && state.freshness.auto_refresh {
+		tracing::info!("reserve: data is stale, updating first");
+		let params = HashMap::from([("wait".to_owned(), "true".to_owned())]);
+		update_prs(State(state.clone()), Query(params), HeaderMap::new()).await?;
+		verdict = state.freshness().await?;
+	}
+	if !verdict.caught_up {
+		return Ok((StatusCode::CONFLICT, verdict.stale_message()).into_response());
+	}
+
+	let ttl = match params.get("duration") {
+		Some(duration) => match parse_duration(duration) {
+			Some(ttl) if ttl <= state.reservation_max_ttl => ttl,
+			Some(_) => {
+				return Ok((
+					StatusCode::BAD_REQUEST,
+					format!(
+						"duration exceeds the maximum of {} hours",
+						state.reservation_max_ttl.num_hours()
+					),
+				)
+					.into_response())
+			},
+			None => return Ok((StatusCode::BAD_REQUEST, format!("invalid duration: {duration:?}")).into_response()),
+		},
+		None => state.reservation_ttl,
+	};
+
+	let note = reservation_note(&params)?;
+	let notify = notify::notify_url(&params)?;
+	let include_conflicts = params.get("include-conflicts").is_some_and(|x| x == "true");
+	let filter = match PullQuery::from_params(&params) {
+		Ok(filter) => filter,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
 
 	let lock = state.update_lock.lock().await;
 
-	let time = Local::now().naive_local().format(TIME_FORMAT).to_string();
+	let now = Utc::now();
+	let time = now.format(UTC_TIME_FORMAT).to_string();
+	let expires_at = (now + ttl).format(UTC_TIME_FORMAT).to_string();
+	let viewer = viewer_identity(&state, ip).await?;
+	let reserver = reserver_identity(&state, ip, &params, &headers).await?;
 
-	let result = with_db!(|db: &mut DB| {
+	let result = with_db!(state, |db: &mut DB| {
 		let tx = db.transaction()?;
-		let pulls = tx.get_pulls(
-			Some(cat),
-			filter.map(|x| &**x).unwrap_or_default(),
-			exclude,
-			true,
-			true,
-			1,
-		)?;
-		if pulls.is_empty() {
-			return Ok(None);
+
+		// checked in the same transaction as the reservation, so concurrent requests can't exceed the limit
+		let held = held_reservations(&tx, &reserver)?;
+		if held.len() >= state.max_reservations {
+			let held = held
+				.iter()
+				.map(|(repo, id, expires_at)| format!("{repo}#{id} (until {})", expires_at.as_deref().unwrap_or("?")))
+				.join(", ");
+			return Ok(Err((
+				StatusCode::TOO_MANY_REQUESTS,
+				format!(
+					"reservation limit of {} reached, currently holding: {held}",
+					state.max_reservations
+				),
+			)));
 		}
-		let mut query = tx.prepare(&format!(
-			"UPDATE pulls
-			SET reserved_by = ?1
-			WHERE id = ?2
-			RETURNING id"
-		))?;
-		let Some(id) = query
-			.query_map(params![format!("{ip}"), pulls[0].number], extract_row!(usize))?
-			.next()
-			.map(Result::unwrap)
-		else {
-			tracing::debug!("no PR to reserve for category {cat}");
-			return Ok(None);
-		};
-		drop(query);
-
-		let mut query = tx.prepare(
-			"INSERT INTO reservations
-			(id, time)
-			VALUES (?1, ?2)
-			ON CONFLICT DO UPDATE SET time = ?2",
-		)?;
-		let _ = query.query_map(params![id, time], |_row| Ok(()))?.count();
-		drop(query);
 
-		if let Err(e) = tx.commit() {
-			tracing::warn!("error in PR reserve: {e:?}");
+		if let Some(number) = number {
+			let row = tx
+				.query_row(
+					"SELECT pull_data(pulls.data, pulls.data_compressed), category, reservations.expires_at
+					FROM pulls
+					LEFT JOIN reservations ON reservations.repo = pulls.repo AND reservations.id = pulls.id
+					WHERE pulls.repo = ?1 AND pulls.id = ?2 AND pulls.state = 'open'",
+					params![repo, number],
+					extract_row!(String Option<String> Option<String>),
+				)
+				.optional()?;
+			let Some((data, category, reserved_until)) = row else {
+				return Ok(Err((
+					StatusCode::NOT_FOUND,
+					format!("PR {repo}#{number} is not tracked"),
+				)));
+			};
+			if let Some(reserved_until) = reserved_until {
+				return Ok(Err((
+					StatusCode::CONFLICT,
+					format!("PR {repo}#{number} is already reserved until {reserved_until}"),
+				)));
+			}
+			let pr = PR::new(repo.clone(), serde_json::from_str(&data)?, category);
+			#[cfg(test)]
+			tests::before_reserve(&tx, &repo, number)?;
+			let reservation = NewReservation {
+				repo: &repo,
+				id: number,
+				reserver: &reserver,
+				time: &time,
+				expires_at: &expires_at,
+				note: note.as_deref(),
+				notify: notify.as_deref(),
+			};
+			if !reserve(&tx, reservation)? {
+				return Ok(Err((
+					StatusCode::CONFLICT,
+					format!("PR {repo}#{number} is already reserved"),
+				)));
+			}
+			tx.commit()?;
+			return Ok(Ok(vec![pr]));
 		}
 
-		Ok(Some(format!("https://github.com/NixOS/nixpkgs/pull/{id}")))
+		let cat = cat.unwrap();
+		let mut query = filter;
+		if !include_conflicts {
+			query = query.exclude_labels(MERGE_CONFLICT)?;
+		}
+		// partially fulfilled if the limit would be exceeded
+		let wanted = count.unwrap_or(1).min(state.max_reservations - held.len());
+		let query = query
+			.category(Some(cat))
+			.only_unreserved()
+			.not_hidden_for(&viewer)
+			.tweak_sort(true)
+			.limit(wanted as u64 + RESERVE_ATTEMPTS);
+		let mut reserved = vec![];
+		let mut lost = 0;
+		for pr in tx.get_pulls(&query)? {
+			if reserved.len() == wanted || lost == RESERVE_ATTEMPTS {
+				break;
+			}
+			#[cfg(test)]
+			tests::before_reserve(&tx, &pr.repo, pr.number as i64)?;
+			let reservation = NewReservation {
+				repo: &pr.repo,
+				id: pr.number as i64,
+				reserver: &reserver,
+				time: &time,
+				expires_at: &expires_at,
+				note: note.as_deref(),
+				notify: notify.as_deref(),
+			};
+			if reserve(&tx, reservation)? {
+				reserved.push(pr);
+			} else {
+				// reserved by a concurrent request since the query, try the next candidate
+				lost += 1;
+			}
+		}
+		if reserved.is_empty() {
+			tracing::debug!("no PR to reserve for category {cat}");
+			return Ok(Ok(reserved));
+		}
+
+		tx.commit()?;
+
+		Ok(Ok(reserved))
 	})?;
 
 	drop(lock);
 
-	if let Some(result) = result {
-		Ok(result)
-	} else {
-		Ok("".to_owned())
+	let json = wants_json(&params, &headers);
+	let response = match (result, count) {
+		(Err(refusal), _) => refusal.into_response(),
+		(Ok(reserved), Some(requested)) if json => Json(BulkReserveResponse {
+			requested,
+			count: reserved.len(),
+			reserved: reserved.iter().map(|pr| describe_json(pr, &expires_at)).collect(),
+		})
+		.into_response(),
+		// nothing available
+		(Ok(reserved), _) if reserved.is_empty() && !json => StatusCode::NO_CONTENT.into_response(),
+		(Ok(reserved), Some(_)) => reserved
+			.iter()
+			.map(|pr| format!("{}\n", pr_url(&pr.repo, pr.number)))
+			.collect::<String>()
+			.into_response(),
+		(Ok(reserved), None) if json => Json(ReserveResponse {
+			reserved: reserved.first().map(|pr| describe_json(pr, &expires_at)),
+		})
+		.into_response(),
+		(Ok(reserved), None) => describe_reservation(&state, &reserved[0], &expires_at).into_response(),
+	};
+	Ok(response)
+}
+
+/// PRs reserved by `reserver` with the expiry of their reservation.
+fn held_reservations(tx: &Transaction, reserver: &str) -> Result<Vec<(String, i64, Option<String>)>, Box<dyn Error>> {
+	let mut stmt = tx.prepare(
+		"SELECT pulls.repo, pulls.id, reservations.expires_at
+		FROM pulls LEFT JOIN reservations ON reservations.repo = pulls.repo AND reservations.id = pulls.id
+		WHERE pulls.reserved_by = ?1
+		ORDER BY pulls.repo, pulls.id",
+	)?;
+	let rows = stmt
+		.query_map(params![reserver], extract_row!(String i64 Option<String>))?
+		.collect::<Result<_, _>>()?;
+	Ok(rows)
+}
+
+/// A reservation of one PR, as stored by `reserve`.
+struct NewReservation<'a> {
+	repo: &'a str,
+	id: i64,
+	reserver: &'a str,
+	time: &'a str,
+	expires_at: &'a str,
+	note: Option<&'a str>,
+	/// URL notified before the reservation expires.
+	notify: Option<&'a str>,
+}
+
+/// Mark the PR as reserved, move it to AwaitingReviewer and record the reservation in the log.
+/// Returns false if the PR is not tracked or already reserved.
+fn reserve(tx: &Transaction, reservation: NewReservation) -> Result<bool, Box<dyn Error>> {
+	let NewReservation {
+		repo,
+		id,
+		reserver,
+		time,
+		expires_at,
+		note,
+		notify,
+	} = reservation;
+	let now_utc = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	tx.execute(
+		"INSERT INTO category_history (repo, pull_id, from_category, to_category, changed_at, reason)
+		SELECT repo, id, category, ?3, ?4, 'reserved' FROM pulls
+		WHERE repo = ?1 AND id = ?2 AND reserved_by IS NULL AND category IS NOT ?3",
+		params![repo, id, AWAITING_REVIEWER, now_utc],
+	)?;
+	// the reviewer is now working on it
+	let mut query = tx.prepare(
+		"UPDATE pulls
+		SET reserved_by = ?1,
+			prev_category = CASE WHEN category = ?3 THEN prev_category ELSE category END,
+			category = ?3,
+			category_since = CASE WHEN category = ?3 THEN category_since ELSE ?4 END
+		WHERE repo = ?5 AND id = ?2 AND reserved_by IS NULL
+		RETURNING id",
+	)?;
+	let Some(id) = query
+		.query_map(
+			params![reserver, id, AWAITING_REVIEWER, now_utc, repo],
+			extract_row!(usize),
+		)?
+		.next()
+		.map(Result::unwrap)
+	else {
+		return Ok(false);
+	};
+	drop(query);
+
+	// errors abort the transaction, so reserved_by is never set without a reservation
+	tx.execute(
+		"INSERT INTO reservations
+		(repo, id, time, reserved_by, expires_at, note, notify, notified)
+		VALUES (?7, ?1, ?2, ?3, ?4, ?5, ?6, 0)
+		ON CONFLICT DO UPDATE SET time = ?2, reserved_by = ?3, expires_at = ?4, note = ?5, notify = ?6, notified = 0",
+		params![id, time, reserver, expires_at, note, notify, repo],
+	)?;
+
+	tx.execute(
+		"INSERT INTO reservation_log
+		(repo, pull_id, title, reserved_by, reserved_at, category)
+		SELECT repo, id, title, ?2, ?3, prev_category FROM pulls WHERE repo = ?4 AND id = ?1",
+		params![id, reserver, now_utc, repo],
+	)?;
+	Ok(true)
+}
+
+fn describe_json(pr: &PR, expires_at: &str) -> Reserved {
+	Reserved {
+		repo: pr.repo.clone(),
+		number: pr.number,
+		url: pr_url(&pr.repo, pr.number),
+		title: pr.title.clone(),
+		author: pr.user.as_ref().map(|x| x.login.clone()),
+		labels: pr
+			.labels
+			.as_deref()
+			.unwrap_or_default()
+			.iter()
+			.map(|x| x.name.clone())
+			.collect(),
+		category: pr.category.clone().unwrap_or_else(|| "New".to_owned()),
+		expires_at: parse_timestamp(expires_at).map(|x| x.to_rfc3339()),
+	}
+}
+
+/// GitHub URL of the reserved PR, followed by lines with details about it.
+fn describe_reservation(state: &AppState, pr: &PR, expires_at: &str) -> String {
+	let mut response = pr_url(&pr.repo, pr.number);
+	response += &format!("\nreserved until: {expires_at}");
+	let estimate = effort::estimate(&state.effort_rules, pr);
+	response += &format!("\neffort: {}", estimate.bucket());
+	if !estimate.signals.is_empty() {
+		response += &format!(" ({})", estimate.describe_signals());
+	}
+	let reviewers = pr.requested_reviewer_names();
+	if !reviewers.is_empty() {
+		response += &format!("\nrequested reviewers: {}", reviewers.join(", "));
+	}
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::header;
+	use chrono::Duration;
+
+	use super::*;
+	use crate::tests::{seed, serve};
+
+	/// Let `bob` win the race for the next PR to be reserved.
+	fn race_with_bob(db: &mut DB) -> Result<(), Box<dyn Error>> {
+		let tx = db.transaction()?;
+		tx.execute("CREATE TEMP TABLE race_with_bob (armed INTEGER)", [])?;
+		tx.commit()?;
+		Ok(())
+	}
+
+	/// Called by the handler between reading a candidate and reserving it. If armed by `race_with_bob`,
+	/// bob reserves the candidate in a transaction of its own and commits, like a request on another connection.
+	pub(super) fn before_reserve(tx: &Transaction, repo: &str, id: i64) -> Result<(), Box<dyn Error>> {
+		let armed = tx
+			.query_row(
+				"SELECT 1 FROM temp.sqlite_master WHERE name = 'race_with_bob'",
+				[],
+				|_| Ok(()),
+			)
+			.optional()?;
+		if armed.is_none() {
+			return Ok(());
+		}
+		tx.execute_batch(
+			"DROP TABLE temp.race_with_bob;
+			COMMIT;
+			BEGIN;",
+		)?;
+		tx.execute(
+			"UPDATE pulls SET reserved_by = 'bob', prev_category = category, category = ?3
+			WHERE repo = ?1 AND id = ?2",
+			params![repo, id, AWAITING_REVIEWER],
+		)?;
+		let now = Utc::now();
+		tx.execute(
+			"INSERT INTO reservations (repo, id, time, reserved_by, expires_at, notified)
+			VALUES (?1, ?2, ?3, 'bob', ?4, 0)",
+			params![
+				repo,
+				id,
+				now.format(UTC_TIME_FORMAT).to_string(),
+				(now + Duration::hours(1)).format(UTC_TIME_FORMAT).to_string()
+			],
+		)?;
+		// the transaction of the handler goes on from here
+		tx.execute_batch("COMMIT; BEGIN;")?;
+		Ok(())
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn lost_race_takes_the_next_candidate() {
+		let (state, base) = serve().await;
+		seed(&state).await;
+		with_db!(state, race_with_bob).unwrap();
+
+		let response = reqwest::Client::new()
+			.post(format!("{base}/reserve-pr?category=NeedsReviewer&as=alice"))
+			.header(header::ACCEPT, "application/json")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let response: serde_json::Value = response.json().await.unwrap();
+		let alice = response["reserved"]["number"].as_i64().unwrap();
+
+		let (holders, history) = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			assert!(tx.check_consistency()?.is_consistent());
+			let mut stmt = tx.prepare("SELECT id, reserved_by FROM reservations ORDER BY id")?;
+			let holders: Vec<(i64, String)> = stmt
+				.query_map([], extract_row!(i64 String))?
+				.collect::<Result<_, _>>()?;
+			drop(stmt);
+			let history: Vec<i64> = tx
+				.prepare("SELECT pull_id FROM category_history WHERE reason = 'reserved'")?
+				.query_map([], |row| row.get(0))?
+				.collect::<Result<_, _>>()?;
+			Ok((holders, history))
+		})
+		.unwrap();
+		let bob = 3 - alice;
+		let mut expected = vec![(alice, "alice".to_owned()), (bob, "bob".to_owned())];
+		expected.sort();
+		assert_eq!(holders, expected);
+		// bob's reservation was not overwritten, and only alice's is recorded
+		assert_eq!(history, [alice]);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn lost_race_for_a_chosen_pr() {
+		let (state, base) = serve().await;
+		seed(&state).await;
+		with_db!(state, race_with_bob).unwrap();
+
+		let response = reqwest::Client::new()
+			.post(format!("{base}/reserve-pr?pr=1&as=alice"))
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+		assert_eq!(response.text().await.unwrap(), "PR NixOS/nixpkgs#1 is already reserved");
+		// rolling back the request of alice keeps the reservation of bob
+		let holders = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			assert!(tx.check_consistency()?.is_consistent());
+			let mut stmt = tx.prepare(
+				"SELECT pulls.id, pulls.reserved_by, reservations.reserved_by
+				FROM pulls JOIN reservations ON reservations.repo = pulls.repo AND reservations.id = pulls.id",
+			)?;
+			let holders: Vec<(i64, String, String)> = stmt
+				.query_map([], extract_row!(i64 String String))?
+				.collect::<Result<_, _>>()?;
+			Ok(holders)
+		})
+		.unwrap();
+		assert_eq!(holders, [(1, "bob".to_owned(), "bob".to_owned())]);
 	}
 }
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use rusqlite::{params, OptionalExtension};
+
+use crate::{
+	category,
+	database::{DB, LABEL_SET_SQL},
+	with_db, AppError, AppState, AWAITING_REVIEWER, UTC_TIME_FORMAT,
+};
+
+/// Set the category of a PR by hand, `New` to uncategorize it. Housekeeping keeps the category
+/// until the labels of the PR change, or until it is unlocked with `?unlock=true`.
+pub async fn set_category(
+	State(state): State<AppState>,
+	headers: HeaderMap,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	if !state.is_admin(&headers) {
+		return Ok((StatusCode::FORBIDDEN, "admin token required").into_response());
+	}
+	let Some(id) = params.get("id").and_then(|x| x.parse::<i64>().ok()) else {
+		return Ok((StatusCode::BAD_REQUEST, "malformed request, requires id").into_response());
+	};
+	let repo = state.repo_param(&params)?;
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	if params.get("unlock").is_some_and(|x| x == "true") {
+		let rows = with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			let rows = tx.execute(
+				"UPDATE pulls SET category_locked = 0, locked_labels = NULL
+				WHERE repo = ?1 AND id = ?2 AND category_locked",
+				params![repo, id],
+			)?;
+			tx.commit()?;
+			Ok(rows)
+		})?;
+		return Ok(format!("unlocked {rows} PRs").into_response());
+	}
+
+	let Some(category) = params.get("category").filter(|x| !x.is_empty()) else {
+		return Ok((
+			StatusCode::BAD_REQUEST,
+			"malformed request, requires category or unlock",
+		)
+			.into_response());
+	};
+	// reservations manage AwaitingReviewer themselves
+	if category != "New" && (!category::CATEGORIES.contains(&&**category) || category == AWAITING_REVIEWER) {
+		return Ok((StatusCode::BAD_REQUEST, format!("invalid category: {category:?}")).into_response());
+	}
+	let category = Some(category).filter(|x| *x != "New");
+
+	let reserved = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let reserved: Option<bool> = tx
+			.query_row(
+				"SELECT reserved_by IS NOT NULL FROM pulls WHERE repo = ?1 AND id = ?2 AND state = 'open'",
+				params![repo, id],
+				|row| row.get(0),
+			)
+			.optional()?;
+		if reserved != Some(false) {
+			return Ok(reserved);
+		}
+		tx.execute(
+			"INSERT INTO category_history (repo, pull_id, from_category, to_category, changed_at, reason)
+			SELECT repo, id, category, ?3, ?4, 'manual' FROM pulls
+			WHERE repo = ?1 AND id = ?2 AND category IS NOT ?3",
+			params![repo, id, category, time],
+		)?;
+		tx.execute(
+			&format!(
+				"UPDATE pulls SET
+				category_since = CASE WHEN category IS ?3 THEN category_since ELSE ?4 END,
+				category = ?3, category_locked = 1, locked_labels = {LABEL_SET_SQL}
+				WHERE repo = ?1 AND id = ?2"
+			),
+			params![repo, id, category, time],
+		)?;
+		tx.commit()?;
+		Ok(reserved)
+	})?;
+	match reserved {
+		None => Ok((StatusCode::NOT_FOUND, "no such open PR").into_response()),
+		Some(true) => Ok((StatusCode::CONFLICT, "PR is reserved").into_response()),
+		Some(false) => Ok(format!("set category of {repo}#{id}").into_response()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::header;
+	use rusqlite::Transaction;
+
+	use super::*;
+	use crate::{
+		route::{run_housekeep, HousekeepScope},
+		tests::test_state,
+		NEEDS_MERGER, NEEDS_REVIEWER,
+	};
+
+	/// Categorized as needing a reviewer by the default rules.
+	const EVALUATED: &str = "10.rebuild-linux: 1-10";
+
+	fn set_labels(tx: &Transaction, labels: &[&str]) -> rusqlite::Result<usize> {
+		let labels: Vec<_> = labels
+			.iter()
+			.map(|x| serde_json::json!({ "name": x, "color": "ffffff" }))
+			.collect();
+		let data = serde_json::json!({ "number": 1, "title": "pkg1", "labels": labels });
+		tx.execute("UPDATE pulls SET data = ?1 WHERE id = 1", params![data.to_string()])
+	}
+
+	/// Housekeep and return the category of PR 1 and whether it is locked.
+	async fn housekeep(state: &AppState) -> (Option<String>, bool) {
+		run_housekeep(state, false, &HousekeepScope::full()).await.unwrap();
+		with_db!(state, |db: &mut DB| {
+			Ok(db
+				.transaction()?
+				.query_row("SELECT category, category_locked FROM pulls WHERE id = 1", [], |row| {
+					Ok((row.get(0)?, row.get(1)?))
+				})?)
+		})
+		.unwrap()
+	}
+
+	// the database pool blocks in place, which needs the multi-threaded runtime
+	#[tokio::test(flavor = "multi_thread")]
+	async fn lock_ends_when_the_labels_change() {
+		let mut state = test_state();
+		state.admin_token = Some("secret".to_owned());
+		let repo = state.repos[0].clone();
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			tx.execute(
+				"INSERT INTO pulls (repo, id, author, last_updated, data, first_seen) VALUES (?1, 1, 'a', ?2, '{}', ?2)",
+				params![repo, Utc::now().format(UTC_TIME_FORMAT).to_string()],
+			)?;
+			set_labels(&tx, &[EVALUATED, "6.topic: python"])?;
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
+		assert_eq!(housekeep(&state).await, (Some(NEEDS_REVIEWER.to_owned()), false));
+
+		let mut headers = HeaderMap::new();
+		headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+		let params = HashMap::from([
+			("id".to_owned(), "1".to_owned()),
+			("category".to_owned(), NEEDS_MERGER.to_owned()),
+		]);
+		let response = set_category(State(state.clone()), headers, Query(params))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(housekeep(&state).await, (Some(NEEDS_MERGER.to_owned()), true));
+
+		// the same labels in another order
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			set_labels(&tx, &["6.topic: python", EVALUATED])?;
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
+		assert_eq!(housekeep(&state).await, (Some(NEEDS_MERGER.to_owned()), true));
+
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			set_labels(&tx, &[EVALUATED])?;
+			tx.commit()?;
+			Ok(())
+		})
+		.unwrap();
+		assert_eq!(housekeep(&state).await, (Some(NEEDS_REVIEWER.to_owned()), false));
+	}
+}
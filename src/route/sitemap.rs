@@ -0,0 +1,112 @@
+use axum::{
+	extract::State,
+	http::{header, HeaderMap},
+	response::IntoResponse,
+};
+
+use crate::{database::DB, extract_row, with_db, AppError, AppState};
+
+/// Maximum number of URLs in a single sitemap file.
+const SITEMAP_LIMIT: usize = 50_000;
+
+pub async fn sitemap(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, AppError> {
+	let origin = state.origin(&headers);
+
+	// PRs of repositories no longer tracked have no permalink
+	let repos = serde_json::to_string(&*state.repos)?;
+	let pulls: Vec<_> = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(&format!(
+			"SELECT repo, id, last_updated FROM pulls
+			WHERE state = 'open' AND repo IN (SELECT value FROM json_each(?1))
+			ORDER BY last_updated DESC LIMIT {SITEMAP_LIMIT}"
+		))?;
+		let rows = stmt
+			.query_map([repos], extract_row!(String u64 String))?
+			.map(Result::unwrap)
+			.collect();
+		Ok(rows)
+	})?;
+
+	let mut xml = String::new();
+	xml += r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+	xml += r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#;
+	for (repo, id, last_updated) in pulls {
+		// stored as UTC, the date part is all a sitemap needs
+		let lastmod = last_updated.get(0..10).unwrap_or_default();
+		xml += &format!(
+			"<url><loc>{origin}{}</loc><lastmod>{lastmod}</lastmod></url>",
+			askama_escape::escape(&state.permalink(&repo, id), askama_escape::Html)
+		);
+	}
+	xml += "</urlset>";
+
+	Ok(([(header::CONTENT_TYPE, "application/xml")], xml))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use axum::body::to_bytes;
+	use rusqlite::params;
+
+	use super::*;
+	use crate::tests::test_state;
+
+	async fn render(repos: &[&str], base_url: Option<&str>) -> String {
+		let mut state = test_state();
+		state.repos = Arc::new(repos.iter().map(|x| (*x).to_owned()).collect());
+		state.base_url = base_url.map(|x| x.to_owned());
+		state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				for (repo, id, last_updated, state) in [
+					("NixOS/nixpkgs", 5, "2024-01-02T03:04:05Z", "open"),
+					("o/r", 7, "2024-02-03T23:59:59Z", "open"),
+					("o/r", 8, "2024-03-01T00:00:00Z", "closed"),
+				] {
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, state)
+						VALUES (?1, ?2, 'a', ?3, ?4, ?5)",
+						params![repo, id, last_updated, format!("{{\"number\":{id}}}"), state],
+					)?;
+				}
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+		let mut headers = HeaderMap::new();
+		headers.insert(header::HOST, "dash.local".parse().unwrap());
+		let response = sitemap(State(state), headers).await.unwrap().into_response();
+		assert_eq!(response.headers()[header::CONTENT_TYPE], "application/xml");
+		String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn lists_open_prs_by_permalink() {
+		let xml = render(&["NixOS/nixpkgs"], None).await;
+		assert!(
+			xml.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?><urlset"#),
+			"{xml}"
+		);
+		// PRs of repositories no longer tracked are left out
+		assert!(
+			xml.ends_with("<url><loc>http://dash.local/pr/5</loc><lastmod>2024-01-02</lastmod></url></urlset>"),
+			"{xml}"
+		);
+		assert_eq!(xml.matches("<url>").count(), 1);
+
+		let xml = render(&["NixOS/nixpkgs", "o/r"], Some("https://example.com/dashboard")).await;
+		let urls: Vec<_> = xml.split("<url>").skip(1).collect();
+		assert_eq!(
+			urls,
+			[
+				"<loc>https://example.com/dashboard/pr/o/r/7</loc><lastmod>2024-02-03</lastmod></url>",
+				"<loc>https://example.com/dashboard/pr/NixOS/nixpkgs/5</loc><lastmod>2024-01-02</lastmod></url></urlset>",
+			]
+		);
+	}
+}
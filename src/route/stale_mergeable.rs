@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	response::{Html, IntoResponse, Response},
+};
+use chrono::{Days, Utc};
+use rusqlite::params;
+
+use crate::{database::DB, extract_row, parse_utc, pr_url, with_db, AppError, AppState, NEEDS_MERGER, UTC_TIME_FORMAT};
+
+static REPORT: &str = "stale-mergeable";
+
+/// Oldest NeedsMerger PRs exceeding the configured SLA.
+/// `?format=text` renders a plain message suitable for posting in chat.
+pub async fn stale_mergeable(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let now = Utc::now();
+	let threshold = now
+		.checked_sub_days(Days::new(state.merger_sla_days as u64))
+		.unwrap()
+		.format(UTC_TIME_FORMAT)
+		.to_string();
+	let week = now.format("%G-W%V").to_string();
+	let limit = state.merger_report_count;
+
+	let rows = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut query = tx.prepare(
+			"SELECT repo, id, title, category_since
+			FROM pulls
+			WHERE state = 'open' AND category = ?1 AND category_since < ?2
+			ORDER BY category_since ASC
+			LIMIT ?3",
+		)?;
+		let pulls: Vec<_> = query
+			.query_map(
+				params![NEEDS_MERGER, threshold, limit],
+				extract_row!(String u64 Option<String> String),
+			)?
+			.map(Result::unwrap)
+			.collect();
+		drop(query);
+
+		// one row per week a PR was listed
+		let mut rows = vec![];
+		for (repo, id, title, since) in pulls {
+			tx.execute(
+				"INSERT INTO report_inclusions
+				(report, repo, pull_id, week)
+				VALUES (?1, ?2, ?3, ?4)
+				ON CONFLICT DO NOTHING",
+				params![REPORT, repo, id, week],
+			)?;
+			let weeks = tx.query_row(
+				"SELECT COUNT(*) FROM report_inclusions WHERE report = ?1 AND repo = ?2 AND pull_id = ?3",
+				params![REPORT, repo, id],
+				|row| row.get::<_, usize>(0),
+			)?;
+			rows.push((repo, id, title.unwrap_or_default(), since, weeks));
+		}
+		// forget PRs that left the queue
+		tx.execute(
+			"DELETE FROM report_inclusions
+			WHERE report = ?1 AND (repo, pull_id) NOT IN (SELECT repo, id FROM pulls WHERE state = 'open' AND category = ?2)",
+			params![REPORT, NEEDS_MERGER],
+		)?;
+		tx.commit()?;
+		Ok(rows)
+	})?;
+
+	let days_waiting = |since: &str| {
+		if since.is_empty() {
+			0
+		} else {
+			(now - parse_utc(since)).num_days()
+		}
+	};
+
+	if params.get("format").map(|x| x == "text").unwrap_or(false) {
+		let mut text = format!(
+			"{} NeedsMerger PRs waiting longer than {} days:\n",
+			rows.len(),
+			state.merger_sla_days
+		);
+		for (repo, id, title, since, weeks) in &rows {
+			text += &format!(
+				"- {} {title} ({} days){}\n",
+				pr_url(repo, *id),
+				days_waiting(since),
+				recurrence(*weeks)
+			);
+		}
+		return Ok(text.into_response());
+	}
+
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>Stale mergeable PRs</title>";
+	html += &format!(
+		"<h1>NeedsMerger PRs waiting longer than {} days</h1>",
+		state.merger_sla_days
+	);
+	if rows.is_empty() {
+		html += "<p>Nothing to report.</p>";
+	}
+	html += "<table><thead><td>PR</td><td>title</td><td>days in queue</td><td></td></thead><tbody>";
+	for (repo, id, title, since, weeks) in &rows {
+		html += &format!(
+			"<tr><td><a href='{}'>{repo}#{id}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+			state.permalink(repo, *id),
+			askama_escape::escape(title, askama_escape::Html),
+			days_waiting(since),
+			recurrence(*weeks)
+		);
+	}
+	html += "</tbody></table>";
+
+	Ok(Html(html).into_response())
+}
+
+fn recurrence(weeks: usize) -> String {
+	if weeks <= 1 {
+		return String::new();
+	}
+	let suffix = match (weeks % 10, weeks % 100) {
+		(_, 11..=13) => "th",
+		(1, _) => "st",
+		(2, _) => "nd",
+		(3, _) => "rd",
+		_ => "th",
+	};
+	format!(" ({weeks}{suffix} week on this list)")
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::body::to_bytes;
+	use chrono::Duration;
+
+	use super::*;
+	use crate::{tests::test_state, NEEDS_REVIEWER};
+
+	#[test]
+	fn recurrence_suffix() {
+		assert_eq!(recurrence(0), "");
+		assert_eq!(recurrence(1), "");
+		assert_eq!(recurrence(2), " (2nd week on this list)");
+		assert_eq!(recurrence(3), " (3rd week on this list)");
+		assert_eq!(recurrence(4), " (4th week on this list)");
+		for weeks in [11, 12, 13, 111] {
+			assert_eq!(recurrence(weeks), format!(" ({weeks}th week on this list)"));
+		}
+		assert_eq!(recurrence(21), " (21st week on this list)");
+		assert_eq!(recurrence(102), " (102nd week on this list)");
+	}
+
+	async fn report(state: &AppState) -> String {
+		let params = HashMap::from([("format".to_owned(), "text".to_owned())]);
+		let response = stale_mergeable(State(state.clone()), Query(params)).await.unwrap();
+		String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn lists_the_queue_beyond_the_threshold() {
+		let mut state = test_state();
+		state.merger_sla_days = 30;
+		state.merger_report_count = 3;
+		let now = Utc::now();
+		state
+			.db
+			.run(move |db: &mut DB| {
+				let tx = db.transaction()?;
+				for (id, category, days, open) in [
+					// waiting the shortest, beyond the number of listed PRs
+					(1, NEEDS_MERGER, 31, true),
+					(2, NEEDS_MERGER, 29, true),
+					(3, NEEDS_MERGER, 90, true),
+					(4, NEEDS_REVIEWER, 90, true),
+					(5, NEEDS_MERGER, 60, false),
+					(6, NEEDS_MERGER, 45, true),
+					(7, NEEDS_MERGER, 35, true),
+				] {
+					let since = (now - Duration::days(days)).format(UTC_TIME_FORMAT).to_string();
+					tx.execute(
+						"INSERT INTO pulls (repo, id, author, last_updated, data, title, category, category_since, state)
+						VALUES ('NixOS/nixpkgs', ?1, 'a', ?2, ?3, ?4, ?5, ?2, ?6)",
+						params![
+							id,
+							since,
+							format!("{{\"number\":{id}}}"),
+							format!("pkg{id}"),
+							category,
+							if open { "open" } else { "closed" }
+						],
+					)?;
+				}
+				// listed in the two weeks before, and a PR that left the queue
+				tx.execute_batch(
+					"INSERT INTO report_inclusions (report, repo, pull_id, week) VALUES
+						('stale-mergeable', 'NixOS/nixpkgs', 3, '2000-W01'),
+						('stale-mergeable', 'NixOS/nixpkgs', 3, '2000-W02'),
+						('stale-mergeable', 'NixOS/nixpkgs', 4, '2000-W02');",
+				)?;
+				tx.commit()?;
+				Ok(())
+			})
+			.await
+			.unwrap();
+
+		let url = |id: u64| pr_url("NixOS/nixpkgs", id);
+		let expected = format!(
+			"3 NeedsMerger PRs waiting longer than 30 days:\n\
+			- {} pkg3 (90 days) (3rd week on this list)\n\
+			- {} pkg6 (45 days)\n\
+			- {} pkg7 (35 days)\n",
+			url(3),
+			url(6),
+			url(7)
+		);
+		assert_eq!(report(&state).await, expected);
+		// the same week counts once
+		assert_eq!(report(&state).await, expected);
+
+		let inclusions = state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				let mut stmt = tx.prepare("SELECT pull_id, COUNT(*) FROM report_inclusions GROUP BY pull_id")?;
+				let rows: Vec<_> = stmt.query_map([], extract_row!(u64 usize))?.collect::<Result<_, _>>()?;
+				Ok(rows)
+			})
+			.await
+			.unwrap();
+		assert_eq!(inclusions, [(3, 3), (6, 1), (7, 1)]);
+	}
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::{Html, IntoResponse, Response},
+	Json,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{
+	database::{CategoryCounts, DB},
+	extract_row, parse_since, parse_utc, with_db, AppError, AppState, TIME_FORMAT, UTC_TIME_FORMAT,
+};
+
+/// Size of the chart on `/stats`, in pixels.
+const CHART_WIDTH: f64 = 800.0;
+const CHART_HEIGHT: f64 = 300.0;
+/// Line colors, in the order of `CategoryCounts::entries`.
+const COLORS: [&str; 8] = [
+	"#0969da", "#bf8700", "#cf222e", "#8250df", "#1a7f37", "#6e7781", "#bc4c00", "#57606a",
+];
+
+#[derive(Serialize)]
+struct Series {
+	category: String,
+	points: Vec<Point>,
+}
+
+#[derive(Serialize)]
+struct Point {
+	time: DateTime<Utc>,
+	count: usize,
+}
+
+/// Number of open PRs per category since `?since=` (default 90 days), as recorded by housekeeping.
+pub async fn api_stats(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let since = match parse_since(&params, "90d") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	Ok(Json(load_series(&state, &since).await?).into_response())
+}
+
+/// Chart and table of the number of open PRs per category since `?since=` (default 90 days).
+pub async fn stats(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let tz = state.timezone(&params)?;
+	let since = match parse_since(&params, "90d") {
+		Ok(since) => since,
+		Err(msg) => return Ok((StatusCode::BAD_REQUEST, msg).into_response()),
+	};
+	let series = load_series(&state, &since).await?;
+
+	let mut html = String::new();
+	html += "<!DOCTYPE html>";
+	html += "<meta charset='utf-8'>";
+	html += "<title>PR backlog</title>";
+	html += &format!(
+		"<h1>Open PRs per category since {}</h1>",
+		since.with_timezone(&tz).format(TIME_FORMAT)
+	);
+	if series.is_empty() {
+		html += "<p>No snapshots yet, they are taken by each housekeeping.</p>";
+		return Ok(Html(html).into_response());
+	}
+
+	let now = Utc::now();
+	let span = (now - since).num_seconds().max(1) as f64;
+	let max = series
+		.iter()
+		.flat_map(|x| &x.points)
+		.map(|x| x.count)
+		.max()
+		.unwrap_or(0)
+		.max(1) as f64;
+	html += &format!(
+		"<svg width='{CHART_WIDTH}' height='{CHART_HEIGHT}' viewBox='0 0 {CHART_WIDTH} {CHART_HEIGHT}' style='border: 1px solid #d0d7de'>"
+	);
+	for x in &series {
+		let points: Vec<_> = x
+			.points
+			.iter()
+			.map(|point| {
+				let x = (point.time - since).num_seconds() as f64 / span * CHART_WIDTH;
+				let y = CHART_HEIGHT - point.count as f64 / max * (CHART_HEIGHT - 10.0);
+				format!("{x:.1},{y:.1}")
+			})
+			.collect();
+		html += &format!(
+			"<polyline fill='none' stroke='{}' stroke-width='2' points='{}'><title>{}</title></polyline>",
+			color(&x.category),
+			points.join(" "),
+			x.category
+		);
+	}
+	html += &format!("<text x='4' y='14' font-size='12'>{max}</text>");
+	html += "</svg>";
+	html += "<p>";
+	for x in &series {
+		html += &format!(
+			"<span style='color: {}'>■</span> {} ({}) ",
+			color(&x.category),
+			x.category,
+			x.points.last().map(|x| x.count).unwrap_or(0)
+		);
+	}
+	html += "</p>";
+
+	// one row per day, with the last snapshot of the day
+	let mut days: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+	for (i, x) in series.iter().enumerate() {
+		for point in &x.points {
+			let day = point.time.with_timezone(&tz).format("%Y-%m-%d").to_string();
+			days.entry(day).or_insert_with(|| vec![0; series.len()])[i] = point.count;
+		}
+	}
+	html += "<table><thead><td>day</td>";
+	for x in &series {
+		html += &format!("<td>{}</td>", x.category);
+	}
+	html += "</thead><tbody>";
+	for (day, counts) in days.iter().rev() {
+		html += &format!("<tr><td>{day}</td>");
+		for count in counts {
+			html += &format!("<td>{count}</td>");
+		}
+		html += "</tr>";
+	}
+	html += "</tbody></table>";
+
+	Ok(Html(html).into_response())
+}
+
+/// Snapshots of all PRs since the given time, one series per category that had PRs,
+/// in the order of `CategoryCounts::entries`.
+async fn load_series(state: &AppState, since: &DateTime<Utc>) -> Result<Vec<Series>, AppError> {
+	let since_param = since.format(UTC_TIME_FORMAT).to_string();
+	let rows: Vec<(String, String, usize)> = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		let mut stmt = tx.prepare(
+			"SELECT category, taken_at, count FROM count_snapshots
+			WHERE filter = '' AND taken_at >= ?1
+			ORDER BY taken_at ASC",
+		)?;
+		let rows = stmt
+			.query_map(params![since_param], extract_row!(String String usize))?
+			.collect::<Result<_, _>>()?;
+		Ok(rows)
+	})?;
+
+	let mut series: Vec<Series> = CategoryCounts::default()
+		.entries()
+		.iter()
+		.map(|(category, _)| Series {
+			category: (*category).to_owned(),
+			points: vec![],
+		})
+		.collect();
+	for (category, taken_at, count) in rows {
+		let Some(x) = series.iter_mut().find(|x| x.category == category) else {
+			continue;
+		};
+		x.points.push(Point {
+			time: parse_utc(&taken_at),
+			count,
+		});
+	}
+	series.retain(|x| x.points.iter().any(|x| x.count > 0));
+	Ok(series)
+}
+
+fn color(category: &str) -> &'static str {
+	CategoryCounts::default()
+		.entries()
+		.iter()
+		.position(|x| x.0 == category)
+		.map(|x| COLORS[x])
+		.unwrap_or("#000000")
+}
@@ -0,0 +1,27 @@
+use axum::{extract::State, Json};
+
+use crate::{AppError, AppState};
+
+/// Freshness policy and whether the instance is currently caught up.
+pub async fn status(State(state): State<AppState>) -> Result<Json<serde_json::Value>, AppError> {
+	let verdict = state.freshness().await?;
+	Ok(Json(serde_json::json!({
+		"caught_up": verdict.caught_up,
+		"last_successful_update": verdict.last_sync,
+		"data_age_seconds": verdict.age.map(|x| x.num_seconds()),
+		"policy": {
+			"max_age_seconds": state.freshness.max_age.num_seconds(),
+			"auto_refresh": state.freshness.auto_refresh,
+		},
+	})))
+}
+
+/// Outcome of the last run of the built-in scheduler.
+pub async fn last_update_status(State(state): State<AppState>) -> Json<serde_json::Value> {
+	let last_run = state.last_scheduled_run.read().await.clone();
+	Json(serde_json::json!({
+		"enabled": state.update_interval.is_some(),
+		"interval_seconds": state.update_interval.map(|x| x.as_secs()),
+		"last_run": last_run,
+	}))
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Response},
+	Json,
+};
+use chrono::Utc;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::{
+	database::{StoredPr, DB},
+	github::GithubError,
+	store_pull, with_db, AppError, AppState, UTC_TIME_FORMAT,
+};
+
+#[derive(Serialize)]
+struct Refreshed {
+	repo: String,
+	id: u64,
+	/// The PR was closed and is no longer tracked.
+	removed: bool,
+	old_category: Option<String>,
+	new_category: Option<String>,
+	labels_added: Vec<String>,
+	labels_removed: Vec<String>,
+}
+
+fn label_names(pr: &StoredPr) -> Vec<String> {
+	pr.labels
+		.as_deref()
+		.unwrap_or_default()
+		.iter()
+		.map(|x| x.name.clone())
+		.collect()
+}
+
+/// Fetch a single PR from GitHub and apply it like `update_prs` would.
+pub async fn update_pr(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+	let id: u64 = params.get("id").expect("malformed request, requires id").parse()?;
+	let repo = state.repo_param(&params)?;
+	let (owner, name) = repo.split_once('/').expect("repositories are validated on startup");
+
+	let mut client = state.gh.pick().await;
+	tracing::debug!("update: using GitHub client {} for {repo}#{id}", client.index);
+	let result = state
+		.gh
+		.with_retry(state.github_retries, &mut client, |gh| async move {
+			gh.pulls(owner, name).get(id).await
+		})
+		.await;
+	let pr = match result {
+		Ok(pr) => pr,
+		Err(GithubError {
+			source: octocrab::Error::GitHub { source, .. },
+			..
+		}) if source.status_code == StatusCode::NOT_FOUND => {
+			return Ok((StatusCode::NOT_FOUND, format!("PR {id} not found in {repo}")).into_response());
+		},
+		Err(err) => {
+			tracing::warn!("update: failed to load {repo}#{id}: {err}");
+			return Err(err.into());
+		},
+	};
+
+	let _lock = state.update_lock.lock().await;
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	let (old_category, old_labels, new_category, removed) = with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		// the data is NULL if it fails to decompress, the refetch replaces it
+		let old: Option<(Option<String>, Option<String>)> = tx
+			.query_row(
+				"SELECT category, pull_data(data, data_compressed) FROM pulls WHERE repo = ?1 AND id = ?2",
+				params![repo, id],
+				|row| Ok((row.get(0)?, row.get(1)?)),
+			)
+			.optional()?;
+		store_pull(&tx, &state, &repo, &pr, &time)?;
+		let new: Option<Option<String>> = tx
+			.query_row(
+				"SELECT category FROM pulls WHERE repo = ?1 AND id = ?2 AND state = 'open'",
+				params![repo, id],
+				|row| row.get(0),
+			)
+			.optional()?;
+		tx.commit()?;
+
+		let (old_category, old_labels) = match old {
+			Some((category, Some(data))) => (category, label_names(&serde_json::from_str(&data)?)),
+			Some((category, None)) => (category, vec![]),
+			None => (None, vec![]),
+		};
+		Ok((old_category, old_labels, new.clone().flatten(), new.is_none()))
+	})?;
+
+	let new_labels = label_names(&StoredPr::from(&pr));
+	Ok(Json(Refreshed {
+		labels_added: new_labels.iter().filter(|x| !old_labels.contains(x)).cloned().collect(),
+		labels_removed: old_labels.iter().filter(|x| !new_labels.contains(x)).cloned().collect(),
+		repo,
+		id,
+		removed,
+		old_category,
+		new_category,
+	})
+	.into_response())
+}
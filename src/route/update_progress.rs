@@ -0,0 +1,23 @@
+use axum::{
+	extract::State,
+	response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream};
+
+use crate::AppState;
+
+/// Stream the progress of the running update as server-sent events.
+/// The latest state is sent on connect, the stream ends once no update is running.
+pub async fn update_progress(State(state): State<AppState>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+	let rx = state.update_progress.subscribe();
+	let stream = stream::unfold(Some((rx, true)), |next| async move {
+		let (mut rx, first) = next?;
+		if !first && rx.changed().await.is_err() {
+			return None;
+		}
+		let progress = rx.borrow_and_update().clone();
+		let event = Event::default().event(progress.event()).json_data(&progress);
+		Some((event, progress.running.then_some((rx, false))))
+	});
+	Sse::new(stream).keep_alive(KeepAlive::default())
+}
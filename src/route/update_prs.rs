@@ -1,13 +1,26 @@
-use std::rc::Rc;
+use std::{
+	collections::{HashMap, HashSet},
+	error::Error,
+	time::Instant,
+};
 
-use axum::extract::State;
-use octocrab::{
-	models::IssueState,
-	params::{pulls::Sort, Direction},
+use axum::{
+	extract::{Query, State},
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Json,
 };
-use rusqlite::{params, params_from_iter};
+use chrono::{DateTime, Utc};
+use octocrab::models::{pulls::PullRequest, IssueState};
+use rusqlite::{params, params_from_iter, OptionalExtension, Transaction};
+use serde::Serialize;
 
-use crate::{database::DB, with_db, AppError, AppState, TIME_FORMAT};
+use crate::{
+	categorize_pull, categorize_updated,
+	database::{listing_etag_key, sync_cursor_key, StoredPr, DB},
+	effort, github, parse_duration, parse_timestamp, wants_json, with_db, AppError, AppState, HousekeepSummary,
+	END_RESERVATION_LOG, UTC_TIME_FORMAT,
+};
 
 /*
 TODO: consider GraphQL to get more accurate last updated
@@ -29,103 +42,865 @@ query {
 }
 */
 
-pub async fn update_prs(State(state): State<AppState>) -> Result<&'static str, AppError> {
-	let update_lock = state.update_lock.lock().await;
-	let last_update = with_db!(|db: &mut DB| db.last_update())?;
-	let gh = state.gh.read().await;
+/// Longest PR description kept, in bytes.
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+/// A full update that would purge more than half of the tracked PRs of a repository, and at least this many,
+/// more likely got a listing cut short by GitHub than missed that many closures, so nothing is purged.
+const PURGE_GUARD_MIN: usize = 10;
 
-	// if we already have some data, we need to catch and remov eclosed PRs too
-	let state = if last_update.is_some() {
-		octocrab::params::State::All
-	} else {
-		octocrab::params::State::Open
+/// Insert or update a PR, with the values returned by `pull_row`.
+/// A reopened PR is revived from its tombstone, keeping its category.
+/// `first_seen` is only set on insert. The data is compressed if a compression level is given.
+pub static UPSERT_PULL: &str = "INSERT INTO pulls
+	(repo,id,author,last_updated,data,milestone,effort,author_association,body,title,data_compressed,first_seen)
+	VALUES (?1,?2,?3,?4,IIF(?11 IS NULL, ?5, ''),?6,?7,?8,?9,?10,compress_data(?5, CAST(?11 AS INTEGER)),strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+	ON CONFLICT DO UPDATE SET
+	author = ?3,
+	last_updated = ?4,
+	data = IIF(?11 IS NULL, ?5, ''),
+	data_compressed = compress_data(?5, CAST(?11 AS INTEGER)),
+	milestone = ?6,
+	effort = ?7,
+	author_association = ?8,
+	body = ?9,
+	title = ?10,
+	state = 'open',
+	closed_at = NULL";
+
+/// Remember a closed PR before it is deleted. Parameters: repo, id, data, merged, time.
+pub static RECORD_DEPARTURE: &str = "INSERT INTO departures
+	(repo, pull_id, data, merged, time)
+	SELECT ?1, ?2, ?3, ?4, ?5 WHERE EXISTS (SELECT 1 FROM pulls WHERE repo = ?1 AND id = ?2 AND state = 'open')
+	ON CONFLICT DO NOTHING";
+
+/// Remember whether a tracked PR was merged or closed, before it is deleted.
+/// Parameters: repo, id, data, merged, time (the fallback if the data has no `closed_at`).
+pub static RECORD_OUTCOME: &str = "INSERT INTO pull_outcomes
+	(repo, id, title, author, outcome, closed_at, last_category, was_reserved_by)
+	SELECT ?1, ?2, json_extract(?3, '$.title'), author, IIF(?4, 'merged', 'closed'),
+		COALESCE(strftime('%Y-%m-%dT%H:%M:%SZ', json_extract(?3, '$.closed_at')), ?5), category, reserved_by
+	FROM pulls WHERE repo = ?1 AND id = ?2 AND state = 'open'
+	ON CONFLICT DO NOTHING";
+
+/// Values for `UPSERT_PULL`, `None` if the PR has no author.
+pub fn pull_row(
+	state: &AppState,
+	repo: &str,
+	pr: &PullRequest,
+) -> Result<Option<Vec<Option<String>>>, serde_json::Error> {
+	let Some(author) = pr.user.as_ref() else {
+		return Ok(None);
 	};
+	let author = author.login.clone();
+	// last_updated is required, PRs without an update time are stored with their creation time
+	let updated_at = pr
+		.updated_at
+		.or(pr.created_at)
+		.map(|x| x.format(UTC_TIME_FORMAT).to_string());
+	let milestone = pr.milestone.as_ref().map(|x| x.title.clone());
+	let stored = StoredPr::from(pr);
+	let effort = effort::estimate(&state.effort_rules, &stored)
+		.effort
+		.map(|x| x.to_string());
+	let data = serde_json::to_string(&stored)?;
+	// stored like GitHub sends it, e.g. `FIRST_TIME_CONTRIBUTOR`
+	let author_association = match &pr.author_association {
+		Some(x) => serde_json::to_value(x)?.as_str().map(|x| x.to_owned()),
+		None => None,
+	};
+	Ok(Some(vec![
+		Some(repo.to_owned()),
+		Some(pr.number.to_string()),
+		Some(author),
+		updated_at,
+		Some(data),
+		milestone,
+		effort,
+		author_association,
+		pr.body.as_deref().map(|x| truncate_body(x).to_owned()),
+		pr.title.clone(),
+		state.compression_level.map(|x| x.to_string()),
+	]))
+}
 
-	let mut pulls = vec![];
-	let mut to_remove = vec![];
-	'pages: for page in 1u32.. {
-		let prs = gh
-			.pulls("NixOS", "nixpkgs")
-			.list()
-			.sort(Sort::Updated)
-			.direction(Direction::Descending)
-			.state(state)
-			.per_page(100)
-			.page(page)
-			.send()
-			.await?;
-		tracing::debug!("update: loading page {page}");
-		if prs.items.is_empty() {
-			break;
+/// Cut a PR description to at most `MAX_BODY_LENGTH` bytes, at a character boundary.
+fn truncate_body(body: &str) -> &str {
+	let mut end = body.len().min(MAX_BODY_LENGTH);
+	while !body.is_char_boundary(end) {
+		end -= 1;
+	}
+	&body[..end]
+}
+
+/// Mark a closed PR as a tombstone, freeing its reservation first.
+pub fn remove_pull(tx: &Transaction, repo: &str, id: i64, time: &str) -> rusqlite::Result<()> {
+	// free reservations of closed PRs
+	let released = tx.execute(
+		"DELETE FROM reservations WHERE repo = ?1 AND id = ?2",
+		params![repo, id],
+	)?;
+	if released > 0 {
+		tracing::debug!("update: released the reservation of closed PR {repo}#{id}");
+		tx.execute(END_RESERVATION_LOG, params![repo, id, time, "closed"])?;
+	}
+	tx.execute(
+		"UPDATE pulls SET state = 'closed', closed_at = ?3, reserved_by = NULL
+		WHERE repo = ?1 AND id = ?2 AND state = 'open'",
+		params![repo, id, time],
+	)?;
+	Ok(())
+}
+
+/// Apply the current state of a single PR: closed PRs are recorded and removed,
+/// others are upserted and categorized.
+pub fn store_pull(
+	tx: &Transaction,
+	state: &AppState,
+	repo: &str,
+	pr: &PullRequest,
+	time: &str,
+) -> Result<(), Box<dyn Error>> {
+	let id = pr.number as i64;
+	if pr.state.as_ref().map(|x| *x == IssueState::Closed).unwrap_or(false) {
+		let data = serde_json::to_string(&StoredPr::from(pr))?;
+		let merged = pr.merged_at.is_some();
+		tx.execute(RECORD_DEPARTURE, params![repo, id, data, merged, time])?;
+		tx.execute(RECORD_OUTCOME, params![repo, id, data, merged, time])?;
+		remove_pull(tx, repo, id, time)?;
+		return Ok(());
+	}
+	let Some(row) = pull_row(state, repo, pr)? else {
+		tracing::warn!("error during pr update of {repo}#{id}: has no author");
+		return Ok(());
+	};
+	tx.execute(UPSERT_PULL, params_from_iter(row.iter()))?;
+	categorize_pull(tx, state, repo, id, time)?;
+	Ok(())
+}
+
+/// Outcome of an update, returned as JSON if the update was cut short or JSON was requested.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateSummary {
+	pub complete: bool,
+	pub pages_fetched: u32,
+	pub prs_inserted: usize,
+	pub prs_updated: usize,
+	pub prs_removed: usize,
+	/// PRs no longer open on GitHub, found by a full update.
+	pub prs_purged: usize,
+	/// Repositories whose stale PRs were kept because the listing looked incomplete, see `PURGE_GUARD_MIN`.
+	pub purge_skipped: Vec<String>,
+	/// Problems with single PRs, which were skipped.
+	pub errors: Vec<String>,
+	pub duration_ms: u64,
+	/// Remaining GitHub requests at the last check.
+	pub rate_limit_remaining: Option<usize>,
+	/// Repository the update stopped at.
+	pub repo: Option<String>,
+	pub error: Option<String>,
+	/// Repositories whose listing was cut off at `PR_DASHBOARD_MAX_PAGES`, their cursor was kept.
+	pub truncated: Vec<String>,
+	/// Repositories whose cursor was kept because `since` or `max-age` started after it.
+	pub cursor_kept: Vec<String>,
+	/// The update stopped because GitHub (or the proxy in front of it) failed, answered with 502.
+	#[serde(skip)]
+	pub upstream_failed: bool,
+	/// PRs inserted or updated, for `housekeep=true`.
+	#[serde(skip)]
+	pub touched: Vec<(String, i64)>,
+}
+
+/// Response of `/update-prs`, with the categorization of the updated PRs for `housekeep=true`.
+#[derive(Serialize)]
+struct UpdateResponse {
+	#[serde(flatten)]
+	update: UpdateSummary,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	housekeep: Option<HousekeepSummary>,
+}
+
+/// State of the last or running update, streamed by `/update-progress`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateProgress {
+	pub running: bool,
+	/// Repository and page written last.
+	pub current_repo: Option<String>,
+	pub current_page: Option<u32>,
+	#[serde(flatten)]
+	pub summary: UpdateSummary,
+}
+
+impl UpdateProgress {
+	/// Name of the server-sent event carrying this state.
+	pub fn event(&self) -> &'static str {
+		if self.running {
+			"progress"
+		} else if self.summary.error.is_some() {
+			"error"
+		} else if self.summary.complete {
+			"done"
+		} else if !self.summary.truncated.is_empty() {
+			"truncated"
+		} else {
+			"idle"
 		}
-		for pr in prs {
-			let id = pr.number as i64;
-			let updated_at = pr.updated_at.map(|x| x.format(TIME_FORMAT).to_string());
+	}
+}
 
-			if pr.state.as_ref().map(|x| *x == IssueState::Closed).unwrap_or(false) {
-				to_remove.push(id);
-				continue;
-			}
+/// Store one page of fetched PRs and its ETag.
+fn write_page(
+	db: &mut DB,
+	repo: &str,
+	pulls: &[Vec<Option<String>>],
+	departures: &[(i64, String, bool)],
+	etag: Option<(String, String)>,
+	time: &str,
+	summary: &mut UpdateSummary,
+) -> Result<(), Box<dyn Error>> {
+	let tx = db.transaction()?;
+	for data in pulls {
+		let id = data[1].as_deref().unwrap_or_default();
+		let known: Option<String> = tx
+			.query_row(
+				"SELECT state FROM pulls WHERE repo = ?1 AND id = ?2",
+				params![repo, id],
+				|row| row.get(0),
+			)
+			.optional()?;
+		if known.as_deref() == Some("closed") {
+			tracing::info!("update: {repo}#{id} was reopened");
+		}
+		let known = known.as_deref() == Some("open");
+		let result = tx.execute(UPSERT_PULL, params_from_iter(data.iter()));
+		if result.is_ok() {
+			summary.touched.push((repo.to_owned(), id.parse()?));
+		}
+		match result {
+			Ok(_) if known => summary.prs_updated += 1,
+			Ok(_) => summary.prs_inserted += 1,
+			Err(err) => {
+				tracing::warn!("error during pr update of {repo}#{id}: {:?}", err);
+				summary.errors.push(format!("{repo}#{id}: {err}"));
+			},
+		}
+	}
+	tracing::debug!("update: removing {} closed PRs", departures.len());
+	for (id, data, merged) in departures {
+		// remember PRs we tracked before deleting them
+		let res = tx
+			.execute(RECORD_DEPARTURE, params![repo, id, data, merged, time])
+			.and_then(|_| tx.execute(RECORD_OUTCOME, params![repo, id, data, merged, time]))
+			.and_then(|_| remove_pull(&tx, repo, *id, time));
+		match res {
+			Ok(()) => summary.prs_removed += 1,
+			Err(err) => {
+				tracing::warn!("error during pr update of {repo}#{id}: {:?}", err);
+				summary.errors.push(format!("{repo}#{id}: {err}"));
+			},
+		}
+	}
+	// the page is only skipped next time once its content is stored
+	if let Some((key, etag)) = etag {
+		tx.execute(
+			"INSERT INTO sync_state (key, value) VALUES (?1, ?2)
+			ON CONFLICT DO UPDATE SET value = ?2",
+			params![key, etag],
+		)?;
+	}
+	tx.commit()?;
+	Ok(())
+}
 
-			if updated_at
-				.as_ref()
-				.map(|x| last_update.as_ref().map(|y| *x < *y).unwrap_or(false))
-				.unwrap_or(false)
-			{
-				tracing::debug!("update: done, PR was updated {updated_at:?}");
-				break 'pages; // we are done here!
-			}
+/// Remove tracked PRs of `repo` that are not in `live`, returns how many were removed.
+/// Nothing is removed if that would drop too many of them, see `PURGE_GUARD_MIN`.
+fn purge_stale(
+	db: &mut DB,
+	repo: &str,
+	live: &HashSet<i64>,
+	time: &str,
+	summary: &mut UpdateSummary,
+) -> Result<usize, Box<dyn Error>> {
+	let tx = db.transaction()?;
+	let mut query = tx.prepare("SELECT id FROM pulls WHERE repo = ?1 AND state = 'open'")?;
+	let tracked: Vec<i64> = query
+		.query_map(params![repo], |row| row.get(0))?
+		.filter_map(Result::ok)
+		.collect();
+	drop(query);
+	let stale: Vec<i64> = tracked.iter().copied().filter(|id| !live.contains(id)).collect();
+	if stale.len() >= PURGE_GUARD_MIN && stale.len() * 2 > tracked.len() {
+		tracing::warn!(
+			"update: full resync of {repo} would purge {} of {} PRs, not purging",
+			stale.len(),
+			tracked.len()
+		);
+		summary.purge_skipped.push(repo.to_owned());
+		return Ok(0);
+	}
+	let mut purged = 0;
+	for id in stale {
+		match remove_pull(&tx, repo, id, time) {
+			Ok(()) => purged += 1,
+			Err(err) => {
+				tracing::warn!("error during pr update of {repo}#{id}: {:?}", err);
+				summary.errors.push(format!("{repo}#{id}: {err}"));
+			},
+		}
+	}
+	tx.commit()?;
+	summary.prs_purged += purged;
+	Ok(purged)
+}
 
-			let Some(author) = pr.user.as_ref() else {
-				tracing::warn!("error during pr update of {id}: has no author");
-				continue;
-			};
-			let author = author.login.clone();
-			let data = serde_json::to_string(&pr)?;
+/// Start of the fetch window from `?since=` (a timestamp) or `?max-age=` (a duration before now).
+fn fetch_window(params: &HashMap<String, String>) -> Result<Option<DateTime<Utc>>, String> {
+	let since = params.get("since").filter(|x| !x.is_empty());
+	let max_age = params.get("max-age").filter(|x| !x.is_empty());
+	match (since, max_age) {
+		(Some(_), Some(_)) => Err("since and max-age can't be combined".to_owned()),
+		(Some(since), None) => parse_timestamp(since)
+			.map(Some)
+			.ok_or_else(|| format!("invalid since: {since:?}")),
+		(None, Some(max_age)) => parse_duration(max_age)
+			.map(|x| Some(Utc::now() - x))
+			.ok_or_else(|| format!("invalid max-age: {max_age:?}")),
+		(None, None) => Ok(None),
+	}
+}
 
-			pulls.push(vec![Some(id.to_string()), Some(author), updated_at, Some(data)]);
+pub async fn update_prs(
+	State(state): State<AppState>,
+	Query(params): Query<HashMap<String, String>>,
+	headers: HeaderMap,
+) -> Result<Response, AppError> {
+	let since = fetch_window(&params).map_err(|err| AppError::new(StatusCode::BAD_REQUEST, err))?;
+	let update_lock = if params.get("wait").is_some_and(|x| x == "true") {
+		state.lock_update().await
+	} else {
+		match state.try_lock_update().await {
+			Ok(x) => x,
+			Err(busy) => return Ok(busy),
 		}
+	};
+	let full = params.get("full").is_some_and(|x| x == "true");
+	let mut summary = run_update(&state, full, since).await?;
+	// new PRs would stay uncategorized until the next housekeeping pass
+	let housekeep = if params.get("housekeep").is_some_and(|x| x == "true") {
+		Some(categorize_updated(&state, &std::mem::take(&mut summary.touched)).await?)
+	} else {
+		None
+	};
+	drop(update_lock);
+
+	let status = if summary.upstream_failed {
+		StatusCode::BAD_GATEWAY
+	} else {
+		StatusCode::OK
+	};
+	if summary.upstream_failed || !summary.complete || wants_json(&params, &headers) {
+		let response = UpdateResponse {
+			update: summary,
+			housekeep,
+		};
+		return Ok((status, Json(response)).into_response());
 	}
+	Ok("done".into_response())
+}
 
-	let to_delete = Rc::new(
-		to_remove
-			.into_iter()
-			.map(rusqlite::types::Value::from)
-			.collect::<Vec<_>>(),
-	);
-	with_db!(|db: &mut DB| {
-		let tx = db.transaction()?;
-		for data in pulls {
-			let res = tx.execute(
-				"INSERT INTO pulls
-				(id,author,last_updated,data)
-				VALUES (?1,?2,?3,?4) ON CONFLICT DO UPDATE SET
-				author = ?2,
-				last_updated = ?3,
-				data = ?4",
-				params_from_iter(data.iter()),
-			);
-			if let Err(err) = res {
-				tracing::warn!("error during pr update: {:?}", err);
+/// Fetch updated PRs of all repositories. The caller holds the `update_lock`.
+/// A `full` update walks all open PRs and purges tracked PRs that are no longer open,
+/// in case their closure was missed.
+/// With `since`, the update stops at PRs last updated before it instead of at the sync cursor.
+pub async fn run_update(state: &AppState, full: bool, since: Option<DateTime<Utc>>) -> Result<UpdateSummary, AppError> {
+	state.update_progress.send_replace(UpdateProgress {
+		running: true,
+		..Default::default()
+	});
+	let result = fetch_updates(state, full, since).await;
+	let summary = match &result {
+		Ok(summary) => summary.clone(),
+		Err(err) => UpdateSummary {
+			error: Some(err.to_string()),
+			..Default::default()
+		},
+	};
+	state.update_progress.send_modify(|progress| {
+		progress.running = false;
+		progress.summary = summary;
+	});
+	result
+}
+
+async fn fetch_updates(state: &AppState, full: bool, since: Option<DateTime<Utc>>) -> Result<UpdateSummary, AppError> {
+	let started = Instant::now();
+	// taken before fetching, the stored data is at least as recent as this
+	let sync_time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	let since = since.map(|x| x.format(UTC_TIME_FORMAT).to_string());
+
+	let mut summary = UpdateSummary::default();
+	for repo in state.repos.iter() {
+		let cursor = with_db!(state, |db: &mut DB| db.last_update(repo))?;
+		// a window starting after the cursor leaves a gap, which the next run still has to fetch
+		let covers_cursor = match (&since, &cursor) {
+			(None, _) => true,
+			(Some(since), Some(cursor)) => since <= cursor,
+			(Some(_), None) => false,
+		};
+		let last_update = since.clone().or(cursor);
+		let mut client = state.gh.pick().await;
+		tracing::debug!("update: using GitHub client {} for {repo}", client.index);
+
+		// if we already have some data, we need to catch and remov eclosed PRs too
+		let pr_state = if last_update.is_some() && !full { "all" } else { "open" };
+		let mut live = HashSet::new();
+		let mut truncated = false;
+
+		for page in 1..=state.max_pages {
+			// stop before the budget runs out, keeping what was fetched so far
+			match state.rate_limit.check(&client.gh).await {
+				Ok(remaining) => summary.rate_limit_remaining = remaining.or(summary.rate_limit_remaining),
+				Err(err) => {
+					summary.repo = Some(repo.clone());
+					summary.error = Some(err);
+					summary.duration_ms = started.elapsed().as_millis() as u64;
+					return Ok(summary);
+				},
+			}
+
+			let etag_key = listing_etag_key(repo, pr_state, page);
+			// an unchanged page says nothing about the PRs of another window
+			let etag = if full || since.is_some() {
+				None
+			} else {
+				with_db!(state, |db: &mut DB| db.sync_state(&etag_key))?
+			};
+			let etag = etag.as_deref();
+			let result = state
+				.gh
+				.with_retry(state.github_retries, &mut client, |gh| async move {
+					github::list_pulls_page(&gh, repo, pr_state, page, state.page_size, etag).await
+				})
+				.await;
+			let (prs, etag) = match result {
+				// the listing is sorted by update time, so nothing on this page or after it changed
+				Ok(None) => {
+					tracing::debug!("update: done with {repo}, page {page} is unchanged");
+					break;
+				},
+				Ok(Some(x)) => x,
+				Err(err) => {
+					tracing::warn!("update: failed to load page {page} of {repo}: {err}");
+					if let Some(sample) = err.sample.as_deref() {
+						tracing::warn!("update: decode error sample: {sample}");
+					}
+					// the pages written so far are kept, the cursor stays so the next run fetches the rest
+					summary.repo = Some(repo.clone());
+					summary.error = Some(err.to_string());
+					summary.upstream_failed = true;
+					summary.duration_ms = started.elapsed().as_millis() as u64;
+					return Ok(summary);
+				},
+			};
+			tracing::debug!("update: loading page {page} of {repo}");
+			if prs.items.is_empty() {
+				break;
+			}
+			summary.pages_fetched += 1;
+			if full {
+				tracing::info!(
+					"update: full resync of {repo}, page {page} with {} PRs",
+					prs.items.len()
+				);
+			}
+
+			let mut pulls = vec![];
+			let mut departures = vec![];
+			let mut done = false;
+			for pr in prs {
+				let id = pr.number as i64;
+				let updated_at = pr.updated_at.map(|x| x.format(UTC_TIME_FORMAT).to_string());
+
+				if pr.state.as_ref().map(|x| *x == IssueState::Closed).unwrap_or(false) {
+					departures.push((id, serde_json::to_string(&StoredPr::from(&pr))?, pr.merged_at.is_some()));
+					continue;
+				}
+
+				live.insert(id);
+				if updated_at.is_none() {
+					// can't tell whether it is older than the last update, so store it and keep going
+					tracing::warn!("update: {repo}#{id} has no update time");
+				}
+				if !full
+					&& updated_at
+						.as_ref()
+						.map(|x| last_update.as_ref().map(|y| *x < *y).unwrap_or(false))
+						.unwrap_or(false)
+				{
+					tracing::debug!("update: done with {repo}, PR was updated {updated_at:?}");
+					done = true; // we are done here!
+					break;
+				}
+
+				let Some(row) = pull_row(state, repo, &pr)? else {
+					tracing::warn!("error during pr update of {repo}#{id}: has no author");
+					summary.errors.push(format!("{repo}#{id}: has no author"));
+					continue;
+				};
+				pulls.push(row);
+			}
+			let etag = etag.map(|x| (etag_key, x));
+			with_db!(state, |db: &mut DB| write_page(
+				db,
+				repo,
+				&pulls,
+				&departures,
+				etag,
+				&sync_time,
+				&mut summary
+			))?;
+			state.update_progress.send_replace(UpdateProgress {
+				running: true,
+				current_repo: Some(repo.clone()),
+				current_page: Some(page),
+				summary: summary.clone(),
+			});
+			if done {
+				break;
+			}
+			if page == state.max_pages {
+				tracing::warn!("update: stopping {repo} after {page} pages, PR_DASHBOARD_MAX_PAGES reached");
+				truncated = true;
 			}
 		}
-		tracing::debug!("update: removing {} closed PRs", to_delete.len());
-		let res = tx.execute(
-			"DELETE FROM pulls
-			WHERE id IN rarray(?1)",
-			params![to_delete],
-		);
-		if let Err(err) = res {
-			tracing::warn!("error during pr update: {:?}", err);
+
+		if truncated {
+			// the pages fetched so far are stored, but the unseen rest must not be skipped by the next run
+			// or purged as stale
+			summary.truncated.push(repo.clone());
+			continue;
 		}
-		let res = tx.commit();
-		if let Err(err) = res {
-			tracing::warn!("error during pr update: {:?}", err);
+		if full && live.is_empty() {
+			// more likely an API hiccup than a repository without open PRs
+			tracing::warn!("update: full resync of {repo} found no open PRs, not purging");
+			summary.purge_skipped.push(repo.clone());
+		} else if full {
+			let purged = with_db!(state, |db: &mut DB| purge_stale(
+				db,
+				repo,
+				&live,
+				&sync_time,
+				&mut summary
+			))?;
+			tracing::info!("update: full resync of {repo} purged {purged} stale PRs");
+		}
+
+		if !covers_cursor && !full {
+			tracing::info!("update: {repo} was fetched since {since:?} only, keeping its cursor");
+			summary.cursor_kept.push(repo.clone());
+			continue;
 		}
+		// the next run only needs PRs updated since this one started
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			tx.execute(
+				"INSERT INTO sync_state (key, value) VALUES (?1, ?2)
+				ON CONFLICT DO UPDATE SET value = ?2",
+				params![sync_cursor_key(repo), sync_time],
+			)?;
+			tx.commit()?;
+			Ok(())
+		})?;
+	}
+
+	summary.duration_ms = started.elapsed().as_millis() as u64;
+	if !summary.truncated.is_empty() {
+		tracing::warn!("update: truncated listing of {:?}", summary.truncated);
+		return Ok(summary);
+	}
+	if !summary.cursor_kept.is_empty() {
+		// older changes may still be missing, so this doesn't count as a successful update
+		tracing::info!("update: kept the cursor of {:?}", summary.cursor_kept);
+		summary.complete = true;
+		return Ok(summary);
+	}
+
+	with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		tx.execute(
+			"INSERT INTO sync_state (key, value) VALUES ('last_success', ?1)
+			ON CONFLICT DO UPDATE SET value = ?1",
+			params![sync_time],
+		)?;
+		tx.commit()?;
 		Ok(())
 	})?;
 
-	drop(update_lock);
+	summary.complete = true;
+	summary.duration_ms = started.elapsed().as_millis() as u64;
+	tracing::info!(
+		"update: {} pages, {} new, {} updated, {} removed PRs, {} errors in {} ms",
+		summary.pages_fetched,
+		summary.prs_inserted,
+		summary.prs_updated,
+		summary.prs_removed,
+		summary.errors.len(),
+		summary.duration_ms
+	);
+	Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		database::{CommonQueries, DB},
+		extract_row,
+		tests::{pull_json, serve_state, FakeGithub},
+	};
+
+	const EARLY: &str = "2024-01-01T00:00:00Z";
+	const LATE: &str = "2024-01-02T00:00:00Z";
 
-	Ok("done")
+	/// State and reservation outcome of each tracked PR, by number.
+	async fn tracked(state: &AppState) -> Vec<(i64, String, Option<String>)> {
+		with_db!(state, |db: &mut DB| {
+			let tx = db.transaction()?;
+			assert!(tx.check_consistency()?.is_consistent());
+			let mut stmt = tx.prepare(
+				"SELECT id, state, (SELECT outcome FROM reservation_log WHERE repo = pulls.repo AND pull_id = id)
+				FROM pulls ORDER BY id",
+			)?;
+			let rows = stmt
+				.query_map([], extract_row!(i64 String Option<String>))?
+				.collect::<Result<_, _>>()?;
+			Ok(rows)
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn body_is_cut_at_char_boundary() {
+		assert_eq!(truncate_body("short"), "short");
+		let ascii = "a".repeat(MAX_BODY_LENGTH + 10);
+		assert_eq!(truncate_body(&ascii).len(), MAX_BODY_LENGTH);
+		// `ä` is two bytes, `🦀` four, so the limit falls inside a character
+		let body = format!("a{}", "ä".repeat(MAX_BODY_LENGTH));
+		assert_eq!(truncate_body(&body).len(), MAX_BODY_LENGTH - 1);
+		let body = format!("ab{}", "🦀".repeat(MAX_BODY_LENGTH));
+		assert_eq!(truncate_body(&body).len(), MAX_BODY_LENGTH - 2);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn fetch_window_skips_etags() {
+		let github = FakeGithub::new(vec![
+			pull_json(1, "open", "2024-01-01T00:00:00Z", &[]),
+			pull_json(2, "open", "2024-01-02T00:00:00Z", &[]),
+		]);
+		let state = github.serve().await;
+		// the first update lists open PRs, the ones after it all PRs and store their ETag
+		for _ in 0..2 {
+			let summary = run_update(&state, false, None).await.unwrap();
+			assert!(summary.complete, "{summary:?}");
+		}
+		let summary = run_update(&state, false, None).await.unwrap();
+		assert_eq!(summary.pages_fetched, 0, "{summary:?}");
+		let first_page = |github: &FakeGithub| github.listings.lock().unwrap().iter().rfind(|x| x.0 == 1).cloned();
+		assert!(first_page(&github).unwrap().1.is_some());
+
+		let since = parse_timestamp("2023-12-01T00:00:00Z");
+		let summary = run_update(&state, false, since).await.unwrap();
+		assert_eq!(summary.pages_fetched, 1, "{summary:?}");
+		assert_eq!(summary.prs_updated, 2, "{summary:?}");
+		assert_eq!(first_page(&github).unwrap().1, None);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn overlapping_update_is_rejected() {
+		let github = FakeGithub::new(vec![
+			pull_json(1, "open", "2024-01-01T00:00:00Z", &[]),
+			pull_json(2, "open", "2024-01-02T00:00:00Z", &[]),
+		]);
+		let state = github.serve().await;
+		let update = || update_prs(State(state.clone()), Query(HashMap::new()), HeaderMap::new());
+
+		// the first update stalls on the listing until the gate opens
+		let gate = github.gate.lock().await;
+		let (first, second) = tokio::join!(update(), async {
+			while state.update_started.lock().unwrap().is_none() {
+				tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+			}
+			let second = update().await;
+			drop(gate);
+			second
+		});
+		assert_eq!(first.unwrap().status(), StatusCode::OK);
+		let second = second.unwrap();
+		assert_eq!(second.status(), StatusCode::CONFLICT);
+		let body = axum::body::to_bytes(second.into_body(), usize::MAX).await.unwrap();
+		let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+		assert_eq!(body["error"], "an update is already in progress");
+		assert!(state.update_started.lock().unwrap().is_none());
+
+		let (open, report) = state
+			.db
+			.run(|db: &mut DB| {
+				let tx = db.transaction()?;
+				let open = tx.query_row("SELECT count(*) FROM pulls WHERE state = 'open'", [], |row| {
+					row.get::<_, usize>(0)
+				})?;
+				Ok((open, tx.check_consistency()?))
+			})
+			.await
+			.unwrap();
+		assert_eq!(open, 2);
+		assert!(report.is_consistent());
+		assert!(state.freshness().await.unwrap().caught_up);
+		// the lock is free again
+		assert_eq!(update().await.unwrap().status(), StatusCode::OK);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn closed_and_removed_prs_lose_their_reservations() {
+		let github = FakeGithub::new((1..=3).map(|id| pull_json(id, "open", EARLY, &[])).collect());
+		let state = github.serve().await;
+		run_update(&state, false, None).await.unwrap();
+		let base = serve_state(state.clone()).await;
+		let client = reqwest::Client::new();
+		for id in [1, 2, 3] {
+			let response = client
+				.post(format!("{base}/reserve-pr?pr={id}&as=alice"))
+				.send()
+				.await
+				.unwrap();
+			assert_eq!(response.status(), StatusCode::OK);
+		}
+
+		// PR 1 is merged, PR 2 vanishes without a trace in the listing of all PRs
+		{
+			let mut pulls = github.pulls.lock().unwrap();
+			pulls[0] = pull_json(1, "closed", LATE, &[]);
+			pulls[0]["merged_at"] = LATE.into();
+			pulls.remove(1);
+		}
+		let summary = run_update(&state, false, None).await.unwrap();
+		assert_eq!((summary.prs_removed, summary.prs_purged), (1, 0), "{summary:?}");
+		assert_eq!(
+			tracked(&state).await,
+			[
+				(1, "closed".to_owned(), Some("closed".to_owned())),
+				(2, "open".to_owned(), None),
+				(3, "open".to_owned(), None),
+			]
+		);
+
+		// only a full update notices PR 2 is gone
+		let summary = run_update(&state, true, None).await.unwrap();
+		assert_eq!((summary.prs_removed, summary.prs_purged), (0, 1), "{summary:?}");
+		assert_eq!(
+			tracked(&state).await,
+			[
+				(1, "closed".to_owned(), Some("closed".to_owned())),
+				(2, "closed".to_owned(), Some("closed".to_owned())),
+				(3, "open".to_owned(), None),
+			]
+		);
+		let reservations = with_db!(state, |db: &mut DB| {
+			Ok(db
+				.transaction()?
+				.query_row("SELECT group_concat(id) FROM reservations", [], |row| {
+					row.get::<_, String>(0)
+				})?)
+		})
+		.unwrap();
+		assert_eq!(reservations, "3");
+	}
+
+	/// The cursor is the start of the update, not the update time of the newest listed PR,
+	/// so a closed PR at the top of the listing doesn't hold it back or push it ahead.
+	#[tokio::test(flavor = "multi_thread")]
+	async fn cursor_moves_on_when_the_newest_pr_closed() {
+		let github = FakeGithub::new(vec![pull_json(1, "open", EARLY, &[]), pull_json(2, "open", LATE, &[])]);
+		let state = github.serve().await;
+		let cursor = || async {
+			with_db!(state, |db: &mut DB| db.last_update("NixOS/nixpkgs"))
+				.unwrap()
+				.unwrap()
+		};
+		run_update(&state, false, None).await.unwrap();
+		let first = cursor().await;
+
+		// closed after the first update
+		let closed = (Utc::now() + chrono::Duration::minutes(1))
+			.format(UTC_TIME_FORMAT)
+			.to_string();
+		github.pulls.lock().unwrap()[1] = pull_json(2, "closed", &closed, &[]);
+		let started = Utc::now().format(UTC_TIME_FORMAT).to_string();
+		let summary = run_update(&state, false, None).await.unwrap();
+		assert_eq!(
+			(summary.prs_removed, summary.prs_updated, summary.prs_inserted),
+			(1, 0, 0),
+			"{summary:?}"
+		);
+		let second = cursor().await;
+		assert!(
+			second >= started && second >= first && second < closed,
+			"{first} {second}"
+		);
+
+		// nothing changed since
+		let summary = run_update(&state, false, None).await.unwrap();
+		assert_eq!((summary.pages_fetched, summary.prs_removed), (0, 0), "{summary:?}");
+		assert!(cursor().await >= second);
+		assert_eq!(
+			tracked(&state).await,
+			[(1, "open".to_owned(), None), (2, "closed".to_owned(), None)]
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn full_update_purges_only_a_few() {
+		let github = FakeGithub::new((1..=30).map(|id| pull_json(id, "open", EARLY, &[])).collect());
+		let state = github.serve().await;
+		run_update(&state, true, None).await.unwrap();
+		let open = || async {
+			let tracked = tracked(&state).await;
+			tracked.iter().filter(|x| x.1 == "open").count()
+		};
+		assert_eq!(open().await, 30);
+
+		// the listing only has 10 PRs left
+		github.pulls.lock().unwrap().truncate(10);
+		let summary = run_update(&state, true, None).await.unwrap();
+		assert_eq!(summary.prs_purged, 0, "{summary:?}");
+		assert_eq!(summary.purge_skipped, ["NixOS/nixpkgs"]);
+		assert_eq!(open().await, 30);
+
+		// a few missed closures
+		github
+			.pulls
+			.lock()
+			.unwrap()
+			.extend((11..=25).map(|id| pull_json(id, "open", EARLY, &[])));
+		let summary = run_update(&state, true, None).await.unwrap();
+		assert_eq!(summary.prs_purged, 5, "{summary:?}");
+		assert!(summary.purge_skipped.is_empty());
+		assert_eq!(open().await, 25);
+
+		github.pulls.lock().unwrap().truncate(16);
+		let summary = run_update(&state, true, None).await.unwrap();
+		assert_eq!(summary.prs_purged, 9, "{summary:?}");
+		assert_eq!(open().await, 16);
+		// below the minimum, a small repository can lose most of its PRs
+		github.pulls.lock().unwrap().truncate(7);
+		let summary = run_update(&state, true, None).await.unwrap();
+		assert_eq!(summary.prs_purged, 9, "{summary:?}");
+		assert_eq!(open().await, 7);
+
+		// an empty listing purges nothing either
+		github.pulls.lock().unwrap().clear();
+		let summary = run_update(&state, true, None).await.unwrap();
+		assert_eq!(summary.prs_purged, 0, "{summary:?}");
+		assert_eq!(summary.purge_skipped, ["NixOS/nixpkgs"]);
+		assert_eq!(open().await, 7);
+	}
 }
@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, error::Error};
+
+use axum::{extract::State, Json};
+use chrono::Utc;
+use octocrab::models::pulls::{Review, ReviewState};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{categorize_pull, database::DB, github::GithubErrorKind, with_db, AppError, AppState, UTC_TIME_FORMAT};
+
+#[derive(Default, Serialize)]
+pub struct ReviewUpdate {
+	prs_checked: usize,
+	reviews_stored: usize,
+	/// Remaining GitHub requests at the last check.
+	rate_limit_remaining: Option<usize>,
+	/// Problems with single PRs, which are retried next time.
+	errors: Vec<String>,
+	/// Why the update stopped early.
+	error: Option<String>,
+}
+
+/// A reviewer's latest verdict.
+struct LatestReview {
+	state: &'static str,
+	submitted_at: Option<String>,
+	/// The commit the review was submitted on.
+	commit_id: Option<String>,
+}
+
+/// Latest verdict per reviewer. Comments do not replace an earlier verdict.
+fn latest_reviews(reviews: &[Review]) -> BTreeMap<String, LatestReview> {
+	let mut reviews: Vec<_> = reviews.iter().collect();
+	reviews.sort_by_key(|x| x.submitted_at);
+	let mut latest = BTreeMap::new();
+	for review in reviews {
+		let (Some(user), Some(state)) = (&review.user, &review.state) else {
+			continue;
+		};
+		let state = match state {
+			ReviewState::Approved => "APPROVED",
+			ReviewState::ChangesRequested => "CHANGES_REQUESTED",
+			ReviewState::Dismissed => "DISMISSED",
+			ReviewState::Commented => "COMMENTED",
+			_ => continue,
+		};
+		if state == "COMMENTED" && latest.contains_key(&user.login) {
+			continue;
+		}
+		let submitted_at = review.submitted_at.map(|x| x.format(UTC_TIME_FORMAT).to_string());
+		latest.insert(
+			user.login.clone(),
+			LatestReview {
+				state,
+				submitted_at,
+				commit_id: review.commit_id.clone(),
+			},
+		);
+	}
+	latest
+}
+
+/// Replace the stored reviews of a PR and recategorize it.
+fn store_reviews(
+	db: &mut DB,
+	state: &AppState,
+	repo: &str,
+	id: u64,
+	last_updated: &str,
+	reviews: &BTreeMap<String, LatestReview>,
+	time: &str,
+) -> Result<(), Box<dyn Error>> {
+	let tx = db.transaction()?;
+	tx.execute(
+		"DELETE FROM pull_reviews WHERE repo = ?1 AND pull_id = ?2",
+		params![repo, id],
+	)?;
+	for (reviewer, review) in reviews {
+		tx.execute(
+			"INSERT INTO pull_reviews (repo, pull_id, reviewer, state, submitted_at, commit_id)
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+			params![repo, id, reviewer, review.state, review.submitted_at, review.commit_id],
+		)?;
+	}
+	tx.execute(
+		"UPDATE pulls SET reviews_synced = ?3 WHERE repo = ?1 AND id = ?2",
+		params![repo, id, last_updated],
+	)?;
+	categorize_pull(&tx, state, repo, id as i64, time)?;
+	tx.commit()?;
+	Ok(())
+}
+
+/// Fetch the reviews of PRs updated since their reviews were last fetched.
+/// This costs one request per PR, so it stops at the rate limit floor.
+pub async fn update_reviews(State(state): State<AppState>) -> Result<Json<ReviewUpdate>, AppError> {
+	let update_lock = state.lock_update().await;
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+
+	let pending = with_db!(state, |db: &mut DB| db.pulls_with_stale_reviews())?;
+	let mut summary = ReviewUpdate::default();
+	let mut client = state.gh.pick().await;
+	tracing::debug!("reviews: using GitHub client {}", client.index);
+	for (repo, id, last_updated) in pending {
+		match state.rate_limit.check(&client.gh).await {
+			Ok(remaining) => summary.rate_limit_remaining = remaining.or(summary.rate_limit_remaining),
+			Err(err) => {
+				summary.error = Some(err);
+				break;
+			},
+		}
+		let (owner, name) = repo.split_once('/').expect("repositories are validated on startup");
+		let result = state
+			.gh
+			.with_retry(state.github_retries, &mut client, |gh| async move {
+				let page = gh.pulls(owner, name).list_reviews(id).per_page(100).send().await?;
+				gh.all_pages(page).await
+			})
+			.await;
+		let reviews = match result {
+			Ok(x) => x,
+			Err(err) => {
+				tracing::warn!("reviews: failed to load reviews of {repo}#{id}: {err}");
+				if err.kind == GithubErrorKind::Auth {
+					summary.error = Some(err.to_string());
+					break;
+				}
+				summary.errors.push(format!("{repo}#{id}: {err}"));
+				continue;
+			},
+		};
+		let latest = latest_reviews(&reviews);
+		with_db!(state, |db: &mut DB| store_reviews(
+			db,
+			&state,
+			&repo,
+			id,
+			&last_updated,
+			&latest,
+			&time
+		))?;
+		summary.prs_checked += 1;
+		summary.reviews_stored += latest.len();
+	}
+	drop(update_lock);
+
+	tracing::info!(
+		"reviews: checked {} PRs, stored {} reviews",
+		summary.prs_checked,
+		summary.reviews_stored
+	);
+	Ok(Json(summary))
+}
@@ -0,0 +1,90 @@
+use axum::{
+	body::Bytes,
+	extract::State,
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use octocrab::models::pulls::PullRequest;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{database::DB, store_pull, with_db, AppError, AppState, UTC_TIME_FORMAT};
+
+#[derive(Deserialize)]
+struct Repository {
+	full_name: String,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+	pull_request: PullRequest,
+	repository: Repository,
+}
+
+/// Check the `X-Hub-Signature-256` header against the HMAC of the body.
+fn signature_valid(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+	let Some(signature) = headers
+		.get("X-Hub-Signature-256")
+		.and_then(|x| x.to_str().ok())
+		.and_then(|x| x.strip_prefix("sha256="))
+		.and_then(|x| hex::decode(x).ok())
+	else {
+		return false;
+	};
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+	mac.update(body);
+	mac.verify_slice(&signature).is_ok()
+}
+
+/// Apply a single `pull_request` or `pull_request_review` event from GitHub.
+pub async fn webhook(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Result<Response, AppError> {
+	let Some(secret) = state.webhook_secret.as_deref() else {
+		return Ok((StatusCode::NOT_FOUND, "webhook not configured").into_response());
+	};
+	if !signature_valid(secret, &headers, &body) {
+		return Ok((StatusCode::UNAUTHORIZED, "invalid signature").into_response());
+	}
+	let event = headers
+		.get("X-GitHub-Event")
+		.and_then(|x| x.to_str().ok())
+		.unwrap_or("");
+	if event != "pull_request" && event != "pull_request_review" {
+		return Ok((StatusCode::OK, "ignored").into_response());
+	}
+	let payload: Payload = match serde_json::from_slice(&body) {
+		Ok(x) => x,
+		Err(err) => return Ok((StatusCode::BAD_REQUEST, format!("malformed payload: {err}")).into_response()),
+	};
+	let repo = payload.repository.full_name;
+	if !state.repos.contains(&repo) {
+		return Ok((StatusCode::OK, "ignored").into_response());
+	}
+	// a redelivery may carry older data than what was applied since
+	let delivery = headers
+		.get("X-GitHub-Delivery")
+		.and_then(|x| x.to_str().ok())
+		.map(|x| x.to_owned());
+	if delivery
+		.as_ref()
+		.is_some_and(|x| state.caches.webhook_deliveries.contains(x))
+	{
+		return Ok((StatusCode::OK, "already applied").into_response());
+	}
+	let pr = payload.pull_request;
+
+	let _lock = state.update_lock.lock().await;
+	let time = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	with_db!(state, |db: &mut DB| {
+		let tx = db.transaction()?;
+		store_pull(&tx, &state, &repo, &pr, &time)?;
+		tx.commit()?;
+		Ok(())
+	})?;
+	if let Some(delivery) = delivery {
+		state.caches.webhook_deliveries.insert(delivery, ());
+	}
+	tracing::debug!("webhook: applied {repo}#{}", pr.number);
+	Ok((StatusCode::OK, "ok").into_response())
+}
@@ -0,0 +1,128 @@
+use std::{env, sync::atomic::Ordering, time::Duration};
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+	github, notify, parse_duration, run_expiry, run_housekeep, run_update, AppState, HousekeepScope, UpdateSummary,
+	UTC_TIME_FORMAT,
+};
+
+/// Longest random delay before the first scheduled run.
+const MAX_STAGGER: Duration = Duration::from_secs(10);
+/// Interval of ending expired reservations, much shorter than the update interval.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Result of the last run of the built-in scheduler.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledRun {
+	pub started: String,
+	pub finished: String,
+	/// `None` if the update failed before producing a summary.
+	pub update: Option<UpdateSummary>,
+	/// Errors of the update and the housekeeping, each step runs even if the other failed.
+	pub errors: Vec<String>,
+}
+
+/// Interval from `PR_DASHBOARD_UPDATE_INTERVAL` (like `15m`), the scheduler is disabled without it.
+pub fn interval_from_env() -> Option<Duration> {
+	let interval = env::var("PR_DASHBOARD_UPDATE_INTERVAL")
+		.ok()
+		.filter(|x| !x.is_empty())?;
+	let interval = parse_duration(&interval)
+		.and_then(|x| x.to_std().ok())
+		.filter(|x| !x.is_zero())
+		.expect("invalid PR_DASHBOARD_UPDATE_INTERVAL");
+	Some(interval)
+}
+
+/// Run updates and housekeeping in the background, instead of external cron jobs.
+/// Expired reservations are ended every `EXPIRY_INTERVAL` in between.
+pub fn spawn(state: AppState, interval: Duration) {
+	let expiry_state = state.clone();
+	tokio::spawn(async move {
+		// replicas started together should not all sync at the same time
+		tokio::time::sleep(MAX_STAGGER.mul_f64(github::jitter())).await;
+		let mut ticker = tokio::time::interval(interval);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			run_and_record(&state).await;
+		}
+	});
+	tokio::spawn(async move {
+		let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			match run_expiry(&expiry_state).await {
+				Ok((_, notifications)) => notify::send(&expiry_state.http, notifications),
+				Err(err) => tracing::warn!("scheduler: ending expired reservations failed: {err}"),
+			}
+		}
+	});
+}
+
+/// Populate an empty or stale database in the background after startup,
+/// enabled by `PR_DASHBOARD_UPDATE_ON_START`.
+pub fn spawn_initial_sync(state: AppState) {
+	state.initial_sync.store(true, Ordering::Relaxed);
+	tokio::spawn(async move {
+		tracing::info!("scheduler: initial sync started");
+		run_and_record(&state).await;
+		state.initial_sync.store(false, Ordering::Relaxed);
+	});
+}
+
+async fn run_and_record(state: &AppState) {
+	let started = Utc::now().format(UTC_TIME_FORMAT).to_string();
+	// in a separate task, so a panic only ends this run
+	let (update, errors) = match tokio::spawn(run_once(state.clone())).await {
+		Ok(x) => x,
+		Err(err) => (None, vec![format!("scheduled run panicked: {err}")]),
+	};
+	let run = ScheduledRun {
+		started,
+		finished: Utc::now().format(UTC_TIME_FORMAT).to_string(),
+		update,
+		errors,
+	};
+	*state.last_scheduled_run.write().await = Some(run);
+}
+
+async fn run_once(state: AppState) -> (Option<UpdateSummary>, Vec<String>) {
+	let update_lock = state.lock_update().await;
+	let mut errors = vec![];
+	let update = match run_update(&state, false, None).await {
+		Ok(summary) => {
+			if let Some(err) = &summary.error {
+				errors.push(format!(
+					"update stopped at {}: {err}",
+					summary.repo.as_deref().unwrap_or("?")
+				));
+			}
+			Some(summary)
+		},
+		Err(err) => {
+			errors.push(format!("update failed: {err}"));
+			None
+		},
+	};
+	let notifications = match run_housekeep(&state, false, &HousekeepScope::full()).await {
+		Ok(x) => x.notifications,
+		Err(err) => {
+			errors.push(format!("housekeeping failed: {err}"));
+			vec![]
+		},
+	};
+	drop(update_lock);
+	notify::send(&state.http, notifications);
+
+	if errors.is_empty() {
+		tracing::info!("scheduler: update and housekeeping done");
+	} else {
+		tracing::warn!("scheduler: run finished with errors: {errors:?}");
+	}
+	(update, errors)
+}